@@ -0,0 +1,105 @@
+//! Helpers for the build scripts of [hyperlight](https://github.com/hyperlight-dev/hyperlight)
+//! guests built with `cargo hyperlight`.
+//!
+//! `cargo hyperlight` passes information about the guest toolchain to build scripts
+//! through environment variables. The functions in this crate read those variables,
+//! so that build scripts don't need to parse them by hand.
+//!
+//! # Re-running the build script
+//!
+//! Reading the variables doesn't print any `cargo:rerun-if-env-changed` directive.
+//! Call [`emit_rerun_if_env_changed`] to re-run the build script when they change.
+//!
+//! Note that once a build script prints any `rerun-if` directive, cargo stops
+//! re-running it when any file of the package changes, and only re-runs it when
+//! one of the printed conditions changes. Build scripts calling
+//! [`emit_rerun_if_env_changed`] should print `cargo:rerun-if-changed` for the
+//! files they read as well, e.g., their C sources.
+//!
+//! # `#[cfg(hyperlight)]`
+//!
+//! `cargo hyperlight` passes `--cfg hyperlight` to guest builds itself (unless
+//! `emit-cfg = false` is set in `[package.metadata.hyperlight]`), so build scripts
+//! don't need to emit it.
+//!
+//! # Examples
+//!
+//! In a guest's `build.rs`:
+//!
+//! ```rust,no_run
+//! hyperlight_build::emit_rerun_if_env_changed();
+//! println!("cargo:rerun-if-changed=src/foo.c");
+//!
+//! if hyperlight_build::is_hyperlight_target() {
+//!     hyperlight_build::emit_link_search();
+//!     for dir in hyperlight_build::include_dirs() {
+//!         println!("include dir: {}", dir.display());
+//!     }
+//! }
+//! ```
+
+use std::env;
+use std::path::PathBuf;
+
+/// Names of the environment variables set by `cargo hyperlight`.
+#[doc(hidden)]
+pub mod vars {
+    /// Path of the hyperlight sysroot
+    pub const SYSROOT: &str = "CARGO_HYPERLIGHT_SYSROOT";
+    /// Directories with the C headers for the guest, in the format of `PATH`
+    pub const INCLUDE_DIRS: &str = "CARGO_HYPERLIGHT_INCLUDE_DIRS";
+    /// Directory with the static libraries of the sysroot
+    pub const LIB_DIR: &str = "CARGO_HYPERLIGHT_LIB_DIR";
+}
+
+/// Reads `key`, treating an empty value as unset.
+fn var(key: &str) -> Option<PathBuf> {
+    env::var_os(key)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Returns whether the build script is building for a hyperlight target.
+pub fn is_hyperlight_target() -> bool {
+    env::var("TARGET").is_ok_and(|target| target.ends_with("-hyperlight-none"))
+}
+
+/// Returns the path of the hyperlight sysroot, if building with `cargo hyperlight`.
+pub fn sysroot() -> Option<PathBuf> {
+    var(vars::SYSROOT)
+}
+
+/// Returns the directories with the C headers for the guest, in order of precedence.
+///
+/// This includes the directories from `include-dirs` and `--include-dir`, followed by
+/// the headers from `hyperlight-guest-bin`.
+/// The list is empty if not building with `cargo hyperlight`.
+pub fn include_dirs() -> Vec<PathBuf> {
+    var(vars::INCLUDE_DIRS)
+        .map(|dirs| env::split_paths(&dirs).collect())
+        .unwrap_or_default()
+}
+
+/// Returns the directory with the static libraries of the sysroot, e.g., the ones from
+/// `static-libs`, if building with `cargo hyperlight`.
+pub fn lib_dir() -> Option<PathBuf> {
+    var(vars::LIB_DIR)
+}
+
+/// Adds the sysroot's static libraries directory to the linker search path.
+pub fn emit_link_search() {
+    if let Some(dir) = lib_dir() {
+        println!("cargo:rustc-link-search=native={}", dir.display());
+    }
+}
+
+/// Tells cargo to re-run the build script when any of the variables set by
+/// `cargo hyperlight` changes, e.g., when building with a different sysroot.
+///
+/// This disables cargo's default of re-running the build script when any file of the
+/// package changes, see the [crate documentation](crate#re-running-the-build-script).
+pub fn emit_rerun_if_env_changed() {
+    for key in [vars::SYSROOT, vars::INCLUDE_DIRS, vars::LIB_DIR] {
+        println!("cargo:rerun-if-env-changed={key}");
+    }
+}
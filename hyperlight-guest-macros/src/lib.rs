@@ -0,0 +1,317 @@
+//! Attribute macros for the guests of [hyperlight](https://github.com/hyperlight-dev/hyperlight)
+//! built with `cargo hyperlight`.
+//!
+//! Guest functions are registered at runtime by name, together with their parameter
+//! and return types, and a function that unpacks the parameters of the `FunctionCall`.
+//! [`macro@guest_function`] generates that code from the signature of a plain Rust
+//! function, instead of parsing the `FunctionCall` by hand.
+//!
+//! The generated code uses `hyperlight-common`, `hyperlight-guest` and
+//! `hyperlight-guest-bin`, and the `alloc` crate, so the guest must depend on them,
+//! and have an `extern crate alloc;`.
+//!
+//! # Checking the interface
+//!
+//! `cargo hyperlight check-interface` finds the `#[guest_function]` attributes in the
+//! sources of a guest, and compares their names and signatures with the
+//! `[package.metadata.hyperlight.interface]` table of its manifest.
+//!
+//! # Examples
+//!
+//! In a guest's `src/main.rs`:
+//!
+//! ```rust,ignore
+//! use hyperlight_guest::error::Result;
+//! use hyperlight_guest_macros::guest_function;
+//!
+//! #[guest_function("PrintOutput")]
+//! fn print_output(message: String) -> Result<i32> {
+//!     // ...
+//!     Ok(message.len() as i32)
+//! }
+//!
+//! #[guest_function]
+//! fn add(a: i32, b: i32) -> i32 {
+//!     a + b
+//! }
+//!
+//! #[unsafe(no_mangle)]
+//! pub extern "C" fn hyperlight_main() {
+//!     print_output::register();
+//!     add::register();
+//! }
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    Error, FnArg, GenericArgument, ItemFn, LitStr, PathArguments, PathSegment, ReturnType, Type,
+    parse_macro_input,
+};
+
+/// Rust types of the parameters of guest functions, for error messages.
+const PARAMETER_TYPES: &str = "i32, u32, i64, u64, f32, f64, String, bool, or Vec<u8>";
+
+/// Registers a function as a hyperlight guest function.
+///
+/// The function is registered with its own name, or with the name given to the
+/// attribute, e.g., `#[guest_function("PrintOutput")]`.
+/// Its parameters must be `i32`, `u32`, `i64`, `u64`, `f32`, `f64`, `String`, `bool`
+/// or `Vec<u8>`, and it can return any of those, or nothing, optionally wrapped in
+/// `hyperlight_guest::error::Result`.
+///
+/// The function is kept as is, and a module with the same name is added next to it,
+/// with a `register()` function to call from `hyperlight_main`.
+/// Calls with parameters that don't match the signature fail with
+/// `ErrorCode::GuestFunctionParameterTypeMismatch`.
+#[proc_macro_attribute]
+pub fn guest_function(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let name = if attr.is_empty() {
+        None
+    } else {
+        Some(parse_macro_input!(attr as LitStr))
+    };
+    let function = parse_macro_input!(item as ItemFn);
+    expand(name, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Returns the first type argument of `segment`, e.g., `T` for `Result<T>`.
+fn type_argument(segment: &PathSegment) -> Option<&Type> {
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    args.args.iter().find_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })
+}
+
+/// Returns whether `ty` is `u8`.
+fn is_u8(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path) if path.path.is_ident("u8"))
+}
+
+/// Returns the `ParameterType` or `ReturnType` variant for the Rust type `ty`.
+fn variant(ty: &Type) -> Option<&'static str> {
+    match ty {
+        Type::Tuple(tuple) if tuple.elems.is_empty() => Some("Void"),
+        Type::Paren(paren) => variant(&paren.elem),
+        Type::Path(path) if path.qself.is_none() => {
+            let segment = path.path.segments.last()?;
+            Some(match segment.ident.to_string().as_str() {
+                "i32" => "Int",
+                "u32" => "UInt",
+                "i64" => "Long",
+                "u64" => "ULong",
+                "f32" => "Float",
+                "f64" => "Double",
+                "String" => "String",
+                "bool" => "Bool",
+                "Vec" if type_argument(segment).is_some_and(is_u8) => "VecBytes",
+                _ => return None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Returns the type a function returns, `None` for nothing, and whether it's wrapped
+/// in a `Result`.
+fn return_type(output: &ReturnType) -> (Option<&Type>, bool) {
+    let ReturnType::Type(_, ty) = output else {
+        return (None, false);
+    };
+    if let Type::Path(path) = ty.as_ref()
+        && let Some(segment) = path.path.segments.last()
+        && segment.ident == "Result"
+        && let Some(ok) = type_argument(segment)
+    {
+        return (Some(ok), true);
+    }
+    (Some(ty), false)
+}
+
+fn expand(name: Option<LitStr>, function: ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &function.sig;
+    if let Some(param) = sig.generics.params.first() {
+        return Err(Error::new_spanned(
+            param,
+            "guest functions can't be generic",
+        ));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(Error::new_spanned(
+            asyncness,
+            "guest functions can't be async",
+        ));
+    }
+
+    let ident = &sig.ident;
+    let name = name.unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+
+    let mut parameters = vec![];
+    for input in &sig.inputs {
+        let FnArg::Typed(arg) = input else {
+            return Err(Error::new_spanned(
+                input,
+                "guest functions can't take `self`",
+            ));
+        };
+        let variant = variant(&arg.ty)
+            .filter(|variant| *variant != "Void")
+            .ok_or_else(|| {
+                Error::new_spanned(
+                    &arg.ty,
+                    format!(
+                        "unsupported guest function parameter type, expected {PARAMETER_TYPES}"
+                    ),
+                )
+            })?;
+        parameters.push(format_ident!("{variant}"));
+    }
+
+    let (ty, fallible) = return_type(&sig.output);
+    let ret = match ty {
+        None => "Void",
+        Some(ty) => variant(ty).ok_or_else(|| {
+            Error::new_spanned(
+                ty,
+                format!(
+                    "unsupported guest function return type, expected {PARAMETER_TYPES}, or ()"
+                ),
+            )
+        })?,
+    };
+    let ret = format_ident!("{ret}");
+
+    let args = (0..parameters.len())
+        .map(|i| format_ident!("arg{i}"))
+        .collect::<Vec<_>>();
+    let pattern = if parameters.is_empty() {
+        quote!(None | Some([]))
+    } else {
+        quote!(Some([#(ParameterValue::#parameters(#args)),*]))
+    };
+    let call = quote!(super::#ident(#(#args.clone()),*));
+    let call = if fallible { quote!(#call?) } else { call };
+    let message = format!("Invalid parameters for {}", name.value());
+    let doc = format!("Registration of the `{}` guest function.", name.value());
+    let vis = &function.vis;
+
+    Ok(quote! {
+        #function
+
+        #[doc = #doc]
+        #vis mod #ident {
+            use ::alloc::string::ToString as _;
+            use ::alloc::vec::Vec;
+
+            use ::hyperlight_common::flatbuffer_wrappers::function_call::FunctionCall;
+            use ::hyperlight_common::flatbuffer_wrappers::function_types::{
+                ParameterType, ParameterValue, ReturnType,
+            };
+            use ::hyperlight_common::flatbuffer_wrappers::guest_error::ErrorCode;
+            use ::hyperlight_common::flatbuffer_wrappers::util::get_flatbuffer_result;
+            use ::hyperlight_guest::error::{HyperlightGuestError, Result};
+            use ::hyperlight_guest_bin::guest_function::definition::GuestFunctionDefinition;
+            use ::hyperlight_guest_bin::guest_function::register::register_function;
+
+            /// Registers the guest function, call it from `hyperlight_main`.
+            pub fn register() {
+                register_function(GuestFunctionDefinition::new(
+                    #name.to_string(),
+                    Vec::from(&[#(ParameterType::#parameters),*]),
+                    ReturnType::#ret,
+                    dispatch as usize,
+                ));
+            }
+
+            fn dispatch(call: &FunctionCall) -> Result<Vec<u8>> {
+                match call.parameters.as_deref() {
+                    #pattern => Ok(get_flatbuffer_result(#call)),
+                    _ => Err(HyperlightGuestError::new(
+                        ErrorCode::GuestFunctionParameterTypeMismatch,
+                        #message.to_string(),
+                    )),
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    #[test]
+    fn variants_of_rust_types() {
+        let variant = |ty: Type| variant(&ty);
+        assert_eq!(variant(parse_quote!(i32)), Some("Int"));
+        assert_eq!(variant(parse_quote!(alloc::string::String)), Some("String"));
+        assert_eq!(variant(parse_quote!(Vec<u8>)), Some("VecBytes"));
+        assert_eq!(variant(parse_quote!(())), Some("Void"));
+        assert_eq!(variant(parse_quote!(Vec<i32>)), None);
+        assert_eq!(variant(parse_quote!(&str)), None);
+    }
+
+    #[test]
+    fn results_are_unwrapped() {
+        let output: ReturnType = parse_quote!(-> Result<String>);
+        let (ty, fallible) = return_type(&output);
+        assert_eq!(ty.and_then(variant), Some("String"));
+        assert!(fallible);
+
+        let output: ReturnType = parse_quote!(-> bool);
+        assert!(!return_type(&output).1);
+        let (ty, fallible) = return_type(&ReturnType::Default);
+        assert!(ty.is_none() && !fallible);
+    }
+
+    #[test]
+    fn unsupported_signatures_fail() {
+        let error = |function: ItemFn| expand(None, function).unwrap_err().to_string();
+        assert_eq!(
+            error(parse_quote!(
+                fn f(s: &str) {}
+            )),
+            format!("unsupported guest function parameter type, expected {PARAMETER_TYPES}")
+        );
+        assert_eq!(
+            error(parse_quote!(
+                fn f<T>(t: T) {}
+            )),
+            "guest functions can't be generic"
+        );
+        assert_eq!(
+            error(parse_quote!(
+                fn f() -> u8 {
+                    0
+                }
+            )),
+            format!("unsupported guest function return type, expected {PARAMETER_TYPES}, or ()")
+        );
+    }
+
+    #[test]
+    fn registers_with_the_given_name() {
+        let code = expand(
+            Some(parse_quote!("Add")),
+            parse_quote!(
+                pub fn add(a: i32, b: i32) -> i32 {
+                    a + b
+                }
+            ),
+        )
+        .unwrap()
+        .to_string();
+        assert!(code.contains("pub mod add"));
+        assert!(code.contains(r#""Add" . to_string ()"#));
+        assert!(code.contains("ParameterType :: Int , ParameterType :: Int"));
+        assert!(code.contains("ReturnType :: Int"));
+    }
+}
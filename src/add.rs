@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::cargo_cmd::{CargoCmd as _, cargo_cmd};
+use crate::cli::{Args, note};
+use crate::metadata;
+
+/// Guest crates added by [`add`], and whether their default features are disabled.
+///
+/// hyperlight-common enables `std` by default, which guests can't use.
+const GUEST_CRATES: &[(&str, bool)] = &[
+    ("hyperlight-guest-bin", false),
+    ("hyperlight-guest", false),
+    ("hyperlight-common", true),
+];
+
+/// Returns the version of `name` in the resolved dependency graph of the workspace.
+fn resolved_version(metadata: &Value, name: &str) -> Option<String> {
+    metadata["packages"]
+        .as_array()?
+        .iter()
+        .find(|pkg| pkg["name"] == name)?["version"]
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Returns the version requirement on `name` of `package`, or of the first workspace
+/// member depending on `name`, without the leading `^`.
+fn required_version(metadata: &Value, package: Option<&str>, name: &str) -> Option<String> {
+    let members = metadata["workspace_members"].as_array()?;
+    metadata["packages"]
+        .as_array()?
+        .iter()
+        .filter(|pkg| members.contains(&pkg["id"]))
+        .filter(|pkg| {
+            package.is_none_or(|package| pkg["name"] == package.split('@').next().unwrap())
+        })
+        .find_map(|pkg| {
+            let req = pkg["dependencies"]
+                .as_array()?
+                .iter()
+                .find(|dep| dep["name"] == name)?["req"]
+                .as_str()?;
+            Some(req.trim_start_matches('^').to_string())
+        })
+}
+
+/// Runs `cargo add` for a single `spec`, e.g., `hyperlight-guest@0.9.0`.
+fn cargo_add(args: &Args, package: Option<&str>, spec: &str, no_default: bool) -> Result<()> {
+    let mut command = cargo_cmd(&args.env)?;
+    command
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .arg("add")
+        .manifest_path(&args.manifest_path)
+        .arg(spec);
+    if let Some(package) = package {
+        command.arg("--package").arg(package);
+    }
+    if no_default {
+        command.arg("--no-default-features");
+    }
+    command
+        .checked_status()
+        .with_context(|| format!("Failed to add {spec}"))
+}
+
+/// Adds hyperlight-guest, hyperlight-guest-bin and hyperlight-common to `package` (or
+/// to the package cargo selects by default), at the same version.
+///
+/// The hyperlight crates are released together, and guests only work with a host of the
+/// same version. The version is, in order of precedence, `version`, the version of
+/// hyperlight-host if the workspace already uses it, or the latest version of
+/// hyperlight-guest-bin.
+pub fn add(args: &Args, package: Option<&str>, version: Option<&str>) -> Result<()> {
+    let version = match version {
+        Some(version) => Some(version.to_string()),
+        None => {
+            let metadata = metadata::cargo_metadata(args, &[])?;
+            let host = resolved_version(&metadata, "hyperlight-host");
+            if let Some(host) = &host {
+                note(format!("using version {host} to match hyperlight-host"));
+            }
+            host
+        }
+    };
+
+    let version = match version {
+        Some(version) => version,
+        None => {
+            // let cargo pick the latest version, and use it for the other crates
+            let (name, no_default) = GUEST_CRATES[0];
+            cargo_add(args, package, name, no_default)?;
+            let metadata = metadata::cargo_metadata(args, &["--no-deps".into()])?;
+            required_version(&metadata, package, name)
+                .with_context(|| format!("Failed to find the version of {name} that was added"))?
+        }
+    };
+
+    for (name, no_default) in GUEST_CRATES {
+        cargo_add(args, package, &format!("{name}@{version}"), *no_default)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn versions_from_metadata() {
+        let metadata = json!({
+            "workspace_members": ["guest 0.1.0", "host 0.1.0"],
+            "packages": [
+                {
+                    "id": "guest 0.1.0",
+                    "name": "guest",
+                    "version": "0.1.0",
+                    "dependencies": [{ "name": "hyperlight-guest-bin", "req": "^0.9.0" }],
+                },
+                {
+                    "id": "host 0.1.0",
+                    "name": "host",
+                    "version": "0.1.0",
+                    "dependencies": [{ "name": "hyperlight-host", "req": "^0.8" }],
+                },
+                {
+                    "id": "hyperlight-host 0.8.1",
+                    "name": "hyperlight-host",
+                    "version": "0.8.1",
+                    "dependencies": [],
+                },
+            ],
+        });
+
+        assert_eq!(
+            resolved_version(&metadata, "hyperlight-host").as_deref(),
+            Some("0.8.1")
+        );
+        assert_eq!(resolved_version(&metadata, "hyperlight-guest"), None);
+        assert_eq!(
+            required_version(&metadata, Some("guest"), "hyperlight-guest-bin").as_deref(),
+            Some("0.9.0")
+        );
+        assert_eq!(
+            required_version(&metadata, None, "hyperlight-guest-bin").as_deref(),
+            Some("0.9.0")
+        );
+        assert_eq!(
+            required_version(&metadata, Some("host"), "hyperlight-guest-bin"),
+            None
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+
+use anyhow::{Result, bail};
+use serde_json::Value;
+
+use crate::cli::Args;
+use crate::metadata;
+
+/// Global allocator of a guest, selected with `allocator` in
+/// `[package.metadata.hyperlight]`.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Allocator {
+    /// The allocator that `hyperlight-guest-bin` registers
+    Hyperlight,
+    /// The `dlmalloc` crate
+    Dlmalloc,
+    /// The `talc` crate
+    Talc,
+    /// A bump allocator defined by the guest
+    Bump,
+}
+
+/// Values of the `hyperlight_allocator` cfg.
+const NAMES: &[&str] = &["hyperlight", "dlmalloc", "talc", "bump"];
+
+impl Allocator {
+    fn name(self) -> &'static str {
+        match self {
+            Allocator::Hyperlight => "hyperlight",
+            Allocator::Dlmalloc => "dlmalloc",
+            Allocator::Talc => "talc",
+            Allocator::Bump => "bump",
+        }
+    }
+
+    /// Returns the crate that provides the allocator, if any.
+    fn provider(self) -> Option<&'static str> {
+        match self {
+            Allocator::Hyperlight => Some("hyperlight-guest-bin"),
+            Allocator::Dlmalloc => Some("dlmalloc"),
+            Allocator::Talc => Some("talc"),
+            Allocator::Bump => None,
+        }
+    }
+
+    /// Returns the rustc flags with the `hyperlight_allocator` cfg, so that guests can
+    /// gate their `#[global_allocator]` on it.
+    pub fn cfg(self) -> [String; 4] {
+        let values = NAMES
+            .iter()
+            .map(|name| format!("\"{name}\""))
+            .collect::<Vec<_>>()
+            .join(",");
+        [
+            "--cfg".into(),
+            format!("hyperlight_allocator=\"{self}\""),
+            "--check-cfg".into(),
+            format!("cfg(hyperlight_allocator,values({values}))"),
+        ]
+    }
+}
+
+impl Display for Allocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Returns the names of the packages in the output of `cargo metadata`.
+fn package_names(metadata: &Value) -> BTreeSet<&str> {
+    metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|pkg| pkg["name"].as_str())
+        .collect()
+}
+
+/// Returns the problems that would make `allocator` not the only global allocator of
+/// a guest depending on `packages`.
+fn problems(allocator: Allocator, packages: &BTreeSet<&str>) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some(provider) = allocator.provider()
+        && !packages.contains(provider)
+    {
+        problems.push(format!(
+            "the {allocator} allocator is selected, but `{provider}` is not a dependency"
+        ));
+    }
+    if allocator != Allocator::Hyperlight && packages.contains("hyperlight-guest-bin") {
+        problems.push(format!(
+            "the {allocator} allocator is selected, but `hyperlight-guest-bin` always \
+            registers its own global allocator, so the guest would have two; select \
+            the hyperlight allocator, or build the guest on `hyperlight-guest` without \
+            `hyperlight-guest-bin`"
+        ));
+    }
+    problems
+}
+
+/// Checks that the allocator selected in the metadata is the only global allocator of
+/// the guest, instead of failing later with a duplicate `#[global_allocator]` link error.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn check(args: &Args) -> Result<()> {
+    let Some(allocator) = args.allocator else {
+        return Ok(());
+    };
+
+    let metadata = metadata::cargo_metadata(args, &[])?;
+    let problems = problems(allocator, &package_names(&metadata));
+    if !problems.is_empty() {
+        bail!("Invalid allocator selection:\n{}", problems.join("\n"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_allocator_must_be_the_only_one() {
+        let packages = BTreeSet::from(["guest", "hyperlight-guest", "hyperlight-guest-bin"]);
+        assert!(problems(Allocator::Hyperlight, &packages).is_empty());
+        assert_eq!(problems(Allocator::Talc, &packages).len(), 2);
+        assert_eq!(problems(Allocator::Bump, &packages).len(), 1);
+
+        let packages = BTreeSet::from(["guest", "hyperlight-guest", "talc"]);
+        assert!(problems(Allocator::Talc, &packages).is_empty());
+        assert!(problems(Allocator::Bump, &packages).is_empty());
+        assert_eq!(
+            problems(Allocator::Dlmalloc, &packages),
+            ["the dlmalloc allocator is selected, but `dlmalloc` is not a dependency"]
+        );
+    }
+
+    #[test]
+    fn cfg_lists_every_allocator() {
+        assert_eq!(
+            Allocator::Talc.cfg().join(" "),
+            "--cfg hyperlight_allocator=\"talc\" --check-cfg \
+            cfg(hyperlight_allocator,values(\"hyperlight\",\"dlmalloc\",\"talc\",\"bump\"))"
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result, ensure};
+use object::{Object as _, ObjectSection as _, ObjectSymbol as _, SymbolKind};
+use regex::Regex;
+
+use crate::artifact::Executable;
+use crate::cargo_cmd::{CargoCmd as _, cargo_cmd};
+use crate::cli::{Args, note};
+use crate::{CargoCommandExt as _, artifact, float, link_map};
+
+/// Name used for code that can't be attributed to a crate, e.g., linker generated.
+const UNKNOWN_CRATE: &str = "<unknown>";
+
+/// Name used for code without a Rust symbol name when reading the symbol table.
+const NON_RUST_CRATE: &str = "<non-rust>";
+
+/// Output sections that are not loaded into the guest's memory.
+fn is_loaded(output: &str) -> bool {
+    !output.starts_with(".debug")
+        && ![".comment", ".symtab", ".strtab", ".shstrtab"].contains(&output)
+}
+
+/// Prints how much of the guest `executable` of `package` comes from each crate, the
+/// dependencies that contribute no code at all, and the sections that were kept
+/// only because of `-znostart-stop-gc`.
+///
+/// The binary must have been built already. If it was built with `--emit-map`, the
+/// link map is used to attribute input sections, including C code, to the crates
+/// that built them. Otherwise the Rust symbols in the symbol table are used.
+///
+/// With `float`, the floating-point and SIMD instructions are reported as well, and
+/// with `deny_float` their presence is an error.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn analyze(
+    args: &Args,
+    package: Option<&str>,
+    executable: Executable,
+    profile: Option<&str>,
+    float: bool,
+    deny_float: bool,
+) -> Result<()> {
+    let path = artifact::artifact_path(args, package, executable, profile)?;
+    ensure!(
+        path.is_file(),
+        "Guest binary {} not found, build it first",
+        path.display()
+    );
+
+    let elf = std::fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+    let elf =
+        object::File::parse(elf.as_slice()).with_context(|| format!("Failed to parse {path:?}"))?;
+
+    let mut map = path.clone().into_os_string();
+    map.push(".map");
+    let sizes = match std::fs::read_to_string(&map) {
+        Ok(map) => sizes_from_map(&link_map::parse(&map)),
+        Err(_) => {
+            note("build with --emit-map to attribute C code to the crates that build it");
+            sizes_from_symbols(&elf)
+        }
+    };
+
+    let dependencies = dependencies(args, package)?;
+    let unused = dependencies
+        .iter()
+        .filter(|lib| sizes.get(*lib).is_none_or(|size| *size == 0))
+        .collect::<Vec<_>>();
+
+    let mut by_size = sizes.iter().collect::<Vec<_>>();
+    by_size.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    println!("Code and data by crate in {}:", path.display());
+    for (name, size) in by_size {
+        println!("  {size:>10}  {name}");
+    }
+
+    println!("Dependencies contributing nothing to the guest:");
+    if unused.is_empty() {
+        println!("  (none)");
+    }
+    for lib in unused {
+        println!("  {lib}");
+    }
+
+    // lld keeps every section whose name is a C identifier when `__start_`/`__stop_`
+    // symbols are used, as hyperlight guests are linked with `-znostart-stop-gc`
+    let identifier = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    println!("Sections kept by -znostart-stop-gc:");
+    let mut kept = false;
+    for section in elf.sections() {
+        let Ok(name) = section.name() else {
+            continue;
+        };
+        if identifier.is_match(name) && section.size() > 0 {
+            println!("  {:>10}  {name}", section.size());
+            kept = true;
+        }
+    }
+    if !kept {
+        println!("  (none)");
+    }
+
+    if float || deny_float {
+        float::analyze(args, &path, deny_float)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the library names of the normal dependencies of `package`, or of the
+/// packages cargo selects by default, excluding proc-macros.
+fn dependencies(args: &Args, package: Option<&str>) -> Result<BTreeSet<String>> {
+    let mut command = cargo_cmd(&args.env)?;
+    command
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .populate_from_args(args)
+        .arg("tree")
+        .manifest_path(&args.manifest_path)
+        .arg("--edges=normal,no-proc-macro")
+        .arg("--prefix=depth")
+        .arg("--format={lib}");
+    if let Some(package) = package {
+        command.arg("--package").arg(package);
+    }
+    let output = command
+        .checked_output()
+        .context("Failed to get the dependency tree of the guest")?;
+
+    Ok(parse_dependencies(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses the output of `cargo tree --prefix=depth --format={lib}`, skipping the roots
+/// and packages without a library.
+fn parse_dependencies(output: &str) -> BTreeSet<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let start = line.find(|c: char| !c.is_ascii_digit())?;
+            let (depth, lib) = line.split_at(start);
+            let lib = lib.trim_end_matches(" (*)").trim();
+            (depth != "0" && !lib.is_empty()).then(|| lib.to_string())
+        })
+        .collect()
+}
+
+/// Attributes the loaded input sections of a link map to the crates that built them.
+fn sizes_from_map(map: &link_map::LinkMap) -> BTreeMap<String, u64> {
+    let mut sizes = BTreeMap::new();
+    for input in map.inputs.iter().filter(|input| is_loaded(&input.output)) {
+        *sizes.entry(input_crate(&input.name)).or_default() += input.size;
+    }
+    sizes
+}
+
+/// Returns the crate an input section comes from, from the path of its object file.
+fn input_crate(input: &str) -> String {
+    let patterns = [
+        // objects in rlibs and static libraries, including the sysroot
+        r"[/\\]lib([A-Za-z0-9_]+)-[0-9a-f]+\.(?:rlib|a)\(",
+        // C code built by build scripts
+        r"[/\\]build[/\\]([A-Za-z0-9_-]+)-[0-9a-f]+[/\\]out[/\\]",
+        // objects of the binary crate itself
+        r"[/\\]deps[/\\]([A-Za-z0-9_]+)-[0-9a-f]+\.",
+    ];
+    patterns
+        .iter()
+        .find_map(|pattern| {
+            let caps = Regex::new(pattern).unwrap().captures(input)?;
+            Some(caps[1].replace('-', "_"))
+        })
+        .unwrap_or_else(|| UNKNOWN_CRATE.to_string())
+}
+
+/// Attributes the functions and data in the symbol table to the crates in their
+/// mangled names.
+fn sizes_from_symbols(elf: &object::File) -> BTreeMap<String, u64> {
+    let mut sizes = BTreeMap::new();
+    for symbol in elf.symbols() {
+        if !matches!(symbol.kind(), SymbolKind::Text | SymbolKind::Data)
+            || symbol.size() == 0
+            || symbol.section_index().is_none()
+        {
+            continue;
+        }
+        let name = symbol.name().unwrap_or_default();
+        *sizes.entry(symbol_crate(name)).or_default() += symbol.size();
+    }
+    sizes
+}
+
+/// Returns the crate of the first path in a mangled Rust symbol name, e.g., `alloc`
+/// for `<alloc::string::String as core::fmt::Debug>::fmt`.
+pub fn symbol_crate(name: &str) -> String {
+    let Ok(demangled) = rustc_demangle::try_demangle(name) else {
+        return NON_RUST_CRATE.to_string();
+    };
+    let demangled = format!("{demangled:#}");
+    let path = demangled.trim_start_matches(['<', '&', '*', '[', '(']);
+    let path = path
+        .strip_prefix("mut ")
+        .or_else(|| path.strip_prefix("const "))
+        .or_else(|| path.strip_prefix("dyn "))
+        .unwrap_or(path);
+    let end = path
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(path.len());
+    match &path[..end] {
+        "" => UNKNOWN_CRATE.to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_crate_from_paths() {
+        let cases = [
+            (
+                "/t/x86_64-hyperlight-none/debug/deps/libfoo_bar-0123abcd.rlib(foo_bar-0123abcd.foo_bar.1-cgu.0.rcgu.o):(.text.foo)",
+                "foo_bar",
+            ),
+            (
+                "/t/sysroot/lib/rustlib/x86_64-hyperlight-none/lib/libcore-89ab.rlib(core-89ab.core.2-cgu.0.rcgu.o):(.text.memcpy)",
+                "core",
+            ),
+            (
+                "/t/x86_64-hyperlight-none/debug/build/libsqlite3-sys-0123abcd/out/libsqlite3.a(sqlite3.o):(.text)",
+                "libsqlite3_sys",
+            ),
+            (
+                "/t/x86_64-hyperlight-none/debug/build/my-sys-0123abcd/out/foo.o:(.text)",
+                "my_sys",
+            ),
+            (
+                "/t/x86_64-hyperlight-none/debug/deps/guest_bin-0123abcd.guest_bin.3-cgu.0.rcgu.o:(.text.main)",
+                "guest_bin",
+            ),
+            ("<internal>:(.bss)", UNKNOWN_CRATE),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(input_crate(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn symbol_crate_from_mangled_names() {
+        let cases = [
+            ("_ZN4core3fmt5write17h0123456789abcdefE", "core"),
+            (
+                "_ZN60_$LT$alloc..string..String$u20$as$u20$core..fmt..Display$GT$3fmt17h0123456789abcdefE",
+                "alloc",
+            ),
+            ("memcpy", NON_RUST_CRATE),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(symbol_crate(name), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn parse_dependencies_skips_roots() {
+        let output = "0guest_bin\n1hyperlight_guest\n2spin\n1spin (*)\n1\n";
+        assert_eq!(
+            parse_dependencies(output),
+            BTreeSet::from(["hyperlight_guest".to_string(), "spin".to_string()])
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io::{BufRead as _, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result, bail, ensure};
+use regex::Regex;
+
+use crate::cargo_cmd::{CargoCmd, cargo_cmd};
+use crate::cli::Args;
+use crate::metadata::PackageMetadata;
+use crate::{compress, failure, provenance};
+
+#[derive(serde::Deserialize)]
+struct CargoMetadata {
+    packages: Vec<CargoMetadataPackage>,
+    workspace_default_members: Vec<String>,
+    workspace_root: PathBuf,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataPackage {
+    id: String,
+    name: String,
+    version: semver::Version,
+    manifest_path: PathBuf,
+    targets: Vec<CargoMetadataTarget>,
+    metadata: Option<PackageMetadata>,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+/// Returns the directory name cargo uses for the artifacts of a profile.
+pub fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+fn workspace_metadata(args: &Args) -> Result<CargoMetadata> {
+    let metadata = cargo_cmd(&args.env)?
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .arg("metadata")
+        .manifest_path(&args.manifest_path)
+        .arg("--format-version=1")
+        .arg("--no-deps")
+        .checked_output()
+        .context("Failed to get cargo metadata")?;
+
+    serde_json::from_slice(&metadata.stdout).context("Failed to parse cargo metadata")
+}
+
+/// Returns the ids of the packages cargo selects when no `--package` is given.
+///
+/// Like cargo, this is the package of the manifest in use (`--manifest-path`, or the
+/// closest `Cargo.toml` to the current directory), unless that manifest is the root
+/// of the workspace, in which case the default members are selected.
+fn default_packages(args: &Args, metadata: &CargoMetadata) -> Vec<String> {
+    let manifest = match &args.manifest_path {
+        Some(path) => Some(args.current_dir.join(path)),
+        None => args
+            .current_dir
+            .ancestors()
+            .map(|dir| dir.join("Cargo.toml"))
+            .find(|path| path.is_file()),
+    };
+    let manifest = manifest.map(|path| path.canonicalize().unwrap_or(path));
+    let root = metadata.workspace_root.join("Cargo.toml");
+    let root = root.canonicalize().unwrap_or(root);
+
+    let member = metadata.packages.iter().find(|pkg| {
+        let path = pkg.manifest_path.canonicalize();
+        Some(path.as_ref().unwrap_or(&pkg.manifest_path)) == manifest.as_ref()
+    });
+
+    match member {
+        Some(pkg) if manifest.as_ref() != Some(&root) => vec![pkg.id.clone()],
+        _ => metadata.workspace_default_members.clone(),
+    }
+}
+
+/// An executable target of a guest package.
+#[derive(Debug, Clone, Copy)]
+pub enum Executable<'a> {
+    /// A binary target, or the only binary target of the package if `None`
+    Bin(Option<&'a str>),
+    /// An example target
+    Example(&'a str),
+}
+
+/// Computes where cargo will place the guest `executable` of `package`
+/// when built with `profile`, without building anything.
+///
+/// If no binary is specified, the package must have exactly one binary target.
+/// Examples are placed in the `examples` directory of the profile.
+/// If `package` is not specified, the packages cargo would build are considered,
+/// see [`default_packages`].
+/// If `profile` is not specified, the `dev` profile is assumed.
+pub fn artifact_path(
+    args: &Args,
+    package: Option<&str>,
+    executable: Executable,
+    profile: Option<&str>,
+) -> Result<PathBuf> {
+    let metadata = workspace_metadata(args)?;
+    let default_packages = default_packages(args, &metadata);
+
+    let packages = metadata
+        .packages
+        .iter()
+        .filter(|pkg| match package {
+            // accept both `name` and `name@version` package specs
+            Some(spec) => pkg.name == spec.split('@').next().unwrap_or(spec),
+            None => default_packages.contains(&pkg.id),
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(spec) = package
+        && packages.is_empty()
+    {
+        bail!("Package {spec:?} not found in workspace");
+    }
+
+    let targets = |kind: &str| {
+        packages
+            .iter()
+            .flat_map(|pkg| &pkg.targets)
+            .filter(|target| target.kind.iter().any(|k| k == kind))
+            .map(|target| target.name.as_str())
+            .collect::<Vec<_>>()
+    };
+
+    let (dir, name) = match executable {
+        Executable::Bin(bin) => {
+            let bins = targets("bin");
+            let bin = match (bin, bins.as_slice()) {
+                (Some(bin), bins) if bins.contains(&bin) => bin,
+                (Some(bin), _) => bail!("No binary target named {bin:?}"),
+                (None, [bin]) => bin,
+                (None, []) => bail!("No binary targets found"),
+                (None, bins) => bail!(
+                    "Multiple binary targets found, specify one with --bin\nAvailable binaries:\n{}",
+                    bins.iter()
+                        .map(|bin| format!(" * {bin}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+            };
+            (None, bin)
+        }
+        Executable::Example(example) => {
+            ensure!(
+                targets("example").contains(&example),
+                "No example target named {example:?}"
+            );
+            (Some("examples"), example)
+        }
+    };
+
+    let profile = profile.unwrap_or("dev");
+
+    let mut path = args
+        .target_dir
+        .join(&args.target)
+        .join(profile_dir(profile));
+    path.extend(dir);
+    Ok(path.join(name))
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum Message {
+    CompilerArtifact {
+        manifest_path: PathBuf,
+        target: MessageTarget,
+        executable: Option<PathBuf>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize)]
+struct MessageTarget {
+    name: String,
+}
+
+/// An executable produced by a cargo build.
+pub struct Artifact {
+    /// Path to the executable
+    pub executable: PathBuf,
+    /// Name of the binary target
+    pub name: String,
+    /// Manifest of the package the binary belongs to
+    pub manifest_path: PathBuf,
+}
+
+/// Returns the value of the `--message-format` flag in `args`, if any.
+fn message_format<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> Option<String> {
+    let mut args = args.into_iter().map(|arg| arg.to_string_lossy());
+    let mut format = None;
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--" => break,
+            "--message-format" => format = args.next().map(|value| value.into_owned()),
+            arg if arg.starts_with("--message-format=") => {
+                format = Some(arg["--message-format=".len()..].to_string())
+            }
+            _ => {}
+        }
+    }
+    format
+}
+
+/// Runs the cargo build `command` and returns the executables it produced.
+///
+/// cargo's JSON messages are used to find the executables, while diagnostics are
+/// still rendered to stderr. Any other output on stdout is passed through.
+///
+/// If the user asked for JSON messages with `--message-format`, every message is
+/// passed through unchanged instead, so that tools reading them are not affected.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+pub fn build(command: &mut Command) -> Result<Vec<Artifact>> {
+    let passthrough = match message_format(command.get_args()) {
+        None => false,
+        Some(format) if format.contains("json") => true,
+        Some(_) => bail!(
+            "--out-dir, --emit-map, --provenance and --pie need cargo's JSON messages, \
+            they can only be combined with a JSON --message-format"
+        ),
+    };
+
+    if !passthrough {
+        command.arg("--message-format=json-render-diagnostics");
+    }
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to execute command:\n{command:?}"))?;
+
+    let stdout = child.stdout.take().context("Failed to capture stdout")?;
+    let mut artifacts = vec![];
+    for line in BufReader::new(stdout).lines() {
+        let line = line.context("Failed to read cargo output")?;
+        match serde_json::from_str::<Message>(&line) {
+            Ok(Message::CompilerArtifact {
+                manifest_path,
+                target,
+                executable: Some(executable),
+            }) => artifacts.push(Artifact {
+                executable,
+                name: target.name,
+                manifest_path,
+            }),
+            Ok(_) => {}
+            Err(_) => {
+                println!("{line}");
+                continue;
+            }
+        }
+        if passthrough {
+            println!("{line}");
+        }
+    }
+
+    let status = child.wait().context("Failed to wait for cargo")?;
+    failure::check_cargo(status, command)?;
+    Ok(artifacts)
+}
+
+const DEFAULT_EXPORT_NAME: &str = "{name}";
+
+/// Copies the `artifacts` to `out_dir`.
+///
+/// The exported file names follow the `[package.metadata.hyperlight.export] name`
+/// template of the artifact's package, which defaults to the binary name, or the one
+/// in `[package.metadata.hyperlight.bin.<name>.export]` for the binary `<name>`.
+///
+/// The sidecar files this build wrote next to an executable, its `<executable>.map`
+/// with `--emit-map` and its `<executable>.provenance.json` with `--provenance`, are
+/// copied as well, and renamed to `<exported name>.<ext>`.
+///
+/// With `--compress`, a compressed copy of each executable is written next to it.
+///
+/// Fails without copying anything if two artifacts would be exported with the same name.
+pub fn export(args: &Args, artifacts: &[Artifact], out_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(out_dir).context("Failed to create output directory")?;
+
+    let metadata = workspace_metadata(args)?;
+
+    // render all the names first, so that nothing is copied if two artifacts collide
+    let mut names = BTreeMap::<String, &Artifact>::new();
+    for artifact in artifacts {
+        let package = metadata
+            .packages
+            .iter()
+            .find(|pkg| pkg.manifest_path == artifact.manifest_path)
+            .with_context(|| format!("Package for {:?} not found in workspace", artifact.name))?;
+
+        let hyperlight = package
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.hyperlight.as_ref());
+        let template = hyperlight
+            .and_then(|hyperlight| hyperlight.bin.get(&artifact.name)?.export.name.as_deref())
+            .or_else(|| hyperlight?.export.name.as_deref())
+            .unwrap_or(DEFAULT_EXPORT_NAME);
+
+        let name = render_name(template, |var| {
+            Ok(match var {
+                "name" => artifact.name.clone(),
+                "package" => package.name.clone(),
+                "version" => package.version.to_string(),
+                "target" => args.target.clone(),
+                "profile" => profile_dir(&args.profile).to_string(),
+                "git" => git_describe(&package.manifest_path)?,
+                var => bail!("Unknown variable {{{var}}} in export name template {template:?}"),
+            })
+        })?;
+
+        if let Some(other) = names.insert(name.clone(), artifact) {
+            bail!(
+                "Both {:?} and {:?} would be exported as {name:?}, \
+                use a different `[package.metadata.hyperlight.export] name` for each",
+                other.executable,
+                artifact.executable,
+            );
+        }
+    }
+
+    // only the files this build wrote next to the executables, not the ones left there
+    // by earlier builds with other flags
+    let sidecars = [
+        args.emit_map.then(|| ".map".to_string()),
+        args.provenance
+            .then(|| format!(".{}", provenance::EXTENSION)),
+    ];
+
+    for (name, artifact) in names {
+        let executable = &artifact.executable;
+
+        let dst = out_dir.join(&name);
+        std::fs::copy(executable, &dst)
+            .with_context(|| format!("Failed to copy {executable:?} to {dst:?}"))?;
+        if let Some(compression) = args.compress {
+            compress::write(&dst, compression)?;
+        }
+
+        for suffix in sidecars.iter().flatten() {
+            let mut src = executable.as_os_str().to_owned();
+            src.push(suffix);
+            let src = PathBuf::from(src);
+            let dst = out_dir.join(format!("{name}{suffix}"));
+            std::fs::copy(&src, &dst)
+                .with_context(|| format!("Failed to copy {src:?} to {dst:?}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces every `{var}` in `template` with the value returned by `value(var)`.
+fn render_name(template: &str, mut value: impl FnMut(&str) -> Result<String>) -> Result<String> {
+    let re = Regex::new(r"\{([a-z-]+)\}").unwrap();
+    let mut name = String::new();
+    let mut last = 0;
+    for captures in re.captures_iter(template) {
+        let var = captures.get(0).unwrap();
+        name.push_str(&template[last..var.start()]);
+        name.push_str(&value(&captures[1])?);
+        last = var.end();
+    }
+    name.push_str(&template[last..]);
+
+    ensure!(
+        !name.is_empty() && !name.contains(['/', '\\']),
+        "Invalid export name {name:?} from template {template:?}"
+    );
+
+    Ok(name)
+}
+
+/// Returns the output of `git describe` for the repository containing `manifest_path`.
+fn git_describe(manifest_path: &Path) -> Result<String> {
+    let dir = manifest_path
+        .parent()
+        .context("Failed to get package directory")?;
+    let output = std::process::Command::new("git")
+        .current_dir(dir)
+        .arg("describe")
+        .arg("--always")
+        .arg("--dirty")
+        .arg("--tags")
+        .checked_output()
+        .context("Failed to run git describe")?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
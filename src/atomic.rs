@@ -0,0 +1,30 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Returns a temporary path next to `path`, used to stage writes before
+/// atomically renaming them into place.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    PathBuf::from(tmp)
+}
+
+/// Copies `src` to `dst` such that `dst` is either left untouched or fully written,
+/// even if the process is killed during the copy.
+pub fn copy(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> Result<()> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let tmp = tmp_path(dst);
+    std::fs::copy(src, &tmp).with_context(|| format!("Failed to copy {src:?} to {tmp:?}"))?;
+    std::fs::rename(&tmp, dst).with_context(|| format!("Failed to rename {tmp:?} to {dst:?}"))
+}
+
+/// Writes `contents` to `path` such that `path` is either left untouched or fully
+/// written, even if the process is killed during the write.
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<()> {
+    let path = path.as_ref();
+    let tmp = tmp_path(path);
+    std::fs::write(&tmp, contents).with_context(|| format!("Failed to write {tmp:?}"))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to rename {tmp:?} to {path:?}"))
+}
@@ -0,0 +1,209 @@
+use std::ffi::OsString;
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::cargo_cmd::{CargoCmd as _, cargo_cmd};
+use crate::cli::{note, resolve_target};
+use crate::command::Command;
+use crate::features;
+use crate::report::BuildReport;
+
+/// Prefix of the variables with the path of each guest binary, passed to the host build.
+const GUEST_VAR_PREFIX: &str = "CARGO_HYPERLIGHT_GUEST_";
+
+/// Returns the variable with the path of the guest binary `name`, e.g.,
+/// `CARGO_HYPERLIGHT_GUEST_MY_GUEST` for `my-guest`.
+fn guest_var(name: &str) -> String {
+    format!(
+        "{GUEST_VAR_PREFIX}{}",
+        name.to_uppercase().replace('-', "_")
+    )
+}
+
+/// Returns the cargo arguments selecting `profile`.
+fn profile_args(profile: Option<&str>) -> Vec<OsString> {
+    match profile {
+        Some(profile) => vec!["--profile".into(), profile.into()],
+        None => vec![],
+    }
+}
+
+/// Returns the first line of `err` and its causes, for a summary table.
+fn first_line(err: &anyhow::Error) -> String {
+    let err = format!("{err:#}");
+    let line = err.lines().next().unwrap_or_default();
+    line.trim_end_matches(':').to_string()
+}
+
+/// Renders a table with the result of building each guest package for `target`.
+fn summary(target: &str, results: &[(String, Result<BuildReport>)]) -> String {
+    let width = results
+        .iter()
+        .map(|(guest, _)| guest.len())
+        .chain(["PACKAGE".len()])
+        .max()
+        .unwrap_or_default();
+    let mut summary = format!("  {:<width$}  {:<24}  RESULT\n", "PACKAGE", "TARGET");
+    for (guest, result) in results {
+        let result = match result {
+            Ok(_) => "ok".to_string(),
+            Err(err) => format!("failed: {}", first_line(err)),
+        };
+        summary.push_str(&format!("  {guest:<width$}  {target:<24}  {result}\n"));
+    }
+    summary
+}
+
+/// Builds each guest package on its own, continuing after failures, and prints a
+/// summary of which ones succeeded.
+fn build_guests_keep_going(
+    command: &Command,
+    target: &str,
+    guests: &[String],
+    profile: Option<&str>,
+) -> Result<BuildReport> {
+    let results = guests
+        .iter()
+        .map(|guest| {
+            let mut guest_args = vec![OsString::from("build"), "--keep-going".into()];
+            guest_args.extend(["-p".into(), guest.into()]);
+            guest_args.extend(profile_args(profile));
+            let report = command.with_cargo_args(guest_args).status_with_report();
+            (guest.clone(), report)
+        })
+        .collect::<Vec<_>>();
+
+    eprint!("Guest builds:\n{}", summary(target, &results));
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        bail!("{failed} of {} guests failed to build", results.len());
+    }
+
+    let mut reports = results.into_iter().filter_map(|(_, result)| result.ok());
+    let mut report = reports.next().context("No guests were built")?;
+    for other in reports {
+        report.artifacts.extend(other.artifacts);
+        report.warnings.extend(other.warnings);
+    }
+    Ok(report)
+}
+
+/// Builds the `guests` for the hyperlight target, and then the `hosts` natively, with
+/// the path of every guest binary in `CARGO_HYPERLIGHT_GUEST_<NAME>`, so that hosts
+/// can embed it with `env!` or read it in their build scripts.
+///
+/// Empty `guests` or `hosts` select all the guests or hosts in the workspace.
+/// With `run`, the host is run with `cargo run` instead, passing it the `run`
+/// arguments.
+///
+/// With `keep_going`, every guest is built even if some fail, and a summary of the
+/// guests that failed is printed before giving up on the hosts.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+pub fn build_all(
+    command: &Command,
+    guests: &[String],
+    hosts: &[String],
+    profile: Option<&str>,
+    run: Option<&[OsString]>,
+    keep_going: bool,
+) -> Result<()> {
+    let args = command.build_args()?;
+
+    let guests = match guests {
+        [] => features::guest_packages(&args)?,
+        guests => guests.to_vec(),
+    };
+    ensure!(
+        !guests.is_empty(),
+        "No hyperlight guest packages found in the workspace"
+    );
+
+    let hosts = match hosts {
+        [] => features::host_packages(&args)?,
+        hosts => hosts.to_vec(),
+    };
+    ensure!(
+        !hosts.is_empty(),
+        "No hyperlight host packages found in the workspace"
+    );
+
+    let report = if keep_going {
+        build_guests_keep_going(command, &args.target, &guests, profile)?
+    } else {
+        let mut guest_args = vec![OsString::from("build")];
+        for guest in &guests {
+            guest_args.extend(["-p".into(), guest.into()]);
+        }
+        guest_args.extend(profile_args(profile));
+        command
+            .with_cargo_args(guest_args)
+            .status_with_report()
+            .context("Failed to build the guests")?
+    };
+
+    let mut host = cargo_cmd(&args.env)?;
+    host.env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .arg(if run.is_some() { "run" } else { "build" })
+        .manifest_path(&args.manifest_path)
+        .target_dir(&args.target_dir);
+    for host_package in &hosts {
+        host.arg("-p").arg(host_package);
+    }
+    host.args(profile_args(profile));
+
+    // a default target in the cargo config, e.g., from `cargo hyperlight setup`, is
+    // meant for the guests
+    if resolve_target(&args.env, &args.current_dir)
+        .ok()
+        .flatten()
+        .is_some()
+    {
+        host.arg("--target").arg("host-tuple");
+    }
+
+    for artifact in &report.artifacts {
+        let var = guest_var(&artifact.name);
+        note(format!("{var}={}", artifact.path.display()));
+        host.env(var, &artifact.path);
+    }
+
+    if let Some(run) = run {
+        host.arg("--").args(run);
+    }
+
+    host.checked_status().context(if run.is_some() {
+        "Failed to run the host"
+    } else {
+        "Failed to build the hosts"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_var_is_uppercase() {
+        assert_eq!(guest_var("guest"), "CARGO_HYPERLIGHT_GUEST_GUEST");
+        assert_eq!(guest_var("my-guest"), "CARGO_HYPERLIGHT_GUEST_MY_GUEST");
+    }
+
+    #[test]
+    fn summary_has_the_first_error_line() {
+        let err = anyhow::anyhow!("Command exited with code 101:\ncargo build")
+            .context("Failed to execute cargo");
+        let results = vec![
+            ("a-long-guest".to_string(), Err(err)),
+            ("b".to_string(), Err(anyhow::anyhow!("boom"))),
+        ];
+        assert_eq!(
+            summary("x86_64-hyperlight-none", &results),
+            "  PACKAGE       TARGET                    RESULT\n\
+            \x20 a-long-guest  x86_64-hyperlight-none    failed: Failed to execute cargo: Command exited with code 101\n\
+            \x20 b             x86_64-hyperlight-none    failed: boom\n"
+        );
+    }
+}
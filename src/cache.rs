@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::atomic;
+use crate::cli::Args;
+
+/// Name of the file in the sysroot directory with the hits and misses of the cache.
+const FILE_NAME: &str = "cache.json";
+
+/// Parts of the sysroot that are reused across builds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entry {
+    /// The cargo build of core and alloc
+    SysrootBuild,
+    /// The libraries, headers and wrappers installed for the target
+    Toolchain,
+    /// The objects of libm
+    Libm,
+    /// The objects of hyperlight-guest-bin's C archive
+    CArchive,
+    /// hyperlight-guest-bin from crates.io, fetched for its headers
+    Headers,
+}
+
+const ENTRIES: &[Entry] = &[
+    Entry::SysrootBuild,
+    Entry::Toolchain,
+    Entry::Libm,
+    Entry::CArchive,
+    Entry::Headers,
+];
+
+impl Entry {
+    fn name(self) -> &'static str {
+        match self {
+            Entry::SysrootBuild => "sysroot build",
+            Entry::Toolchain => "toolchain",
+            Entry::Libm => "libm",
+            Entry::CArchive => "C archive",
+            Entry::Headers => "fetched headers",
+        }
+    }
+
+    fn path(self, args: &Args) -> PathBuf {
+        match self {
+            Entry::SysrootBuild => args.build_dir(),
+            Entry::Toolchain => args.triplet_dir(),
+            Entry::Libm => args.sysroot_dir().join("libm"),
+            Entry::CArchive => args.sysroot_dir().join("c-archive"),
+            Entry::Headers => args.sysroot_dir().join("headers"),
+        }
+    }
+}
+
+/// Hits and misses of an entry, and when it was last used, in seconds since the epoch.
+#[derive(serde::Serialize, serde::Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+struct EntryState {
+    hits: u64,
+    misses: u64,
+    last_used: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct State {
+    entries: BTreeMap<String, EntryState>,
+}
+
+fn read_state(sysroot_dir: &Path) -> State {
+    std::fs::read(sysroot_dir.join(FILE_NAME))
+        .ok()
+        .and_then(|state| serde_json::from_slice(&state).ok())
+        .unwrap_or_default()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// Records that `entry` was reused (`hit`) or had to be rebuilt.
+///
+/// The statistics are best effort: concurrent builds can lose updates, and nothing is
+/// recorded in a read-only sysroot.
+pub fn record(args: &Args, entry: Entry, hit: bool) {
+    let sysroot_dir = args.sysroot_dir();
+    let mut state = read_state(&sysroot_dir);
+    let stats = state.entries.entry(entry.name().to_string()).or_default();
+    if hit {
+        stats.hits += 1;
+    } else {
+        stats.misses += 1;
+    }
+    stats.last_used = Some(now());
+    let _ = atomic::write(
+        sysroot_dir.join(FILE_NAME),
+        serde_json::to_vec_pretty(&state).unwrap(),
+    );
+}
+
+/// Returns the total size of the files under `path`, without following symlinks.
+fn disk_usage(path: &Path) -> Result<u64> {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return Ok(0);
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    let entries = path
+        .read_dir()
+        .with_context(|| format!("Failed to read directory {path:?}"))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read directory {path:?}"))?;
+        size += disk_usage(&entry.path())?;
+    }
+    Ok(size)
+}
+
+/// Renders how long ago `time`, in seconds since the epoch, was.
+fn ago(time: u64, now: u64) -> String {
+    let elapsed = now.saturating_sub(time);
+    match elapsed {
+        0..60 => "just now".into(),
+        60..3600 => format!("{} minutes ago", elapsed / 60),
+        3600..86400 => format!("{} hours ago", elapsed / 3600),
+        _ => format!("{} days ago", elapsed / 86400),
+    }
+}
+
+/// Prints the disk usage of every cache entry of the sysroot, and of the exported
+/// artifacts with `--out-dir`, together with the hits and misses of each entry.
+pub fn stats(args: &Args) -> Result<()> {
+    let sysroot_dir = args.sysroot_dir();
+    let state = read_state(&sysroot_dir);
+    let now = now();
+
+    println!("Cache in {}:", sysroot_dir.display());
+    println!(
+        "  {:<16}  {:>12}  {:>6}  {:>6}  LAST USED",
+        "ENTRY", "SIZE", "HITS", "MISSES"
+    );
+    for entry in ENTRIES {
+        let size = disk_usage(&entry.path(args))?;
+        let stats = state.entries.get(entry.name()).copied();
+        let (hits, misses) = match stats {
+            Some(stats) => (stats.hits.to_string(), stats.misses.to_string()),
+            None => ("-".into(), "-".into()),
+        };
+        let last_used = stats
+            .and_then(|stats| stats.last_used)
+            .map_or("never".into(), |time| ago(time, now));
+        println!(
+            "  {:<16}  {size:>12}  {hits:>6}  {misses:>6}  {last_used}",
+            entry.name()
+        );
+    }
+    println!("  {:<16}  {:>12}", "total", disk_usage(&sysroot_dir)?);
+
+    if let Some(out_dir) = &args.out_dir {
+        println!("Exported artifacts in {}:", out_dir.display());
+        println!("  {:<16}  {:>12}", "total", disk_usage(out_dir)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_use_is_relative_to_now() {
+        assert_eq!(ago(1000, 1030), "just now");
+        assert_eq!(ago(1000, 1000 + 5 * 60), "5 minutes ago");
+        assert_eq!(ago(1000, 1000 + 3 * 3600 + 1), "3 hours ago");
+        assert_eq!(ago(1000, 1000 + 2 * 86400), "2 days ago");
+        // the clock can go backwards
+        assert_eq!(ago(1000, 900), "just now");
+    }
+}
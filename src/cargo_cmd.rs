@@ -6,16 +6,28 @@ use std::process::{Command, Stdio};
 
 use anyhow::{Result, bail};
 
+use crate::cli::warning_once;
+
 pub trait CargoCmd {
     fn manifest_path(&mut self, path: &Option<impl AsRef<Path>>) -> &mut Self;
     fn target_dir(&mut self, path: impl AsRef<Path>) -> &mut Self;
     fn target(&mut self, triplet: impl AsRef<str>) -> &mut Self;
     fn cc_env(&mut self, triplet: impl AsRef<str>, cc: impl AsRef<Path>) -> &mut Self;
+    fn cc_wrapper_env(&mut self, triplet: impl AsRef<str>, wrapper: impl AsRef<Path>) -> &mut Self;
     fn ar_env(&mut self, triplet: impl AsRef<str>, ar: impl AsRef<Path>) -> &mut Self;
+    fn linker_env(&mut self, triplet: impl AsRef<str>, linker: impl AsRef<Path>) -> &mut Self;
     fn sysroot(&mut self, path: impl AsRef<Path>) -> &mut Self;
     fn entrypoint(&mut self, entry: impl AsRef<str>) -> &mut Self;
-    fn append_rustflags(&mut self, flags: impl AsRef<OsStr>) -> &mut Self;
+    fn bin_link_args(&mut self, bin: impl AsRef<str>, link_args: &[String]) -> &mut Self;
+    fn hyperlight_cfg(&mut self, triplet: impl AsRef<str>) -> &mut Self;
+    fn append_rustflags(&mut self, flags: impl IntoIterator<Item = impl AsRef<OsStr>>)
+    -> &mut Self;
+    fn append_rustdocflags(
+        &mut self,
+        flags: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> &mut Self;
     fn append_cflags(&mut self, triplet: impl AsRef<str>, flags: impl AsRef<OsStr>) -> &mut Self;
+    fn replace_cflags(&mut self, triplet: impl AsRef<str>, flags: impl AsRef<OsStr>) -> &mut Self;
     fn append_bindgen_cflags(&mut self, flags: impl AsRef<OsStr>) -> &mut Self;
     fn allow_unstable(&mut self) -> &mut Self;
     fn resolve_env(
@@ -30,11 +42,28 @@ pub trait CargoCmd {
 pub struct CargoBinary {
     pub path: PathBuf,
     pub rustup_toolchain: Option<OsString>,
+    /// Whether to run rustup's cargo proxy instead of `path` if it isn't one, so that
+    /// `rustup_toolchain` applies, as a toolchain's own cargo ignores it
+    pub rustup_proxy: bool,
 }
 
 impl CargoBinary {
-    pub fn command(&self) -> Command {
-        let mut cmd = Command::new(&self.path);
+    /// Returns the cargo program to run with the environment `env`, which is rustup's
+    /// proxy from `PATH` or the `CARGO_HOME` of `env` when [`CargoBinary::rustup_proxy`]
+    /// is set.
+    pub fn program(&self, env: &HashMap<OsString, OsString>) -> PathBuf {
+        if self.rustup_proxy
+            && !is_rustup_proxy(&self.path)
+            && let Some(proxy) = find_rustup_proxy(env.get(OsStr::new("CARGO_HOME")).cloned())
+        {
+            return proxy;
+        }
+        self.path.clone()
+    }
+
+    /// Returns a command for cargo, to run with the environment `env`.
+    pub fn command(&self, env: &HashMap<OsString, OsString>) -> Command {
+        let mut cmd = Command::new(self.program(env));
         if let Some(rustup_toolchain) = &self.rustup_toolchain {
             cmd.env("RUSTUP_TOOLCHAIN", rustup_toolchain);
         }
@@ -43,19 +72,66 @@ impl CargoBinary {
 }
 
 pub fn find_cargo() -> Result<CargoBinary> {
-    let cargo = match env::var_os("CARGO") {
-        Some(cargo) => Path::new(&cargo).canonicalize()?,
-        None => which::which("cargo")?.canonicalize()?,
+    find_cargo_with(env::var_os("CARGO"), env::var_os("RUSTUP_TOOLCHAIN"))
+}
+
+fn find_cargo_with(
+    cargo: Option<OsString>,
+    rustup_toolchain: Option<OsString>,
+) -> Result<CargoBinary> {
+    let cargo = match cargo {
+        Some(cargo) => PathBuf::from(cargo),
+        None => which::which("cargo")?,
+    };
+    // keep the proxy path to let rustup pick the toolchain for the current directory,
+    // e.g., from rust-toolchain.toml
+    let cargo = if is_rustup_proxy(&cargo) {
+        cargo
+    } else {
+        cargo.canonicalize()?
     };
-    let rustup_toolchain = env::var_os("RUSTUP_TOOLCHAIN");
     Ok(CargoBinary {
         path: cargo,
         rustup_toolchain,
+        rustup_proxy: false,
     })
 }
 
-pub fn cargo_cmd() -> Result<Command> {
-    Ok(find_cargo()?.command())
+/// Returns the variable with the extra linker arguments for the binary `bin`, e.g.,
+/// `CARGO_HYPERLIGHT_LINK_ARGS_MY_GUEST` for `my-guest`.
+pub fn link_args_var(bin: &str) -> String {
+    format!(
+        "CARGO_HYPERLIGHT_LINK_ARGS_{}",
+        bin.to_uppercase().replace('-', "_")
+    )
+}
+
+/// Returns whether `path` is one of rustup's proxies, which are links to rustup itself
+/// that dispatch on the name they are invoked with.
+pub fn is_rustup_proxy(path: &Path) -> bool {
+    path.canonicalize()
+        .is_ok_and(|path| path.file_stem().is_some_and(|stem| stem == "rustup"))
+}
+
+/// Returns rustup's cargo proxy, from `PATH` or else `cargo_home`, if rustup is installed.
+pub fn find_rustup_proxy(cargo_home: Option<OsString>) -> Option<PathBuf> {
+    let cargo_home = cargo_home.map(|home| {
+        PathBuf::from(home)
+            .join("bin")
+            .join(format!("cargo{}", env::consts::EXE_SUFFIX))
+    });
+    which::which("cargo")
+        .ok()
+        .into_iter()
+        .chain(cargo_home)
+        .find(|path| is_rustup_proxy(path))
+}
+
+/// Returns a command for the cargo binary selected by the `CARGO` and `RUSTUP_TOOLCHAIN`
+/// variables in `env`, which is the environment the command will run with.
+pub fn cargo_cmd(env: &HashMap<OsString, OsString>) -> Result<Command> {
+    let var = |key: &str| env.get(OsStr::new(key)).cloned();
+    Ok(find_cargo_with(var("CARGO"), var("RUSTUP_TOOLCHAIN"))?.command(env))
 }
 
 pub struct CheckedOutput {
@@ -110,33 +186,72 @@ impl CargoCmd for Command {
         self
     }
 
+    fn cc_wrapper_env(&mut self, triplet: impl AsRef<str>, wrapper: impl AsRef<Path>) -> &mut Self {
+        // override CC_<triplet> only, CLANG_PATH must keep pointing to the real clang
+        self.env(format!("CC_{}", triplet.as_ref()), wrapper.as_ref());
+        self
+    }
+
     fn ar_env(&mut self, triplet: impl AsRef<str>, ar: impl AsRef<Path>) -> &mut Self {
         // set AR_<triplet> so that cc-rs can pick it up
         self.env(format!("AR_{}", triplet.as_ref()), ar.as_ref());
         self
     }
 
+    fn linker_env(&mut self, triplet: impl AsRef<str>, linker: impl AsRef<Path>) -> &mut Self {
+        // set CARGO_TARGET_<TRIPLET>_LINKER, which cargo passes to rustc as -Clinker
+        let triplet = triplet.as_ref().to_uppercase().replace('-', "_");
+        self.env(format!("CARGO_TARGET_{triplet}_LINKER"), linker.as_ref());
+        self
+    }
+
     fn sysroot(&mut self, path: impl AsRef<Path>) -> &mut Self {
-        self.append_rustflags("--sysroot")
-            .append_rustflags(path.as_ref())
+        let flags = [OsStr::new("--sysroot"), path.as_ref().as_os_str()];
+        // rustdoc needs the sysroot too, to find `core` and `alloc` for the target
+        self.append_rustflags(flags).append_rustdocflags(flags)
+    }
+
+    fn bin_link_args(&mut self, bin: impl AsRef<str>, link_args: &[String]) -> &mut Self {
+        // picked up by the linker wrapper when linking the binary
+        self.env(link_args_var(bin.as_ref()), link_args.join(" "))
     }
 
     fn entrypoint(&mut self, entry: impl AsRef<str>) -> &mut Self {
         let entry = entry.as_ref();
-        self.append_rustflags(format!("-Clink-args=-e{entry}"))
+        self.append_rustflags([format!("-Clink-args=-e{entry}")])
     }
 
-    fn append_rustflags(&mut self, flags: impl AsRef<OsStr>) -> &mut Self {
-        if flags.as_ref().is_empty() {
-            return self;
-        }
+    fn hyperlight_cfg(&mut self, triplet: impl AsRef<str>) -> &mut Self {
+        let triplet = triplet.as_ref();
+        let (arch, _) = triplet.split_once('-').unwrap_or((triplet, ""));
+        let arch = format!("hyperlight_arch=\"{arch}\"");
+        let flags = [
+            "--cfg",
+            "hyperlight",
+            "--cfg",
+            &arch,
+            "--check-cfg",
+            "cfg(hyperlight)",
+            "--check-cfg",
+            "cfg(hyperlight_arch,values(any()))",
+        ];
+        // documentation should show the items that are only compiled for guests
+        self.append_rustflags(flags).append_rustdocflags(flags)
+    }
 
-        let mut new_flags = get_env(self, "RUSTFLAGS").unwrap_or_default();
-        if !new_flags.is_empty() {
-            new_flags.push(" ");
-        }
-        new_flags.push(flags.as_ref());
-        self.env("RUSTFLAGS", new_flags);
+    fn append_rustflags(
+        &mut self,
+        flags: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> &mut Self {
+        append_rustc_flags(self, "RUSTFLAGS", &flag_list(flags));
+        self
+    }
+
+    fn append_rustdocflags(
+        &mut self,
+        flags: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> &mut Self {
+        append_rustc_flags(self, "RUSTDOCFLAGS", &flag_list(flags));
         self
     }
 
@@ -145,30 +260,17 @@ impl CargoCmd for Command {
             return self;
         }
 
-        let triplet = triplet.as_ref();
-        let triplet_snake_case = triplet.replace('-', "_");
-        let triplet_snake_case_upper = triplet_snake_case.to_uppercase();
-
-        let search_keys = [
-            format!("CFLAGS_{triplet}"),
-            format!("CFLAGS_{triplet_snake_case}"),
-            format!("CFLAGS_{triplet_snake_case_upper}"),
-            "CFLAGS_hyperlight".to_string(),
-            "CFLAGS_HYPERLIGHT".to_string(),
-            "HYPERLIGHT_CFLAGS".to_string(),
-            "TARGET_CFLAGS".to_string(),
-            "CFLAGS".to_string(),
-        ];
+        let search_keys = cflags_keys(triplet.as_ref());
 
-        let mut new_flags = search_keys
+        // Take the flags from the highest priority variable that is set, and write
+        // the merged flags to the highest priority variable, so that cc-rs picks
+        // them up regardless of which variable the user set.
+        let current_flags = search_keys
             .iter()
             .find_map(|key| get_env(self, key))
             .unwrap_or_default();
 
-        if !new_flags.is_empty() {
-            new_flags.push(" ");
-        }
-        new_flags.push(flags.as_ref());
+        let new_flags = merge_flags(&current_flags, flags.as_ref());
         self.env(&search_keys[0], new_flags);
 
         self.append_bindgen_cflags(flags);
@@ -176,23 +278,36 @@ impl CargoCmd for Command {
         self
     }
 
+    fn replace_cflags(&mut self, triplet: impl AsRef<str>, flags: impl AsRef<OsStr>) -> &mut Self {
+        let search_keys = cflags_keys(triplet.as_ref());
+
+        // Unset the lower priority variables as well, as cc-rs concatenates some of them
+        for key in &search_keys[1..] {
+            self.env_remove(key);
+        }
+        self.env(&search_keys[0], flags.as_ref());
+        self.env(
+            "BINDGEN_EXTRA_CLANG_ARGS",
+            escape_bindgen_flags(flags.as_ref()),
+        );
+
+        self
+    }
+
     fn append_bindgen_cflags(&mut self, flags: impl AsRef<OsStr>) -> &mut Self {
         if flags.as_ref().is_empty() {
             return self;
         }
 
-        // For some reason we need to escape backslashes on Windows
-        // TODO(jprendes): check if we need to do any better escaping for other special characters
-        let flags = flags.as_ref().to_string_lossy().replace("\\", "\\\\");
+        let flags = escape_bindgen_flags(flags.as_ref());
 
         // TODO(jprendes): account and use the target specific variants of BINDGEN_EXTRA_CLANG_ARGS
         // see https://github.com/rust-lang/rust-bindgen/tree/main?tab=readme-ov-file#environment-variables
-        let mut new_flags = get_env(self, "BINDGEN_EXTRA_CLANG_ARGS").unwrap_or_default();
-        if !new_flags.is_empty() {
-            new_flags.push(" ");
-        }
-        new_flags.push(flags);
-        self.env("BINDGEN_EXTRA_CLANG_ARGS", new_flags);
+        let current_flags = get_env(self, "BINDGEN_EXTRA_CLANG_ARGS").unwrap_or_default();
+        self.env(
+            "BINDGEN_EXTRA_CLANG_ARGS",
+            merge_flags(&current_flags, &flags),
+        );
         self
     }
 
@@ -236,6 +351,321 @@ impl CargoCmd for Command {
     }
 }
 
+/// Returns the environment variables cc-rs reads the C flags of `triplet` from,
+/// in order of precedence.
+/// see https://docs.rs/cc/latest/cc/#external-configuration-via-environment-variables
+fn cflags_keys(triplet: &str) -> [String; 8] {
+    let triplet_snake_case = triplet.replace('-', "_");
+    let triplet_snake_case_upper = triplet_snake_case.to_uppercase();
+    [
+        format!("CFLAGS_{triplet}"),
+        format!("CFLAGS_{triplet_snake_case}"),
+        format!("CFLAGS_{triplet_snake_case_upper}"),
+        "CFLAGS_hyperlight".to_string(),
+        "CFLAGS_HYPERLIGHT".to_string(),
+        "HYPERLIGHT_CFLAGS".to_string(),
+        "TARGET_CFLAGS".to_string(),
+        "CFLAGS".to_string(),
+    ]
+}
+
+fn escape_bindgen_flags(flags: &OsStr) -> OsString {
+    // For some reason we need to escape backslashes on Windows
+    // TODO(jprendes): check if we need to do any better escaping for other special characters
+    flags.to_string_lossy().replace("\\", "\\\\").into()
+}
+
+/// Flags whose value is passed as a separate argument, e.g., `-isystem <dir>`.
+const FLAGS_WITH_VALUE: &[&str] = &[
+    "-isystem",
+    "-idirafter",
+    "-iquote",
+    "-I",
+    "-include",
+    "-D",
+    "-U",
+    "-target",
+    "-Xclang",
+];
+
+/// Rustc flags whose value is passed as a separate argument, e.g., `-C opt-level=2`.
+const RUSTFLAGS_WITH_VALUE: &[&str] = &[
+    "--sysroot",
+    "-C",
+    "-Z",
+    "--cfg",
+    "--check-cfg",
+    "-L",
+    "-l",
+    "-A",
+    "-W",
+    "-D",
+    "-F",
+    "--cap-lints",
+    "--target",
+];
+
+/// Separator of the flags in `CARGO_ENCODED_RUSTFLAGS` and `CARGO_ENCODED_RUSTDOCFLAGS`.
+const ENCODED_SEPARATOR: char = '\x1f';
+
+/// Splits `flags` into groups of a flag and its separate value, if any, for the flags
+/// in `with_value`.
+fn group_flags<'a>(
+    flags: impl IntoIterator<Item = &'a str>,
+    with_value: &[&str],
+) -> Vec<Vec<&'a str>> {
+    let mut groups: Vec<Vec<&str>> = vec![];
+    for flag in flags {
+        match groups.last_mut() {
+            Some(group) if group.len() == 1 && with_value.contains(&group[0]) => group.push(flag),
+            _ => groups.push(vec![flag]),
+        }
+    }
+    groups
+}
+
+/// Appends `flags` to `current`, skipping the flags that are already present in `current`.
+fn merge_flags(current: &OsStr, flags: &OsStr) -> OsString {
+    let current = current.to_string_lossy();
+    let flags = flags.to_string_lossy();
+
+    let mut groups = group_flags(current.split_whitespace(), FLAGS_WITH_VALUE);
+    for group in group_flags(flags.split_whitespace(), FLAGS_WITH_VALUE) {
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+
+    groups
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .into()
+}
+
+/// Which flag is kept when the user's rustflags and ours set the same thing.
+#[derive(Clone, Copy)]
+enum Precedence {
+    Ours,
+    Theirs,
+}
+
+/// Returns what the rustflag `group` sets, if it's something that can only be set
+/// once, and which flag is kept in a conflict.
+fn rustflag_category(group: &[&str]) -> Option<(&'static str, Precedence)> {
+    let codegen = match group {
+        ["-C", value] => Some(*value),
+        [flag] => flag.strip_prefix("-C"),
+        _ => None,
+    };
+    let link_arg = codegen.and_then(|value| {
+        value
+            .strip_prefix("link-args=")
+            .or_else(|| value.strip_prefix("link-arg="))
+    });
+    match group {
+        // rustc rejects a repeated `--sysroot`, and guests can only be built with ours
+        ["--sysroot", ..] => Some(("sysroot", Precedence::Ours)),
+        [flag] if flag.starts_with("--sysroot=") => Some(("sysroot", Precedence::Ours)),
+        // the user may have their own entry point on purpose
+        _ if link_arg.is_some_and(|arg| arg.starts_with("-e") || arg.starts_with("--entry")) => {
+            Some(("entry point", Precedence::Theirs))
+        }
+        _ => None,
+    }
+}
+
+/// Appends the rustflags `flags` to `current`, skipping the flags that are already
+/// present in `current`, and reconciling the ones that set the same thing according
+/// to their [`Precedence`].
+///
+/// Returns the merged flags, and a description of every flag that was dropped from
+/// the variable `key`.
+fn merge_rustflags<'a>(
+    key: &str,
+    current: impl IntoIterator<Item = &'a str>,
+    flags: &'a [String],
+) -> (Vec<String>, Vec<String>) {
+    let mut groups = group_flags(current, RUSTFLAGS_WITH_VALUE);
+    let mut conflicts = vec![];
+    for group in group_flags(flags.iter().map(String::as_str), RUSTFLAGS_WITH_VALUE) {
+        if groups.contains(&group) {
+            continue;
+        }
+        let category = rustflag_category(&group);
+        let conflict = category.and_then(|(name, precedence)| {
+            let index = groups
+                .iter()
+                .position(|other| rustflag_category(other).is_some_and(|(n, _)| n == name))?;
+            Some((name, precedence, index))
+        });
+        match conflict {
+            Some((name, Precedence::Ours, index)) => {
+                let theirs = groups.remove(index);
+                conflicts.push(format!(
+                    "ignoring `{}` from {key}, cargo-hyperlight sets the {name} with `{}`",
+                    theirs.join(" "),
+                    group.join(" ")
+                ));
+                groups.push(group);
+            }
+            Some((name, Precedence::Theirs, index)) => {
+                conflicts.push(format!(
+                    "using the {name} from `{}` in {key} instead of cargo-hyperlight's `{}`",
+                    groups[index].join(" "),
+                    group.join(" ")
+                ));
+            }
+            None => groups.push(group),
+        }
+    }
+
+    let flags = groups.into_iter().flatten().map(str::to_string).collect();
+    (flags, conflicts)
+}
+
+/// Returns the flags to merge `flags` into from `env`, and the variable they come from:
+/// `CARGO_ENCODED_<key>` if set, which cargo uses instead of `key` even if it's empty,
+/// or else `key`.
+fn current_rustc_flags(env: impl Fn(&str) -> Option<OsString>, key: &str) -> (Vec<String>, String) {
+    let encoded_key = format!("CARGO_ENCODED_{key}");
+    match env(&encoded_key) {
+        Some(flags) => (decode_flags(&flags), encoded_key),
+        None => {
+            let flags = env(key).unwrap_or_default();
+            (split_flags(&flags), key.to_string())
+        }
+    }
+}
+
+/// Merges `flags` into the rustc or rustdoc flags in the variable `key` with
+/// [`merge_rustflags`], warning about the dropped flags.
+///
+/// The merged flags are written to `CARGO_ENCODED_<key>` if it was already set, or if
+/// any flag contains a space, e.g., a sysroot path, which `key` can't represent.
+fn append_rustc_flags(cmd: &mut Command, key: &str, flags: &[String]) {
+    if flags.is_empty() {
+        return;
+    }
+
+    let (current, source) = current_rustc_flags(|key| get_env(cmd, key), key);
+    let (new_flags, conflicts) =
+        merge_rustflags(&source, current.iter().map(String::as_str), flags);
+    for conflict in conflicts {
+        warning_once("rustflags-conflict", conflict);
+    }
+
+    let encoded_key = format!("CARGO_ENCODED_{key}");
+    if source == encoded_key {
+        cmd.env(encoded_key, encode_flags(&new_flags));
+    } else if new_flags
+        .iter()
+        .any(|flag| flag.contains(char::is_whitespace))
+    {
+        cmd.env(encoded_key, encode_flags(&new_flags));
+        // the flags moved, cargo would ignore it anyway
+        cmd.env_remove(key);
+    } else {
+        cmd.env(key, new_flags.join(" "));
+    }
+}
+
+/// Returns the warnings that merging `flags` into the rustc or rustdoc flags in `env`
+/// for the variable `key` emits, like [`CargoCmd::append_rustflags`] does.
+pub fn rustflags_conflicts(
+    env: &HashMap<OsString, OsString>,
+    key: &str,
+    flags: &[String],
+) -> Vec<String> {
+    let (current, source) = current_rustc_flags(|key| env.get(OsStr::new(key)).cloned(), key);
+    merge_rustflags(&source, current.iter().map(String::as_str), flags).1
+}
+
+/// Converts `flags` to a list of strings.
+fn flag_list(flags: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Vec<String> {
+    flags
+        .into_iter()
+        .map(|flag| flag.as_ref().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Splits the space separated flags of a variable like `RUSTFLAGS`.
+fn split_flags(flags: &OsStr) -> Vec<String> {
+    flags
+        .to_string_lossy()
+        .split_whitespace()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Splits the flags of a variable like `CARGO_ENCODED_RUSTFLAGS`, which are separated
+/// by `\x1f` and can contain spaces.
+fn decode_flags(flags: &OsStr) -> Vec<String> {
+    let flags = flags.to_string_lossy();
+    if flags.is_empty() {
+        return vec![];
+    }
+    flags.split(ENCODED_SEPARATOR).map(str::to_string).collect()
+}
+
+/// Joins `flags` for a variable like `CARGO_ENCODED_RUSTFLAGS`.
+fn encode_flags(flags: &[String]) -> String {
+    flags.join(&ENCODED_SEPARATOR.to_string())
+}
+
+/// Returns the flags in `CARGO_ENCODED_RUSTFLAGS`, if set, or else in `RUSTFLAGS`,
+/// which is what cargo uses.
+pub fn rustflags(env: impl Fn(&str) -> Option<OsString>) -> Option<Vec<String>> {
+    match env("CARGO_ENCODED_RUSTFLAGS") {
+        Some(flags) => Some(decode_flags(&flags)),
+        None => env("RUSTFLAGS").map(|flags| split_flags(&flags)),
+    }
+}
+
+/// Moves the rustflags of `cmd` to the cargo `--config` value it returns, setting
+/// `target.<triplet>.rustflags`, so that they only apply to units built for the target.
+///
+/// `RUSTFLAGS` would take precedence over the setting, and
+/// `CARGO_TARGET_<TRIPLET>_RUSTFLAGS` would split the flags on spaces, while a TOML
+/// array keeps every flag whole.
+pub fn target_rustflags_config(cmd: &mut Command, triplet: &str) -> String {
+    let flags = rustflags(|key| get_env(cmd, key)).unwrap_or_default();
+    cmd.env_remove("RUSTFLAGS");
+    cmd.env_remove("CARGO_ENCODED_RUSTFLAGS");
+    // cargo would append it to the setting instead
+    cmd.env_remove(format!(
+        "CARGO_TARGET_{}_RUSTFLAGS",
+        triplet.to_uppercase().replace('-', "_")
+    ));
+    format!("target.{triplet}.rustflags={}", toml_array(&flags))
+}
+
+/// Returns `values` as a TOML array of strings.
+pub fn toml_array(values: &[String]) -> String {
+    // TOML basic strings escape like JSON strings
+    serde_json::to_string(values).unwrap()
+}
+
+/// Returns the rustflags cargo builds the units for `triplet` with when running `cmd`.
+pub fn target_rustflags(cmd: &Command, triplet: &str) -> Option<Vec<String>> {
+    let config = format!("target.{triplet}.rustflags=");
+    let mut args = cmd.get_args().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--config"
+            && let Some(value) = args.next()
+            && let Some(flags) = value.strip_prefix(&config)
+        {
+            return serde_json::from_str(flags).ok();
+        }
+    }
+    let key = format!(
+        "CARGO_TARGET_{}_RUSTFLAGS",
+        triplet.to_uppercase().replace('-', "_")
+    );
+    rustflags(|key| get_env(cmd, key)).or_else(|| get_env(cmd, &key).map(|f| split_flags(&f)))
+}
+
 fn get_env(cmd: &Command, key: &str) -> Option<OsString> {
     let mut envs = cmd.get_envs();
     match envs.find(|(k, _)| *k == key) {
@@ -263,3 +693,245 @@ pub fn merge_env(
 
     base
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIPLET: &str = "x86_64-hyperlight-none";
+
+    fn cflags(cmd: &Command) -> String {
+        get_env(cmd, "CFLAGS_x86_64-hyperlight-none")
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn append_cflags_uses_highest_priority_variable() {
+        let mut cmd = Command::new("true");
+        cmd.env("CFLAGS", "-O0")
+            .env("TARGET_CFLAGS", "-O1")
+            .env("CFLAGS_x86_64_hyperlight_none", "-O2")
+            .append_cflags(TRIPLET, "-nostdinc");
+        assert_eq!(cflags(&cmd), "-O2 -nostdinc");
+    }
+
+    #[test]
+    fn append_cflags_skips_duplicated_flags() {
+        let mut cmd = Command::new("true");
+        cmd.env("CFLAGS", "")
+            .append_cflags(TRIPLET, "-nostdinc -isystem /a -isystem /b")
+            .append_cflags(TRIPLET, "-nostdinc -isystem /b -isystem /c -DFOO");
+        assert_eq!(
+            cflags(&cmd),
+            "-nostdinc -isystem /a -isystem /b -isystem /c -DFOO"
+        );
+    }
+
+    #[test]
+    fn replace_cflags_ignores_environment() {
+        let mut cmd = Command::new("true");
+        cmd.env("CFLAGS", "-O0")
+            .env("CFLAGS_x86_64_hyperlight_none", "-O2")
+            .replace_cflags(TRIPLET, "-nostdinc");
+        assert_eq!(cflags(&cmd), "-nostdinc");
+        assert_eq!(get_env(&cmd, "CFLAGS_x86_64_hyperlight_none"), None);
+        assert_eq!(get_env(&cmd, "CFLAGS"), None);
+    }
+
+    #[test]
+    fn group_flags_keeps_values_together() {
+        assert_eq!(
+            group_flags(
+                "-isystem /a -DFOO -D BAR -O2".split_whitespace(),
+                FLAGS_WITH_VALUE
+            ),
+            [
+                vec!["-isystem", "/a"],
+                vec!["-DFOO"],
+                vec!["-D", "BAR"],
+                vec!["-O2"]
+            ]
+        );
+    }
+
+    fn rustflags(current: &str, flags: &str) -> (String, Vec<String>) {
+        let flags = split_flags(OsStr::new(flags));
+        let (flags, conflicts) = merge_rustflags("RUSTFLAGS", current.split_whitespace(), &flags);
+        (flags.join(" "), conflicts)
+    }
+
+    #[test]
+    fn merge_rustflags_skips_duplicated_flags() {
+        let (flags, conflicts) = rustflags(
+            "-C opt-level=2 --cfg hyperlight",
+            "--cfg hyperlight --cfg hyperlight_arch=\"x86_64\" -Copt-level=2",
+        );
+        assert_eq!(
+            flags,
+            "-C opt-level=2 --cfg hyperlight --cfg hyperlight_arch=\"x86_64\" -Copt-level=2"
+        );
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge_rustflags_reconciles_conflicts() {
+        // our sysroot wins
+        let (flags, conflicts) = rustflags("--sysroot=/theirs -Dwarnings", "--sysroot /ours");
+        assert_eq!(flags, "-Dwarnings --sysroot /ours");
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].starts_with("ignoring `--sysroot=/theirs`"));
+
+        // their entry point wins
+        let (flags, conflicts) = rustflags("-C link-arg=-emain", "-Clink-args=-eentrypoint");
+        assert_eq!(flags, "-C link-arg=-emain");
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].starts_with("using the entry point from `-C link-arg=-emain`"));
+
+        // other link args are not an entry point
+        let (flags, conflicts) = rustflags("-Clink-args=-s", "-Clink-args=-eentrypoint");
+        assert_eq!(flags, "-Clink-args=-s -Clink-args=-eentrypoint");
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn target_rustflags_only_apply_to_target() {
+        let mut cmd = Command::new("true");
+        cmd.env("RUSTFLAGS", "-Dwarnings")
+            .env("CARGO_TARGET_X86_64_HYPERLIGHT_NONE_RUSTFLAGS", "-Dunused")
+            .sysroot("/sysroot");
+        let config = target_rustflags_config(&mut cmd, TRIPLET);
+        assert_eq!(get_env(&cmd, "RUSTFLAGS"), None);
+        assert_eq!(
+            get_env(&cmd, "CARGO_TARGET_X86_64_HYPERLIGHT_NONE_RUSTFLAGS"),
+            None
+        );
+        assert_eq!(
+            config,
+            r#"target.x86_64-hyperlight-none.rustflags=["-Dwarnings","--sysroot","/sysroot"]"#
+        );
+    }
+
+    #[test]
+    fn sysroot_and_cfg_apply_to_rustdoc() {
+        let mut cmd = Command::new("true");
+        cmd.env("RUSTFLAGS", "")
+            .env("RUSTDOCFLAGS", "--document-private-items")
+            .sysroot("/sysroot")
+            .hyperlight_cfg(TRIPLET);
+        let rustdocflags = get_env(&cmd, "RUSTDOCFLAGS").unwrap();
+        let rustdocflags = rustdocflags.to_string_lossy();
+        assert!(rustdocflags.starts_with("--document-private-items --sysroot /sysroot"));
+        assert!(rustdocflags.contains("--cfg hyperlight_arch=\"x86_64\""));
+        assert_eq!(
+            get_env(&cmd, "RUSTFLAGS").unwrap(),
+            rustdocflags
+                .strip_prefix("--document-private-items ")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn encoded_rustflags_take_precedence() {
+        let mut cmd = Command::new("true");
+        cmd.env("RUSTFLAGS", "-Dwarnings")
+            .env(
+                "CARGO_ENCODED_RUSTFLAGS",
+                "-Clink-arg=-Wl,--a b\x1f--sysroot\x1f/theirs",
+            )
+            .sysroot("/sysroot");
+        assert_eq!(get_env(&cmd, "RUSTFLAGS").unwrap(), "-Dwarnings");
+        assert_eq!(
+            get_env(&cmd, "CARGO_ENCODED_RUSTFLAGS").unwrap(),
+            "-Clink-arg=-Wl,--a b\x1f--sysroot\x1f/sysroot"
+        );
+
+        // an empty CARGO_ENCODED_RUSTFLAGS still overrides RUSTFLAGS
+        let mut cmd = Command::new("true");
+        cmd.env("RUSTFLAGS", "-Dwarnings")
+            .env("CARGO_ENCODED_RUSTFLAGS", "")
+            .sysroot("/sysroot");
+        assert_eq!(
+            get_env(&cmd, "CARGO_ENCODED_RUSTFLAGS").unwrap(),
+            "--sysroot\x1f/sysroot"
+        );
+    }
+
+    #[test]
+    fn target_rustflags_are_read_from_either_variable() {
+        let mut cmd = Command::new("true");
+        cmd.env("RUSTFLAGS", "-Dwarnings").sysroot("/sysroot");
+        assert_eq!(
+            target_rustflags(&cmd, TRIPLET).unwrap(),
+            ["-Dwarnings", "--sysroot", "/sysroot"]
+        );
+        let config = target_rustflags_config(&mut cmd, TRIPLET);
+        cmd.arg("--config").arg(config);
+        assert_eq!(
+            target_rustflags(&cmd, TRIPLET).unwrap(),
+            ["-Dwarnings", "--sysroot", "/sysroot"]
+        );
+    }
+
+    #[test]
+    fn target_rustflags_include_encoded_rustflags() {
+        let mut cmd = Command::new("true");
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", "-Dwarnings")
+            .sysroot("/sysroot");
+        let config = target_rustflags_config(&mut cmd, TRIPLET);
+        assert_eq!(get_env(&cmd, "CARGO_ENCODED_RUSTFLAGS"), None);
+        assert_eq!(
+            config,
+            r#"target.x86_64-hyperlight-none.rustflags=["-Dwarnings","--sysroot","/sysroot"]"#
+        );
+    }
+
+    #[test]
+    fn flags_with_spaces_stay_whole() {
+        // the user's flag and our sysroot both have a space
+        let mut cmd = Command::new("true");
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", "-Clink-arg=-Wl,--a b")
+            .sysroot("/my sysroot");
+        assert_eq!(
+            get_env(&cmd, "CARGO_ENCODED_RUSTFLAGS").unwrap(),
+            "-Clink-arg=-Wl,--a b\x1f--sysroot\x1f/my sysroot"
+        );
+        assert_eq!(
+            target_rustflags_config(&mut cmd, TRIPLET),
+            r#"target.x86_64-hyperlight-none.rustflags=["-Clink-arg=-Wl,--a b","--sysroot","/my sysroot"]"#
+        );
+
+        // RUSTFLAGS can't hold the sysroot, the flags move to CARGO_ENCODED_RUSTFLAGS
+        let mut cmd = Command::new("true");
+        cmd.env("RUSTFLAGS", "-Dwarnings").sysroot("/my sysroot");
+        assert_eq!(get_env(&cmd, "RUSTFLAGS"), None);
+        assert_eq!(
+            get_env(&cmd, "CARGO_ENCODED_RUSTFLAGS").unwrap(),
+            "-Dwarnings\x1f--sysroot\x1f/my sysroot"
+        );
+        assert_eq!(
+            target_rustflags(&cmd, TRIPLET).unwrap(),
+            ["-Dwarnings", "--sysroot", "/my sysroot"]
+        );
+    }
+
+    #[test]
+    fn merge_env_overrides_and_removes() {
+        let base = [("A", "base"), ("B", "base"), ("C", "base")];
+        let envs = [("A", Some("cmd")), ("B", None), ("D", Some("cmd"))];
+        let env = merge_env(base, envs);
+        let get = |key: &str| env.get(OsStr::new(key)).map(|v| v.to_str().unwrap());
+        assert_eq!(get("A"), Some("cmd"));
+        assert_eq!(get("B"), None);
+        assert_eq!(get("C"), Some("base"));
+        assert_eq!(get("D"), Some("cmd"));
+
+        // the last setting of a variable wins, like with `Command::env`
+        let env = merge_env(
+            base,
+            [("A", Some("first")), ("A", None), ("A", Some("last"))],
+        );
+        assert_eq!(env[OsStr::new("A")], "last");
+    }
+}
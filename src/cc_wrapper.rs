@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+
+use crate::cli::{Args, warning_once};
+use crate::{generated, toolchain};
+
+/// Flags some build scripts add that are not supported in hyperlight guests.
+/// The wrapper removes them before calling clang.
+const STRIPPED_FLAGS: &[&str] = &[
+    "-fstack-protector",
+    "-fstack-protector-all",
+    "-fstack-protector-strong",
+    "-fstack-protector-explicit",
+    "-mred-zone",
+];
+
+/// Writes the C compiler wrapper to [`Args::cc_wrapper_path`].
+///
+/// cc-rs is pointed to the wrapper instead of clang, so that C builds are the same
+/// regardless of what flags build scripts add. The wrapper:
+/// * logs every invocation to [`Args::cc_log_path`], if it's writable
+/// * removes the [`STRIPPED_FLAGS`], except on Windows, where a warning says so instead
+/// * appends hyperlight's own flags, followed by the extra flags added with
+///   [`Command::cflag`](crate::Command::cflag), so that they take precedence over
+///   the flags from the environment
+/// * appends the flags configured for the package being built, found through
+///   `CARGO_PKG_NAME`, which cargo sets for build scripts
+/// * calls the real clang, through the compiler launcher (e.g., `ccache`) if there's one
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn install(args: &Args) -> Result<()> {
+    let Some(clang) = &args.clang else {
+        // without clang, cc-rs builds will fail anyway
+        return Ok(());
+    };
+
+    let clang = clang.to_string_lossy();
+    let launcher = args
+        .compiler_launcher
+        .as_ref()
+        .map(|launcher| launcher.to_string_lossy());
+    let log = args.cc_log_path().to_string_lossy().into_owned();
+    let flags = toolchain::cflags_list(args)
+        .iter()
+        .chain(&args.cflags)
+        .map(|flag| flag.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let script = if cfg!(windows) {
+        // batch splits arguments on `=` as well, so the wrapper can't filter them without
+        // breaking flags like `-DFOO=1`
+        warning_once(
+            "cc-flags-not-stripped",
+            format!(
+                "the C compiler wrapper doesn't remove unsupported flags on Windows, \
+                 build scripts that add {} may fail to build",
+                STRIPPED_FLAGS.join(", ")
+            ),
+        );
+        batch_script(launcher.as_deref(), &clang, &log, &flags, &args.dep_cflags)
+    } else {
+        shell_script(launcher.as_deref(), &clang, &log, &flags, &args.dep_cflags)
+    };
+
+    std::fs::create_dir_all(args.triplet_dir()).context("Failed to create sysroot directory")?;
+
+    // start a new log on every invocation
+    std::fs::write(args.cc_log_path(), "").context("Failed to truncate C compiler log")?;
+
+    // avoid touching the wrapper if it's unchanged, so that cc-rs doesn't see a new compiler
+    let path = args.cc_wrapper_path();
+    if std::fs::read_to_string(&path).is_ok_and(|current| current == script) {
+        return Ok(());
+    }
+
+    generated::write(args, &path, &script).context("Failed to write C compiler wrapper")?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .context("Failed to make C compiler wrapper executable")?;
+    }
+
+    Ok(())
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+fn shell_script(
+    launcher: Option<&str>,
+    clang: &str,
+    log: &str,
+    flags: &[String],
+    dep_cflags: &BTreeMap<String, Vec<String>>,
+) -> String {
+    let quote_all = |flags: &[String]| {
+        flags
+            .iter()
+            .map(|flag| shell_quote(flag))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let mut script = String::from("#!/bin/sh\n");
+
+    // filter the arguments in place
+    script.push_str("for arg do\n    shift\n    case \"$arg\" in\n");
+    let stripped = STRIPPED_FLAGS.join("|");
+    writeln!(script, "        {stripped}) ;;").unwrap();
+    script.push_str("        *) set -- \"$@\" \"$arg\" ;;\n    esac\ndone\n");
+
+    writeln!(script, "set -- \"$@\" {}", quote_all(flags)).unwrap();
+
+    script.push_str("case \"$CARGO_PKG_NAME\" in\n");
+    for (package, flags) in dep_cflags {
+        writeln!(
+            script,
+            "    {}) set -- \"$@\" {} ;;",
+            shell_quote(package),
+            quote_all(flags)
+        )
+        .unwrap();
+    }
+    script.push_str("esac\n");
+
+    // a prebuilt sysroot can be read-only, so failing to log is not an error
+    writeln!(
+        script,
+        "{{ printf '[%s] %s %s\\n' \"$CARGO_PKG_NAME\" {} \"$*\" >> {}; }} 2>/dev/null",
+        shell_quote(clang),
+        shell_quote(log)
+    )
+    .unwrap();
+    let launcher = launcher.map(|launcher| shell_quote(launcher) + " ");
+    writeln!(
+        script,
+        "exec {}{} \"$@\"",
+        launcher.unwrap_or_default(),
+        shell_quote(clang)
+    )
+    .unwrap();
+    script
+}
+
+fn batch_script(
+    launcher: Option<&str>,
+    clang: &str,
+    log: &str,
+    flags: &[String],
+    dep_cflags: &BTreeMap<String, Vec<String>>,
+) -> String {
+    let quote_all = |flags: &[String]| {
+        flags
+            .iter()
+            .map(|flag| format!("\"{flag}\""))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+
+    let mut script = String::from("@echo off\r\nset HYPERLIGHT_DEP_CFLAGS=\r\n");
+    for (package, flags) in dep_cflags {
+        write!(
+            script,
+            "if \"%CARGO_PKG_NAME%\"==\"{package}\" set HYPERLIGHT_DEP_CFLAGS={}\r\n",
+            quote_all(flags)
+        )
+        .unwrap();
+    }
+    let flags = quote_all(flags);
+    write!(
+        script,
+        "echo [%CARGO_PKG_NAME%] \"{clang}\" %* {flags} %HYPERLIGHT_DEP_CFLAGS% >> \"{log}\" 2>nul\r\n"
+    )
+    .unwrap();
+    let launcher = launcher
+        .map(|launcher| format!("\"{launcher}\" "))
+        .unwrap_or_default();
+    write!(
+        script,
+        "{launcher}\"{clang}\" %* {flags} %HYPERLIGHT_DEP_CFLAGS%\r\nexit /b %ERRORLEVEL%\r\n"
+    )
+    .unwrap();
+    script
+}
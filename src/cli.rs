@@ -1,32 +1,106 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::Infallible;
-use std::env;
 use std::env::consts::ARCH;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{env, iter};
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use const_format::formatcp;
+use serde_json::Value;
 
-use crate::cargo_cmd::{CargoCmd as _, cargo_cmd};
-use crate::toolchain;
+use crate::allocator::Allocator;
+use crate::cargo_cmd::{CargoCmd as _, cargo_cmd, rustflags_conflicts};
+use crate::code_model::{CodeModel, Models, RelocationModel, TlsModel};
+use crate::compress::Compression;
+use crate::doctest::{self, Doctests};
+use crate::failure::{Failure, WithFailure as _};
+use crate::metadata::{HyperlightMetadata, ProfileMetadata};
+use crate::{compat, report, toolchain};
 
 pub struct Args {
     pub manifest_path: Option<PathBuf>,
     pub target_dir: PathBuf,
     pub target: String,
+    /// Target spec file given with `--target path/to/spec.json`, whose name is `target`
+    pub custom_target: Option<PathBuf>,
     pub env: HashMap<OsString, OsString>,
     pub current_dir: PathBuf,
     pub clang: Option<PathBuf>,
     pub ar: Option<PathBuf>,
+    pub out_dir: Option<PathBuf>,
+    /// Compression of the copies of the guest binaries written to `out_dir`
+    pub compress: Option<Compression>,
+    pub verbose: u8,
+    /// Profile selected in the cargo arguments, e.g., `dev` or `release`
+    pub profile: String,
+    /// How the cargo arguments involve doctests
+    pub doctests: Doctests,
+    /// Whether cargo builds the guests as artifact dependencies of a host crate, with
+    /// `-Zbindeps`, instead of building everything for the hyperlight target
+    pub artifact_deps: bool,
+    /// Whether `--frozen` is among the cargo arguments, so that the generated inputs of
+    /// the build must already be up to date instead of being written
+    pub frozen: bool,
+    /// Whether the cargo command runs the guests it builds, e.g., `cargo run` or
+    /// `cargo test`
+    pub runs_guests: bool,
+    /// Whether to only build the guests, instead of running them, when no hypervisor
+    /// is available
+    pub no_run_fallback: bool,
+    /// Whether to apply safe fixes for known problems automatically
+    pub fix: bool,
+    pub include_dirs: Vec<PathBuf>,
+    pub static_libs: Vec<PathBuf>,
+    pub libm: bool,
+    /// Whether to compile C code to LLVM bitcode and link it with LTO together with Rust
+    pub cross_language_lto: bool,
+    /// Whether hyperlight-guest-bin's C sources are built once into the sysroot, and
+    /// linked instead of running its build script
+    pub cached_c_archive: bool,
+    /// Linker used instead of `rust-lld`, must be a flavor of LLD
+    pub linker: Option<PathBuf>,
+    /// Program clang is run through, e.g., `ccache` or `sccache`
+    pub compiler_launcher: Option<PathBuf>,
+    /// Extra linker arguments for specific binaries, by binary name
+    pub bin_link_args: BTreeMap<String, Vec<String>>,
+    /// Whether to write a link map next to every guest binary
+    pub emit_map: bool,
+    /// Whether to write a provenance file next to every guest binary
+    pub provenance: bool,
+    /// Whether to time the sysroot build and merge it into cargo's timing report
+    pub timings: bool,
+    /// Prebuilt sysroot to use without writing to it, instead of preparing one in the
+    /// target directory
+    pub prebuilt_sysroot: Option<PathBuf>,
+    /// Whether to only warn about unsupported versions of rustc and the hyperlight crates
+    pub allow_unsupported: bool,
+    /// Extra C flags from the profile settings, followed by the ones added with
+    /// [`Command::cflag`](crate::Command::cflag)
+    pub cflags: Vec<OsString>,
+    /// Whether to ignore the C flags from the environment
+    pub replace_cflags: bool,
+    /// Extra C flags for the build scripts of specific packages, by package name
+    pub dep_cflags: BTreeMap<String, Vec<String>>,
+    /// Whether to pass `--cfg hyperlight` to guest builds
+    pub emit_cfg: bool,
+    /// Settings from `[package.metadata.hyperlight.profile.<profile>]`
+    pub profile_settings: ProfileMetadata,
+    /// Global allocator selected in the metadata
+    pub allocator: Option<Allocator>,
+    /// Code, relocation and TLS models, from the metadata and the command line
+    pub models: Models,
 }
 
 pub trait WarningLevel {
-    type Error;
+    type Error: Into<anyhow::Error>;
     fn warning<T: Debug>(
         &self,
+        code: &'static str,
         msg: &str,
         err: impl Into<anyhow::Error>,
         default: T,
@@ -39,14 +113,12 @@ pub struct Warning;
 pub mod warning {
     pub struct WarningIgnore;
     pub struct WarningWarn;
-    #[allow(dead_code)]
     pub struct WarningError;
 }
 
 impl Warning {
     pub const IGNORE: warning::WarningIgnore = warning::WarningIgnore;
     pub const WARN: warning::WarningWarn = warning::WarningWarn;
-    #[allow(dead_code)]
     pub const ERROR: warning::WarningError = warning::WarningError;
 }
 
@@ -54,6 +126,7 @@ impl WarningLevel for warning::WarningIgnore {
     type Error = Infallible;
     fn warning<T: Debug>(
         &self,
+        _code: &'static str,
         _msg: &str,
         _err: impl Into<anyhow::Error>,
         default: T,
@@ -66,13 +139,16 @@ impl WarningLevel for warning::WarningWarn {
     type Error = Infallible;
     fn warning<T: Debug>(
         &self,
+        code: &'static str,
         msg: &str,
         err: impl Into<anyhow::Error>,
         default: T,
     ) -> Result<T, Self::Error> {
-        warning(msg);
-        warning(format!("{:?}", err.into()));
-        warning(format!("using {default:?}"));
+        emit(report::Warning {
+            code,
+            message: format!("{msg}: {:#}", err.into()),
+            default: Some(format!("{default:?}")),
+        });
         Ok(default)
     }
 }
@@ -81,6 +157,7 @@ impl WarningLevel for warning::WarningError {
     type Error = anyhow::Error;
     fn warning<T: Debug>(
         &self,
+        _code: &'static str,
         msg: &str,
         err: impl Into<anyhow::Error>,
         _default: T,
@@ -95,16 +172,33 @@ impl Args {
         env: impl IntoIterator<Item = (impl Into<OsString>, impl Into<OsString>)>,
         cwd: Option<impl Into<PathBuf>>,
         warn: W,
-    ) -> Result<Args, W::Error> {
-        let mut args = ArgsImpl::parse_from(args);
+    ) -> Result<Args>
+    where
+        anyhow::Error: From<W::Error>,
+    {
+        let split = split_args(args);
+        // `parse_from` would exit the process, which a library must not do
+        let mut args = ArgsImpl::try_parse_from(
+            iter::once(OsString::from("cargo-hyperlight")).chain(split.hyperlight),
+        )
+        .map_err(|err| anyhow::anyhow!(flag_error(&err)))
+        .failure(Failure::Config)?;
+        args.profile = resolve_profile(&split.rest);
+        args.doctests = doctest::resolve(&split.rest);
+        args.artifact_deps = resolve_artifact_deps(&split.rest);
+        args.frozen = resolve_frozen(&split.rest);
+        args.runs_guests = resolve_runs_guests(&split.rest);
         args.env = env.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
         let cwd = match cwd {
             Some(cwd) => cwd.into(),
             None => match env::current_dir() {
                 Ok(cwd) => cwd,
-                Err(err) => {
-                    warn.warning("Could not get current directory", err, PathBuf::from("."))?
-                }
+                Err(err) => warn.warning(
+                    "current-dir",
+                    "Could not get current directory",
+                    err,
+                    PathBuf::from("."),
+                )?,
             },
         };
         args.current_dir = cwd.clone();
@@ -112,12 +206,206 @@ impl Args {
     }
 }
 
-fn warning(msg: impl AsRef<str>) {
+/// Returns an error about the `value` passed to `flag`, pointing at it, e.g.,
+///
+/// ```text
+/// invalid value for `--manifest-path`: no such file
+///   --manifest-path guest/Cargo.tml
+///                   ^^^^^^^^^^^^^^^
+///   = help: ...
+/// ```
+fn invalid_value(flag: &str, value: &Path, problem: &str, help: &str) -> anyhow::Error {
+    let value = value.display().to_string();
+    anyhow::anyhow!(
+        "invalid value for `{flag}`: {problem}\n  {flag} {value}\n  {}{}\n  = help: {help}",
+        " ".repeat(flag.chars().count() + 1),
+        "^".repeat(value.chars().count().max(1)),
+    )
+}
+
+/// Checks that `--manifest-path` is an existing `Cargo.toml` file, relative to `cwd`.
+fn validate_manifest_path(manifest_path: Option<&Path>, cwd: &Path) -> Result<()> {
+    let Some(manifest_path) = manifest_path else {
+        return Ok(());
+    };
+    let invalid = |problem, help| invalid_value("--manifest-path", manifest_path, problem, help);
+    let path = cwd.join(manifest_path);
+    if path.file_name() != Some(OsStr::new("Cargo.toml")) {
+        return Err(invalid(
+            "not a `Cargo.toml` file",
+            "the manifest path must be the path to a `Cargo.toml` file, e.g., `guest/Cargo.toml`",
+        ));
+    }
+    if !path.exists() {
+        return Err(invalid(
+            "no such file",
+            &format!(
+                "relative paths are resolved from the current directory, {}",
+                cwd.display()
+            ),
+        ));
+    }
+    if !path.is_file() {
+        return Err(invalid(
+            "not a file",
+            "the manifest path must be the path to a `Cargo.toml` file, e.g., `guest/Cargo.toml`",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `--target-dir` is a directory, or doesn't exist yet, relative to `cwd`.
+fn validate_target_dir(target_dir: Option<&Path>, cwd: &Path) -> Result<()> {
+    let Some(target_dir) = target_dir else {
+        return Ok(());
+    };
+    let path = cwd.join(target_dir);
+    if path.exists() && !path.is_dir() {
+        return Err(invalid_value(
+            "--target-dir",
+            target_dir,
+            "not a directory",
+            "the target directory is where cargo writes the build artifacts, it's created \
+            if it doesn't exist",
+        ));
+    }
+    Ok(())
+}
+
+/// Handler of the warnings emitted by cargo-hyperlight, see [`Command::warning_handler`].
+///
+/// [`Command::warning_handler`]: crate::Command::warning_handler
+pub type WarningHandler = Arc<dyn Fn(report::Warning) + Send + Sync>;
+
+/// Expands the response files among `args`, i.e., arguments like `@path/to/args`,
+/// into the arguments listed in the file, resolving relative paths from `cwd`.
+/// Arguments after `--` are passed to the program being run, and are not expanded.
+pub fn expand_response_files(args: &[OsString], cwd: &Path) -> Result<Vec<OsString>> {
+    let mut expanded = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            expanded.push(arg.clone());
+            expanded.extend(args.by_ref().cloned());
+            break;
+        }
+        let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix('@')) else {
+            expanded.push(arg.clone());
+            continue;
+        };
+        let path = cwd.join(path);
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read response file {path:?}"))?;
+        expanded.extend(parse_response_file(&content));
+    }
+    Ok(expanded)
+}
+
+/// Returns the arguments in a response file, one per line, skipping empty lines
+/// and comments starting with `#`.
+fn parse_response_file(content: &str) -> impl Iterator<Item = OsString> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(OsString::from)
+}
+
+/// Returns whether `--strict` is among the flags handled by cargo-hyperlight in `args`.
+pub fn is_strict(args: impl IntoIterator<Item = impl Into<OsString> + Clone>) -> bool {
+    split_args(args)
+        .hyperlight
+        .iter()
+        .any(|arg| arg == "--strict")
+}
+
+thread_local! {
+    /// Warnings emitted in the current thread, while being captured with [`capture_warnings`].
+    static CAPTURED_WARNINGS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+
+    /// Handler of the warnings emitted in the current thread, set with [`with_warning_handler`].
+    static WARNING_HANDLER: RefCell<Option<WarningHandler>> = const { RefCell::new(None) };
+
+    /// Warnings already emitted with [`warning_once`] in the current thread.
+    static EMITTED_ONCE: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Runs `f`, returning its result together with the warnings it emitted.
+/// The warnings are still printed to stderr, or passed to the warning handler.
+pub fn capture_warnings<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    let previous = CAPTURED_WARNINGS.replace(Some(vec![]));
+    let result = f();
+    let warnings = CAPTURED_WARNINGS.replace(previous).unwrap_or_default();
+    (result, warnings)
+}
+
+/// Runs `f` passing the warnings it emits to `handler` instead of printing them.
+/// Without a `handler`, the warnings are handled as they would be outside of `f`.
+pub fn with_warning_handler<R>(handler: Option<&WarningHandler>, f: impl FnOnce() -> R) -> R {
+    let Some(handler) = handler else {
+        return f();
+    };
+    let previous = WARNING_HANDLER.replace(Some(handler.clone()));
+    let result = f();
+    WARNING_HANDLER.set(previous);
+    result
+}
+
+/// Emits a warning identified by `code`, see [`Warning::code`].
+///
+/// [`Warning::code`]: crate::Warning::code
+pub fn warning(code: &'static str, msg: impl AsRef<str>) {
+    emit(report::Warning {
+        code,
+        message: msg.as_ref().to_string(),
+        default: None,
+    });
+}
+
+/// Like [`warning`], but only the first time `msg` is emitted, for warnings about
+/// settings that are applied to several cargo invocations.
+pub fn warning_once(code: &'static str, msg: impl AsRef<str>) {
+    if EMITTED_ONCE.with_borrow_mut(|emitted| emitted.insert(msg.as_ref().to_string())) {
+        warning(code, msg);
+    }
+}
+
+fn emit(warning: report::Warning) {
+    CAPTURED_WARNINGS.with_borrow_mut(|warnings| {
+        if let Some(warnings) = warnings {
+            warnings.push(warning.message.clone());
+        }
+    });
+    if let Some(handler) = WARNING_HANDLER.with_borrow(Clone::clone) {
+        return handler(warning);
+    }
+    let lines = iter::once(warning.message)
+        .chain(warning.default.map(|default| format!("using {default}")));
+    for line in lines {
+        eprintln!(
+            "{}{}{}",
+            console::style("warning").for_stderr().yellow().bold(),
+            console::style(": ").for_stderr().bold(),
+            console::style(line).for_stderr().bold(),
+        );
+    }
+}
+
+pub fn help(msg: impl AsRef<str>) {
     eprintln!(
         "{}{}{}",
-        console::style("warning").yellow().bold(),
-        console::style(": ").bold(),
-        console::style(msg.as_ref()).bold(),
+        console::style("help").for_stderr().green().bold(),
+        console::style(": ").for_stderr().bold(),
+        msg.as_ref(),
+    );
+}
+
+pub fn note(msg: impl AsRef<str>) {
+    eprintln!(
+        "{}{}{}",
+        console::style("note").for_stderr().cyan().bold(),
+        console::style(": ").for_stderr().bold(),
+        msg.as_ref(),
     );
 }
 
@@ -130,26 +418,80 @@ impl TryFrom<ArgsImpl> for Args {
 }
 
 impl Args {
-    fn try_from_with_defaults<W: WarningLevel>(warn: W, value: ArgsImpl) -> Result<Self, W::Error> {
+    fn try_from_with_defaults<W: WarningLevel>(warn: W, value: ArgsImpl) -> Result<Self>
+    where
+        anyhow::Error: From<W::Error>,
+    {
+        // cargo would also reject these, but only after cargo-hyperlight's own cargo
+        // invocations fail with less helpful errors
+        validate_manifest_path(value.manifest_path.as_deref(), &value.current_dir)?;
+        validate_target_dir(value.target_dir.as_deref(), &value.current_dir)?;
+
         let manifest_path = value.manifest_path;
 
-        let target_dir = match value.target_dir {
-            Some(dir) => dir,
-            None => match resolve_target_dir(&manifest_path, &value.env, &value.current_dir) {
-                Ok(dir) => dir,
-                Err(err) => warn.warning(
-                    "could not resolve target directory",
-                    err,
-                    value.current_dir.join("target"),
-                )?,
-            },
+        // Pin the toolchain for all the cargo invocations, so that they all use the one
+        // from rust-toolchain.toml even if the environment is cleared
+        let mut env = value.env;
+        if !env.contains_key(OsStr::new("RUSTUP_TOOLCHAIN"))
+            && let Some(toolchain) = resolve_rustup_toolchain(&env, &value.current_dir)
+        {
+            env.insert("RUSTUP_TOOLCHAIN".into(), toolchain);
+        }
+
+        // `--color` is forwarded to the user's cargo command, this applies it to ours
+        // and to our own output, which otherwise follows NO_COLOR, CLICOLOR and
+        // whether stderr is a terminal
+        let color = value.color.or_else(|| {
+            env.get(OsStr::new("CARGO_TERM_COLOR"))
+                .map(|color| color.to_string_lossy().into_owned())
+        });
+        match color.as_deref() {
+            Some("always") => console::set_colors_enabled_stderr(true),
+            Some("never") => console::set_colors_enabled_stderr(false),
+            _ => {}
+        }
+        if let Some(color) = color {
+            env.insert("CARGO_TERM_COLOR".into(), color.into());
+        }
+
+        // `-j` is forwarded to the user's cargo command, this applies it to ours
+        if let Some(jobs) = &value.jobs {
+            env.insert("CARGO_BUILD_JOBS".into(), jobs.into());
+        }
+
+        let metadata = resolve_metadata(&manifest_path, &env, &value.current_dir);
+
+        let target_dir = match (value.target_dir, &metadata) {
+            (Some(dir), _) => dir,
+            (None, Ok(metadata)) => metadata.target_directory.clone(),
+            (None, Err(err)) => warn.warning(
+                "target-dir",
+                "could not resolve target directory",
+                anyhow::anyhow!("{err:#}"),
+                value.current_dir.join("target"),
+            )?,
         };
 
-        let target = match value.target {
+        // If cargo metadata failed, cargo itself will report the error when the
+        // user's command runs, but an invalid hyperlight table is only ours to report
+        let config = match &metadata {
+            Ok(metadata) => metadata.config(&manifest_path, &value.current_dir)?,
+            Err(_) => HyperlightMetadata::default(),
+        };
+
+        compat::check_required_version(
+            config.required_version.as_deref(),
+            value.allow_unsupported,
+        )?;
+
+        let target_flag = value.target.is_some();
+        let target = match value.target.or_else(|| configured_target(&env, &config)) {
             Some(triplet) => triplet,
-            None => match resolve_target(&value.env, &value.current_dir) {
-                Ok(triplet) => triplet,
+            None => match resolve_target(&env, &value.current_dir) {
+                Ok(Some(triplet)) => triplet,
+                Ok(None) => DEFAULT_TARGET.into(),
                 Err(err) => warn.warning(
+                    "target",
                     "could not resolve target triple",
                     err,
                     DEFAULT_TARGET.to_string(),
@@ -157,33 +499,625 @@ impl Args {
             },
         };
 
-        let target = if target.ends_with("-hyperlight-none") {
-            target
+        let (target, custom_target) = if target.ends_with(".json") {
+            // like rustc, the target is named after the spec file
+            let path = value.current_dir.join(&target);
+            let name = path
+                .file_stem()
+                .with_context(|| format!("Invalid target spec path: {target:?}"))?;
+            (name.to_string_lossy().into_owned(), Some(path))
+        } else if target.ends_with("-hyperlight-none") {
+            (target, None)
         } else {
             let (arch, _) = target.split_once('-').unwrap_or((&target, ""));
-            warn.warning(
+            let target = warn.warning(
+                "non-hyperlight-target",
                 "requested target is not a hyperlight target",
-                anyhow::anyhow!("invalid hyperlight target: {target}"),
+                anyhow::anyhow!(
+                    "invalid hyperlight target: {target}, from {}",
+                    target_source(target_flag, &env, &config)
+                ),
                 format!("{arch}-hyperlight-none"),
-            )?
+            )?;
+            (target, None)
         };
 
+        let artifact_deps = value.artifact_deps
+            || env
+                .get(OsStr::new("CARGO_UNSTABLE_BINDEPS"))
+                .is_some_and(|value| value == "true");
+
         let target_dir = value.current_dir.join(target_dir);
+        let out_dir = value.out_dir.map(|dir| value.current_dir.join(dir));
+        let prebuilt_sysroot = value
+            .prebuilt_sysroot
+            .or_else(|| {
+                env.get(OsStr::new("CARGO_HYPERLIGHT_SYSROOT"))
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from)
+            })
+            .map(|dir| value.current_dir.join(dir));
+
+        let profile = config.profile(&value.profile);
 
-        Ok(Args {
+        let include_dirs = config
+            .include_dirs
+            .iter()
+            .cloned()
+            .chain(
+                value
+                    .include_dir
+                    .iter()
+                    .map(|dir| value.current_dir.join(dir)),
+            )
+            .collect();
+        let static_libs = config
+            .static_libs
+            .iter()
+            .cloned()
+            .chain(
+                value
+                    .static_lib
+                    .iter()
+                    .map(|lib| value.current_dir.join(lib)),
+            )
+            .collect();
+
+        let cross_language_lto = value.cross_language_lto || config.cross_language_lto;
+        let (clang, ar) = if cross_language_lto {
+            toolchain::find_lto_tools(&env)
+        } else {
+            (toolchain::find_cc().ok(), toolchain::find_ar().ok())
+        };
+
+        // a linker that can't be found is reported when preparing the sysroot
+        let linker = value.linker.or(config.linker).map(|linker| {
+            toolchain::find_program(&linker, &value.current_dir, &env).unwrap_or(linker)
+        });
+
+        let models = config.models.or(Models {
+            code_model: value.code_model,
+            relocation_model: value.relocation_model,
+            tls_model: value.tls_model,
+            pie: value.pie,
+        });
+        models.check()?;
+
+        let compiler_launcher = value
+            .compiler_launcher
+            .or_else(|| {
+                env.get(OsStr::new("CARGO_HYPERLIGHT_COMPILER_LAUNCHER"))
+                    .filter(|launcher| !launcher.is_empty())
+                    .map(PathBuf::from)
+            })
+            .or(config.compiler_launcher);
+        let compiler_launcher = match compiler_launcher {
+            Some(launcher) => match toolchain::find_program(&launcher, &value.current_dir, &env) {
+                Some(launcher) => Some(launcher),
+                None => {
+                    warn.warning(
+                        "compiler-launcher",
+                        "could not find the compiler launcher",
+                        anyhow::anyhow!("{} is not in PATH", launcher.display()),
+                        "no launcher",
+                    )?;
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let args = Args {
             manifest_path,
             target_dir,
             target,
-            env: value.env,
+            custom_target,
+            env,
             current_dir: value.current_dir,
-            clang: toolchain::find_cc().ok(),
-            ar: toolchain::find_ar().ok(),
-        })
+            clang,
+            ar,
+            out_dir,
+            compress: value.compress,
+            verbose: value.verbose,
+            profile: value.profile,
+            doctests: value.doctests,
+            artifact_deps,
+            frozen: value.frozen,
+            runs_guests: value.runs_guests,
+            no_run_fallback: value.no_run_fallback,
+            fix: value.fix,
+            include_dirs,
+            static_libs,
+            libm: value.libm || config.libm,
+            cross_language_lto,
+            cached_c_archive: value.cached_c_archive || config.cached_c_archive,
+            linker,
+            compiler_launcher,
+            bin_link_args: config
+                .bin
+                .iter()
+                .filter(|(_, bin)| !bin.link_args.is_empty())
+                .map(|(name, bin)| (name.clone(), bin.link_args.clone()))
+                .collect(),
+            emit_map: value.emit_map,
+            provenance: value.provenance,
+            timings: value.timings.is_some(),
+            prebuilt_sysroot,
+            allow_unsupported: value.allow_unsupported,
+            cflags: profile.cflags.iter().map(OsString::from).collect(),
+            replace_cflags: false,
+            dep_cflags: config.cflags,
+            emit_cfg: config.emit_cfg.unwrap_or(true),
+            profile_settings: profile,
+            allocator: config.allocator,
+            models,
+        };
+        args.check_env();
+        Ok(args)
     }
 }
 
 const DEFAULT_TARGET: &str = const { formatcp!("{ARCH}-hyperlight-none") };
 
+/// Hyperlight targets that cargo-hyperlight can build a sysroot for.
+pub const SUPPORTED_TARGETS: &[&str] = &["x86_64-hyperlight-none"];
+
+impl Args {
+    /// Checks that the resolved target is supported, with a message explaining how to
+    /// pick a supported one otherwise.
+    pub fn validate_target(&self) -> Result<()> {
+        // custom target specs are validated when they are installed in the sysroot
+        if self.custom_target.is_some() || SUPPORTED_TARGETS.contains(&self.target.as_str()) {
+            return Ok(());
+        }
+
+        let supported = SUPPORTED_TARGETS
+            .iter()
+            .map(|target| format!(" * {target}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let reason = if self.target == DEFAULT_TARGET {
+            format!(
+                "\nThe target defaults to the host architecture ({ARCH}), which hyperlight doesn't support."
+            )
+        } else {
+            String::new()
+        };
+        anyhow::bail!(
+            "Unsupported target triple: {:?}{reason}
+Supported values are:
+{supported}
+To build guests for a supported target from this host, pass it explicitly, e.g.,
+    cargo hyperlight build --target {}
+or set the `CARGO_HYPERLIGHT_TARGET` environment variable, `target` in
+`[package.metadata.hyperlight]`, or `build.target` in your cargo config.",
+            self.target,
+            SUPPORTED_TARGETS[0],
+        )
+    }
+}
+
+impl Args {
+    /// Warns about the environment variables that conflict with how cargo-hyperlight
+    /// builds guests, naming them, before they fail the build or are silently ignored.
+    fn check_env(&self) {
+        let sysroot = [
+            "--sysroot".to_string(),
+            self.sysroot_dir().to_string_lossy().into_owned(),
+        ];
+        for key in ["RUSTFLAGS", "RUSTDOCFLAGS"] {
+            // the same warnings as when the flags are merged, so they are only emitted once
+            for conflict in rustflags_conflicts(&self.env, key, &sysroot) {
+                warning_once("rustflags-conflict", conflict);
+            }
+        }
+        let cc_wrapper = self.cc_wrapper_path();
+        for conflict in env_conflicts(&self.env, &self.target, self.artifact_deps, &cc_wrapper) {
+            warning_once("env-conflict", conflict);
+        }
+    }
+}
+
+/// Returns the variables in `env` that cargo-hyperlight overrides when building guests
+/// for `target`, which would otherwise be silently ignored.
+///
+/// Setting the C compiler of the target to `cc_wrapper`, like the cargo commands of
+/// cargo-hyperlight do, is not a conflict.
+fn env_conflicts(
+    env: &HashMap<OsString, OsString>,
+    target: &str,
+    artifact_deps: bool,
+    cc_wrapper: &Path,
+) -> Vec<String> {
+    let mut conflicts = vec![];
+
+    // in order of precedence for cc-rs, cargo-hyperlight sets the first one
+    let snake_case = target.replace('-', "_");
+    for key in [
+        format!("CC_{target}"),
+        format!("CC_{snake_case}"),
+        "TARGET_CC".into(),
+    ] {
+        let Some(cc) = env.get(OsStr::new(&key)) else {
+            continue;
+        };
+        let is_clang = Path::new(cc)
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().contains("clang"));
+        if !is_clang && Path::new(cc) != cc_wrapper {
+            conflicts.push(format!(
+                "ignoring `{key}={}`, guests are compiled with clang",
+                cc.to_string_lossy()
+            ));
+        }
+    }
+
+    let target_rustflags = format!("CARGO_TARGET_{}_RUSTFLAGS", snake_case.to_uppercase());
+    if env.contains_key(OsStr::new(&target_rustflags)) {
+        let reason = if artifact_deps {
+            "cargo-hyperlight passes the guest flags through a `--config` override instead"
+        } else {
+            "cargo-hyperlight passes the guest flags through `RUSTFLAGS`, which takes precedence"
+        };
+        conflicts.push(format!(
+            "ignoring `{target_rustflags}`, {reason}, set the flags in `RUSTFLAGS` instead"
+        ));
+    }
+    if !artifact_deps && env.contains_key(OsStr::new("CARGO_BUILD_RUSTFLAGS")) {
+        conflicts.push(
+            "ignoring `CARGO_BUILD_RUSTFLAGS`, cargo-hyperlight passes the guest flags \
+            through `RUSTFLAGS`, which takes precedence, set the flags in `RUSTFLAGS` instead"
+                .into(),
+        );
+    }
+
+    conflicts
+}
+
+/// A command line flag handled by cargo-hyperlight.
+struct Flag {
+    long: &'static str,
+    short: Option<&'static str>,
+    /// Whether the flag takes a value
+    value: bool,
+    /// Whether the flag should also be forwarded to cargo
+    forward: bool,
+}
+
+const HYPERLIGHT_FLAGS: &[Flag] = &[
+    Flag {
+        long: "--manifest-path",
+        short: None,
+        value: true,
+        forward: true,
+    },
+    Flag {
+        long: "--target-dir",
+        short: None,
+        value: true,
+        forward: true,
+    },
+    Flag {
+        long: "--target",
+        short: None,
+        value: true,
+        forward: true,
+    },
+    Flag {
+        long: "--verbose",
+        short: Some("-v"),
+        value: false,
+        forward: true,
+    },
+    Flag {
+        long: "--color",
+        short: None,
+        value: true,
+        forward: true,
+    },
+    Flag {
+        long: "--jobs",
+        short: Some("-j"),
+        value: true,
+        forward: true,
+    },
+    Flag {
+        long: "--timings",
+        short: None,
+        value: false,
+        forward: true,
+    },
+    Flag {
+        long: "--out-dir",
+        short: Some("-o"),
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--compress",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--include-dir",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--static-lib",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--libm",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--cross-language-lto",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--cached-c-archive",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--linker",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--compiler-launcher",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--emit-map",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--provenance",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--code-model",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--relocation-model",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--tls-model",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--pie",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--prebuilt-sysroot",
+        short: None,
+        value: true,
+        forward: false,
+    },
+    Flag {
+        long: "--allow-unsupported",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--hyperlight-fix",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--no-run-fallback",
+        short: None,
+        value: false,
+        forward: false,
+    },
+    Flag {
+        long: "--strict",
+        short: None,
+        value: false,
+        forward: false,
+    },
+];
+
+/// Returns the message of a clap error for the flags handled by cargo-hyperlight, with
+/// the offending flag and value, but without clap's usage line, which would describe
+/// a `cargo-hyperlight` command that doesn't exist.
+fn flag_error(err: &clap::Error) -> String {
+    let msg = err.to_string();
+    let msg = msg
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    msg.strip_prefix("error: ").unwrap_or(&msg).to_string()
+}
+
+/// Returns whether `arg` is the short flag `short`, or a repetition of it
+/// (e.g., `-vv`) for flags that don't take a value.
+fn is_short(short: &str, value: bool, arg: &str) -> bool {
+    match arg.strip_prefix('-') {
+        _ if arg == short => true,
+        Some(rest) if !value && !rest.is_empty() => rest.chars().all(|c| short.ends_with(c)),
+        // attached numeric values, e.g., `-j8`
+        _ if value => arg
+            .strip_prefix(short)
+            .is_some_and(|value| !value.is_empty() && value.chars().all(|c| c.is_ascii_digit())),
+        _ => false,
+    }
+}
+
+/// The command line split by who handles each argument.
+pub struct SplitArgs {
+    /// Flags handled by cargo-hyperlight
+    pub hyperlight: Vec<OsString>,
+    /// Arguments to forward to cargo, which excludes hyperlight-only flags
+    pub cargo: Vec<OsString>,
+    /// Arguments not handled by cargo-hyperlight
+    pub rest: Vec<OsString>,
+}
+
+/// Splits the command line into the flags handled by cargo-hyperlight and
+/// everything else, so that our flags are recognized wherever they appear
+/// (up to a `--` separator).
+pub fn split_args(args: impl IntoIterator<Item = impl Into<OsString>>) -> SplitArgs {
+    let mut split = SplitArgs {
+        hyperlight: vec![],
+        cargo: vec![],
+        rest: vec![],
+    };
+    let mut args = args.into_iter().map(Into::into);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            let trailing = iter::once(arg).chain(args).collect::<Vec<_>>();
+            split.cargo.extend_from_slice(&trailing);
+            split.rest.extend(trailing);
+            break;
+        }
+        let flag = arg.to_str().and_then(|arg| {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, _)) => (name, true),
+                None => (arg, false),
+            };
+            let flag = HYPERLIGHT_FLAGS
+                .iter()
+                .find(|f| f.long == name || f.short.is_some_and(|s| is_short(s, f.value, arg)))?;
+            let attached_value = flag
+                .short
+                .is_some_and(|s| arg != s && !arg.starts_with("--"));
+            Some((flag, flag.value && !inline_value && !attached_value))
+        });
+        let Some((flag, takes_value)) = flag else {
+            split.cargo.push(arg.clone());
+            split.rest.push(arg);
+            continue;
+        };
+        let mut values = vec![arg];
+        if takes_value && let Some(value) = args.next() {
+            values.push(value);
+        }
+        if flag.forward {
+            split.cargo.extend_from_slice(&values);
+        }
+        split.hyperlight.extend(values);
+    }
+    split
+}
+
+/// Cargo options before the subcommand whose value is passed as a separate argument,
+/// e.g., `--config build.jobs=2`.
+const CARGO_GLOBAL_WITH_VALUE: &[&str] = &["--config", "-Z", "--color", "-C"];
+
+/// Returns the position of the subcommand in the cargo arguments `args`, i.e., the
+/// first argument before `--` that is neither a flag nor the value of one of the
+/// [`CARGO_GLOBAL_WITH_VALUE`].
+pub fn subcommand_index(args: &[OsString]) -> Option<usize> {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy()).enumerate();
+    while let Some((index, arg)) = args.next() {
+        match arg.as_ref() {
+            "--" => return None,
+            arg if CARGO_GLOBAL_WITH_VALUE.contains(&arg) => {
+                args.next();
+            }
+            arg if !arg.starts_with(['-', '+']) => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the subcommand of the cargo arguments `args`, see [`subcommand_index`].
+pub fn subcommand(args: &[OsString]) -> Option<String> {
+    subcommand_index(args).map(|index| args[index].to_string_lossy().into_owned())
+}
+
+/// Returns the profile selected by the cargo arguments `args`, which don't include
+/// the flags handled by cargo-hyperlight.
+///
+/// Like cargo, `--release` selects `release`, `--profile` selects the given one,
+/// and otherwise `test` and `bench` default to their own profiles and everything
+/// else to `dev`.
+fn resolve_profile(args: &[OsString]) -> String {
+    let subcommand = subcommand(args);
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--" => break,
+            "--release" | "-r" => return "release".into(),
+            "--profile" => return args.next().unwrap_or_default().into_owned(),
+            arg if arg.starts_with("--profile=") => return arg["--profile=".len()..].into(),
+            _ => {}
+        }
+    }
+    match subcommand.as_deref() {
+        Some(profile @ ("test" | "bench")) => profile.into(),
+        _ => "dev".into(),
+    }
+}
+
+/// Returns whether the cargo arguments `args` enable artifact dependencies with
+/// `-Zbindeps`.
+fn resolve_artifact_deps(args: &[OsString]) -> bool {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--" => break,
+            "-Zbindeps" => return true,
+            "-Z" if args.next().is_some_and(|value| value == "bindeps") => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Returns whether the cargo arguments `args` include `--frozen`.
+fn resolve_frozen(args: &[OsString]) -> bool {
+    args.iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--frozen")
+}
+
+/// Returns whether the cargo arguments `args` run what they build, i.e., `run`, `test`
+/// or `bench` without `--no-run`.
+fn resolve_runs_guests(args: &[OsString]) -> bool {
+    matches!(
+        subcommand(args).as_deref(),
+        Some("run" | "r" | "test" | "t" | "bench")
+    ) && !args
+        .iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--no-run")
+}
+
 #[derive(Parser)]
 #[command(disable_help_subcommand = true)]
 struct ArgsImpl {
@@ -199,9 +1133,124 @@ struct ArgsImpl {
     #[arg(long, value_name = "TRIPLE")]
     target: Option<String>,
 
-    /// Arguments to pass to cargo
-    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
-    cargo_args: Vec<OsString>,
+    /// Use verbose output
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Coloring of the output: auto, always or never
+    #[arg(long, value_name = "WHEN")]
+    color: Option<String>,
+
+    /// Number of parallel jobs, also used for the sysroot build and every other cargo
+    /// invocation of cargo-hyperlight
+    #[arg(short, long, value_name = "N", allow_hyphen_values = true)]
+    jobs: Option<String>,
+
+    /// Output a build timing report, merged with the sysroot build's
+    #[arg(long, value_name = "FMTS", num_args = 0..=1, require_equals = true)]
+    timings: Option<Option<String>>,
+
+    /// Copy the final guest binaries to this directory after a successful build
+    #[arg(short, long, value_name = "DIRECTORY")]
+    out_dir: Option<PathBuf>,
+
+    /// Also write a compressed copy of every guest binary copied to `--out-dir`
+    #[arg(long, value_name = "FORMAT", requires = "out_dir")]
+    compress: Option<Compression>,
+
+    /// Extra include directory for C code, searched before hyperlight's headers
+    #[arg(long, value_name = "DIRECTORY")]
+    include_dir: Vec<PathBuf>,
+
+    /// Prebuilt static library to make available to the linker
+    #[arg(long, value_name = "PATH")]
+    static_lib: Vec<PathBuf>,
+
+    /// Build a libm from hyperlight's musl sources so that `-lm` resolves
+    #[arg(long)]
+    libm: bool,
+
+    /// Link C code and Rust code with cross-language LTO, requires clang with the same
+    /// LLVM version as rustc
+    #[arg(long)]
+    cross_language_lto: bool,
+
+    /// Build hyperlight-guest-bin's C sources once per sysroot instead of in its build
+    /// script
+    #[arg(long)]
+    cached_c_archive: bool,
+
+    /// Linker to use instead of `rust-lld`, e.g., `ld.lld-20`, must be a flavor of LLD
+    #[arg(long, value_name = "PATH")]
+    linker: Option<PathBuf>,
+
+    /// Run clang through this program, e.g., `ccache` or `sccache`
+    #[arg(long, value_name = "PATH")]
+    compiler_launcher: Option<PathBuf>,
+
+    /// Write a link map next to every guest binary, and summarize it with `-v`
+    #[arg(long)]
+    emit_map: bool,
+
+    /// Write where and how every guest binary was built next to it
+    #[arg(long)]
+    provenance: bool,
+
+    /// Code model of the guest, overrides `code-model` in the metadata
+    #[arg(long, value_name = "MODEL")]
+    code_model: Option<CodeModel>,
+
+    /// Relocation model of the guest, overrides `relocation-model` in the metadata
+    #[arg(long, value_name = "MODEL")]
+    relocation_model: Option<RelocationModel>,
+
+    /// Thread local storage model of the guest, overrides `tls-model` in the metadata
+    #[arg(long, value_name = "MODEL")]
+    tls_model: Option<TlsModel>,
+
+    /// Build static position independent executables, and check that Hyperlight can
+    /// process their relocations
+    #[arg(long)]
+    pie: bool,
+
+    /// Use a prebuilt sysroot without writing to it, after checking its fingerprint
+    #[arg(long, value_name = "PATH")]
+    prebuilt_sysroot: Option<PathBuf>,
+
+    /// Build even if rustc or the hyperlight crates are not supported by this version
+    /// of cargo-hyperlight
+    #[arg(long)]
+    allow_unsupported: bool,
+
+    /// Automatically apply safe fixes for known problems, e.g., installing rust-src
+    // not `--fix`, which belongs to `cargo fix` and `cargo clippy`
+    #[arg(long = "hyperlight-fix")]
+    fix: bool,
+
+    /// Only build the guests of `run`, `test` and `bench` when no hypervisor is
+    /// available to run them, instead of failing
+    #[arg(long)]
+    no_run_fallback: bool,
+
+    /// Fail instead of falling back to a default when a setting can't be resolved,
+    /// e.g., the target directory or the target triple
+    #[arg(long)]
+    strict: bool,
+
+    #[arg(skip)]
+    profile: String,
+
+    #[arg(skip)]
+    doctests: Doctests,
+
+    #[arg(skip)]
+    artifact_deps: bool,
+
+    #[arg(skip)]
+    frozen: bool,
+
+    #[arg(skip)]
+    runs_guests: bool,
 
     #[arg(skip)]
     env: HashMap<OsString, OsString>,
@@ -223,14 +1272,89 @@ enum BuildCommands {
 #[derive(serde::Deserialize)]
 struct CargoMetadata {
     target_directory: PathBuf,
+    workspace_root: PathBuf,
+    /// Parsed on its own with [`HyperlightMetadata::from_metadata`], so that an
+    /// invalid hyperlight table is not mistaken for a failure of cargo
+    #[serde(default)]
+    metadata: Value,
+    packages: Vec<CargoMetadataPackage>,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataPackage {
+    name: String,
+    manifest_path: PathBuf,
+    #[serde(default)]
+    metadata: Value,
+    targets: Vec<CargoMetadataTarget>,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+impl CargoMetadata {
+    /// Returns the hyperlight configuration for the current package.
+    ///
+    /// The `[package.metadata.hyperlight]` table of the current package is used if
+    /// present, otherwise `[workspace.metadata.hyperlight]` is used.
+    /// Relative paths are resolved against the directory of the manifest declaring them.
+    ///
+    /// A warning is emitted for the `[package.metadata.hyperlight.bin.<name>]` tables
+    /// that don't match a binary target of the package, and an invalid table is an
+    /// error.
+    fn config(&self, manifest_path: &Option<PathBuf>, cwd: &Path) -> Result<HyperlightMetadata> {
+        let current = match manifest_path {
+            Some(path) => Some(cwd.join(path)),
+            None => cwd
+                .ancestors()
+                .map(|dir| dir.join("Cargo.toml"))
+                .find(|path| path.is_file()),
+        };
+        let current = current.and_then(|path| path.canonicalize().ok());
+
+        let package = self
+            .packages
+            .iter()
+            .find(|pkg| current.is_some() && pkg.manifest_path.canonicalize().ok() == current);
+
+        if let Some(pkg) = package
+            && let Some(config) =
+                HyperlightMetadata::from_metadata(&pkg.metadata, "package.metadata.hyperlight")?
+        {
+            let bins = pkg
+                .targets
+                .iter()
+                .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+                .map(|target| target.name.as_str())
+                .collect::<Vec<_>>();
+            for bin in config.bin.keys() {
+                if !bins.contains(&bin.as_str()) {
+                    warning_once(
+                        "unknown-bin",
+                        format!(
+                            "`[package.metadata.hyperlight.bin.{bin}]` doesn't match any binary target of {:?}",
+                            pkg.name
+                        ),
+                    );
+                }
+            }
+        }
+
+        let package = package.and_then(|pkg| Some((&pkg.metadata, pkg.manifest_path.parent()?)));
+        let config = HyperlightMetadata::resolve(package, (&self.metadata, &self.workspace_root))?;
+        Ok(config)
+    }
 }
 
-fn resolve_target_dir(
+fn resolve_metadata(
     manifest_path: &Option<PathBuf>,
     env: &HashMap<OsString, OsString>,
     cwd: &PathBuf,
-) -> Result<PathBuf> {
-    let output = cargo_cmd()?
+) -> Result<CargoMetadata> {
+    let output = cargo_cmd(env)?
         .env_clear()
         .envs(env.iter())
         .current_dir(cwd)
@@ -241,14 +1365,68 @@ fn resolve_target_dir(
         .checked_output()
         .context("Failed to get cargo metadata")?;
 
-    let metadata: CargoMetadata =
-        serde_json::from_slice(&output.stdout).context("Failed to parse cargo metadata")?;
+    serde_json::from_slice(&output.stdout).context("Failed to parse cargo metadata")
+}
+
+/// Returns the rustup toolchain that applies to `cwd`, e.g., from a `rust-toolchain.toml`,
+/// or `None` if rustup is not used.
+fn resolve_rustup_toolchain(env: &HashMap<OsString, OsString>, cwd: &PathBuf) -> Option<OsString> {
+    let output = std::process::Command::new("rustup")
+        .env_clear()
+        .envs(env.iter())
+        .current_dir(cwd)
+        .arg("show")
+        .arg("active-toolchain")
+        .checked_output()
+        .ok()?;
 
-    Ok(metadata.target_directory)
+    // the output looks like "stable-x86_64-unknown-linux-gnu (overridden by '...')"
+    let output = String::from_utf8_lossy(&output.stdout);
+    let toolchain = output.split_whitespace().next()?;
+    Some(toolchain.into())
+}
+
+/// Returns the target configured for cargo-hyperlight: `CARGO_HYPERLIGHT_TARGET`,
+/// or else the `target` setting of the manifest metadata.
+///
+/// Both take precedence over `build.target` from the cargo config, which
+/// `cargo hyperlight setup` writes for plain `cargo` invocations.
+fn configured_target(
+    env: &HashMap<OsString, OsString>,
+    config: &HyperlightMetadata,
+) -> Option<String> {
+    env.get(&OsString::from("CARGO_HYPERLIGHT_TARGET"))
+        .map(|target| target.to_string_lossy().into_owned())
+        .filter(|target| !target.is_empty())
+        .or_else(|| config.target.clone())
+}
+
+/// Returns where the target was taken from, for error messages, following the
+/// precedence of the target resolution.
+fn target_source(
+    target_flag: bool,
+    env: &HashMap<OsString, OsString>,
+    config: &HyperlightMetadata,
+) -> &'static str {
+    let is_set = |key: &str| {
+        env.get(OsStr::new(key))
+            .is_some_and(|value| !value.is_empty())
+    };
+    if target_flag {
+        "`--target`"
+    } else if is_set("CARGO_HYPERLIGHT_TARGET") {
+        "`CARGO_HYPERLIGHT_TARGET`"
+    } else if config.target.is_some() {
+        "`target` in `[package.metadata.hyperlight]`"
+    } else if is_set("CARGO_BUILD_TARGET") {
+        "`CARGO_BUILD_TARGET`"
+    } else {
+        "`build.target` in the cargo config"
+    }
 }
 
-fn resolve_target(env: &HashMap<OsString, OsString>, cwd: &PathBuf) -> Result<String> {
-    let output = cargo_cmd()?
+pub fn resolve_target(env: &HashMap<OsString, OsString>, cwd: &PathBuf) -> Result<Option<String>> {
+    let output = cargo_cmd(env)?
         .env_clear()
         .envs(env.iter())
         .current_dir(cwd)
@@ -270,8 +1448,404 @@ fn resolve_target(env: &HashMap<OsString, OsString>, cwd: &PathBuf) -> Result<St
     let target = target.trim_matches(|c| c == '"' || c == '\'');
 
     if target.is_empty() {
-        Ok(DEFAULT_TARGET.into())
+        Ok(None)
     } else {
-        Ok(target.into())
+        Ok(Some(target.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(args: &[&str]) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let split = split_args(args);
+        let strings = |args: Vec<OsString>| {
+            args.into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+        (
+            strings(split.hyperlight),
+            strings(split.cargo),
+            strings(split.rest),
+        )
+    }
+
+    #[test]
+    fn warning_handler_receives_structured_warnings() {
+        let received = Arc::new(std::sync::Mutex::new(vec![]));
+        let handler: WarningHandler = {
+            let received = received.clone();
+            Arc::new(move |warning| received.lock().unwrap().push(warning))
+        };
+
+        let ((), captured) = capture_warnings(|| {
+            with_warning_handler(Some(&handler), || {
+                warning("some-code", "something happened");
+                let _ = Warning::WARN.warning(
+                    "other-code",
+                    "could not resolve it",
+                    anyhow::anyhow!("it failed"),
+                    42,
+                );
+            })
+        });
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            *received,
+            [
+                report::Warning {
+                    code: "some-code",
+                    message: "something happened".into(),
+                    default: None,
+                },
+                report::Warning {
+                    code: "other-code",
+                    message: "could not resolve it: it failed".into(),
+                    default: Some("42".into()),
+                },
+            ]
+        );
+        assert_eq!(
+            captured,
+            ["something happened", "could not resolve it: it failed"]
+        );
+        assert!(WARNING_HANDLER.with_borrow(Option::is_none));
+    }
+
+    #[test]
+    fn response_files_list_one_arg_per_line() {
+        let content = "# build the guest\nbuild\n\n  --profile\nrelease build\r\n# done\n";
+        assert_eq!(
+            parse_response_file(content).collect::<Vec<_>>(),
+            ["build", "--profile", "release build"]
+        );
+
+        let args = ["build", "--", "@args"].map(OsString::from);
+        let expanded = expand_response_files(&args, Path::new("/nonexistent")).unwrap();
+        assert_eq!(expanded, args);
+        assert!(expand_response_files(&["@args".into()], Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn validate_paths_points_at_the_value() {
+        let cwd = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert!(validate_manifest_path(None, cwd).is_ok());
+        assert!(validate_manifest_path(Some(Path::new("Cargo.toml")), cwd).is_ok());
+        assert!(validate_manifest_path(Some(Path::new("missing/Cargo.toml")), cwd).is_err());
+
+        let err = validate_manifest_path(Some(Path::new("Cargo.tml")), cwd).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid value for `--manifest-path`: not a `Cargo.toml` file\n  \
+            --manifest-path Cargo.tml\n  \
+            \x20               ^^^^^^^^^\n  \
+            = help: the manifest path must be the path to a `Cargo.toml` file, e.g., \
+            `guest/Cargo.toml`"
+        );
+
+        assert!(validate_target_dir(Some(Path::new("target")), cwd).is_ok());
+        assert!(validate_target_dir(Some(Path::new("missing")), cwd).is_ok());
+        assert!(validate_target_dir(Some(Path::new("Cargo.toml")), cwd).is_err());
+    }
+
+    #[test]
+    fn strict_mode_fails_instead_of_warning() {
+        assert!(is_strict(["build", "--strict", "--release"]));
+        assert!(!is_strict(["build", "--release"]));
+        assert!(!is_strict(["run", "--", "--strict"]));
+
+        let err = Warning::ERROR
+            .warning(
+                "non-hyperlight-target",
+                "requested target is not a hyperlight target",
+                anyhow::anyhow!("invalid hyperlight target: x86_64-unknown-linux-gnu"),
+                "x86_64-hyperlight-none",
+            )
+            .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "requested target is not a hyperlight target: invalid hyperlight target: \
+            x86_64-unknown-linux-gnu"
+        );
+    }
+
+    #[test]
+    fn env_conflicts_name_the_variables() {
+        let target = "x86_64-hyperlight-none";
+        let wrapper = Path::new("/target/sysroot/cc");
+        let conflicts = |vars: &[(&str, &str)], artifact_deps| {
+            let env = vars
+                .iter()
+                .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+                .collect();
+            env_conflicts(&env, target, artifact_deps, wrapper)
+        };
+
+        assert_eq!(
+            conflicts(&[("CC_x86_64-hyperlight-none", "gcc")], false),
+            ["ignoring `CC_x86_64-hyperlight-none=gcc`, guests are compiled with clang"]
+        );
+        assert!(conflicts(&[("TARGET_CC", "/usr/bin/clang-18")], false).is_empty());
+        assert!(
+            conflicts(
+                &[("CC_x86_64_hyperlight_none", "/target/sysroot/cc")],
+                false
+            )
+            .is_empty()
+        );
+        // the host's compiler is not overridden
+        assert!(conflicts(&[("CC", "gcc")], false).is_empty());
+
+        let rustflags = [
+            ("CARGO_BUILD_RUSTFLAGS", "-Dwarnings"),
+            (
+                "CARGO_TARGET_X86_64_HYPERLIGHT_NONE_RUSTFLAGS",
+                "-Dwarnings",
+            ),
+        ];
+        let all = conflicts(&rustflags, false);
+        assert_eq!(all.len(), 2);
+        assert!(all[0].starts_with("ignoring `CARGO_TARGET_X86_64_HYPERLIGHT_NONE_RUSTFLAGS`"));
+        assert!(all[1].starts_with("ignoring `CARGO_BUILD_RUSTFLAGS`"));
+        // with artifact dependencies the host still gets CARGO_BUILD_RUSTFLAGS
+        assert_eq!(conflicts(&rustflags, true).len(), 1);
+    }
+
+    #[test]
+    fn target_source_follows_precedence() {
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+                .collect::<HashMap<_, _>>()
+        };
+        let config = HyperlightMetadata::default();
+        let build_target = env(&[("CARGO_BUILD_TARGET", "x86_64-unknown-linux-gnu")]);
+        assert_eq!(target_source(true, &build_target, &config), "`--target`");
+        assert_eq!(
+            target_source(false, &build_target, &config),
+            "`CARGO_BUILD_TARGET`"
+        );
+        assert_eq!(
+            target_source(false, &env(&[("CARGO_HYPERLIGHT_TARGET", "x")]), &config),
+            "`CARGO_HYPERLIGHT_TARGET`"
+        );
+        assert_eq!(
+            target_source(false, &env(&[]), &config),
+            "`build.target` in the cargo config"
+        );
+    }
+
+    #[test]
+    fn is_short_matches_flag_and_repetitions() {
+        let cases = [
+            ("-v", false, "-v", true),
+            ("-v", false, "-vv", true),
+            ("-v", false, "-vvv", true),
+            ("-v", false, "-vx", false),
+            ("-v", false, "-", false),
+            ("-v", false, "--verbose", false),
+            ("-o", true, "-o", true),
+            ("-o", true, "-oo", false),
+            ("-o", true, "-p", false),
+            ("-j", true, "-j8", true),
+            ("-j", true, "-jx", false),
+        ];
+        for (short, value, arg, expected) in cases {
+            assert_eq!(is_short(short, value, arg), expected, "{short} {arg}");
+        }
+    }
+
+    /// Resolves the arguments of `cargo hyperlight build` in a new package with the
+    /// `[package.metadata.hyperlight]` table `config`.
+    fn parse_with_config(name: &str, config: &str) -> Result<Args> {
+        let dir = env::temp_dir().join(format!("cargo-hyperlight-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src"))?;
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}")?;
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"guest\"\nedition = \"2024\"\n\n\
+                [package.metadata.hyperlight]\n{config}\n\n[workspace]\n"
+            ),
+        )?;
+        let args = Args::parse(["build"], env::vars_os(), Some(&dir), Warning::ERROR);
+        std::fs::remove_dir_all(&dir)?;
+        args
+    }
+
+    #[test]
+    fn invalid_metadata_names_the_key() {
+        let args = parse_with_config("valid-metadata", "libm = true").unwrap();
+        assert!(args.libm);
+
+        let err = parse_with_config("invalid-metadata", "libm = \"yes\"")
+            .err()
+            .unwrap();
+        assert_eq!(crate::Failure::of(&err), Some(crate::Failure::Config));
+        assert!(
+            err.to_string()
+                .starts_with("Invalid `libm` in `[package.metadata.hyperlight]`"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn unsupported_models_fail_the_build() {
+        for (name, config) in [
+            ("code-model", "code-model = \"tiny\""),
+            ("relocation-model", "relocation-model = \"dynamic-no-pic\""),
+            ("tls-model", "tls-model = \"global-dynamic\""),
+        ] {
+            let err = parse_with_config(name, config).err().unwrap();
+            assert_eq!(crate::Failure::of(&err), Some(crate::Failure::Config));
+            assert_eq!(
+                err.to_string(),
+                format!("Invalid `{name}` in `[package.metadata.hyperlight]`")
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_artifact_deps_finds_bindeps() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert!(resolve_artifact_deps(&args(&["build", "-Zbindeps"])));
+        assert!(resolve_artifact_deps(&args(&["build", "-Z", "bindeps"])));
+        assert!(!resolve_artifact_deps(&args(&["build", "-Z", "build-std"])));
+        assert!(!resolve_artifact_deps(&args(&["run", "--", "-Zbindeps"])));
+    }
+
+    #[test]
+    fn resolve_frozen_stops_at_separator() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert!(resolve_frozen(&args(&["build", "--frozen"])));
+        assert!(!resolve_frozen(&args(&["build", "--locked"])));
+        assert!(!resolve_frozen(&args(&["run", "--", "--frozen"])));
+    }
+
+    #[test]
+    fn resolve_runs_guests_finds_the_subcommand() {
+        let cases: &[(&[&str], bool)] = &[
+            (&["run", "--release"], true),
+            (&["+nightly", "t"], true),
+            (&["test", "--no-run"], false),
+            (&["build", "--", "run"], false),
+            (&["--config", "build.jobs=2", "run"], true),
+            (&["-Z", "bindeps", "test"], true),
+            (&["-C", "guest", "--color", "never", "bench"], true),
+            (&["--config=build.jobs=2", "-Zbindeps", "t"], true),
+            (&["--config", "run", "build"], false),
+        ];
+        for (args, expected) in cases {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            assert_eq!(resolve_runs_guests(&args), *expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn resolve_profile_follows_cargo() {
+        let cases: &[(&[&str], &str)] = &[
+            (&["build"], "dev"),
+            (&["build", "--release"], "release"),
+            (&["build", "-r"], "release"),
+            (&["build", "--profile", "custom"], "custom"),
+            (&["build", "--profile=custom"], "custom"),
+            (&["test"], "test"),
+            (&["bench"], "bench"),
+            (&["test", "--release"], "release"),
+            (&["run", "--", "--release"], "dev"),
+            (&["--config", "build.jobs=2", "test"], "test"),
+        ];
+        for (args, expected) in cases {
+            let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+            assert_eq!(resolve_profile(&args), *expected, "{args:?}");
+        }
+    }
+
+    /// Arguments, and the expected hyperlight flags, cargo arguments, and rest.
+    type SplitCase = (
+        &'static [&'static str],
+        &'static [&'static str],
+        &'static [&'static str],
+        &'static [&'static str],
+    );
+
+    #[test]
+    fn split_args_separates_hyperlight_flags() {
+        let cases: &[SplitCase] = &[
+            // flag with a separate value, forwarded to cargo
+            (
+                &["build", "--target", "x86_64-hyperlight-none"],
+                &["--target", "x86_64-hyperlight-none"],
+                &["build", "--target", "x86_64-hyperlight-none"],
+                &["build"],
+            ),
+            // flag with an inline value
+            (
+                &["build", "--target-dir=out", "-p", "guest"],
+                &["--target-dir=out"],
+                &["build", "--target-dir=out", "-p", "guest"],
+                &["build", "-p", "guest"],
+            ),
+            // repeated short flag
+            (&["build", "-vv"], &["-vv"], &["build", "-vv"], &["build"]),
+            // short flag with an attached or separate value
+            (
+                &["build", "-j8", "-j", "4"],
+                &["-j8", "-j", "4"],
+                &["build", "-j8", "-j", "4"],
+                &["build"],
+            ),
+            // flag with an optional inline value
+            (
+                &["build", "--timings", "--timings=json"],
+                &["--timings", "--timings=json"],
+                &["build", "--timings", "--timings=json"],
+                &["build"],
+            ),
+            // hyperlight-only flags are not forwarded
+            (
+                &["build", "-o", "dist", "--libm", "--include-dir=inc"],
+                &["-o", "dist", "--libm", "--include-dir=inc"],
+                &["build"],
+                &["build"],
+            ),
+            // trailing flag with a missing value
+            (
+                &["build", "--target"],
+                &["--target"],
+                &["build", "--target"],
+                &["build"],
+            ),
+            // cargo's own `--fix` is forwarded
+            (
+                &["clippy", "--fix", "--allow-dirty", "--hyperlight-fix"],
+                &["--hyperlight-fix"],
+                &["clippy", "--fix", "--allow-dirty"],
+                &["clippy", "--fix", "--allow-dirty"],
+            ),
+            // cargo's own `--no-run` is forwarded
+            (
+                &["test", "--no-run-fallback", "--no-run"],
+                &["--no-run-fallback"],
+                &["test", "--no-run"],
+                &["test", "--no-run"],
+            ),
+            // nothing after `--` is ours
+            (
+                &["run", "--", "--target", "-o", "x"],
+                &[],
+                &["run", "--", "--target", "-o", "x"],
+                &["run", "--", "--target", "-o", "x"],
+            ),
+        ];
+        for (args, hyperlight, cargo, rest) in cases {
+            let (h, c, r) = split(args);
+            assert_eq!(h, *hyperlight, "hyperlight flags of {args:?}");
+            assert_eq!(c, *cargo, "cargo args of {args:?}");
+            assert_eq!(r, *rest, "rest of {args:?}");
+        }
     }
 }
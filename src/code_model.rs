@@ -0,0 +1,240 @@
+use anyhow::{Result, bail};
+use target_spec_json::TargetSpec;
+
+/// Code model of a guest, the `code-model` in `[package.metadata.hyperlight]`.
+#[derive(
+    serde::Deserialize, serde::Serialize, clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum CodeModel {
+    /// Code and data in the lower 2 GiB of the address space, the default
+    Small,
+    /// Code and data in the upper 2 GiB of the address space
+    Kernel,
+    /// Code in the lower 2 GiB of the address space, data anywhere
+    Medium,
+    /// Code and data anywhere
+    Large,
+}
+
+/// Relocation model of a guest, the `relocation-model` in
+/// `[package.metadata.hyperlight]`.
+///
+/// The Hyperlight loader only applies relative relocations, so the models that
+/// need a dynamic loader, or that are specific to other architectures, are not
+/// supported.
+#[derive(
+    serde::Deserialize, serde::Serialize, clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum RelocationModel {
+    /// A non-relocatable executable, loaded at the address it was linked at
+    Static,
+    /// A static position independent executable, the default
+    Pic,
+    /// Like `pic`, assuming the code ends up in an executable
+    Pie,
+}
+
+/// Thread local storage model of a guest, the `tls-model` in
+/// `[package.metadata.hyperlight]`.
+///
+/// Guests are static executables without a dynamic loader to resolve `__tls_get_addr`,
+/// so only the executable models are supported.
+#[derive(
+    serde::Deserialize, serde::Serialize, clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsModel {
+    LocalExec,
+    InitialExec,
+}
+
+impl CodeModel {
+    fn name(self) -> &'static str {
+        match self {
+            CodeModel::Small => "small",
+            CodeModel::Kernel => "kernel",
+            CodeModel::Medium => "medium",
+            CodeModel::Large => "large",
+        }
+    }
+}
+
+impl RelocationModel {
+    fn name(self) -> &'static str {
+        match self {
+            RelocationModel::Static => "static",
+            RelocationModel::Pic => "pic",
+            RelocationModel::Pie => "pie",
+        }
+    }
+}
+
+impl TlsModel {
+    fn name(self) -> &'static str {
+        match self {
+            TlsModel::LocalExec => "local-exec",
+            TlsModel::InitialExec => "initial-exec",
+        }
+    }
+}
+
+/// Code generation models of a guest, applied to both the target spec and the C flags.
+/// Unset models keep the defaults of the target.
+#[derive(
+    serde::Deserialize, serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq, Hash,
+)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Models {
+    pub code_model: Option<CodeModel>,
+    pub relocation_model: Option<RelocationModel>,
+    pub tls_model: Option<TlsModel>,
+    /// Whether to build static position independent executables that Hyperlight can
+    /// load at any address, and check their relocations after the build
+    pub pie: bool,
+}
+
+impl Models {
+    /// Returns `self`, with the models set in `overrides` replacing its own.
+    pub fn or(self, overrides: Models) -> Models {
+        Models {
+            code_model: overrides.code_model.or(self.code_model),
+            relocation_model: overrides.relocation_model.or(self.relocation_model),
+            tls_model: overrides.tls_model.or(self.tls_model),
+            pie: overrides.pie || self.pie,
+        }
+    }
+
+    /// Returns the relocation model, which is `pic` when building position independent
+    /// executables without one.
+    fn relocation_model(&self) -> Option<RelocationModel> {
+        match self.relocation_model {
+            None if self.pie => Some(RelocationModel::Pic),
+            relocation_model => relocation_model,
+        }
+    }
+
+    /// Checks that the combination of models can be compiled and loaded.
+    pub fn check(&self) -> Result<()> {
+        if self.pie && self.relocation_model == Some(RelocationModel::Static) {
+            bail!(
+                "Position independent guests can't use the static relocation model\n\
+                Remove `relocation-model = \"static\"` or `pie = true` from \
+                `[package.metadata.hyperlight]`"
+            );
+        }
+        if self.code_model == Some(CodeModel::Kernel)
+            && self.relocation_model != Some(RelocationModel::Static)
+        {
+            bail!(
+                "The kernel code model can't be used with position independent code\n\
+                Set `relocation-model = \"static\"` in `[package.metadata.hyperlight]`, \
+                or pass `--relocation-model static`"
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies the models to the target spec of the sysroot.
+    pub fn apply(&self, spec: &mut TargetSpec) {
+        let code_model = self.code_model.unwrap_or(CodeModel::Small);
+        spec.code_model = Some(code_model.name().into());
+        if let Some(relocation_model) = self.relocation_model() {
+            spec.relocation_model = Some(relocation_model.name().into());
+            let pie = relocation_model != RelocationModel::Static;
+            spec.position_independent_executables = Some(pie);
+            spec.static_position_independent_executables = Some(pie);
+        }
+        if let Some(tls_model) = self.tls_model {
+            spec.tls_model = Some(tls_model.name().into());
+        }
+    }
+
+    /// Returns the C flags that compile C code with the same models as Rust code.
+    pub fn cflags(&self) -> Vec<String> {
+        let mut flags = vec![];
+        if let Some(code_model) = self.code_model {
+            flags.push(format!("-mcmodel={}", code_model.name()));
+        }
+        match self.relocation_model() {
+            Some(RelocationModel::Static) => flags.push("-fno-pic".into()),
+            Some(RelocationModel::Pic) => flags.push("-fPIC".into()),
+            Some(RelocationModel::Pie) => flags.push("-fPIE".into()),
+            None => {}
+        }
+        if let Some(tls_model) = self.tls_model {
+            flags.push(format!("-ftls-model={}", tls_model.name()));
+        }
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn kernel_code_model_needs_static_relocations() {
+        let models = Models {
+            code_model: Some(CodeModel::Kernel),
+            ..Default::default()
+        };
+        assert!(models.check().is_err());
+
+        let models = models.or(Models {
+            relocation_model: Some(RelocationModel::Static),
+            ..Default::default()
+        });
+        assert!(models.check().is_ok());
+        assert_eq!(models.cflags(), ["-mcmodel=kernel", "-fno-pic"]);
+    }
+
+    #[test]
+    fn unset_models_keep_the_target_defaults() {
+        assert!(Models::default().check().is_ok());
+        assert!(Models::default().cflags().is_empty());
+
+        let models = Models {
+            code_model: Some(CodeModel::Large),
+            relocation_model: Some(RelocationModel::Pie),
+            tls_model: Some(TlsModel::LocalExec),
+            pie: false,
+        };
+        assert_eq!(
+            models.cflags(),
+            ["-mcmodel=large", "-fPIE", "-ftls-model=local-exec"]
+        );
+    }
+
+    #[test]
+    fn pie_defaults_to_pic() {
+        let models = Models {
+            pie: true,
+            ..Default::default()
+        };
+        assert!(models.check().is_ok());
+        assert_eq!(models.cflags(), ["-fPIC"]);
+
+        let models = models.or(Models {
+            relocation_model: Some(RelocationModel::Static),
+            ..Default::default()
+        });
+        assert!(models.check().is_err());
+    }
+
+    #[test]
+    fn unsupported_models_are_rejected() {
+        let models: Models = serde_json::from_value(json!({ "code-model": "large" })).unwrap();
+        assert_eq!(models.code_model, Some(CodeModel::Large));
+        assert!(
+            serde_json::from_value::<Models>(json!({ "relocation-model": "dynamic-no-pic" }))
+                .is_err()
+        );
+        assert!(
+            serde_json::from_value::<Models>(json!({ "tls-model": "global-dynamic" })).is_err()
+        );
+    }
+}
@@ -0,0 +1,667 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result, bail};
+
+use crate::atomic;
+use crate::cli::{Args, note};
+use crate::features::guest_packages;
+use crate::metadata::{self, FunctionSignature, InterfaceMetadata, PackageMetadata};
+
+/// Rust keywords that can't be used as function names without `r#`.
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+    "pub", "ref", "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe",
+    "use", "where", "while",
+];
+
+/// Returns the Rust type for a `ParameterType` or `ReturnType` variant.
+fn rust_type(variant: &str) -> Result<&'static str> {
+    Ok(match variant {
+        "Int" => "i32",
+        "UInt" => "u32",
+        "Long" => "i64",
+        "ULong" => "u64",
+        "Float" => "f32",
+        "Double" => "f64",
+        "String" => "String",
+        "Bool" => "bool",
+        "VecBytes" => "Vec<u8>",
+        "Void" => "()",
+        variant => bail!(
+            "Unknown type {variant:?}, expected one of Int, UInt, Long, ULong, Float, Double, \
+             String, Bool, VecBytes, or Void for return types"
+        ),
+    })
+}
+
+/// Converts a function name like `PrintOutput` to a Rust function name like `print_output`.
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            snake.push('_');
+            continue;
+        }
+        if c.is_ascii_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|c| c.is_ascii_lowercase());
+            if prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || prev.is_ascii_uppercase() && next_lower
+            {
+                snake.push('_');
+            }
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    if snake.starts_with(|c: char| c.is_ascii_digit()) {
+        snake.insert(0, '_');
+    }
+    if KEYWORDS.contains(&snake.as_str()) {
+        snake.insert_str(0, "r#");
+    }
+    snake
+}
+
+/// A function of the interface, with its types resolved.
+struct Function<'a> {
+    /// Name the function is registered with
+    name: &'a str,
+    /// Rust function name
+    ident: String,
+    /// `ParameterType` variants
+    parameters: Vec<&'a str>,
+    /// `ReturnType` variant
+    return_type: &'a str,
+}
+
+impl<'a> Function<'a> {
+    fn new(name: &'a str, signature: &'a FunctionSignature) -> Result<Self> {
+        let parameters = signature
+            .parameters
+            .iter()
+            .flatten()
+            .map(|param| {
+                ensure_parameter(param)
+                    .with_context(|| format!("Invalid parameter type of {name:?}"))?;
+                Ok(param.as_str())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let return_type = signature.return_type.as_deref().unwrap_or("Void");
+        rust_type(return_type).with_context(|| format!("Invalid return type of {name:?}"))?;
+        Ok(Function {
+            name,
+            ident: snake_case(name),
+            parameters,
+            return_type,
+        })
+    }
+
+    /// `arg0: String, arg1: i32`
+    fn params(&self) -> String {
+        self.parameters
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| format!("arg{i}: {}", rust_type(ty).unwrap()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// `arg0, arg1`
+    fn args(&self) -> String {
+        (0..self.parameters.len())
+            .map(|i| format!("arg{i}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn ret(&self) -> &'static str {
+        rust_type(self.return_type).unwrap()
+    }
+}
+
+fn ensure_parameter(variant: &str) -> Result<()> {
+    if variant == "Void" {
+        bail!("Void is only valid as a return type");
+    }
+    rust_type(variant).map(|_| ())
+}
+
+fn functions(functions: &BTreeMap<String, FunctionSignature>) -> Result<Vec<Function<'_>>> {
+    functions
+        .iter()
+        .map(|(name, signature)| Function::new(name, signature))
+        .collect()
+}
+
+/// Returns the C type for a scalar `ParameterType` or `ReturnType` variant, or `None`
+/// for `String` and `VecBytes`, which need a pointer and are handled separately.
+fn c_type(variant: &str) -> Option<&'static str> {
+    Some(match variant {
+        "Int" => "int32_t",
+        "UInt" => "uint32_t",
+        "Long" => "int64_t",
+        "ULong" => "uint64_t",
+        "Float" => "float",
+        "Double" => "double",
+        "Bool" => "bool",
+        "Void" => "void",
+        _ => return None,
+    })
+}
+
+const HEADER: &str = "// Generated by `cargo hyperlight generate` from the\n\
+    // `[package.metadata.hyperlight.interface]` table, do not edit.\n";
+
+const C_HEADER: &str = "/* Generated by `cargo hyperlight generate` from the\n \
+    * `[package.metadata.hyperlight.interface]` table, do not edit. */\n";
+
+/// Generates the guest side of `interface`: a `Guest` trait with a method per guest
+/// function, a `register` function registering them, and a function per host function.
+fn guest_module(interface: &InterfaceMetadata) -> Result<String> {
+    let guest_functions = functions(&interface.guest_functions)?;
+    let host_functions = functions(&interface.host_functions)?;
+
+    let mut code = String::from(HEADER);
+    code.push_str(
+        "\n\
+        use alloc::string::{String, ToString as _};\n\
+        use alloc::vec::Vec;\n\
+        \n\
+        use hyperlight_common::flatbuffer_wrappers::function_call::FunctionCall;\n\
+        use hyperlight_common::flatbuffer_wrappers::function_types::{\n    \
+            ParameterType, ParameterValue, ReturnType,\n\
+        };\n\
+        use hyperlight_common::flatbuffer_wrappers::guest_error::ErrorCode;\n\
+        use hyperlight_common::flatbuffer_wrappers::util::get_flatbuffer_result;\n\
+        use hyperlight_guest::error::{HyperlightGuestError, Result};\n\
+        use hyperlight_guest_bin::guest_function::definition::GuestFunctionDefinition;\n\
+        use hyperlight_guest_bin::guest_function::register::register_function;\n\
+        use hyperlight_guest_bin::host_comm::call_host_function;\n",
+    );
+
+    code.push_str("\n/// The guest functions of the interface.\npub trait Guest {\n");
+    for function in &guest_functions {
+        writeln!(
+            code,
+            "    fn {}({}) -> {};",
+            function.ident,
+            function.params(),
+            function.ret()
+        )
+        .unwrap();
+    }
+    code.push_str("}\n");
+
+    code.push_str(
+        "\n/// Registers the guest functions of the interface, call it from `hyperlight_main`.\n\
+        pub fn register<G: Guest>() {\n",
+    );
+    for function in &guest_functions {
+        let types = function
+            .parameters
+            .iter()
+            .map(|ty| format!("ParameterType::{ty}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            code,
+            "    register_function(GuestFunctionDefinition::new(\n        \
+                {:?}.to_string(),\n        \
+                Vec::from(&[{types}]),\n        \
+                ReturnType::{},\n        \
+                __{}::<G> as usize,\n    \
+            ));",
+            function.name,
+            function.return_type,
+            function.ident.trim_start_matches("r#"),
+        )
+        .unwrap();
+    }
+    code.push_str("}\n");
+
+    for function in &guest_functions {
+        let pattern = match function.parameters.as_slice() {
+            [] => "None | Some([])".to_string(),
+            parameters => {
+                let values = parameters
+                    .iter()
+                    .enumerate()
+                    .map(|(i, ty)| format!("ParameterValue::{ty}(arg{i})"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Some([{values}])")
+            }
+        };
+        let args = (0..function.parameters.len())
+            .map(|i| format!("arg{i}.clone()"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(
+            code,
+            "\nfn __{ident}<G: Guest>(call: &FunctionCall) -> Result<Vec<u8>> {{\n    \
+                match call.parameters.as_deref() {{\n        \
+                    {pattern} => Ok(get_flatbuffer_result(G::{method}({args}))),\n        \
+                    _ => Err(HyperlightGuestError::new(\n            \
+                        ErrorCode::GuestFunctionParameterTypeMismatch,\n            \
+                        {message:?}.to_string(),\n        \
+                    )),\n    \
+                }}\n\
+            }}",
+            ident = function.ident.trim_start_matches("r#"),
+            method = function.ident,
+            message = format!("Invalid parameters for {}", function.name),
+        )
+        .unwrap();
+    }
+
+    for function in &host_functions {
+        let values = match function.parameters.as_slice() {
+            [] => "None".to_string(),
+            parameters => {
+                let values = parameters
+                    .iter()
+                    .enumerate()
+                    .map(|(i, ty)| format!("ParameterValue::{ty}(arg{i})"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Some(Vec::from(&[{values}]))")
+            }
+        };
+        writeln!(
+            code,
+            "\n/// Calls the `{name}` host function.\n\
+            pub fn {ident}({params}) -> Result<{ret}> {{\n    \
+                call_host_function::<{ret}>({name:?}, {values}, ReturnType::{return_type})\n\
+            }}",
+            name = function.name,
+            ident = function.ident,
+            params = function.params(),
+            ret = function.ret(),
+            return_type = function.return_type,
+        )
+        .unwrap();
+    }
+
+    Ok(code)
+}
+
+/// Generates C declarations for the host functions of `interface` in a header, and the
+/// Rust functions implementing them on top of the safe wrappers of [`guest_module`],
+/// returned as `(header, rust)`.
+///
+/// `String` parameters are NUL terminated strings, and `VecBytes` parameters are a
+/// pointer and a length. Host functions returning `String` or `VecBytes` can't be
+/// called from C, as there's no allocator the C code could free the result with.
+/// A failing host call aborts the guest, as C has no way to receive the error.
+fn c_shims(interface: &InterfaceMetadata) -> Result<(String, String)> {
+    let host_functions = functions(&interface.host_functions)?;
+
+    let mut header = String::from(C_HEADER);
+    header.push_str(
+        "\n#pragma once\n\n\
+        #include <stdbool.h>\n\
+        #include <stddef.h>\n\
+        #include <stdint.h>\n\n\
+        #ifdef __cplusplus\n\
+        extern \"C\" {\n\
+        #endif\n",
+    );
+    let mut rust = String::from(
+        "\n/// C entry points of the host functions, declared in `host_functions.h`.\n\
+        mod c {\n    \
+            use alloc::vec::Vec;\n",
+    );
+
+    for function in &host_functions {
+        let ident = function.ident.trim_start_matches("r#");
+        let Some(c_ret) = c_type(function.return_type) else {
+            write!(
+                header,
+                "\n/* {} returns {} and can't be called from C */\n",
+                function.name, function.return_type
+            )
+            .unwrap();
+            continue;
+        };
+
+        let mut c_params = vec![];
+        let mut rust_params = vec![];
+        let mut conversions = String::new();
+        for (i, ty) in function.parameters.iter().enumerate() {
+            match *ty {
+                "String" => {
+                    c_params.push(format!("const char *arg{i}"));
+                    rust_params.push(format!("arg{i}: *const core::ffi::c_char"));
+                    writeln!(
+                        conversions,
+                        "        let arg{i} = unsafe {{ core::ffi::CStr::from_ptr(arg{i}) }}\n            \
+                            .to_string_lossy()\n            \
+                            .into_owned();"
+                    )
+                    .unwrap();
+                }
+                "VecBytes" => {
+                    c_params.push(format!("const uint8_t *arg{i}, size_t arg{i}_len"));
+                    rust_params.push(format!("arg{i}: *const u8, arg{i}_len: usize"));
+                    writeln!(
+                        conversions,
+                        "        let arg{i}: Vec<u8> =\n            \
+                            unsafe {{ core::slice::from_raw_parts(arg{i}, arg{i}_len) }}.to_vec();"
+                    )
+                    .unwrap();
+                }
+                ty => {
+                    c_params.push(format!("{} arg{i}", c_type(ty).unwrap()));
+                    rust_params.push(format!("arg{i}: {}", rust_type(ty).unwrap()));
+                }
+            }
+        }
+
+        let c_params = match c_params.join(", ") {
+            params if params.is_empty() => "void".to_string(),
+            params => params,
+        };
+        write!(
+            header,
+            "\n/* Calls the `{}` host function, aborts the guest if the call fails */\n\
+            {c_ret} {ident}({c_params});\n",
+            function.name,
+        )
+        .unwrap();
+
+        let ret = match function.return_type {
+            "Void" => String::new(),
+            _ => format!(" -> {}", function.ret()),
+        };
+        write!(
+            rust,
+            "\n    #[unsafe(export_name = {ident:?})]\n    \
+            unsafe extern \"C\" fn {ident}({params}){ret} {{\n\
+            {conversions}        \
+                super::{method}({args}).expect({message:?})\n    \
+            }}\n",
+            params = rust_params.join(", "),
+            method = function.ident,
+            args = function.args(),
+            message = format!("Failed to call the {} host function", function.name),
+        )
+        .unwrap();
+    }
+
+    header.push_str("\n#ifdef __cplusplus\n}\n#endif\n");
+    rust.push_str("}\n");
+    Ok((header, rust))
+}
+
+/// Generates the host side of `interface`: a `GuestInterface` trait implemented for
+/// `MultiUseSandbox` with a method per guest function, and a `Host` trait with a
+/// method per host function together with a function registering them.
+fn host_module(interface: &InterfaceMetadata) -> Result<String> {
+    let guest_functions = functions(&interface.guest_functions)?;
+    let host_functions = functions(&interface.host_functions)?;
+
+    let mut code = String::from(HEADER);
+    code.push_str("\nuse hyperlight_host::{MultiUseSandbox, Result, UninitializedSandbox};\n");
+
+    code.push_str("\n/// The guest functions of the interface.\npub trait GuestInterface {\n");
+    for function in &guest_functions {
+        let params = match function.params() {
+            params if params.is_empty() => String::new(),
+            params => format!(", {params}"),
+        };
+        writeln!(
+            code,
+            "    fn {}(&mut self{params}) -> Result<{}>;",
+            function.ident,
+            function.ret()
+        )
+        .unwrap();
+    }
+    code.push_str("}\n\nimpl GuestInterface for MultiUseSandbox {\n");
+    for function in &guest_functions {
+        let params = match function.params() {
+            params if params.is_empty() => String::new(),
+            params => format!(", {params}"),
+        };
+        let args = match function.parameters.len() {
+            0 => "()".to_string(),
+            1 => format!("({},)", function.args()),
+            _ => format!("({})", function.args()),
+        };
+        writeln!(
+            code,
+            "    fn {ident}(&mut self{params}) -> Result<{ret}> {{\n        \
+                self.call::<{ret}>({name:?}, {args})\n    \
+            }}",
+            ident = function.ident,
+            ret = function.ret(),
+            name = function.name,
+        )
+        .unwrap();
+    }
+    code.push_str("}\n");
+
+    code.push_str("\n/// The host functions of the interface.\npub trait Host {\n");
+    for function in &host_functions {
+        writeln!(
+            code,
+            "    fn {}({}) -> {};",
+            function.ident,
+            function.params(),
+            function.ret()
+        )
+        .unwrap();
+    }
+    code.push_str(
+        "}\n\n/// Registers the host functions of the interface in `sandbox`.\n\
+        pub fn register<H: Host>(sandbox: &mut UninitializedSandbox) -> Result<()> {\n",
+    );
+    for function in &host_functions {
+        writeln!(
+            code,
+            "    sandbox.register({:?}, |{}| Ok(H::{}({})))?;",
+            function.name,
+            function.params(),
+            function.ident,
+            function.args()
+        )
+        .unwrap();
+    }
+    code.push_str("    Ok(())\n}\n");
+
+    Ok(code)
+}
+
+/// Generates the guest and host code for the `[package.metadata.hyperlight.interface]`
+/// of the guest `packages`, or all the guests in the workspace if `packages` is empty.
+///
+/// The code for each guest is written to `<dir>/<package>/guest.rs` and
+/// `<dir>/<package>/host.rs`, where `<dir>` is `--out-dir`, or
+/// `target/hyperlight/interface` by default.
+/// With `c_header`, the host functions are also declared in
+/// `<dir>/<package>/host_functions.h`, and implemented in `guest.rs`, see [`c_shims`].
+pub fn generate(args: &Args, packages: &[String], c_header: bool) -> Result<()> {
+    let packages = match packages {
+        [] => guest_packages(args)?,
+        packages => packages.to_vec(),
+    };
+
+    if packages.is_empty() {
+        bail!("No hyperlight guest packages found in the workspace");
+    }
+
+    let metadata = metadata::cargo_metadata(args, &["--no-deps".into()])?;
+    let out_dir = match &args.out_dir {
+        Some(dir) => dir.clone(),
+        None => args.target_dir.join("hyperlight").join("interface"),
+    };
+
+    for package in &packages {
+        let pkg = metadata["packages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|pkg| pkg["name"] == package.as_str())
+            .with_context(|| format!("Package {package:?} not found in workspace"))?;
+
+        let interface = serde_json::from_value::<Option<PackageMetadata>>(pkg["metadata"].clone())
+            .with_context(|| format!("Failed to parse the metadata of {package:?}"))?
+            .and_then(|metadata| metadata.hyperlight)
+            .map(|hyperlight| hyperlight.interface)
+            .unwrap_or_default();
+
+        if interface.guest_functions.is_empty() && interface.host_functions.is_empty() {
+            note(format!(
+                "guest {package} has no [package.metadata.hyperlight.interface] to generate"
+            ));
+            continue;
+        }
+
+        let mut guest = guest_module(&interface)
+            .with_context(|| format!("Failed to generate the guest code of {package:?}"))?;
+        let host = host_module(&interface)
+            .with_context(|| format!("Failed to generate the host code of {package:?}"))?;
+
+        let dir = out_dir.join(package);
+        std::fs::create_dir_all(&dir).context("Failed to create output directory")?;
+        atomic::write(dir.join("host.rs"), host).context("Failed to write host code")?;
+        if c_header {
+            let (header, shims) = c_shims(&interface)
+                .with_context(|| format!("Failed to generate the C header of {package:?}"))?;
+            guest.push_str(&shims);
+            atomic::write(dir.join("host_functions.h"), header)
+                .context("Failed to write C header")?;
+        }
+        atomic::write(dir.join("guest.rs"), guest).context("Failed to write guest code")?;
+        note(format!(
+            "generated the interface of {package} in {}",
+            dir.display()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(parameters: &[&str], return_type: &str) -> FunctionSignature {
+        FunctionSignature {
+            parameters: Some(parameters.iter().map(|p| p.to_string()).collect()),
+            return_type: Some(return_type.to_string()),
+        }
+    }
+
+    #[test]
+    fn snake_case_names() {
+        let cases = [
+            ("PrintOutput", "print_output"),
+            ("HTTPGet", "http_get"),
+            ("Echo2Times", "echo2_times"),
+            ("already_snake", "already_snake"),
+            ("Type", "r#type"),
+            ("3D", "_3_d"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(snake_case(name), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn invalid_types_are_rejected() {
+        let interface = InterfaceMetadata {
+            guest_functions: BTreeMap::from([("Bad".to_string(), signature(&["Void"], "Int"))]),
+            host_functions: BTreeMap::new(),
+        };
+        assert!(guest_module(&interface).is_err());
+
+        let interface = InterfaceMetadata {
+            guest_functions: BTreeMap::from([("Bad".to_string(), signature(&[], "Str"))]),
+            host_functions: BTreeMap::new(),
+        };
+        assert!(host_module(&interface).is_err());
+    }
+
+    #[test]
+    fn generated_code() {
+        let interface = InterfaceMetadata {
+            guest_functions: BTreeMap::from([
+                ("Echo".to_string(), signature(&["String", "Int"], "String")),
+                ("Ping".to_string(), signature(&[], "Void")),
+            ]),
+            host_functions: BTreeMap::from([(
+                "HostPrint".to_string(),
+                signature(&["String"], "Int"),
+            )]),
+        };
+
+        let guest = guest_module(&interface).unwrap();
+        for expected in [
+            "    fn echo(arg0: String, arg1: i32) -> String;",
+            "        Vec::from(&[ParameterType::String, ParameterType::Int]),",
+            "        __ping::<G> as usize,",
+            "        Some([ParameterValue::String(arg0), ParameterValue::Int(arg1)]) => Ok(get_flatbuffer_result(G::echo(arg0.clone(), arg1.clone()))),",
+            "        None | Some([]) => Ok(get_flatbuffer_result(G::ping())),",
+            "pub fn host_print(arg0: String) -> Result<i32> {",
+            "    call_host_function::<i32>(\"HostPrint\", Some(Vec::from(&[ParameterValue::String(arg0)])), ReturnType::Int)",
+        ] {
+            assert!(
+                guest.contains(expected),
+                "missing {expected:?} in:\n{guest}"
+            );
+        }
+
+        let host = host_module(&interface).unwrap();
+        for expected in [
+            "    fn echo(&mut self, arg0: String, arg1: i32) -> Result<String>;",
+            "        self.call::<String>(\"Echo\", (arg0, arg1))",
+            "    fn ping(&mut self) -> Result<()>;",
+            "        self.call::<()>(\"Ping\", ())",
+            "    fn host_print(arg0: String) -> i32;",
+            "    sandbox.register(\"HostPrint\", |arg0: String| Ok(H::host_print(arg0)))?;",
+        ] {
+            assert!(host.contains(expected), "missing {expected:?} in:\n{host}");
+        }
+    }
+
+    #[test]
+    fn c_shims_for_host_functions() {
+        let interface = InterfaceMetadata {
+            guest_functions: BTreeMap::new(),
+            host_functions: BTreeMap::from([
+                ("HostPrint".to_string(), signature(&["String"], "Int")),
+                (
+                    "HostLog".to_string(),
+                    signature(&["VecBytes", "Bool"], "Void"),
+                ),
+                ("HostName".to_string(), signature(&[], "String")),
+            ]),
+        };
+
+        let (header, rust) = c_shims(&interface).unwrap();
+        for expected in [
+            "int32_t host_print(const char *arg0);",
+            "void host_log(const uint8_t *arg0, size_t arg0_len, bool arg1);",
+            "/* HostName returns String and can't be called from C */",
+        ] {
+            assert!(
+                header.contains(expected),
+                "missing {expected:?} in:\n{header}"
+            );
+        }
+        for expected in [
+            "    #[unsafe(export_name = \"host_print\")]",
+            "    unsafe extern \"C\" fn host_print(arg0: *const core::ffi::c_char) -> i32 {",
+            "    unsafe extern \"C\" fn host_log(arg0: *const u8, arg0_len: usize, arg1: bool) {",
+            "        super::host_log(arg0, arg1).expect(\"Failed to call the HostLog host function\")",
+        ] {
+            assert!(rust.contains(expected), "missing {expected:?} in:\n{rust}");
+        }
+        assert!(!rust.contains("host_name"));
+    }
+}
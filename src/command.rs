@@ -1,17 +1,87 @@
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::Infallible;
-use std::env::VarsOs;
 use std::ffi::{OsStr, OsString, c_char};
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::process::Command as StdCommand;
+use std::sync::Arc;
+use std::time::Instant;
 use std::{env, iter};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use target_spec_json::TargetSpec;
 
-use crate::CargoCommandExt;
+use crate::artifact::Executable;
 use crate::cargo_cmd::{CargoBinary, CargoCmd as _, find_cargo, merge_env};
-use crate::cli::{Args, Warning};
+use crate::cli::{self, Args, WarningHandler, capture_warnings, split_args};
+use crate::failure::{Failure, WithFailure as _};
+use crate::report::{ArtifactReport, BuildReport, Warning};
+use crate::subcommand::Subcommand;
+use crate::{
+    CargoCommandExt, artifact, doctest, failure, hint, hypervisor, link_map, pie, provenance,
+    sysroot, timings,
+};
+
+/// Environment variables inherited by default even after [`Command::env_clear`].
+const PRESERVED_ENVS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "RUSTUP_HOME",
+    "CARGO_HOME",
+    "TERM",
+    // registry authentication, so that private dependencies resolve, including the
+    // tokens of `CARGO_REGISTRIES_<NAME>_TOKEN` and the credential providers
+    "CARGO_REGISTRY_*",
+    "CARGO_REGISTRIES_*",
+    "CARGO_NET_*",
+    // proxies and credentials for downloads, e.g., of dependencies
+    "CARGO_HTTP_*",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "ALL_PROXY",
+    "NO_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "all_proxy",
+    "no_proxy",
+    "NETRC",
+    // custom CA bundles, e.g., of a TLS intercepting proxy
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+    // the jobserver, so that nested builds, e.g., from build scripts, share the job
+    // slots of the outer build instead of starting a job pool of their own
+    "CARGO_MAKEFLAGS",
+    "MAKEFLAGS",
+    "MFLAGS",
+    #[cfg(windows)]
+    "USERPROFILE",
+    #[cfg(windows)]
+    "SYSTEMROOT",
+    #[cfg(windows)]
+    "TEMP",
+    #[cfg(windows)]
+    "TMP",
+];
+
+/// Returns whether the environment variable `key` matches the `preserved` name, where
+/// a trailing `*` matches any suffix.
+fn is_preserved(preserved: &OsStr, key: &OsStr) -> bool {
+    // environment variable names are case insensitive on windows
+    let normalize = |name: &OsStr| {
+        let name = name.to_string_lossy();
+        if cfg!(windows) {
+            name.to_ascii_uppercase()
+        } else {
+            name.into_owned()
+        }
+    };
+    let (preserved, key) = (normalize(preserved), normalize(key));
+    match preserved.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => preserved == key,
+    }
+}
 
 /// A process builder for cargo commands, providing a similar API to `std::process::Command`.
 ///
@@ -55,16 +125,26 @@ pub struct Command {
     args: Vec<OsString>,
     /// Environment variable mappings to set for the child process
     inherit_envs: bool,
+    /// Environment variables inherited even after `env_clear`
+    preserved_envs: BTreeSet<OsString>,
     envs: BTreeMap<OsString, Option<OsString>>,
     // Working directory for the child process
     current_dir: Option<PathBuf>,
+    /// Extra C flags to pass to C compilations
+    cflags: Vec<OsString>,
+    /// Whether to ignore the C flags from the environment
+    replace_cflags: bool,
+    /// Handler of the warnings emitted by cargo-hyperlight, instead of printing them
+    warning_handler: Option<WarningHandler>,
+    /// Whether settings that could not be resolved are errors instead of warnings
+    strict: bool,
 }
 
 impl Debug for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let args = self.build_args_infallible();
-        let mut cmd = self.command();
-        cmd.populate_from_args(&args);
+        // with invalid arguments, show the command as it is, which fails when it runs
+        let args = self.build_args_quiet().ok();
+        let cmd = self.command(args.as_ref());
 
         write!(f, "env ")?;
         if let Some(current_dir) = &self.current_dir {
@@ -80,7 +160,7 @@ impl Debug for Command {
             }
         }
         write!(f, "{:?} ", self.get_program())?;
-        for arg in &self.args {
+        for arg in cmd.get_args() {
             write!(f, "{arg:?} ")?;
         }
         writeln!(f)
@@ -122,7 +202,12 @@ impl Command {
             args: Vec::new(),
             envs: BTreeMap::new(),
             inherit_envs: true,
+            preserved_envs: PRESERVED_ENVS.iter().map(OsString::from).collect(),
             current_dir: None,
+            cflags: Vec::new(),
+            replace_cflags: false,
+            warning_handler: None,
+            strict: false,
         })
     }
 
@@ -261,6 +346,16 @@ impl Command {
     /// including those that would normally be inherited from the parent process.
     /// Environment variables can be added back individually using [`env`].
     ///
+    /// Variables that cargo and rustup need to work are still inherited from the parent
+    /// process, by default `PATH`, `HOME`, `RUSTUP_HOME`, `CARGO_HOME`, `TERM`, the
+    /// registry authentication and network settings `CARGO_REGISTRY_*`,
+    /// `CARGO_REGISTRIES_*`, `CARGO_NET_*` and `CARGO_HTTP_*`, the proxy variables
+    /// `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` (also in lowercase),
+    /// `NETRC`, the CA bundles `SSL_CERT_FILE` and `SSL_CERT_DIR`, and the jobserver
+    /// variables `CARGO_MAKEFLAGS`, `MAKEFLAGS` and `MFLAGS` (plus `USERPROFILE`,
+    /// `SYSTEMROOT`, `TEMP` and `TMP` on Windows).
+    /// Use [`env_preserve`] to change that list.
+    ///
     /// If `RUSTUP_TOOLCHAIN` was set in the parent process, it will be preserved.
     ///
     /// # Examples
@@ -279,12 +374,43 @@ impl Command {
     /// ```
     ///
     /// [`env`]: Command::env
+    /// [`env_preserve`]: Command::env_preserve
     pub fn env_clear(&mut self) -> &mut Self {
         self.inherit_envs = false;
         self.envs.clear();
         self
     }
 
+    /// Sets the environment variables that are still inherited from the parent process
+    /// after calling [`env_clear`], replacing the default list.
+    ///
+    /// A name ending with `*` preserves every variable starting with the rest of it, e.g.,
+    /// `CARGO_REGISTRIES_*`. Pass an empty list to inherit no variables at all.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .env_preserve(["PATH", "HOME", "CARGO_REGISTRY_TOKEN"])
+    ///     .env_clear()
+    ///     .arg("build")
+    ///     .exec();
+    /// ```
+    ///
+    /// [`env_clear`]: Command::env_clear
+    pub fn env_preserve(&mut self, keys: impl IntoIterator<Item = impl AsRef<OsStr>>) -> &mut Self {
+        self.preserved_envs = keys
+            .into_iter()
+            .map(|key| key.as_ref().to_os_string())
+            .collect();
+        self
+    }
+
     /// Removes an explicitly set environment variable and prevents inheriting
     /// it from a parent process.
     ///
@@ -373,6 +499,197 @@ impl Command {
         self
     }
 
+    /// Adds a flag to pass to C compilations, through `cc-rs` and `bindgen`.
+    ///
+    /// Only one flag can be passed per use, e.g., `-DFOO` or `-O2`, and
+    /// flags taking a separate value like `-isystem <dir>` need to be passed
+    /// in two calls.
+    ///
+    /// The C flags of the child process are resolved in this order, where later
+    /// flags take precedence over earlier ones:
+    /// 1. The first set of these environment variables: `CFLAGS_<target>`,
+    ///    `CFLAGS_<target_with_underscores>`, `CFLAGS_<TARGET_WITH_UNDERSCORES>`,
+    ///    `CFLAGS_hyperlight`, `CFLAGS_HYPERLIGHT`, `HYPERLIGHT_CFLAGS`,
+    ///    `TARGET_CFLAGS`, `CFLAGS`
+    /// 2. hyperlight's own flags
+    /// 3. The flags added with this method
+    ///
+    /// cc-rs passes the flags from the environment, and the C compiler wrapper
+    /// generated by cargo-hyperlight appends hyperlight's flags and the flags added
+    /// with this method. For bindgen, all of them are set in `BINDGEN_EXTRA_CLANG_ARGS`,
+    /// skipping any flag that is already present, so that repeated invocations don't
+    /// accumulate duplicated flags.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .cflag("-DGUEST_LOG_LEVEL=2")
+    ///     .arg("build")
+    ///     .exec();
+    /// ```
+    pub fn cflag(&mut self, flag: impl AsRef<OsStr>) -> &mut Self {
+        self.cflags.push(flag.as_ref().to_os_string());
+        self
+    }
+
+    /// Replaces the C flags from the environment with `flags`.
+    ///
+    /// Unlike [`cflag`], the C flags environment variables listed there are ignored,
+    /// and C compilations only use hyperlight's own flags followed by `flags`.
+    /// Any flag previously added with [`cflag`] is discarded.
+    ///
+    /// [`cflag`]: Command::cflag
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .cflags_replace(["-O2", "-DNDEBUG"])
+    ///     .arg("build")
+    ///     .exec();
+    /// ```
+    pub fn cflags_replace(
+        &mut self,
+        flags: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> &mut Self {
+        self.cflags = flags
+            .into_iter()
+            .map(|flag| flag.as_ref().to_os_string())
+            .collect();
+        self.replace_cflags = true;
+        self
+    }
+
+    /// Fails instead of falling back to a default when a setting can't be resolved,
+    /// e.g., the target directory when `cargo metadata` fails, or the target triple
+    /// when the configured one isn't a hyperlight target.
+    ///
+    /// By default these fallbacks only emit a warning, see [`warning_handler`].
+    /// Strict mode makes builds reproducible by never depending on a guessed value.
+    ///
+    /// This is also available from the command line with `--strict`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .strict(true)
+    ///     .arg("build")
+    ///     .status()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// [`warning_handler`]: Command::warning_handler
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Passes the warnings emitted by cargo-hyperlight to `handler` instead of
+    /// printing them to stderr.
+    ///
+    /// Each [`Warning`] has a stable `code` identifying its kind, so that tools
+    /// can filter or escalate specific warnings, e.g., failing a build when the
+    /// target directory can't be resolved. Warnings about settings that couldn't be
+    /// resolved also carry the `default` value used instead.
+    ///
+    /// The warnings are still included in the [`BuildReport`] returned by
+    /// [`status_with_report`].
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .warning_handler(|warning| {
+    ///         eprintln!("[{}] {}", warning.code, warning.message);
+    ///     })
+    ///     .arg("build")
+    ///     .status()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// [`status_with_report`]: Command::status_with_report
+    pub fn warning_handler(
+        &mut self,
+        handler: impl Fn(Warning) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.warning_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Sets the path of the cargo program to execute, instead of the one found in
+    /// [`cargo`](crate::cargo).
+    ///
+    /// The same cargo program is used for all the cargo invocations needed to prepare
+    /// the sysroot.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .cargo_path("/opt/rust/nightly/bin/cargo")
+    ///     .arg("build")
+    ///     .exec();
+    /// ```
+    pub fn cargo_path(&mut self, path: impl AsRef<Path>) -> &mut Self {
+        self.cargo.path = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Sets the rustup toolchain to use, passed to the cargo program as `RUSTUP_TOOLCHAIN`,
+    /// instead of the one from the current process's environment.
+    ///
+    /// A toolchain's own cargo binary ignores `RUSTUP_TOOLCHAIN`, so if the cargo program
+    /// isn't a rustup proxy, e.g., it's the one in `CARGO` when running as a cargo
+    /// subcommand, rustup's cargo proxy is used instead, when rustup is installed.
+    /// The proxy is looked up in `PATH`, or else in the `CARGO_HOME` of the environment
+    /// the command runs with, when it runs.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// cargo()
+    ///     .unwrap()
+    ///     .rustup_toolchain("nightly-2025-06-01")
+    ///     .arg("build")
+    ///     .exec();
+    /// ```
+    pub fn rustup_toolchain(&mut self, name: impl AsRef<OsStr>) -> &mut Self {
+        self.cargo.rustup_toolchain = Some(name.as_ref().to_os_string());
+        self.cargo.rustup_proxy = true;
+        self
+    }
+
     /// Returns an iterator over the arguments that will be passed to the cargo program.
     ///
     /// This does not include the program name itself (which can be retrieved with
@@ -455,27 +772,41 @@ impl Command {
     /// from the current process, taking into account whether [`env_clear`] has been called.
     ///
     /// [`env_clear`]: Command::env_clear
-    fn base_env(&self) -> VarsOs {
-        let mut env = env::vars_os();
-        if !self.inherit_envs {
-            // iterate over the whole VarOs to consume it
-            env.find(|_| false);
-        }
-        env
+    fn base_env(&self) -> impl Iterator<Item = (OsString, OsString)> {
+        env::vars_os().filter(|(k, _)| {
+            self.inherit_envs
+                || self
+                    .preserved_envs
+                    .iter()
+                    .any(|preserved| is_preserved(preserved, k))
+        })
     }
 
     fn resolve_env(&self) -> HashMap<OsString, OsString> {
-        merge_env(self.base_env(), self.get_envs())
+        let mut env = merge_env(self.base_env(), self.get_envs());
+        // make internal cargo invocations use the same cargo binary
+        env.insert("CARGO".into(), self.cargo.program(&env).into());
+        if let Some(rustup_toolchain) = &self.cargo.rustup_toolchain {
+            env.insert("RUSTUP_TOOLCHAIN".into(), rustup_toolchain.clone());
+        }
+        env
     }
 
-    fn command(&self) -> StdCommand {
-        let mut command = self.cargo.command();
-        command.args(self.get_args());
+    /// Returns the cargo command to run, populated from `args` if given.
+    fn command(&self, args: Option<&Args>) -> StdCommand {
+        let mut command = self
+            .cargo
+            .command(&merge_env(self.base_env(), self.get_envs()));
         if let Some(cwd) = &self.current_dir {
             command.current_dir(cwd);
         }
         if !self.inherit_envs {
             command.env_clear();
+            command.envs(self.base_env());
+            // keep the toolchain selected by CargoBinary::command
+            if let Some(rustup_toolchain) = &self.cargo.rustup_toolchain {
+                command.env("RUSTUP_TOOLCHAIN", rustup_toolchain);
+            }
         }
         for (k, v) in self.get_envs() {
             match v {
@@ -483,6 +814,19 @@ impl Command {
                 None => command.env_remove(k),
             };
         }
+        if let Some(args) = args {
+            command.populate_from_args(args);
+            // `--config` goes before the subcommand, so that it's never after a `--`
+            for config in command.cargo_config(args) {
+                command.arg("--config").arg(config);
+            }
+        }
+        let mut cargo_args = split_args(self.get_args()).cargo;
+        doctest::skip(&mut cargo_args);
+        if args.is_some_and(hypervisor::falls_back) {
+            hypervisor::skip_runs(&mut cargo_args);
+        }
+        command.args(cargo_args);
         command
     }
 
@@ -500,31 +844,202 @@ impl Command {
         self.cargo.path.as_os_str()
     }
 
-    fn build_args(&self) -> Args {
+    /// Returns the path where cargo will place the guest binary, without building it.
+    ///
+    /// The path is computed as `<target-dir>/<target>/<profile-dir>/<bin>`, using the
+    /// target directory and target triple resolved from this command's arguments
+    /// and environment.
+    ///
+    /// * `package` selects the package containing the binary. If `None`, the
+    ///   packages cargo would build are considered: the package in the current
+    ///   directory, or the default members of the workspace from its root.
+    /// * `bin` selects the binary target. If `None`, there must be exactly one
+    ///   binary target to choose from.
+    /// * `profile` selects the profile, e.g., `"release"`. If `None`, the `dev`
+    ///   profile is assumed.
+    ///
+    /// This is also available from the command line as
+    /// `cargo hyperlight print artifact-path`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// let path = cargo()
+    ///     .unwrap()
+    ///     .current_dir("path/to/guest")
+    ///     .target_artifact_path(None, Some("guest"), Some("release"))
+    ///     .unwrap();
+    ///
+    /// println!("guest binary: {}", path.display());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if:
+    /// - The cargo metadata for the workspace could not be retrieved
+    /// - The requested package or binary does not exist
+    /// - `bin` is `None` and there is not exactly one binary target
+    pub fn target_artifact_path(
+        &self,
+        package: Option<&str>,
+        bin: Option<&str>,
+        profile: Option<&str>,
+    ) -> anyhow::Result<PathBuf> {
+        let args = self.expanded()?.build_args()?;
+        artifact::artifact_path(&args, package, Executable::Bin(bin), profile)
+    }
+
+    /// Returns the path where cargo will place the guest example `example`, without
+    /// building it.
+    ///
+    /// Like [`target_artifact_path`], but the path is computed as
+    /// `<target-dir>/<target>/<profile-dir>/examples/<example>`.
+    ///
+    /// This is also available from the command line as
+    /// `cargo hyperlight print artifact-path --example <example>`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// let path = cargo()
+    ///     .unwrap()
+    ///     .current_dir("path/to/guest")
+    ///     .target_example_path(None, "hello", None)
+    ///     .unwrap();
+    ///
+    /// println!("guest example: {}", path.display());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if:
+    /// - The cargo metadata for the workspace could not be retrieved
+    /// - The requested package or example does not exist
+    ///
+    /// [`target_artifact_path`]: Command::target_artifact_path
+    pub fn target_example_path(
+        &self,
+        package: Option<&str>,
+        example: &str,
+        profile: Option<&str>,
+    ) -> anyhow::Result<PathBuf> {
+        let args = self.expanded()?.build_args()?;
+        artifact::artifact_path(&args, package, Executable::Example(example), profile)
+    }
+
+    /// Returns the spec of the hyperlight target that the sysroot is built for, without
+    /// building anything.
+    ///
+    /// This is the spec rustc prints for the base target, with the overrides of
+    /// cargo-hyperlight for the settings resolved from this command's arguments and
+    /// environment, e.g., the linker and the code model. Tools like IDE plugins can use
+    /// it to configure themselves like a guest build.
+    ///
+    /// This is also available from the command line as
+    /// `cargo hyperlight print target-spec-json`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// let spec = cargo()
+    ///     .unwrap()
+    ///     .current_dir("path/to/guest")
+    ///     .target_spec()
+    ///     .unwrap();
+    ///
+    /// println!("linker: {:?}", spec.linker);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if:
+    /// - The target is not a supported hyperlight target
+    /// - rustc could not print the spec of the base target
+    pub fn target_spec(&self) -> anyhow::Result<TargetSpec> {
+        let args = self.expanded()?.build_args()?;
+        sysroot::target_spec(&args)
+    }
+
+    /// Returns a copy of this command with the cargo arguments replaced by `args`,
+    /// keeping the flags handled by cargo-hyperlight, e.g., `--target-dir`.
+    pub(crate) fn with_cargo_args(
+        &self,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Command {
+        let mut command = self.clone();
+        command.args = split_args(self.get_args()).hyperlight;
+        command.args.extend(args.into_iter().map(Into::into));
+        command
+    }
+
+    pub(crate) fn build_args(&self) -> Result<Args> {
         // parse the arguments and environment variables
-        match Args::parse(
-            self.get_args(),
-            self.resolve_env(),
-            self.get_current_dir(),
-            Warning::WARN,
-        ) {
-            Ok(args) => args,
-        }
+        let args = self.with_warning_handler(|| {
+            if self.strict || cli::is_strict(self.get_args()) {
+                return Args::parse(
+                    self.get_args(),
+                    self.resolve_env(),
+                    self.get_current_dir(),
+                    cli::Warning::ERROR,
+                );
+            }
+            Args::parse(
+                self.get_args(),
+                self.resolve_env(),
+                self.get_current_dir(),
+                cli::Warning::WARN,
+            )
+        });
+        let args = args.failure(Failure::Config)?;
+        Ok(self.with_cflags(args))
     }
 
-    fn build_args_infallible(&self) -> Args {
-        match Args::parse(
+    fn build_args_quiet(&self) -> Result<Args> {
+        let args = Args::parse(
             self.get_args(),
             self.resolve_env(),
             self.get_current_dir(),
-            Warning::IGNORE,
-        ) {
-            Ok(args) => args,
-            Err(err) => {
-                eprintln!("Failed to parse arguments: {err}");
-                std::process::exit(1);
-            }
+            cli::Warning::IGNORE,
+        )?;
+        Ok(self.with_cflags(args))
+    }
+
+    /// Returns this command with the response files among its arguments, like
+    /// `@path/to/args`, replaced by the arguments they list.
+    fn expanded(&self) -> Result<Cow<'_, Command>> {
+        if !self
+            .args
+            .iter()
+            .any(|arg| arg.to_string_lossy().starts_with('@'))
+        {
+            return Ok(Cow::Borrowed(self));
         }
+        let cwd = match self.get_current_dir() {
+            Some(cwd) => cwd.to_path_buf(),
+            None => env::current_dir().context("Failed to get current directory")?,
+        };
+        let mut command = self.clone();
+        command.args = cli::expand_response_files(&self.args, &cwd)?;
+        Ok(Cow::Owned(command))
+    }
+
+    /// Runs `f` with the warnings it emits passed to the [`warning_handler`], if any.
+    ///
+    /// [`warning_handler`]: Command::warning_handler
+    fn with_warning_handler<R>(&self, f: impl FnOnce() -> R) -> R {
+        cli::with_warning_handler(self.warning_handler.as_ref(), f)
+    }
+
+    fn with_cflags(&self, mut args: Args) -> Args {
+        args.cflags.extend(self.cflags.iter().cloned());
+        args.replace_cflags = self.replace_cflags;
+        args
     }
 
     /// Executes a cargo command as a child process, waiting for it to finish and
@@ -532,6 +1047,14 @@ impl Command {
     ///
     /// The process stdin, stdout and stderr are inherited from the parent.
     ///
+    /// The sysroot is prepared only once per process for the same configuration, so
+    /// repeated calls only pay for running cargo.
+    ///
+    /// If `--out-dir` (or `-o`) is among the arguments, the final guest binaries are
+    /// copied to that directory after a successful build, together with any sidecar
+    /// files cargo-hyperlight generates next to them, like the link maps written with
+    /// `--emit-map` and the provenance files written with `--provenance`.
+    ///
     /// # Examples
     ///
     /// Basic usage:
@@ -553,26 +1076,187 @@ impl Command {
     /// # Errors
     ///
     /// This method will return an error if:
+    /// - `--manifest-path` is not an existing `Cargo.toml` file, or `--target-dir` is
+    ///   not a directory
+    /// - A setting can't be resolved in [`strict`](Command::strict) mode
     /// - The sysroot preparation fails
     /// - The cargo process could not be spawned
     /// - The cargo process returned a non-zero exit status
+    /// - The artifacts could not be copied to the `--out-dir` directory
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(args = ?self.args)))]
     pub fn status(&self) -> anyhow::Result<()> {
-        let args = self.build_args();
+        self.with_warning_handler(|| self.expanded()?.status_impl())
+    }
+
+    fn status_impl(&self) -> anyhow::Result<()> {
+        if Subcommand::run_from_args(self)? {
+            return Ok(());
+        }
+        self.cargo_status()
+    }
+
+    /// Runs the cargo command, even if its arguments name a cargo-hyperlight subcommand,
+    /// for subcommands that wrap a cargo command of the same name.
+    pub(crate) fn cargo_status(&self) -> anyhow::Result<()> {
+        self.cargo_status_with(&self.build_args()?)
+    }
+
+    /// Runs the cargo command with the already resolved `args`.
+    fn cargo_status_with(&self, args: &Args) -> anyhow::Result<()> {
+        hint::with_recovery(args, || {
+            args.prepare_sysroot()
+                .context("Failed to prepare sysroot")?;
+
+            let mut command = self.command(Some(args));
+
+            if args.out_dir.is_none() && !args.emit_map && !args.provenance && !args.models.pie {
+                let status = command.status().context("Failed to execute cargo")?;
+                failure::check_cargo(status, &command).context("Failed to execute cargo")?;
+                timings::merge(args).context("Failed to merge timing reports")?;
+                return Ok(());
+            }
+
+            let artifacts = artifact::build(&mut command).context("Failed to execute cargo")?;
+            if args.models.pie {
+                pie::check(&artifacts)
+                    .context("Failed to check guest relocations")
+                    .failure(Failure::Validation)?;
+            }
+            if args.emit_map {
+                link_map::attach(args, &artifacts).context("Failed to attach link maps")?;
+            }
+            if args.provenance {
+                provenance::write(args, &command, &artifacts)
+                    .context("Failed to write provenance")?;
+            }
+            if let Some(out_dir) = &args.out_dir {
+                artifact::export(args, &artifacts, out_dir)
+                    .context("Failed to export artifacts")?;
+            }
+            timings::merge(args).context("Failed to merge timing reports")?;
+
+            Ok(())
+        })
+    }
+
+    /// Like [`status`], but returns a [`BuildReport`] with the time spent in each phase,
+    /// whether the sysroot was rebuilt, the produced executables with their sizes and
+    /// hashes, and the warnings emitted by cargo-hyperlight.
+    ///
+    /// Cargo's JSON messages are used to find the executables, so `--message-format`
+    /// can only be among the arguments with a JSON format, whose messages are then
+    /// passed through to stdout unchanged.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::cargo;
+    ///
+    /// let report = cargo()
+    ///     .unwrap()
+    ///     .arg("build")
+    ///     .status_with_report()
+    ///     .unwrap();
+    ///
+    /// println!("sysroot rebuilt: {}", report.sysroot_rebuilt);
+    /// for artifact in &report.artifacts {
+    ///     println!("{}: {} bytes, {}", artifact.name, artifact.size, artifact.hash);
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error in the same situations as [`status`], and if
+    /// the arguments are a cargo-hyperlight subcommand rather than a cargo command.
+    ///
+    /// [`status`]: Command::status
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(args = ?self.args)))]
+    pub fn status_with_report(&self) -> anyhow::Result<BuildReport> {
+        self.with_warning_handler(|| self.expanded()?.status_with_report_impl())
+    }
+
+    fn status_with_report_impl(&self) -> anyhow::Result<BuildReport> {
+        if Subcommand::is_subcommand(self.get_args()) {
+            bail!("Build reports are only available for cargo commands");
+        }
+
+        let (report, warnings) = capture_warnings(|| -> Result<BuildReport> {
+            let start = Instant::now();
+            let args = self.build_args()?;
+            let resolve_time = start.elapsed();
+
+            let (sysroot_rebuilt, command, artifacts, sysroot_time, cargo_time) =
+                hint::with_recovery(&args, || {
+                    let start = Instant::now();
+                    let sysroot_rebuilt = args
+                        .prepare_sysroot()
+                        .context("Failed to prepare sysroot")?;
+                    let sysroot_time = start.elapsed();
+
+                    let start = Instant::now();
+                    let mut command = self.command(Some(&args));
+                    let artifacts =
+                        artifact::build(&mut command).context("Failed to execute cargo")?;
+                    let cargo_time = start.elapsed();
+
+                    Ok((
+                        sysroot_rebuilt,
+                        command,
+                        artifacts,
+                        sysroot_time,
+                        cargo_time,
+                    ))
+                })?;
+
+            let start = Instant::now();
+            if args.models.pie {
+                pie::check(&artifacts)
+                    .context("Failed to check guest relocations")
+                    .failure(Failure::Validation)?;
+            }
+            if args.emit_map {
+                link_map::attach(&args, &artifacts).context("Failed to attach link maps")?;
+            }
+            if args.provenance {
+                provenance::write(&args, &command, &artifacts)
+                    .context("Failed to write provenance")?;
+            }
+            if let Some(out_dir) = &args.out_dir {
+                artifact::export(&args, &artifacts, out_dir)
+                    .context("Failed to export artifacts")?;
+            }
+            timings::merge(&args).context("Failed to merge timing reports")?;
+            let export_time = start.elapsed();
+
+            let artifacts = artifacts
+                .into_iter()
+                .map(|artifact| ArtifactReport::new(artifact.name, artifact.executable))
+                .collect::<Result<_>>()?;
 
-        args.prepare_sysroot()
-            .context("Failed to prepare sysroot")?;
+            Ok(BuildReport {
+                resolve_time,
+                sysroot_time,
+                cargo_time,
+                export_time,
+                sysroot_rebuilt,
+                artifacts,
+                warnings: vec![],
+            })
+        });
 
-        self.command()
-            .populate_from_args(&args)
-            .checked_status()
-            .context("Failed to execute cargo")?;
-        Ok(())
+        Ok(BuildReport {
+            warnings,
+            ..report?
+        })
     }
 
     /// Executes the cargo command, replacing the current process.
     ///
     /// This function will never return on success, as it replaces the current process
-    /// with the cargo process. On error, it will print the error and exit with code 101.
+    /// with the cargo process. On error, it will print the error and exit with the code
+    /// of its [`Failure`].
     ///
     /// # Examples
     ///
@@ -589,14 +1273,15 @@ impl Command {
     ///
     /// # Errors
     ///
-    /// This function will exit the process with code 101 if:
+    /// This function will exit the process with [`Failure::exit_code_of`] the error if:
+    /// - The arguments or configuration are invalid
     /// - The sysroot preparation fails
     /// - The process replacement fails
     pub fn exec(&self) -> ! {
-        match self.exec_impl() {
+        match self.with_warning_handler(|| self.expanded()?.exec_impl()) {
             Err(e) => {
                 eprintln!("{e:?}");
-                std::process::exit(101);
+                std::process::exit(Failure::exit_code_of(&e));
             }
         }
     }
@@ -605,14 +1290,31 @@ impl Command {
     ///
     /// This method prepares the sysroot and then calls the low-level `exec` function
     /// to replace the current process.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(args = ?self.args)))]
     fn exec_impl(&self) -> anyhow::Result<Infallible> {
-        let args = self.build_args();
+        if Subcommand::run_from_args(self)? {
+            std::process::exit(0);
+        }
+
+        let args = self.build_args()?;
+
+        if args.out_dir.is_some()
+            || args.emit_map
+            || args.provenance
+            || args.models.pie
+            || args.timings
+        {
+            // artifacts can only be exported or checked, and timing reports merged, after
+            // cargo finishes, so we can't replace the current process
+            self.cargo_status_with(&args)?;
+            std::process::exit(0);
+        }
 
-        args.prepare_sysroot()
-            .context("Failed to prepare sysroot")?;
+        hint::with_recovery(&args, || {
+            args.prepare_sysroot().context("Failed to prepare sysroot")
+        })?;
 
-        let mut command = self.command();
-        command.populate_from_args(&args);
+        let command = self.command(Some(&args));
 
         if let Some(cwd) = self.get_current_dir() {
             env::set_current_dir(cwd).context("Failed to change current directory")?;
@@ -690,3 +1392,82 @@ fn exec(
 
     Err(std::io::Error::last_os_error())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo;
+    use crate::cargo_cmd::find_rustup_proxy;
+
+    #[test]
+    fn preserved_envs_match_names_and_prefixes() {
+        let preserved = |key: &str| {
+            PRESERVED_ENVS
+                .iter()
+                .any(|preserved| is_preserved(OsStr::new(preserved), OsStr::new(key)))
+        };
+        assert!(preserved("CARGO_HOME"));
+        assert!(preserved("CARGO_REGISTRY_TOKEN"));
+        assert!(preserved("CARGO_REGISTRIES_MY_REGISTRY_TOKEN"));
+        assert!(preserved("CARGO_REGISTRY_GLOBAL_CREDENTIAL_PROVIDERS"));
+        assert!(preserved("CARGO_NET_GIT_FETCH_WITH_CLI"));
+        assert!(preserved("RUSTUP_HOME"));
+        assert!(preserved("https_proxy"));
+        assert!(preserved("NO_PROXY"));
+        assert!(preserved("NETRC"));
+        assert!(preserved("SSL_CERT_FILE"));
+        assert!(!preserved("CARGO_HOME_EXTRA"));
+        assert!(!preserved("CARGO_TARGET_DIR"));
+        assert!(!preserved("RUSTFLAGS"));
+    }
+
+    #[test]
+    fn invalid_flag_values_are_errors() {
+        let err = cargo()
+            .unwrap()
+            .args(["build", "--code-model=bogus"])
+            .status()
+            .unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::Config));
+        assert!(
+            err.to_string()
+                .starts_with("invalid value 'bogus' for '--code-model <MODEL>'"),
+            "{err:#}"
+        );
+
+        let err = cargo()
+            .unwrap()
+            .args(["build", "--compress", "gzip"])
+            .status()
+            .unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::Config));
+        assert!(!err.to_string().contains("Usage:"), "{err:#}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rustup_proxy_comes_from_the_final_environment() {
+        let home = env::temp_dir().join(format!("cargo-hyperlight-home-{}", std::process::id()));
+        let bin = home.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        std::fs::write(bin.join("rustup"), "").unwrap();
+        let _ = std::fs::remove_file(bin.join("cargo"));
+        std::os::unix::fs::symlink(bin.join("rustup"), bin.join("cargo")).unwrap();
+
+        let program = |toolchain_first: bool| {
+            let mut cmd = cargo().unwrap();
+            cmd.cargo_path("/nonexistent/cargo");
+            if toolchain_first {
+                cmd.rustup_toolchain("nightly").env("CARGO_HOME", &home);
+            } else {
+                cmd.env("CARGO_HOME", &home).rustup_toolchain("nightly");
+            }
+            cmd.resolve_env().remove(OsStr::new("CARGO")).unwrap()
+        };
+        let proxy = find_rustup_proxy(Some(home.clone().into())).unwrap();
+        assert_eq!(program(true), proxy.as_os_str());
+        assert_eq!(program(false), proxy.as_os_str());
+
+        std::fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+use semver::{Version, VersionReq};
+use serde_json::Value;
+
+use crate::cli::{Args, warning};
+use crate::{metadata, toolchain};
+
+/// The hyperlight crates whose versions are checked.
+const CRATES: &[&str] = &[
+    "hyperlight-guest-bin",
+    "hyperlight-guest",
+    "hyperlight-common",
+];
+
+/// A release of cargo-hyperlight and the versions it supports.
+struct Release {
+    version: &'static str,
+    /// Supported versions of the hyperlight guest crates
+    hyperlight: &'static str,
+    /// Supported versions of rustc, ignoring pre-release tags like `-nightly`
+    rustc: &'static str,
+}
+
+/// Every release of cargo-hyperlight, newest first.
+///
+/// Add a new entry, or update the one of the current version, whenever the target
+/// spec or the way the guest crates are built changes.
+const RELEASES: &[Release] = &[Release {
+    version: "0.1.0",
+    hyperlight: ">=0.9.0, <0.18.0",
+    rustc: ">=1.89.0",
+}];
+
+/// Returns the entry of the running version of cargo-hyperlight in [`RELEASES`].
+fn current() -> &'static Release {
+    RELEASES
+        .iter()
+        .find(|release| release.version == env!("CARGO_PKG_VERSION"))
+        .expect("the current version of cargo-hyperlight is missing from RELEASES")
+}
+
+fn req(req: &str) -> VersionReq {
+    VersionReq::parse(req).unwrap()
+}
+
+/// Returns the newest release other than the current one for which `supports` holds.
+fn find_release(supports: impl Fn(&Release) -> bool) -> Option<&'static Release> {
+    RELEASES
+        .iter()
+        .filter(|release| release.version != env!("CARGO_PKG_VERSION"))
+        .find(|release| supports(release))
+}
+
+/// Describes why `version` of `name` isn't supported by `release`, and how to fix it.
+fn crate_problem(release: &Release, name: &str, version: &Version) -> String {
+    let supported = &release.hyperlight;
+    let fix = match find_release(|other| req(other.hyperlight).matches(version)) {
+        Some(other) => format!(
+            "install cargo-hyperlight {0} with `cargo install cargo-hyperlight@{0}`",
+            other.version
+        ),
+        None if newer_than(version, supported) => {
+            "update cargo-hyperlight with `cargo install cargo-hyperlight`".to_string()
+        }
+        None => format!(
+            "update the hyperlight crates to a version matching `{supported}`, \
+            e.g., with `cargo hyperlight add --version <version>`"
+        ),
+    };
+    format!(
+        "{name} {version} is not supported by cargo-hyperlight {}, which supports `{supported}`\n  \
+        to fix it, {fix}",
+        release.version
+    )
+}
+
+/// Describes why `version` of rustc isn't supported by `release`, and how to fix it.
+fn rustc_problem(release: &Release, version: &Version) -> String {
+    let supported = &release.rustc;
+    let fix = match find_release(|other| req(other.rustc).matches(version)) {
+        Some(other) => format!(
+            "install cargo-hyperlight {0} with `cargo install cargo-hyperlight@{0}`",
+            other.version
+        ),
+        None if newer_than(version, supported) => {
+            "update cargo-hyperlight with `cargo install cargo-hyperlight`".to_string()
+        }
+        None => "update your toolchain with `rustup update`".to_string(),
+    };
+    format!(
+        "rustc {version} is not supported by cargo-hyperlight {}, which supports `{supported}`\n  \
+        to fix it, {fix}",
+        release.version
+    )
+}
+
+/// Returns whether `version` is newer than every version matching `req`, assuming
+/// that `req` is a range with a lower bound.
+fn newer_than(version: &Version, req: &str) -> bool {
+    let lower = req.split(',').next().unwrap_or_default();
+    VersionReq::parse(lower).is_ok_and(|lower| lower.matches(version))
+}
+
+/// Returns the version of rustc, without any pre-release tag.
+fn rustc_version(args: &Args) -> Result<Version> {
+    let output = toolchain::rustc_version_verbose(&args.env)?;
+    let re = Regex::new(r"(?m)^release: (\d+)\.(\d+)\.(\d+)").unwrap();
+    let caps = re
+        .captures(&output)
+        .context("Failed to find the release in the rustc version")?;
+    Ok(Version::new(
+        caps[1].parse()?,
+        caps[2].parse()?,
+        caps[3].parse()?,
+    ))
+}
+
+/// Returns the resolved versions of the hyperlight crates in `metadata`.
+fn resolved_versions(metadata: &Value) -> BTreeSet<(String, Version)> {
+    metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|pkg| CRATES.iter().any(|name| pkg["name"] == *name))
+        .filter_map(|pkg| {
+            let name = pkg["name"].as_str()?.to_string();
+            let version = Version::parse(pkg["version"].as_str()?).ok()?;
+            Some((name, version))
+        })
+        .collect()
+}
+
+/// Returns the problems of `versions` of the hyperlight crates and `rustc` against
+/// `release`.
+fn problems(
+    release: &Release,
+    versions: &BTreeSet<(String, Version)>,
+    rustc: &Version,
+) -> Vec<String> {
+    let mut problems = versions
+        .iter()
+        .filter(|(_, version)| !req(release.hyperlight).matches(version))
+        .map(|(name, version)| crate_problem(release, name, version))
+        .collect::<Vec<_>>();
+    if !req(release.rustc).matches(rustc) {
+        problems.push(rustc_problem(release, rustc));
+    }
+    problems
+}
+
+/// Checks that the resolved hyperlight crates and rustc are supported by this version
+/// of cargo-hyperlight, according to [`RELEASES`].
+///
+/// Unsupported versions fail in confusing ways, e.g., the target spec failing to
+/// parse, so this fails early with how to fix it instead. With
+/// `--allow-unsupported`, the problems are only warnings.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn check(args: &Args) -> Result<()> {
+    let metadata = metadata::cargo_metadata(args, &[])?;
+    let versions = resolved_versions(&metadata);
+    let rustc = rustc_version(args)?;
+
+    let problems = problems(current(), &versions, &rustc);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    if args.allow_unsupported {
+        for problem in problems {
+            warning("unsupported-version", problem);
+        }
+        return Ok(());
+    }
+
+    bail!(
+        "Unsupported versions found:\n{}\n\
+        Pass --allow-unsupported to build anyway",
+        problems.join("\n")
+    );
+}
+
+/// Returns the problem with running cargo-hyperlight `version` against a workspace
+/// that declares `required-version = required`, if any.
+fn required_version_problem(required: &str, version: &Version) -> Result<Option<String>> {
+    let required = VersionReq::parse(required).with_context(|| {
+        format!("Invalid `required-version` in the hyperlight metadata: {required:?}")
+    })?;
+    if required.matches(version) {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "this workspace requires cargo-hyperlight {required}, but this is cargo-hyperlight \
+        {version}; install a matching version with `cargo install cargo-hyperlight --locked \
+        --version '{required}'`"
+    )))
+}
+
+/// Checks that the running cargo-hyperlight matches the `required-version` of the
+/// workspace, so that everyone working on it builds guests the same way.
+/// With `--allow-unsupported`, a mismatch is only a warning.
+pub fn check_required_version(required: Option<&str>, allow_unsupported: bool) -> Result<()> {
+    let Some(required) = required else {
+        return Ok(());
+    };
+    let version = Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+    let Some(problem) = required_version_problem(required, &version)? else {
+        return Ok(());
+    };
+    if allow_unsupported {
+        warning("required-version", problem);
+        return Ok(());
+    }
+    bail!(
+        "Unsupported cargo-hyperlight version: {problem}\nPass --allow-unsupported to build anyway"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const RELEASE: Release = Release {
+        version: "0.1.0",
+        hyperlight: ">=0.9.0, <0.18.0",
+        rustc: ">=1.89.0",
+    };
+
+    #[test]
+    fn releases_are_valid() {
+        current();
+        for release in RELEASES {
+            Version::parse(release.version).unwrap();
+            req(release.hyperlight);
+            req(release.rustc);
+        }
+    }
+
+    #[test]
+    fn resolved_versions_of_hyperlight_crates() {
+        let metadata = json!({
+            "packages": [
+                { "name": "hyperlight-guest-bin", "version": "0.9.0" },
+                { "name": "hyperlight-common", "version": "0.9.0" },
+                { "name": "hyperlight-host", "version": "0.9.0" },
+                { "name": "spin", "version": "0.10.0" },
+            ],
+        });
+        assert_eq!(
+            resolved_versions(&metadata),
+            BTreeSet::from([
+                ("hyperlight-common".to_string(), Version::new(0, 9, 0)),
+                ("hyperlight-guest-bin".to_string(), Version::new(0, 9, 0)),
+            ])
+        );
+    }
+
+    #[test]
+    fn problems_suggest_fixes() {
+        let rustc = Version::new(1, 90, 0);
+        let versions = BTreeSet::from([("hyperlight-guest".to_string(), Version::new(0, 9, 1))]);
+        assert!(problems(&RELEASE, &versions, &rustc).is_empty());
+
+        let versions = BTreeSet::from([
+            ("hyperlight-guest".to_string(), Version::new(0, 8, 0)),
+            ("hyperlight-guest-bin".to_string(), Version::new(0, 18, 0)),
+        ]);
+        let problems = problems(&RELEASE, &versions, &Version::new(1, 85, 0));
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].starts_with("hyperlight-guest 0.8.0 is not supported"));
+        assert!(problems[0].contains("cargo hyperlight add"));
+        assert!(problems[1].starts_with("hyperlight-guest-bin 0.18.0 is not supported"));
+        assert!(problems[1].contains("cargo install cargo-hyperlight`"));
+        assert!(problems[2].starts_with("rustc 1.85.0 is not supported"));
+        assert!(problems[2].contains("rustup update"));
+    }
+
+    #[test]
+    fn required_version_is_checked() {
+        let version = Version::parse("0.3.1").unwrap();
+        assert_eq!(required_version_problem(">=0.3", &version).unwrap(), None);
+        assert_eq!(
+            required_version_problem(">=0.4", &version)
+                .unwrap()
+                .unwrap(),
+            "this workspace requires cargo-hyperlight >=0.4, but this is cargo-hyperlight \
+            0.3.1; install a matching version with `cargo install cargo-hyperlight --locked \
+            --version '>=0.4'`"
+        );
+        assert!(required_version_problem("not a version", &version).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::io::{Read as _, Write as _};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use flate2::GzBuilder;
+use flate2::read::GzDecoder;
+use ruzstd::decoding::StreamingDecoder;
+use ruzstd::encoding::CompressionLevel;
+
+use crate::atomic;
+use crate::report::fnv1a;
+
+/// Identifier of the gzip extra field with the size and hash of the guest binary.
+const EXTRA_ID: [u8; 2] = *b"HL";
+
+/// Magic number of the gzip format.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Magic number of the zstd skippable frame with the size and hash of the guest binary,
+/// which zstd decoders skip. The frame holds the same field as the gzip header.
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D2A5A;
+
+/// Size and FNV-1a hash of a guest binary, recorded when it's compressed.
+type Recorded = (u64, u64);
+
+/// Compression of the guest binaries exported with `--out-dir`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// A gzip file, with the uncompressed size and hash in its header
+    Gzip,
+    /// A zstd file, with the uncompressed size and hash in a skippable frame before
+    /// the compressed one
+    Zstd,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+/// Returns the gzip extra field with the size and FNV-1a hash of `data`.
+fn extra(data: &[u8]) -> Vec<u8> {
+    let mut extra = EXTRA_ID.to_vec();
+    extra.extend(16u16.to_le_bytes());
+    extra.extend((data.len() as u64).to_le_bytes());
+    extra.extend(fnv1a(data).to_le_bytes());
+    extra
+}
+
+/// Returns the size and hash recorded in the gzip extra field `extra`, if any.
+fn parse_extra(mut extra: &[u8]) -> Option<Recorded> {
+    while let [si1, si2, len0, len1, rest @ ..] = extra {
+        let len = u16::from_le_bytes([*len0, *len1]) as usize;
+        let data = rest.get(..len)?;
+        if [*si1, *si2] == EXTRA_ID && len == 16 {
+            let size = u64::from_le_bytes(data[..8].try_into().unwrap());
+            let hash = u64::from_le_bytes(data[8..].try_into().unwrap());
+            return Some((size, hash));
+        }
+        extra = &rest[len..];
+    }
+    None
+}
+
+/// Compresses the guest binary `data` with `compression`.
+fn compress(data: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = GzBuilder::new()
+                .extra(extra(data))
+                .write(vec![], flate2::Compression::best());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::Zstd => {
+            let extra = extra(data);
+            let mut compressed = ZSTD_SKIPPABLE_MAGIC.to_le_bytes().to_vec();
+            compressed.extend((extra.len() as u32).to_le_bytes());
+            compressed.extend(extra);
+            // the pure Rust encoder only implements zstd's fastest level
+            ruzstd::encoding::compress(data, &mut compressed, CompressionLevel::Fastest);
+            Ok(compressed)
+        }
+    }
+}
+
+/// Writes a copy of the exported guest binary at `path` compressed with `compression`,
+/// next to it, returning the path of the copy.
+pub fn write(path: &Path, compression: Compression) -> Result<PathBuf> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let mut dst = path.as_os_str().to_owned();
+    dst.push(".");
+    dst.push(compression.extension());
+    let dst = PathBuf::from(dst);
+    let compressed =
+        compress(&data, compression).with_context(|| format!("Failed to compress {path:?}"))?;
+    atomic::write(&dst, compressed).with_context(|| format!("Failed to write {dst:?}"))?;
+    Ok(dst)
+}
+
+/// Decompresses a guest binary exported with `--compress gzip` or `--compress zstd`,
+/// checking its size and hash against the ones recorded when it was compressed.
+///
+/// The compressed files are regular gzip or zstd files, so they can be decompressed
+/// with any tool as well, this only adds the integrity check.
+///
+/// # Examples
+///
+/// ```no_run
+/// let compressed = std::fs::read("guests/guest.gz").unwrap();
+/// let guest = cargo_hyperlight::decompress(&compressed).unwrap();
+/// ```
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let (guest, recorded) = if data.starts_with(&GZIP_MAGIC) {
+        decompress_gzip(data)?
+    } else {
+        decompress_zstd(data)?
+    };
+    let Some((size, hash)) = recorded else {
+        bail!("The compressed guest binary doesn't record its size and hash");
+    };
+    ensure!(
+        guest.len() as u64 == size && fnv1a(&guest) == hash,
+        "The decompressed guest binary doesn't match the size and hash it was compressed with"
+    );
+    Ok(guest)
+}
+
+/// Decompresses a gzip file, returning the guest binary and the size and hash recorded
+/// in its header.
+fn decompress_gzip(data: &[u8]) -> Result<(Vec<u8>, Option<Recorded>)> {
+    let mut decoder = GzDecoder::new(data);
+    let mut guest = vec![];
+    decoder
+        .read_to_end(&mut guest)
+        .context("Failed to decompress guest binary")?;
+    let header = decoder.header().context("Missing gzip header")?;
+    Ok((guest, header.extra().and_then(parse_extra)))
+}
+
+/// Decompresses a zstd file, returning the guest binary and the size and hash recorded
+/// in its leading skippable frame.
+fn decompress_zstd(data: &[u8]) -> Result<(Vec<u8>, Option<Recorded>)> {
+    let recorded = match data {
+        [m0, m1, m2, m3, l0, l1, l2, l3, rest @ ..]
+            if u32::from_le_bytes([*m0, *m1, *m2, *m3]) == ZSTD_SKIPPABLE_MAGIC =>
+        {
+            let len = u32::from_le_bytes([*l0, *l1, *l2, *l3]) as usize;
+            let frame = rest.get(..len).context("Truncated zstd skippable frame")?;
+            Some((parse_extra(frame), &rest[len..]))
+        }
+        _ => None,
+    };
+    let Some((recorded, frame)) = recorded else {
+        bail!("The guest binary is neither a gzip file nor a zstd file from cargo-hyperlight");
+    };
+    let mut decoder = StreamingDecoder::new(frame).context("Failed to decompress guest binary")?;
+    let mut guest = vec![];
+    decoder
+        .read_to_end(&mut guest)
+        .context("Failed to decompress guest binary")?;
+    Ok((guest, recorded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressed_guests_are_checked() {
+        let guest = b"\x7fELF guest binary".repeat(100);
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut compressed = compress(&guest, compression).unwrap();
+            assert!(compressed.len() < guest.len(), "{compression:?}");
+            assert_eq!(decompress(&compressed).unwrap(), guest, "{compression:?}");
+
+            // corrupt the recorded size, which is part of the header
+            let offset = compressed.windows(2).position(|w| w == EXTRA_ID).unwrap() + 4;
+            compressed[offset] ^= 1;
+            assert!(decompress(&compressed).is_err(), "{compression:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_extra_fields_are_skipped() {
+        let mut extra = b"AB\x01\x00x".to_vec();
+        extra.extend(super::extra(b"guest"));
+        assert_eq!(parse_extra(&extra), Some((5, fnv1a(b"guest"))));
+        assert_eq!(parse_extra(b"AB\x01\x00x"), None);
+    }
+}
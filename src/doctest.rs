@@ -0,0 +1,140 @@
+use std::ffi::OsString;
+
+use anyhow::{Result, bail};
+
+use crate::cli::{Args, note, subcommand};
+
+/// Flags of `cargo test` that select which targets are tested. With any of them,
+/// doctests only run if `--doc` is among them.
+const TARGET_SELECTION: &[&str] = &[
+    "--lib",
+    "--bin",
+    "--bins",
+    "--example",
+    "--examples",
+    "--test",
+    "--tests",
+    "--bench",
+    "--benches",
+    "--all-targets",
+    "--doc",
+];
+
+/// How a cargo command involves doctests, which can't run on the hyperlight target.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Doctests {
+    /// The command doesn't run doctests
+    #[default]
+    None,
+    /// `cargo test` without target selection flags, which would run doctests along
+    /// with the unit and integration tests, so they are skipped
+    Skipped,
+    /// `cargo test --doc`
+    Only,
+}
+
+/// Returns how the cargo arguments `args`, which don't include the flags handled by
+/// cargo-hyperlight, involve doctests.
+pub fn resolve(args: &[OsString]) -> Doctests {
+    if !matches!(subcommand(args).as_deref(), Some("test" | "t")) {
+        return Doctests::None;
+    }
+
+    let args = args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .collect::<Vec<_>>();
+
+    let flag = |arg: &str| {
+        arg.split_once('=')
+            .map_or(arg, |(flag, _)| flag)
+            .to_string()
+    };
+    let selection = args
+        .iter()
+        .map(|arg| flag(arg))
+        .filter(|arg| TARGET_SELECTION.contains(&arg.as_str()))
+        .collect::<Vec<_>>();
+    match selection.iter().any(|arg| arg == "--doc") {
+        true => Doctests::Only,
+        false if selection.is_empty() => Doctests::Skipped,
+        false => Doctests::None,
+    }
+}
+
+/// Adds `--tests` to the cargo arguments `args` if doctests would otherwise run,
+/// which keeps the unit and integration tests that `cargo test` runs by default.
+pub fn skip(args: &mut Vec<OsString>) {
+    if resolve(args) != Doctests::Skipped {
+        return;
+    }
+    let index = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    args.insert(index, "--tests".into());
+}
+
+/// Fails with an explanation for `cargo test --doc`, and notes that the doctests are
+/// skipped for a plain `cargo test`.
+pub fn check(args: &Args) -> Result<()> {
+    match args.doctests {
+        Doctests::None => Ok(()),
+        Doctests::Skipped => {
+            note("doctests are skipped, they can't run on the hyperlight target");
+            Ok(())
+        }
+        Doctests::Only => bail!(
+            "Doctests can't run on the hyperlight target, guests only run inside a \
+            hyperlight sandbox\n\
+            Run them for the host with `cargo test --doc` instead of \
+            `cargo hyperlight test --doc`"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn resolve_doctests() {
+        let cases: &[(&[&str], Doctests)] = &[
+            (&["build"], Doctests::None),
+            (&["test"], Doctests::Skipped),
+            (
+                &["t", "-p", "guest", "--", "--nocapture"],
+                Doctests::Skipped,
+            ),
+            (&["+nightly", "test", "--release"], Doctests::Skipped),
+            (&["test", "--lib"], Doctests::None),
+            (&["test", "--test=integration"], Doctests::None),
+            (&["test", "--doc"], Doctests::Only),
+            (&["test", "--lib", "--doc"], Doctests::Only),
+            (&["test", "--", "--doc"], Doctests::Skipped),
+            (&["-Z", "bindeps", "test"], Doctests::Skipped),
+        ];
+        for (cargo, expected) in cases {
+            assert_eq!(resolve(&args(cargo)), *expected, "{cargo:?}");
+        }
+    }
+
+    #[test]
+    fn skip_adds_tests_before_separator() {
+        let mut cargo = args(&["test", "-p", "guest", "--", "--nocapture"]);
+        skip(&mut cargo);
+        assert_eq!(
+            cargo,
+            args(&["test", "-p", "guest", "--tests", "--", "--nocapture"])
+        );
+
+        let mut cargo = args(&["test", "--lib"]);
+        skip(&mut cargo);
+        assert_eq!(cargo, args(&["test", "--lib"]));
+    }
+}
@@ -0,0 +1,76 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::cli::note;
+use crate::command::Command;
+use crate::toolchain;
+
+/// Returns the cargo arguments that print the macro expanded source of a crate with
+/// `rustc -Zunpretty=expanded`, like `cargo expand` does.
+fn rustc_args(args: &[OsString]) -> Vec<OsString> {
+    let mut rustc_args = vec!["rustc".into(), "--profile=check".into()];
+    rustc_args.extend(args.iter().cloned());
+    if !args.iter().any(|arg| arg == "--") {
+        rustc_args.push("--".into());
+    }
+    rustc_args.push("-Zunpretty=expanded".into());
+    rustc_args
+}
+
+/// Prints the macro expanded source of a guest crate, built for the hyperlight target
+/// with its sysroot, through `cargo expand` if it's installed.
+///
+/// Without `cargo expand`, `cargo rustc` prints it with `-Zunpretty=expanded`, which
+/// is an unstable rustc flag, so `RUSTC_BOOTSTRAP` is set like `cargo expand` does.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+pub fn expand(command: &Command, args: &[OsString]) -> Result<()> {
+    let build_args = command.build_args()?;
+    let cargo_expand = toolchain::find_program(
+        Path::new("cargo-expand"),
+        &build_args.current_dir,
+        &build_args.env,
+    );
+
+    if cargo_expand.is_some() {
+        let mut expand_args = vec![OsString::from("expand")];
+        expand_args.extend(args.iter().cloned());
+        return command.with_cargo_args(expand_args).cargo_status();
+    }
+
+    note("cargo-expand is not installed, using `cargo rustc -- -Zunpretty=expanded`");
+    command
+        .with_cargo_args(rustc_args(args))
+        .env("RUSTC_BOOTSTRAP", "1")
+        .cargo_status()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpretty_goes_to_rustc() {
+        assert_eq!(
+            rustc_args(&["--lib".into()]),
+            [
+                "rustc",
+                "--profile=check",
+                "--lib",
+                "--",
+                "-Zunpretty=expanded"
+            ]
+        );
+        assert_eq!(
+            rustc_args(&["--".into(), "-Dwarnings".into()]),
+            [
+                "rustc",
+                "--profile=check",
+                "--",
+                "-Dwarnings",
+                "-Zunpretty=expanded"
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::process::{Command, ExitStatus};
+
+use anyhow::{Result, anyhow, bail};
+
+/// The kind of failure of a cargo-hyperlight invocation, which determines its exit
+/// code, so that scripts and CI can tell them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Failure {
+    /// Invalid arguments or configuration, e.g., an unknown flag, a malformed
+    /// `[package.metadata.hyperlight]` table, or an unsupported target
+    Config,
+    /// A required tool is missing or unsupported, e.g., clang, ar, or the rust-src
+    /// component
+    Toolchain,
+    /// The sysroot failed to build
+    Sysroot,
+    /// Cargo failed, with its exit code
+    Cargo(i32),
+    /// A check of the guests failed, e.g., `check-features`, `check-interface`, or
+    /// the relocation check of `--pie`
+    Validation,
+}
+
+impl Failure {
+    /// Exit code of any other failure, the same cargo uses for its own errors.
+    pub const OTHER_EXIT_CODE: i32 = 101;
+
+    /// Returns the exit code cargo-hyperlight exits with on this failure.
+    ///
+    /// | Failure      | Exit code   |
+    /// |--------------|-------------|
+    /// | `Config`     | 2           |
+    /// | `Toolchain`  | 3           |
+    /// | `Sysroot`    | 4           |
+    /// | `Validation` | 5           |
+    /// | `Cargo`      | cargo's own |
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Config => 2,
+            Failure::Toolchain => 3,
+            Failure::Sysroot => 4,
+            Failure::Validation => 5,
+            Failure::Cargo(code) => code,
+        }
+    }
+
+    /// Returns the kind of failure of `err`, if known.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use cargo_hyperlight::{Failure, cargo};
+    ///
+    /// if let Err(err) = cargo().unwrap().arg("build").status() {
+    ///     if Failure::of(&err) == Some(Failure::Toolchain) {
+    ///         eprintln!("install clang and try again");
+    ///     }
+    /// }
+    /// ```
+    pub fn of(err: &anyhow::Error) -> Option<Failure> {
+        err.downcast_ref::<Marked>().map(|marked| marked.failure)
+    }
+
+    /// Returns the exit code for `err`, [`Failure::OTHER_EXIT_CODE`] if its kind of
+    /// failure isn't known.
+    pub fn exit_code_of(err: &anyhow::Error) -> i32 {
+        Failure::of(err).map_or(Self::OTHER_EXIT_CODE, Failure::exit_code)
+    }
+}
+
+/// An error marked with its kind of failure, which displays exactly like the error.
+struct Marked {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Debug for Marked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Marked {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub(crate) trait WithFailure<T> {
+    /// Marks the error with the kind of `failure`, unless it's already marked with a
+    /// more specific one.
+    fn failure(self, failure: Failure) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithFailure<T> for Result<T, E> {
+    fn failure(self, failure: Failure) -> Result<T> {
+        self.map_err(|error| {
+            let error = error.into();
+            if Failure::of(&error).is_some() {
+                return error;
+            }
+            anyhow::Error::new(Marked { failure, error })
+        })
+    }
+}
+
+/// Checks the exit `status` of the cargo `command` run for the user, passing its exit
+/// code through on failure.
+pub(crate) fn check_cargo(status: ExitStatus, command: &Command) -> Result<()> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(anyhow!("Command exited with code {code}:\n{command:?}"))
+            .failure(Failure::Cargo(code)),
+        None => bail!("Command terminated by signal:\n{command:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+
+    use super::*;
+
+    #[test]
+    fn innermost_failure_wins_through_contexts() {
+        let err = Err::<(), _>(anyhow!("Could not find 'clang' in PATH"))
+            .failure(Failure::Toolchain)
+            .context("Failed to build libm")
+            .failure(Failure::Sysroot)
+            .context("Failed to prepare sysroot")
+            .unwrap_err();
+        assert_eq!(Failure::of(&err), Some(Failure::Toolchain));
+        assert_eq!(Failure::exit_code_of(&err), 3);
+        assert_eq!(
+            format!("{err:#}"),
+            "Failed to prepare sysroot: Failed to build libm: Could not find 'clang' in PATH"
+        );
+    }
+
+    #[test]
+    fn cargo_exit_codes_pass_through() {
+        let err = Err::<(), _>(anyhow!("Command exited with code 7"))
+            .failure(Failure::Cargo(7))
+            .context("Failed to execute cargo")
+            .unwrap_err();
+        assert_eq!(Failure::exit_code_of(&err), 7);
+        assert_eq!(Failure::exit_code_of(&anyhow!("unknown")), 101);
+    }
+}
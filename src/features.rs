@@ -0,0 +1,220 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result, bail};
+
+use crate::cargo_cmd::{CargoCmd as _, cargo_cmd};
+use crate::cli::{Args, note};
+use crate::{CargoCommandExt as _, metadata};
+
+/// Features that pull in `std`, which is not available in hyperlight guests.
+const STD_FEATURES: &[&str] = &["std"];
+
+/// Features that pull in `alloc`, which needs a global allocator in the guest.
+const ALLOC_FEATURES: &[&str] = &["alloc"];
+
+/// Returns the workspace members that are hyperlight guests.
+pub fn guest_packages(args: &Args) -> Result<Vec<String>> {
+    packages_with_role(args, "guest")
+}
+
+/// Returns the workspace members that are hyperlight hosts.
+pub fn host_packages(args: &Args) -> Result<Vec<String>> {
+    packages_with_role(args, "host")
+}
+
+/// Returns the workspace members with the `role` of [`metadata::cargo_metadata`].
+fn packages_with_role(args: &Args, role: &str) -> Result<Vec<String>> {
+    let metadata = metadata::cargo_metadata(args, &["--no-deps".into()])?;
+
+    let members = metadata["workspace_members"]
+        .as_array()
+        .context("Failed to parse cargo metadata workspace members")?;
+
+    let mut packages = vec![];
+    for pkg in metadata["packages"].as_array().into_iter().flatten() {
+        let id = &pkg["id"];
+        if members.contains(id)
+            && metadata["hyperlight"]["packages"][id.as_str().unwrap_or_default()]["role"] == role
+        {
+            packages.push(pkg["name"].as_str().unwrap_or_default().to_string());
+        }
+    }
+    Ok(packages)
+}
+
+/// Returns, for each of the `guests`, the packages in its dependency graph that have
+/// any of the `features` enabled.
+///
+/// The dependency graph of the whole workspace is resolved at once, as cargo does
+/// when building several members together, so that features enabled by host members
+/// through feature unification are visible.
+fn feature_leaks(
+    args: &Args,
+    guests: &[String],
+    features: &[&str],
+) -> Result<BTreeMap<String, BTreeSet<String>>> {
+    let output = cargo_cmd(&args.env)?
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .populate_from_args(args)
+        .arg("tree")
+        .manifest_path(&args.manifest_path)
+        .arg("--workspace")
+        .arg("--edges=normal")
+        .arg("--prefix=depth")
+        .arg("--no-dedupe")
+        .arg("--format={p}|{f}")
+        .checked_output()
+        .context("Failed to get the dependency tree of the workspace")?;
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    Ok(parse_leaks(&output, guests, features))
+}
+
+/// Parses the output of `cargo tree --prefix=depth --format={p}|{f}`, see [`feature_leaks`].
+fn parse_leaks(
+    output: &str,
+    guests: &[String],
+    features: &[&str],
+) -> BTreeMap<String, BTreeSet<String>> {
+    let mut leaks = BTreeMap::<String, BTreeSet<String>>::new();
+    let mut root = None;
+    // depth of the proc-macro whose subtree is being skipped, as it's built for the host
+    let mut host_depth = None;
+
+    for line in output.lines() {
+        let Some(start) = line.find(|c: char| !c.is_ascii_digit()) else {
+            continue;
+        };
+        let Ok(depth) = line[..start].parse::<usize>() else {
+            continue;
+        };
+        let Some((pkg, enabled)) = line[start..].split_once('|') else {
+            continue;
+        };
+
+        if depth == 0 {
+            let name = pkg.split(' ').next().unwrap_or(pkg);
+            root = guests
+                .iter()
+                .any(|guest| guest == name)
+                .then(|| name.to_string());
+            host_depth = None;
+        }
+        match host_depth {
+            Some(host) if depth > host => continue,
+            _ => host_depth = None,
+        }
+        if pkg.contains("(proc-macro)") {
+            host_depth = Some(depth);
+            continue;
+        }
+        let Some(root) = &root else {
+            continue;
+        };
+
+        if enabled
+            .split(',')
+            .any(|feature| features.contains(&feature))
+        {
+            // strip the " (*)" and path markers
+            let pkg = pkg.split(" (").next().unwrap_or(pkg).to_string();
+            leaks.entry(root.clone()).or_default().insert(pkg);
+        }
+    }
+    leaks
+}
+
+/// Checks that no `std` feature (and with `deny_alloc`, no `alloc` feature) is enabled
+/// in the dependency graph of the guest `packages`, or of all the guests in the
+/// workspace if `packages` is empty.
+///
+/// When the same crate is used by host and guest packages, cargo's feature unification
+/// can enable `std` in the guest build, which then fails in confusing ways.
+pub fn check(args: &Args, packages: &[String], deny_alloc: bool) -> Result<()> {
+    let packages = match packages {
+        [] => guest_packages(args)?,
+        packages => packages.to_vec(),
+    };
+
+    if packages.is_empty() {
+        bail!("No hyperlight guest packages found in the workspace");
+    }
+
+    let mut features = STD_FEATURES.to_vec();
+    if deny_alloc {
+        features.extend(ALLOC_FEATURES);
+    }
+    let leaks = feature_leaks(args, &packages, &features)?;
+
+    for package in &packages {
+        let Some(leaks) = leaks.get(package) else {
+            note(format!(
+                "no {} features enabled for guest {package}",
+                features.join("/")
+            ));
+            continue;
+        };
+
+        let list = leaks
+            .iter()
+            .map(|pkg| format!(" * {pkg}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        eprintln!(
+            "guest {package} enables the {} features of:\n{list}",
+            features.join("/")
+        );
+    }
+
+    if !leaks.is_empty() {
+        bail!(
+            "{} features are enabled in guest builds\n\
+            To find out which crate enables them, run:\n    \
+            cargo hyperlight tree --workspace -e features -i <crate>\n\
+            Common fixes are:\n \
+            * depending on the crate with `default-features = false`\n \
+            * building guests separately from host packages with `-p <guest>`\n \
+            * resolving features per package with nightly cargo's `-Zfeature-unification=package`\n \
+            * moving guests to their own workspace",
+            features.join("/")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TREE: &str = "\
+0guest v0.1.0 (/ws/guest)|
+1shared v0.1.0 (/ws/shared)|default,std
+2serde v1.0.0|alloc,std
+1derive v0.1.0 (proc-macro)|
+2syn v2.0.0|default,std
+0host v0.1.0 (/ws/host)|
+1shared v0.1.0 (/ws/shared)|default,std
+1log v0.4.0|std
+";
+
+    #[test]
+    fn parse_leaks_only_reports_guest_dependencies() {
+        let guests = vec!["guest".to_string()];
+        let leaks = parse_leaks(TREE, &guests, STD_FEATURES);
+        assert_eq!(leaks.len(), 1);
+        assert_eq!(
+            leaks["guest"].iter().collect::<Vec<_>>(),
+            ["serde v1.0.0", "shared v0.1.0"]
+        );
+    }
+
+    #[test]
+    fn parse_leaks_reports_alloc_when_requested() {
+        let guests = vec!["guest".to_string()];
+        let leaks = parse_leaks(TREE, &guests, ALLOC_FEATURES);
+        assert_eq!(leaks["guest"].iter().collect::<Vec<_>>(), ["serde v1.0.0"]);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Args;
+use crate::code_model::Models;
+use crate::{atomic, metadata, toolchain};
+
+/// Name of the fingerprint file in the sysroot directory.
+pub const FILE_NAME: &str = "fingerprint.json";
+
+/// Everything a prepared sysroot depends on, stored in the sysroot so that a prebuilt
+/// sysroot can be checked before it's used.
+///
+/// Unlike [`Args::fingerprint`], this is stable across runs and versions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Fingerprint {
+    /// Version of cargo-hyperlight that prepared the sysroot
+    pub cargo_hyperlight: String,
+    pub target: String,
+    /// Hash of the target spec given with `--target path/to/spec.json`
+    #[serde(default)]
+    pub custom_target: Option<String>,
+    /// `release` and `commit-hash` of `rustc -vV`
+    pub rustc: String,
+    /// Version of hyperlight-guest-bin the C headers come from
+    pub hyperlight_guest_bin: Option<String>,
+    /// Where the sysroot was prepared, which the C compiler wrapper refers to
+    pub sysroot: PathBuf,
+    pub clang: Option<PathBuf>,
+    pub ar: Option<PathBuf>,
+    pub linker: Option<PathBuf>,
+    #[serde(default)]
+    pub compiler_launcher: Option<PathBuf>,
+    pub libm: bool,
+    pub cross_language_lto: bool,
+    #[serde(default)]
+    pub cached_c_archive: bool,
+    pub emit_map: bool,
+    /// Code, relocation and TLS models written into the target spec
+    #[serde(default)]
+    pub models: Models,
+    /// C flags written into the C compiler wrapper
+    pub cflags: Vec<String>,
+    pub dep_cflags: BTreeMap<String, Vec<String>>,
+    /// Hash of every static library copied into the sysroot, by file name
+    pub static_libs: BTreeMap<String, String>,
+}
+
+impl Fingerprint {
+    /// Returns the fingerprint of the sysroot `args` would prepare.
+    pub fn of(args: &Args) -> Result<Self> {
+        let rustc = toolchain::rustc_version_verbose(&args.env)?;
+        let rustc = rustc
+            .lines()
+            .filter(|line| line.starts_with("release: ") || line.starts_with("commit-hash: "))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let metadata = metadata::cargo_metadata(args, &[])?;
+        let hyperlight_guest_bin = metadata["packages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|pkg| pkg["name"] == "hyperlight-guest-bin")
+            .and_then(|pkg| pkg["version"].as_str())
+            .map(str::to_string);
+
+        let mut static_libs = BTreeMap::new();
+        for lib in &args.static_libs {
+            let name = lib.file_name().unwrap_or_default().to_string_lossy();
+            static_libs.insert(name.into_owned(), toolchain::hash_file(lib)?);
+        }
+
+        let custom_target = match &args.custom_target {
+            Some(path) => Some(toolchain::hash_file(path)?),
+            None => None,
+        };
+
+        Ok(Self {
+            cargo_hyperlight: env!("CARGO_PKG_VERSION").to_string(),
+            target: args.target.clone(),
+            custom_target,
+            rustc,
+            hyperlight_guest_bin,
+            sysroot: args.sysroot_dir(),
+            clang: args.clang.clone(),
+            ar: args.ar.clone(),
+            linker: args.linker.clone(),
+            compiler_launcher: args.compiler_launcher.clone(),
+            libm: args.libm,
+            cross_language_lto: args.cross_language_lto,
+            cached_c_archive: args.cached_c_archive,
+            emit_map: args.emit_map,
+            models: args.models,
+            cflags: toolchain::cflags_list(args)
+                .iter()
+                .chain(&args.cflags)
+                .map(|flag| flag.to_string_lossy().into_owned())
+                .collect(),
+            dep_cflags: args.dep_cflags.clone(),
+            static_libs,
+        })
+    }
+
+    /// Reads the fingerprint of the sysroot in `sysroot_dir`.
+    pub fn read(sysroot_dir: &Path) -> Result<Self> {
+        let path = sysroot_dir.join(FILE_NAME);
+        let content = std::fs::read(&path)
+            .with_context(|| format!("Failed to read sysroot fingerprint {path:?}"))?;
+        serde_json::from_slice(&content)
+            .with_context(|| format!("Failed to parse sysroot fingerprint {path:?}"))
+    }
+
+    /// Updates the sysroot path, and the C flags that refer to it, for a sysroot that
+    /// was moved to `sysroot_dir`.
+    pub fn relocate(&mut self, sysroot_dir: &Path) {
+        for flag in &mut self.cflags {
+            if let Ok(rest) = Path::new(flag.as_str()).strip_prefix(&self.sysroot) {
+                *flag = sysroot_dir.join(rest).to_string_lossy().into_owned();
+            }
+        }
+        self.sysroot = sysroot_dir.to_path_buf();
+    }
+
+    /// Returns a description of every field that differs between `self`, the expected
+    /// fingerprint, and `found`.
+    pub fn diff(&self, found: &Self) -> Vec<String> {
+        let expected = serde_json::to_value(self).unwrap();
+        let found = serde_json::to_value(found).unwrap();
+        let (Some(expected), Some(found)) = (expected.as_object(), found.as_object()) else {
+            return vec![];
+        };
+        expected
+            .iter()
+            .filter(|(key, value)| found.get(*key) != Some(value))
+            .map(|(key, value)| {
+                let found = found.get(key).unwrap_or(&serde_json::Value::Null);
+                format!("{key}: expected {value}, found {found}")
+            })
+            .collect()
+    }
+}
+
+/// Renders the differences returned by [`Fingerprint::diff`] as a list.
+pub fn list(problems: &[String]) -> String {
+    problems
+        .iter()
+        .map(|problem| format!(" * {problem}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Writes the fingerprint of the sysroot prepared for `args`.
+pub fn write(args: &Args) -> Result<()> {
+    let fingerprint = Fingerprint::of(args)?;
+    atomic::write(
+        args.sysroot_dir().join(FILE_NAME),
+        serde_json::to_vec_pretty(&fingerprint).unwrap(),
+    )
+    .context("Failed to write sysroot fingerprint")
+}
+
+/// Checks that the prebuilt sysroot matches what `args` would prepare, without
+/// writing anything to it.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn check(args: &Args) -> Result<()> {
+    let sysroot_dir = args.sysroot_dir();
+    let found = Fingerprint::read(&sysroot_dir)?;
+    let problems = Fingerprint::of(args)?.diff(&found);
+    if !problems.is_empty() {
+        bail!(
+            "The prebuilt sysroot at {sysroot_dir:?} was prepared for a different build:\n{}\n\
+            Prepare it again with the same options, or build without a prebuilt sysroot",
+            list(&problems)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint() -> Fingerprint {
+        Fingerprint {
+            cargo_hyperlight: "0.1.0".into(),
+            target: "x86_64-hyperlight-none".into(),
+            custom_target: None,
+            rustc: "release: 1.90.0, commit-hash: 0123".into(),
+            hyperlight_guest_bin: Some("0.9.0".into()),
+            sysroot: "/cache/sysroot".into(),
+            clang: Some("/usr/bin/clang".into()),
+            ar: None,
+            linker: None,
+            compiler_launcher: None,
+            libm: false,
+            cross_language_lto: false,
+            cached_c_archive: false,
+            emit_map: false,
+            models: Models::default(),
+            cflags: vec!["-nostdinc".into()],
+            dep_cflags: BTreeMap::new(),
+            static_libs: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let expected = fingerprint();
+        assert!(expected.diff(&expected).is_empty());
+
+        let mut found = fingerprint();
+        found.rustc = "release: 1.89.0, commit-hash: 4567".into();
+        found.libm = true;
+        assert_eq!(
+            expected.diff(&found),
+            [
+                r#"libm: expected false, found true"#,
+                r#"rustc: expected "release: 1.90.0, commit-hash: 0123", found "release: 1.89.0, commit-hash: 4567""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn relocate_updates_include_dirs() {
+        let mut fingerprint = fingerprint();
+        fingerprint.cflags = vec![
+            "-isystem".into(),
+            "/cache/sysroot/lib/rustlib/x86_64-hyperlight-none/include".into(),
+            "-isystem".into(),
+            "/usr/include/foo".into(),
+        ];
+        fingerprint.relocate(Path::new("/work/target/sysroot"));
+        assert_eq!(fingerprint.sysroot, Path::new("/work/target/sysroot"));
+        assert_eq!(
+            fingerprint.cflags,
+            [
+                "-isystem",
+                "/work/target/sysroot/lib/rustlib/x86_64-hyperlight-none/include",
+                "-isystem",
+                "/usr/include/foo",
+            ]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::analyze::symbol_crate;
+use crate::cargo_cmd::CargoCmd as _;
+use crate::cli::Args;
+use crate::failure::{Failure, WithFailure as _};
+use crate::toolchain;
+
+/// Instructions that only save, restore or configure the floating-point state, e.g.,
+/// when switching contexts, without computing anything.
+const STATE_INSTRUCTIONS: &[&str] = &[
+    "fxsave",
+    "fxsave64",
+    "fxrstor",
+    "fxrstor64",
+    "xsave",
+    "xsave64",
+    "xsaveopt",
+    "xsavec",
+    "xsaves",
+    "xrstor",
+    "xrstor64",
+    "xrstors",
+    "fninit",
+    "finit",
+    "fnclex",
+    "fclex",
+    "fldcw",
+    "fnstcw",
+    "fstcw",
+    "fnstsw",
+    "fstsw",
+    "ldmxcsr",
+    "stmxcsr",
+    "fwait",
+    "wait",
+];
+
+/// Floating-point and SIMD instructions in a function.
+#[derive(Default, Debug, PartialEq)]
+struct Usage {
+    count: usize,
+    /// Kinds of instructions, see [`instruction_kind`]
+    kinds: BTreeSet<&'static str>,
+}
+
+/// Returns the kind of floating-point or SIMD instruction of `mnemonic`, in AT&T
+/// syntax, if it's one.
+fn instruction_kind(mnemonic: &str, operands: &str) -> Option<&'static str> {
+    if STATE_INSTRUCTIONS.contains(&mnemonic) {
+        return None;
+    }
+    let mmx = operands
+        .match_indices("%mm")
+        .any(|(i, _)| operands[i + 3..].starts_with(|c: char| c.is_ascii_digit()));
+    if ["%xmm", "%ymm", "%zmm"]
+        .iter()
+        .any(|register| operands.contains(register))
+    {
+        Some("sse/avx")
+    } else if mmx {
+        Some("mmx")
+    } else if mnemonic.starts_with('f') || operands.contains("%st") {
+        Some("x87")
+    } else {
+        None
+    }
+}
+
+/// Returns the floating-point and SIMD usage of each function in the `objdump -d` (or
+/// `llvm-objdump -d`) `output`, by symbol name.
+fn parse_disassembly(output: &str) -> BTreeMap<String, Usage> {
+    let function = Regex::new(r"^[0-9a-f]+ <(.+)>:$").unwrap();
+    let instruction = Regex::new(r"^\s*[0-9a-f]+:\s+(\S+)\s*(.*)$").unwrap();
+
+    let mut usage = BTreeMap::<String, Usage>::new();
+    let mut current = None;
+    for line in output.lines() {
+        if let Some(captures) = function.captures(line) {
+            current = Some(captures[1].to_string());
+            continue;
+        }
+        let (Some(name), Some(captures)) = (&current, instruction.captures(line)) else {
+            continue;
+        };
+        if let Some(kind) = instruction_kind(&captures[1], &captures[2]) {
+            let usage = usage.entry(name.clone()).or_default();
+            usage.count += 1;
+            usage.kinds.insert(kind);
+        }
+    }
+    usage
+}
+
+/// Prints the floating-point and SIMD instructions of the guest binary at `path`, by
+/// crate and by function, failing if there is any and `deny` is set.
+///
+/// The binary is disassembled with `llvm-objdump`, or `objdump` if it's not installed.
+pub fn analyze(args: &Args, path: &Path, deny: bool) -> Result<()> {
+    let objdump = ["llvm-objdump", "objdump"]
+        .into_iter()
+        .find_map(|program| {
+            toolchain::find_program(Path::new(program), &args.current_dir, &args.env)
+        })
+        .context("Could not find 'llvm-objdump' or 'objdump' in PATH")
+        .failure(Failure::Toolchain)?;
+
+    let output = std::process::Command::new(objdump)
+        .env_clear()
+        .envs(args.env.iter())
+        .arg("--disassemble")
+        .arg("--no-show-raw-insn")
+        .arg(path)
+        .checked_output()
+        .with_context(|| format!("Failed to disassemble {path:?}"))?;
+    let usage = parse_disassembly(&String::from_utf8_lossy(&output.stdout));
+
+    let mut by_crate = BTreeMap::<String, usize>::new();
+    for (name, usage) in &usage {
+        *by_crate.entry(symbol_crate(name)).or_default() += usage.count;
+    }
+    let mut by_count = by_crate.iter().collect::<Vec<_>>();
+    by_count.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    println!("Floating-point and SIMD instructions by crate:");
+    if by_count.is_empty() {
+        println!("  (none)");
+    }
+    for (name, count) in by_count {
+        println!("  {count:>10}  {name}");
+    }
+
+    println!("Functions with floating-point or SIMD instructions:");
+    if usage.is_empty() {
+        println!("  (none)");
+    }
+    for (name, usage) in &usage {
+        let kinds = usage.kinds.iter().copied().collect::<Vec<_>>().join(",");
+        let name = rustc_demangle::demangle(name);
+        println!("  {:>10}  {kinds:<12}  {name:#}", usage.count);
+    }
+
+    if deny && !usage.is_empty() {
+        let count = usage.values().map(|usage| usage.count).sum::<usize>();
+        return Err(anyhow::anyhow!(
+            "{count} floating-point or SIMD instructions in {} functions of {}",
+            usage.len(),
+            path.display()
+        ))
+        .context("The guest uses floating-point or SIMD instructions")
+        .failure(Failure::Validation);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instruction_kinds() {
+        assert_eq!(instruction_kind("movaps", "%xmm0, (%rsp)"), Some("sse/avx"));
+        assert_eq!(
+            instruction_kind("vaddpd", "%ymm1, %ymm2, %ymm3"),
+            Some("sse/avx")
+        );
+        assert_eq!(instruction_kind("paddb", "%mm1, %mm0"), Some("mmx"));
+        assert_eq!(instruction_kind("fldt", "8(%rsp)"), Some("x87"));
+        assert_eq!(instruction_kind("faddp", "%st, %st(1)"), Some("x87"));
+        assert_eq!(instruction_kind("fxsave", "(%rdi)"), None);
+        assert_eq!(instruction_kind("ldmxcsr", "(%rsp)"), None);
+        assert_eq!(instruction_kind("movq", "%rax, %rbx"), None);
+        assert_eq!(instruction_kind("lfence", ""), None);
+    }
+
+    #[test]
+    fn disassembly_by_function() {
+        // llvm-objdump separates the operands with a tab, GNU objdump with spaces
+        let output = "\n\
+            guest:\tfile format elf64-x86-64\n\
+            \n\
+            Disassembly of section .text:\n\
+            \n\
+            0000000000001000 <_ZN4libm4sqrt17h0123456789abcdefE>:\n\
+            \x20   1000:      \tsqrtsd\t%xmm0, %xmm0\n\
+            \x20   1004:      \tretq\n\
+            \n\
+            0000000000001010 <memcpy>:\n\
+            \x20   1010:\tmov    %rdi,%rax\n\
+            \x20   1013:\tmovups (%rsi),%xmm0\n\
+            \x20   1016:\tfldt   (%rsi)\n\
+            \x20   1019:\tret\n\
+            \n\
+            0000000000001020 <entrypoint>:\n\
+            \x20   1020:\tfninit\n\
+            \x20   1022:\tret\n";
+        let usage = parse_disassembly(output);
+        assert_eq!(
+            usage.keys().collect::<Vec<_>>(),
+            ["_ZN4libm4sqrt17h0123456789abcdefE", "memcpy"]
+        );
+        assert_eq!(usage["memcpy"].count, 2);
+        assert_eq!(usage["memcpy"].kinds, BTreeSet::from(["sse/avx", "x87"]));
+        assert_eq!(symbol_crate("_ZN4libm4sqrt17h0123456789abcdefE"), "libm");
+    }
+}
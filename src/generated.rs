@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::atomic;
+use crate::cli::Args;
+
+/// Returns a line diff from `old` to `new`, with the removed lines prefixed by `-` and
+/// the added ones by `+`.
+fn diff(old: &str, new: &str) -> String {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // lengths of the longest common subsequences of every pair of suffixes
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = String::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            (i, j) = (i + 1, j + 1);
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Returns why `path` doesn't have the generated `contents`, if it doesn't.
+fn outdated(path: &Path, contents: &[u8]) -> Option<String> {
+    let Ok(current) = std::fs::read(path) else {
+        return Some(format!("{} doesn't exist", path.display()));
+    };
+    if current == contents {
+        return None;
+    }
+    match (std::str::from_utf8(&current), std::str::from_utf8(contents)) {
+        (Ok(current), Ok(contents)) => Some(format!(
+            "{} is out of date:\n{}",
+            path.display(),
+            diff(current, contents)
+        )),
+        _ => Some(format!("{} is out of date", path.display())),
+    }
+}
+
+/// Writes the generated input `contents` of the build to `path`.
+///
+/// With `--frozen`, nothing is written, and `path` must already have the `contents`,
+/// for build systems that forbid writing the inputs of the build.
+pub fn write(args: &Args, path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let contents = contents.as_ref();
+    if !args.frozen {
+        return atomic::write(path, contents);
+    }
+    if let Some(problem) = outdated(path, contents) {
+        bail!("{problem}\n`--frozen` forbids writing the generated inputs of the build");
+    }
+    Ok(())
+}
+
+/// Copies the input `src` of the build to `dst`, like [`write`].
+pub fn copy(args: &Args, src: &Path, dst: &Path) -> Result<()> {
+    if !args.frozen {
+        std::fs::create_dir_all(dst.parent().unwrap())
+            .with_context(|| format!("Failed to create {:?}", dst.parent().unwrap()))?;
+        return atomic::copy(src, dst);
+    }
+    let contents = std::fs::read(src).with_context(|| format!("Failed to read {src:?}"))?;
+    write(args, dst, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_shows_changed_lines() {
+        let old = "{\n  \"arch\": \"x86_64\",\n  \"os\": \"none\"\n}\n";
+        let new =
+            "{\n  \"arch\": \"x86_64\",\n  \"code-model\": \"small\",\n  \"os\": \"none\"\n}\n";
+        assert_eq!(diff(old, new), "+  \"code-model\": \"small\",\n");
+        assert_eq!(diff(new, old), "-  \"code-model\": \"small\",\n");
+        assert_eq!(diff("a\nb\n", "a\nc\n"), "-b\n+c\n");
+        assert_eq!(diff(old, old), "");
+    }
+}
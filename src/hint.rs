@@ -0,0 +1,543 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::cargo_cmd::CargoCmd as _;
+use crate::cli::{Args, help, note};
+use crate::{hypervisor, metadata, sysroot};
+
+/// Items that can only be defined once in a guest, the errors of rustc when they are
+/// defined twice, and the crates known to define them.
+const UNIQUE_ITEMS: &[(&str, &[&str], &[&str])] = &[
+    (
+        "#[panic_handler]",
+        // E0152
+        &["found duplicate lang item `panic_impl`"],
+        &[
+            "hyperlight-guest-bin",
+            "panic-abort",
+            "panic-halt",
+            "panic-never",
+            "panic-probe",
+            "panic-rtt-target",
+            "panic-semihosting",
+        ],
+    ),
+    (
+        "#[global_allocator]",
+        &[
+            "conflicts with global allocator in",
+            "cannot define multiple global allocators",
+        ],
+        &["hyperlight-guest-bin"],
+    ),
+];
+
+/// Errors of cargo (curl) and rustup when a TLS certificate can't be verified.
+const TLS_ERRORS: &[&str] = &[
+    "SSL certificate problem",
+    "certificate verify failed",
+    "invalid peer certificate",
+    "UnknownIssuer",
+];
+
+/// Errors of cc-rs, and of the sysroot build, when clang can't be found.
+const CLANG_ERRORS: &[&str] = &["Could not find 'clang'", "failed to find tool \"clang"];
+
+/// A fix that is safe to apply without asking, for `--hyperlight-fix`.
+enum Fix {
+    /// Run a command
+    Command(Vec<OsString>),
+    /// Rebuild the sysroot from scratch
+    RebuildSysroot,
+}
+
+/// A suggestion on how to fix a failed build.
+pub struct Hint {
+    /// What most likely went wrong
+    problem: String,
+    /// Instructions to fix it
+    fix: String,
+    /// How to fix the problem automatically, if it can be
+    command: Option<Fix>,
+}
+
+impl Hint {
+    /// Returns a hint for the failure `err` of a build with `args` that started at
+    /// `start`, if it's a known one.
+    pub fn find(args: &Args, err: &anyhow::Error, start: SystemTime) -> Option<Hint> {
+        let msg = format!("{err:?}");
+        let toolchain = args
+            .env
+            .get(&OsString::from("RUSTUP_TOOLCHAIN"))
+            .map(|toolchain| toolchain.to_string_lossy().into_owned());
+
+        if msg.contains("rust-src") || msg.contains("library/Cargo.lock") {
+            let mut command = vec!["rustup", "component", "add", "rust-src"];
+            if let Some(toolchain) = &toolchain {
+                command.extend(["--toolchain", toolchain]);
+            }
+            return Some(Hint {
+                problem: "the rust-src component is missing".into(),
+                fix: format!("run `{}`", command.join(" ")),
+                command: Some(Fix::Command(
+                    command.into_iter().map(OsString::from).collect(),
+                )),
+            });
+        }
+
+        if TLS_ERRORS.iter().any(|error| msg.contains(error)) {
+            return Some(Hint {
+                problem: "a download failed because its TLS certificate couldn't be verified, \
+                    e.g., behind a proxy with its own certificate authority"
+                    .into(),
+                fix: "point `CARGO_HTTP_CAINFO` (for cargo) and `SSL_CERT_FILE` (for rustup) \
+                    to the CA bundle, and set `HTTPS_PROXY` if there's a proxy"
+                    .into(),
+                command: None,
+            });
+        }
+
+        if msg.contains("Failed to parse target spec JSON") {
+            return Some(Hint {
+                problem: "this version of cargo-hyperlight doesn't support your Rust toolchain"
+                    .into(),
+                fix: "update cargo-hyperlight with `cargo install cargo-hyperlight`".into(),
+                command: None,
+            });
+        }
+
+        let cargo_failed = msg.contains("Failed to execute cargo");
+        if cargo_failed && let Some(errors) = sysroot::probe(args) {
+            if args.verbose > 0 {
+                note(format!("sysroot check output:\n{errors}"));
+            }
+            return Some(match args.prebuilt_sysroot {
+                Some(_) => Hint {
+                    problem: "the prebuilt sysroot is broken, or was built by another Rust \
+                        toolchain"
+                        .into(),
+                    fix: "prepare or import it again with this toolchain".into(),
+                    command: None,
+                },
+                None => Hint {
+                    problem: "the sysroot is broken, or was built by another Rust toolchain".into(),
+                    fix: "run `cargo hyperlight sysroot clean` and build again".into(),
+                    command: Some(Fix::RebuildSysroot),
+                },
+            });
+        }
+        if cargo_failed && let Some(hint) = link_error(args) {
+            return Some(hint);
+        }
+        if cargo_failed && let Some(conflict) = duplicate_item(args, start) {
+            return Some(conflict);
+        }
+
+        if cargo_failed && args.runs_guests && !args.no_run_fallback && !hypervisor::available() {
+            return Some(Hint {
+                problem: "no hypervisor is available to run the guests, neither KVM nor mshv"
+                    .into(),
+                fix: "make sure virtualization is enabled, and that your user can access \
+                    `/dev/kvm`, e.g., with `sudo usermod -aG kvm $USER`, or pass \
+                    `--no-run-fallback` to only build the guests"
+                    .into(),
+                command: None,
+            });
+        }
+
+        if args.clang.is_none()
+            && (clang_missing(&msg) || clang_missing(&build_script_output(args, start)))
+        {
+            let install = if cfg!(windows) {
+                "winget install LLVM.LLVM"
+            } else if cfg!(target_os = "macos") {
+                "brew install llvm"
+            } else {
+                "sudo apt install clang (or your distribution's equivalent)"
+            };
+            return Some(Hint {
+                problem: "clang was not found, and it's needed to build C dependencies".into(),
+                fix: format!("install it with `{install}`, or add it to your PATH"),
+                command: None,
+            });
+        }
+
+        None
+    }
+
+    /// Prints how to fix the problem.
+    pub fn print(&self, can_fix: bool) {
+        help(format!("{}\n  to fix it, {}", self.problem, self.fix));
+        if self.command.is_some() && !can_fix {
+            help("run again with `--hyperlight-fix` to fix it automatically");
+        }
+    }
+
+    /// Applies the fix, if there's a safe one.
+    /// Returns whether the fix was applied.
+    pub fn apply(&self, args: &Args) -> Result<bool> {
+        let program_args = match &self.command {
+            None => return Ok(false),
+            Some(Fix::RebuildSysroot) => {
+                note("fixing: rebuilding the sysroot from scratch");
+                sysroot::invalidate(args).context("Failed to apply fix")?;
+                return Ok(true);
+            }
+            Some(Fix::Command(command)) => command.as_slice(),
+        };
+        let [program, cmd_args @ ..] = program_args else {
+            return Ok(false);
+        };
+        note(format!("fixing: {}", self.fix));
+        std::process::Command::new(program)
+            .env_clear()
+            .envs(args.env.iter())
+            .args(cmd_args)
+            // stdout belongs to the cargo command being fixed, e.g., for `--message-format=json`
+            .stdout(std::io::stderr())
+            .stderr(Stdio::inherit())
+            .checked_output()
+            .context("Failed to apply fix")?;
+        Ok(true)
+    }
+}
+
+/// Returns whether `output` says that clang couldn't be found.
+fn clang_missing(output: &str) -> bool {
+    CLANG_ERRORS.iter().any(|error| output.contains(error))
+}
+
+/// Returns the entries of the directory `dir`, none if it can't be read.
+fn entries(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect()
+}
+
+/// Returns the per-unit directories of cargo named `name`, e.g., `build`, in every
+/// profile directory of the target directory, with the target triple in between when
+/// cross compiling, e.g., `target/x86_64-hyperlight-none/debug/build/<package>-<hash>`.
+fn unit_dirs(args: &Args, name: &str) -> Vec<PathBuf> {
+    entries(&args.target_dir)
+        .into_iter()
+        .flat_map(|dir| entries(&dir).into_iter().chain([dir]))
+        .flat_map(|profile| entries(&profile.join(name)))
+        .collect()
+}
+
+/// Returns the contents of the `files` modified since `start`.
+fn read_since(files: impl IntoIterator<Item = PathBuf>, start: SystemTime) -> String {
+    files
+        .into_iter()
+        .filter(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified >= start)
+        })
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .collect()
+}
+
+/// Returns the standard error of the build scripts that ran since `start`, which
+/// cargo keeps in `build/<package>-<hash>/stderr`.
+fn build_script_output(args: &Args, start: SystemTime) -> String {
+    let files = unit_dirs(args, "build")
+        .into_iter()
+        .map(|dir| dir.join("stderr"));
+    read_since(files, start)
+}
+
+/// Returns the diagnostics of rustc since `start`, as JSON messages, which cargo keeps
+/// in `.fingerprint/<package>-<hash>/output-<kind>-<name>`, also for failed builds.
+fn rustc_diagnostics(args: &Args, start: SystemTime) -> String {
+    let files = unit_dirs(args, ".fingerprint")
+        .into_iter()
+        .flat_map(|dir| entries(&dir))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("output-"))
+        });
+    read_since(files, start)
+}
+
+/// C memory functions that compiler_builtins provides with its `mem` feature.
+const MEM_FUNCTIONS: &[&str] = &["memcpy", "memmove", "memset", "memcmp", "bcmp", "strlen"];
+
+/// Math functions from libm, also in their `f` and `l` suffixed variants.
+const MATH_FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "asin", "acos", "atan", "atan2", "sinh", "cosh", "tanh", "exp", "exp2",
+    "log", "log2", "log10", "pow", "sqrt", "cbrt", "hypot", "floor", "ceil", "round", "trunc",
+    "fmod", "fmin", "fmax", "frexp", "ldexp", "modf",
+];
+
+/// Reads and clears the output of the links that failed since the last call, and
+/// returns a hint for the first undefined symbol that is a known problem of
+/// freestanding targets. With `-v`, the output of the linker is printed as well.
+fn link_error(args: &Args) -> Option<Hint> {
+    let log = std::fs::read_to_string(args.ld_log_path()).ok()?;
+    let _ = std::fs::write(args.ld_log_path(), "");
+    if log.is_empty() {
+        return None;
+    }
+    if args.verbose > 0 {
+        note(format!("linker output:\n{}", log.trim_end()));
+    }
+    undefined_symbols(&log).into_iter().find_map(symbol_hint)
+}
+
+/// Returns the undefined symbols in the output of a linker, as reported by LLD or GNU ld.
+fn undefined_symbols(log: &str) -> Vec<&str> {
+    let re = Regex::new(r"undefined (?:symbol: |reference to [`'])([\w.$]+)").unwrap();
+    re.captures_iter(log)
+        .filter_map(|captures| Some(captures.get(1)?.as_str()))
+        .collect()
+}
+
+/// Returns a hint for the undefined `symbol`, if it's a known problem.
+fn symbol_hint(symbol: &str) -> Option<Hint> {
+    let math = symbol
+        .strip_suffix(['f', 'l'])
+        .filter(|name| MATH_FUNCTIONS.contains(name))
+        .unwrap_or(symbol);
+
+    let (problem, fix) = if MEM_FUNCTIONS.contains(&symbol) {
+        (
+            format!(
+                "`{symbol}` is provided by the `mem` feature of compiler_builtins, which \
+                the guest was linked without"
+            ),
+            "cargo-hyperlight's sysroot enables it, make sure no `--sysroot` or \
+            `-Zbuild-std` in RUSTFLAGS or the cargo config replaces it"
+                .to_string(),
+        )
+    } else if symbol.starts_with("__stack_chk_") {
+        (
+            format!(
+                "`{symbol}` comes from C code compiled with a stack protector, which \
+                hyperlight guests don't support"
+            ),
+            "add `-fno-stack-protector` to the C flags of the library, or rebuild the \
+            prebuilt static library without a stack protector"
+                .to_string(),
+        )
+    } else if MATH_FUNCTIONS.contains(&math) {
+        (
+            format!("`{symbol}` is a math function from libm, which isn't linked by default"),
+            "pass `--libm`, or set `libm = true` in `[package.metadata.hyperlight]`".to_string(),
+        )
+    } else if symbol.starts_with("__") && (symbol.ends_with("tf2") || symbol.ends_with("tf3")) {
+        (
+            format!(
+                "`{symbol}` is a builtin for 128-bit floating point arithmetic, which \
+                compiler_builtins doesn't provide for hyperlight guests"
+            ),
+            "it usually comes from C code using `long double`, use `double` instead".to_string(),
+        )
+    } else {
+        return None;
+    };
+
+    Some(Hint {
+        problem,
+        fix,
+        command: None,
+    })
+}
+
+/// Returns a hint if rustc failed since `start` because an item in [`UNIQUE_ITEMS`] is
+/// defined twice. Only then is `cargo metadata` run to find the crates defining it.
+fn duplicate_item(args: &Args, start: SystemTime) -> Option<Hint> {
+    let diagnostics = rustc_diagnostics(args, start);
+    let duplicated = UNIQUE_ITEMS
+        .iter()
+        .any(|(_, errors, _)| errors.iter().any(|error| diagnostics.contains(error)));
+    if !duplicated {
+        return None;
+    }
+    let metadata = metadata::cargo_metadata(args, &[]).ok()?;
+    duplicate_items(&metadata, &diagnostics).into_iter().next()
+}
+
+/// Returns a hint for every item in [`UNIQUE_ITEMS`] that rustc reported as defined
+/// twice in `diagnostics`, naming two of the packages in the output of `cargo metadata`
+/// that define it, e.g., a `#[panic_handler]` from two versions of
+/// `hyperlight-guest-bin`.
+fn duplicate_items(metadata: &Value, diagnostics: &str) -> Vec<Hint> {
+    let packages = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|pkg| Some((pkg["name"].as_str()?, pkg["version"].as_str()?)))
+        .collect::<Vec<_>>();
+
+    let mut hints = vec![];
+    for (item, errors, crates) in UNIQUE_ITEMS {
+        if !errors.iter().any(|error| diagnostics.contains(error)) {
+            continue;
+        }
+        let definitions = packages
+            .iter()
+            .filter(|(name, _)| crates.contains(name))
+            .collect::<Vec<_>>();
+        let [(first, first_version), (second, second_version), ..] = definitions[..] else {
+            continue;
+        };
+        let fix = if first == second {
+            format!(
+                "make all the dependencies use the same version of `{first}`, \
+                see `cargo tree -i {first}`"
+            )
+        } else {
+            format!(
+                "remove one of them from the dependencies, see `cargo tree -i {first}` \
+                and `cargo tree -i {second}`"
+            )
+        };
+        hints.push(Hint {
+            problem: format!(
+                "{item} is defined by both `{first} {first_version}` and \
+                `{second} {second_version}`, and a guest can only have one"
+            ),
+            fix,
+            command: None,
+        });
+    }
+    hints
+}
+
+/// Runs `f`, and if it fails with a known problem, prints how to fix it.
+/// With `--hyperlight-fix`, safe fixes are applied and `f` is retried once.
+pub fn with_recovery<T>(args: &Args, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let start = SystemTime::now();
+    let err = match f() {
+        Ok(value) => return Ok(value),
+        Err(err) => err,
+    };
+
+    let Some(hint) = Hint::find(args, &err, start) else {
+        return Err(err);
+    };
+
+    if args.fix && hint.apply(args)? {
+        return f();
+    }
+
+    hint.print(args.fix);
+    Err(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// rustc's errors when two crates define a `#[panic_handler]`, and a
+    /// `#[global_allocator]`.
+    const DUPLICATE_ERRORS: &str = "error[E0152]: found duplicate lang item `panic_impl`\n\
+        error: the `#[global_allocator]` in this crate conflicts with global allocator in: \
+        hyperlight_guest_bin";
+
+    fn problems(metadata: Value) -> Vec<String> {
+        duplicate_items(&metadata, DUPLICATE_ERRORS)
+            .into_iter()
+            .map(|hint| hint.problem)
+            .collect()
+    }
+
+    #[test]
+    fn undefined_symbols_from_lld_and_ld() {
+        let log = "rust-lld: error: undefined symbol: __stack_chk_fail\n\
+            >>> referenced by foo.c\n\
+            ld: foo.o: in function `bar': undefined reference to `sqrtf'\n";
+        assert_eq!(undefined_symbols(log), ["__stack_chk_fail", "sqrtf"]);
+    }
+
+    #[test]
+    fn clang_is_missing_only_if_cc_rs_says_so() {
+        assert!(clang_missing(
+            "error occurred in cc-rs: failed to find tool \"clang\": No such file or \
+            directory (os error 2)"
+        ));
+        assert!(!clang_missing(
+            "error[E0308]: mismatched types\n  expected `u32`, found `&str`"
+        ));
+    }
+
+    #[test]
+    fn known_symbols_have_hints() {
+        let fix = |symbol| symbol_hint(symbol).map(|hint| hint.fix);
+        assert!(fix("memcpy").unwrap().contains("--sysroot"));
+        assert!(
+            fix("__stack_chk_guard")
+                .unwrap()
+                .contains("-fno-stack-protector")
+        );
+        assert!(fix("sqrtf").unwrap().contains("--libm"));
+        assert!(fix("pow").unwrap().contains("--libm"));
+        assert!(fix("__addtf3").unwrap().contains("long double"));
+        assert!(fix("my_host_function").is_none());
+    }
+
+    #[test]
+    fn duplicate_items_name_both_crates() {
+        assert!(
+            problems(json!({
+                "packages": [
+                    { "name": "hyperlight-guest-bin", "version": "0.9.0" },
+                    { "name": "spin", "version": "0.10.0" },
+                ],
+            }))
+            .is_empty()
+        );
+
+        assert_eq!(
+            problems(json!({
+                "packages": [
+                    { "name": "hyperlight-guest-bin", "version": "0.9.0" },
+                    { "name": "panic-halt", "version": "1.0.0" },
+                ],
+            })),
+            [
+                "#[panic_handler] is defined by both `hyperlight-guest-bin 0.9.0` and \
+            `panic-halt 1.0.0`, and a guest can only have one"
+            ]
+        );
+
+        assert_eq!(
+            problems(json!({
+                "packages": [
+                    { "name": "hyperlight-guest-bin", "version": "0.9.0" },
+                    { "name": "hyperlight-guest-bin", "version": "0.10.0" },
+                ],
+            }))
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn duplicate_items_need_rustc_errors() {
+        let metadata = json!({
+            "packages": [
+                { "name": "hyperlight-guest-bin", "version": "0.9.0" },
+                { "name": "hyperlight-guest-bin", "version": "0.10.0" },
+            ],
+        });
+        let mismatched = "error[E0308]: mismatched types";
+        assert!(duplicate_items(&metadata, mismatched).is_empty());
+
+        let panic_handler = "error[E0152]: found duplicate lang item `panic_impl`";
+        let hints = duplicate_items(&metadata, panic_handler);
+        assert_eq!(hints.len(), 1);
+        assert!(hints[0].problem.starts_with("#[panic_handler]"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+
+use crate::cli::{Args, subcommand_index, warning_once};
+
+/// Devices of the hypervisors Hyperlight runs guests on in Linux, KVM and mshv.
+const DEVICES: &[&str] = &["/dev/kvm", "/dev/mshv"];
+
+/// Returns whether a hypervisor is available to run guests, i.e., whether one of the
+/// [`DEVICES`] can be opened.
+///
+/// Other platforms are assumed to have one, e.g., Windows Hypervisor Platform.
+pub fn available() -> bool {
+    if !cfg!(target_os = "linux") {
+        return true;
+    }
+    DEVICES.iter().any(|device| {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device)
+            .is_ok()
+    })
+}
+
+/// Returns whether the cargo command only builds the guests it would run, because no
+/// hypervisor is available and `--no-run-fallback` was passed.
+pub fn falls_back(args: &Args) -> bool {
+    args.runs_guests && args.no_run_fallback && !available()
+}
+
+/// Warns before a cargo command that runs guests when no hypervisor is available, with
+/// what's needed to run them, or that they are only built with `--no-run-fallback`.
+pub fn check(args: &Args) {
+    if !args.runs_guests || available() {
+        return;
+    }
+    if args.no_run_fallback {
+        warning_once(
+            "no-hypervisor",
+            "no hypervisor is available to run the guests, neither KVM nor mshv, \
+             only building them",
+        );
+    } else {
+        warning_once(
+            "no-hypervisor",
+            "no hypervisor is available to run the guests, neither KVM nor mshv, \
+             make sure virtualization is enabled and that your user can access `/dev/kvm`, \
+             or pass `--no-run-fallback` to only build them",
+        );
+    }
+}
+
+/// Turns the cargo arguments `args` of a command that runs guests into one that only
+/// builds them, `cargo build` for `cargo run`, and `--no-run` for `cargo test` and
+/// `cargo bench`.
+pub fn skip_runs(args: &mut Vec<OsString>) {
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    let Some(index) = subcommand_index(args) else {
+        return;
+    };
+    if args[index] == "run" || args[index] == "r" {
+        // `cargo build` doesn't take the arguments for the binary
+        args[index] = "build".into();
+        args.truncate(end);
+    } else {
+        args.insert(end, "--no-run".into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn skip_runs_only_builds() {
+        let cases: &[(&[&str], &[&str])] = &[
+            (&["run", "--release"], &["build", "--release"]),
+            (
+                &["+nightly", "r", "--bin", "guest", "--", "arg"],
+                &["+nightly", "build", "--bin", "guest"],
+            ),
+            (&["test"], &["test", "--no-run"]),
+            (
+                &["t", "-p", "guest", "--", "--nocapture"],
+                &["t", "-p", "guest", "--no-run", "--", "--nocapture"],
+            ),
+            (&["bench"], &["bench", "--no-run"]),
+            (
+                &["--config", "build.jobs=2", "run", "--", "arg"],
+                &["--config", "build.jobs=2", "build"],
+            ),
+            (
+                &["-Z", "bindeps", "test"],
+                &["-Z", "bindeps", "test", "--no-run"],
+            ),
+        ];
+        for (input, expected) in cases {
+            let mut input = args(input);
+            skip_runs(&mut input);
+            assert_eq!(input, args(expected));
+        }
+    }
+}
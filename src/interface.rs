@@ -0,0 +1,436 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use regex::Regex;
+
+use crate::cli::{Args, note};
+use crate::features::guest_packages;
+use crate::metadata::{self, FunctionSignature, PackageMetadata};
+
+/// A guest function registration found in the sources of a guest.
+#[derive(Debug, PartialEq)]
+struct Registration {
+    name: String,
+    signature: FunctionSignature,
+    /// `file:line` of the registration
+    location: String,
+}
+
+/// Returns the `ParameterType` or `ReturnType` variant for a Rust type, the way the
+/// `#[guest_function]` attribute of `hyperlight-guest-macros` maps them.
+fn variant(ty: &str) -> Option<&'static str> {
+    let ty = ty.split_whitespace().collect::<String>();
+    // the last path segment, e.g., `String` for `alloc::string::String`
+    let end = ty.find('<').unwrap_or(ty.len());
+    let ty = &ty[ty[..end].rfind("::").map_or(0, |i| i + 2)..];
+    Some(match ty {
+        "i32" => "Int",
+        "u32" => "UInt",
+        "i64" => "Long",
+        "u64" => "ULong",
+        "f32" => "Float",
+        "f64" => "Double",
+        "String" => "String",
+        "bool" => "Bool",
+        "Vec<u8>" => "VecBytes",
+        "()" => "Void",
+        _ => return None,
+    })
+}
+
+/// Returns the signature of a `#[guest_function]` from its Rust `parameters` and
+/// `return_type`, leaving out the parts with types that don't map to a variant.
+fn attribute_signature(parameters: &str, return_type: Option<&str>) -> FunctionSignature {
+    let parameters = parameters
+        .split(',')
+        .filter(|param| !param.trim().is_empty())
+        .map(|param| {
+            let (_, ty) = param.split_once(':')?;
+            variant(ty)
+                .filter(|variant| *variant != "Void")
+                .map(String::from)
+        })
+        .collect();
+    let return_type = match return_type.map(str::trim) {
+        None | Some("") => Some("Void"),
+        Some(ty) => {
+            let ty = ty.split_whitespace().collect::<String>();
+            let ok = ty
+                .strip_suffix('>')
+                .and_then(|ty| ty.split_once("Result<"))
+                .filter(|(path, _)| path.is_empty() || path.ends_with("::"))
+                .map(|(_, ok)| ok);
+            variant(ok.unwrap_or(&ty))
+        }
+    };
+    FunctionSignature {
+        parameters,
+        return_type: return_type.map(String::from),
+    }
+}
+
+/// Finds the guest functions registered in `source`, with
+/// `GuestFunctionDefinition::new` or the `#[guest_function]` attribute.
+///
+/// The signature is only known for `GuestFunctionDefinition::new` with literal
+/// parameter and return types, and for `#[guest_function]` with the Rust types it
+/// supports, otherwise only the name is checked.
+fn registrations(source: &str, file: &Path) -> Vec<Registration> {
+    let definition =
+        Regex::new(r#"GuestFunctionDefinition::new\(\s*(?:String::from\(\s*)?"([^"]*)""#).unwrap();
+    let signature = Regex::new(
+        r#"^[^,]*,\s*(?:Vec::from\(\s*&?\[([^\]]*)\]\s*\)|vec!\[([^\]]*)\]|(Vec::new\(\)))\s*,\s*ReturnType::(\w+)"#,
+    )
+    .unwrap();
+    let attribute = Regex::new(
+        r#"#\[guest_function(?:\(\s*"([^"]*)"\s*\))?\]\s*(?:pub(?:\([^)]*\))?\s+)?fn\s+(\w+)(?:\s*\(([^)]*)\)\s*(?:->\s*([^{]*?))?\s*\{)?"#,
+    )
+    .unwrap();
+
+    let location = |offset: usize| {
+        let line = source[..offset].matches('\n').count() + 1;
+        format!("{}:{line}", file.display())
+    };
+
+    let mut found = vec![];
+    for caps in definition.captures_iter(source) {
+        let whole = caps.get(0).unwrap();
+        let signature = match signature.captures(&source[whole.end()..]) {
+            Some(sig) => {
+                let parameters = sig.get(1).or(sig.get(2)).map_or("", |m| m.as_str());
+                let parameters = parameters
+                    .split(',')
+                    .map(|param| param.trim())
+                    .filter(|param| !param.is_empty())
+                    .map(|param| param.trim_start_matches("ParameterType::").to_string())
+                    .collect();
+                FunctionSignature {
+                    parameters: Some(parameters),
+                    return_type: Some(sig[4].to_string()),
+                }
+            }
+            None => FunctionSignature::default(),
+        };
+        found.push(Registration {
+            name: caps[1].to_string(),
+            signature,
+            location: location(whole.start()),
+        });
+    }
+    for caps in attribute.captures_iter(source) {
+        let name = caps.get(1).unwrap_or_else(|| caps.get(2).unwrap());
+        let signature = match caps.get(3) {
+            Some(parameters) => {
+                attribute_signature(parameters.as_str(), caps.get(4).map(|m| m.as_str()))
+            }
+            None => FunctionSignature::default(),
+        };
+        found.push(Registration {
+            name: name.as_str().to_string(),
+            signature,
+            location: location(caps.get(0).unwrap().start()),
+        });
+    }
+    found
+}
+
+/// Returns the Rust source files in `dirs` and their subdirectories.
+fn source_files(dirs: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut pending = dirs.into_iter().collect::<Vec<_>>();
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = dir.read_dir() else {
+            continue;
+        };
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Compares the `expected` guest functions with the ones `found`, returning a
+/// description of every difference.
+fn compare(expected: &BTreeMap<String, FunctionSignature>, found: &[Registration]) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut by_name = BTreeMap::<&str, Vec<&Registration>>::new();
+    for registration in found {
+        by_name
+            .entry(&registration.name)
+            .or_default()
+            .push(registration);
+    }
+
+    for (name, registrations) in &by_name {
+        let locations = || {
+            registrations
+                .iter()
+                .map(|r| r.location.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if registrations.len() > 1 {
+            problems.push(format!(
+                "{name:?} is registered more than once, at {}",
+                locations()
+            ));
+        }
+        let Some(signature) = expected.get(*name) else {
+            problems.push(format!(
+                "{name:?} is registered at {} but not declared in the interface",
+                locations()
+            ));
+            continue;
+        };
+        for registration in registrations {
+            let found = &registration.signature;
+            if let (Some(expected), Some(found)) = (&signature.parameters, &found.parameters)
+                && expected != found
+            {
+                problems.push(format!(
+                    "{name:?} at {} takes ({}), but the interface declares ({})",
+                    registration.location,
+                    found.join(", "),
+                    expected.join(", "),
+                ));
+            }
+            if let (Some(expected), Some(found)) = (&signature.return_type, &found.return_type)
+                && expected != found
+            {
+                problems.push(format!(
+                    "{name:?} at {} returns {found}, but the interface declares {expected}",
+                    registration.location,
+                ));
+            }
+        }
+    }
+
+    for name in expected.keys() {
+        if !by_name.contains_key(name.as_str()) {
+            problems.push(format!(
+                "{name:?} is declared in the interface but never registered"
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Checks that the guest functions registered by the guest `packages`, or all the
+/// guests in the workspace if `packages` is empty, match the
+/// `[package.metadata.hyperlight.interface]` table of their manifest.
+///
+/// Guest functions are registered at runtime by name, so a typo only shows up when
+/// the host calls the function. This finds the registrations in the sources instead.
+pub fn check(args: &Args, packages: &[String]) -> Result<()> {
+    let packages = match packages {
+        [] => guest_packages(args)?,
+        packages => packages.to_vec(),
+    };
+
+    if packages.is_empty() {
+        bail!("No hyperlight guest packages found in the workspace");
+    }
+
+    let metadata = metadata::cargo_metadata(args, &["--no-deps".into()])?;
+
+    let mut failed = vec![];
+    for package in &packages {
+        let pkg = metadata["packages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|pkg| pkg["name"] == package.as_str())
+            .with_context(|| format!("Package {package:?} not found in workspace"))?;
+
+        let interface = serde_json::from_value::<Option<PackageMetadata>>(pkg["metadata"].clone())
+            .with_context(|| format!("Failed to parse the metadata of {package:?}"))?
+            .and_then(|metadata| metadata.hyperlight)
+            .map(|hyperlight| hyperlight.interface.guest_functions)
+            .unwrap_or_default();
+
+        if interface.is_empty() {
+            note(format!(
+                "guest {package} has no [package.metadata.hyperlight.interface] to check"
+            ));
+            continue;
+        }
+
+        let dirs = pkg["targets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|target| {
+                target["kind"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .any(|kind| kind == "bin" || kind == "lib")
+            })
+            .filter_map(|target| {
+                Some(Path::new(target["src_path"].as_str()?).parent()?.to_owned())
+            });
+
+        let mut found = vec![];
+        for file in source_files(dirs) {
+            let source = std::fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {file:?}"))?;
+            found.extend(registrations(&source, &file));
+        }
+
+        let problems = compare(&interface, &found);
+        if problems.is_empty() {
+            note(format!(
+                "guest {package} registers the {} functions of its interface",
+                interface.len()
+            ));
+            continue;
+        }
+
+        let list = problems
+            .iter()
+            .map(|problem| format!(" * {problem}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        eprintln!("guest {package} doesn't match its interface:\n{list}");
+        failed.push(package.as_str());
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "The guest functions of {} don't match their interface",
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r#"
+#[unsafe(no_mangle)]
+pub extern "C" fn hyperlight_main() {
+    let print_output_def = GuestFunctionDefinition::new(
+        "PrintOutput".to_string(),
+        Vec::from(&[ParameterType::String]),
+        ReturnType::Int,
+        print_output as usize,
+    );
+    register_function(print_output_def);
+
+    register_function(GuestFunctionDefinition::new(
+        String::from("Echo"),
+        vec![ParameterType::String, ParameterType::Int],
+        ReturnType::String,
+        echo as usize,
+    ));
+
+    register_function(GuestFunctionDefinition::new("Ping".into(), Vec::new(), ReturnType::Void, ping as usize));
+
+    register_function(GuestFunctionDefinition::new(NAME.into(), params(), ret(), dynamic as usize));
+}
+
+#[guest_function("Add")]
+fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+#[guest_function]
+pub fn multiply(a: i32, b: i32) -> i32 {
+    a * b
+}
+"#;
+
+    fn signature(parameters: &[&str], return_type: &str) -> FunctionSignature {
+        FunctionSignature {
+            parameters: Some(parameters.iter().map(|p| p.to_string()).collect()),
+            return_type: Some(return_type.to_string()),
+        }
+    }
+
+    #[test]
+    fn registrations_in_source() {
+        let found = registrations(SOURCE, Path::new("src/main.rs"));
+        let found = found
+            .iter()
+            .map(|r| (r.name.as_str(), &r.signature, r.location.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                (
+                    "PrintOutput",
+                    &signature(&["String"], "Int"),
+                    "src/main.rs:4"
+                ),
+                (
+                    "Echo",
+                    &signature(&["String", "Int"], "String"),
+                    "src/main.rs:12"
+                ),
+                ("Ping", &signature(&[], "Void"), "src/main.rs:19"),
+                ("Add", &signature(&["Int", "Int"], "Int"), "src/main.rs:24"),
+                (
+                    "multiply",
+                    &signature(&["Int", "Int"], "Int"),
+                    "src/main.rs:29"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn attribute_signatures_from_rust_types() {
+        assert_eq!(
+            attribute_signature("message: String, data: Vec<u8>", Some("Result<i32>")),
+            signature(&["String", "VecBytes"], "Int")
+        );
+        assert_eq!(attribute_signature("", None), signature(&[], "Void"));
+        assert_eq!(
+            attribute_signature("s: &str", Some("hyperlight_guest::error::Result<()>")),
+            FunctionSignature {
+                parameters: None,
+                return_type: Some("Void".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn compare_reports_every_mismatch() {
+        let expected = BTreeMap::from([
+            ("PrintOutput".to_string(), signature(&["String"], "Int")),
+            ("Echo".to_string(), signature(&["String"], "Int")),
+            ("Add".to_string(), signature(&["Int", "Int"], "Int")),
+            ("Missing".to_string(), FunctionSignature::default()),
+        ]);
+        let mut found = registrations(SOURCE, Path::new("src/main.rs"));
+        found.extend(registrations(
+            r#"GuestFunctionDefinition::new("Add".into(), vec![], ReturnType::Int, f)"#,
+            Path::new("src/lib.rs"),
+        ));
+
+        assert_eq!(
+            compare(&expected, &found),
+            [
+                r#""Add" is registered more than once, at src/main.rs:24, src/lib.rs:1"#,
+                r#""Add" at src/lib.rs:1 takes (), but the interface declares (Int, Int)"#,
+                r#""Echo" at src/main.rs:12 takes (String, Int), but the interface declares (String)"#,
+                r#""Echo" at src/main.rs:12 returns String, but the interface declares Int"#,
+                r#""Ping" is registered at src/main.rs:19 but not declared in the interface"#,
+                r#""multiply" is registered at src/main.rs:29 but not declared in the interface"#,
+                r#""Missing" is declared in the interface but never registered"#,
+            ]
+        );
+    }
+}
@@ -1,14 +1,57 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::sync::{Mutex, PoisonError};
+
 use anyhow::Result;
 
+mod add;
+mod allocator;
+mod analyze;
+mod artifact;
+mod atomic;
+mod build_all;
+mod cache;
 mod cargo_cmd;
+mod cc_wrapper;
 mod cli;
+mod code_model;
+mod codegen;
 mod command;
+mod compat;
+mod compress;
+mod doctest;
+mod expand;
+mod failure;
+mod features;
+mod fingerprint;
+mod float;
+mod generated;
+mod hint;
+mod hypervisor;
+mod interface;
+mod link_map;
+mod metadata;
+mod pie;
+mod provenance;
+mod report;
+mod setup;
+mod subcommand;
 mod sysroot;
+mod timings;
 mod toolchain;
+mod vendor;
 
 use cargo_cmd::CargoCmd;
 use cli::Args;
 pub use command::Command;
+pub use compress::decompress;
+pub use failure::Failure;
+use failure::WithFailure as _;
+/// Helpers for the build scripts of guests built with `cargo hyperlight`.
+pub use hyperlight_build as build;
+pub use report::{ArtifactReport, BuildReport, Warning};
+pub use target_spec_json::TargetSpec;
 
 /// Constructs a new `Command` for launching cargo targeting
 /// [hyperlight](https://github.com/hyperlight-dev/hyperlight) guest code.
@@ -42,9 +85,27 @@ pub fn cargo() -> Result<Command> {
     Command::new()
 }
 
+/// Returns the spec of the hyperlight target `triple` that cargo-hyperlight generates
+/// for the project in the current directory, without building anything.
+///
+/// This is a shorthand for [`Command::target_spec`] with `--target triple`.
+///
+/// # Examples
+///
+/// ```no_run
+/// let spec = cargo_hyperlight::target_spec("x86_64-hyperlight-none").unwrap();
+/// println!("{}", serde_json::to_string_pretty(&spec).unwrap());
+/// ```
+pub fn target_spec(triple: &str) -> Result<TargetSpec> {
+    cargo()?.arg("--target").arg(triple).target_spec()
+}
+
 impl Args {
     pub fn sysroot_dir(&self) -> std::path::PathBuf {
-        self.target_dir.join("sysroot")
+        match &self.prebuilt_sysroot {
+            Some(dir) => dir.clone(),
+            None => self.target_dir.join("sysroot"),
+        }
     }
 
     pub fn triplet_dir(&self) -> std::path::PathBuf {
@@ -73,19 +134,119 @@ impl Args {
     pub fn build_plan_dir(&self) -> std::path::PathBuf {
         self.sysroot_dir().join("build-plan")
     }
+
+    pub fn cc_wrapper_path(&self) -> std::path::PathBuf {
+        if cfg!(windows) {
+            self.triplet_dir().join("cc.bat")
+        } else {
+            self.triplet_dir().join("cc")
+        }
+    }
+
+    pub fn ld_wrapper_path(&self) -> std::path::PathBuf {
+        self.triplet_dir().join("ld")
+    }
+
+    pub fn cc_log_path(&self) -> std::path::PathBuf {
+        self.sysroot_dir().join("cc.log")
+    }
+
+    pub fn ld_log_path(&self) -> std::path::PathBuf {
+        self.sysroot_dir().join("ld.log")
+    }
 }
 
 trait CargoCommandExt {
     fn populate_from_args(&mut self, args: &Args) -> &mut Self;
+    fn build_script_env(&mut self, args: &Args) -> &mut Self;
+    fn profile_settings(&mut self, args: &Args) -> &mut Self;
+    fn cargo_config(&mut self, args: &Args) -> Vec<String>;
 }
 
 impl CargoCommandExt for std::process::Command {
+    fn build_script_env(&mut self, args: &Args) -> &mut Self {
+        use hyperlight_build::vars;
+
+        self.env(vars::SYSROOT, args.sysroot_dir());
+        self.env(vars::LIB_DIR, args.libs_dir());
+        let include_dirs = args
+            .include_dirs
+            .iter()
+            .cloned()
+            .chain([args.includes_dir()]);
+        if let Ok(include_dirs) = std::env::join_paths(include_dirs) {
+            self.env(vars::INCLUDE_DIRS, include_dirs);
+        }
+        self
+    }
+
+    fn profile_settings(&mut self, args: &Args) -> &mut Self {
+        let settings = &args.profile_settings;
+        if !settings.rustflags.is_empty() {
+            self.append_rustflags(&settings.rustflags);
+        }
+
+        // the profile settings from the environment take precedence, like they do
+        // over the ones in `Cargo.toml`
+        let profile = args.profile.to_uppercase().replace('-', "_");
+        for (name, value) in [("STRIP", &settings.strip), ("LTO", &settings.lto)] {
+            let var = format!("CARGO_PROFILE_{profile}_{name}");
+            if let Some(value) = value
+                && !args.env.contains_key(OsStr::new(&var))
+            {
+                self.env(var, value.to_string());
+            }
+        }
+        self
+    }
+
+    /// Returns the `--config` values for the cargo command populated from `args`, moving
+    /// the settings that can't be passed through the environment to them.
+    fn cargo_config(&mut self, args: &Args) -> Vec<String> {
+        let mut config = toolchain::c_archive_config(args);
+        if args.artifact_deps {
+            // our rustflags must only apply to the guests, the user's RUSTFLAGS are
+            // kept for the host as well
+            config.push(cargo_cmd::target_rustflags_config(self, &args.target));
+            // `build.rustflags` as a TOML array, `CARGO_BUILD_RUSTFLAGS` would split
+            // flags with spaces
+            if let Some(flags) = cargo_cmd::rustflags(|key| args.env.get(OsStr::new(key)).cloned())
+            {
+                config.push(format!("build.rustflags={}", cargo_cmd::toml_array(&flags)));
+            }
+        }
+        config
+    }
+
     fn populate_from_args(&mut self, args: &Args) -> &mut Self {
         self.target(&args.target);
         self.sysroot(args.sysroot_dir());
+        self.build_script_env(args);
         self.entrypoint("entrypoint");
+        for (bin, link_args) in &args.bin_link_args {
+            self.bin_link_args(bin, link_args);
+        }
+        if args.emit_cfg {
+            self.hyperlight_cfg(&args.target);
+        }
+        if let Some(allocator) = args.allocator {
+            self.append_rustflags(allocator.cfg());
+        }
+        if args.cross_language_lto {
+            // the linker shares rustc's LLVM, so it can read the bitcode from both
+            self.append_rustflags(["-Clinker-plugin-lto"]);
+        }
+        self.profile_settings(args);
+        if cfg!(unix) {
+            self.linker_env(&args.target, args.ld_wrapper_path());
+        } else if let Some(linker) = &args.linker {
+            // also set in the target spec, this covers anything that doesn't read it
+            self.linker_env(&args.target, linker);
+        }
         if let Some(clang) = &args.clang {
             self.cc_env(&args.target, clang);
+            // only cc-rs goes through the wrapper, bindgen still needs the real clang
+            self.cc_wrapper_env(&args.target, args.cc_wrapper_path());
         } else {
             // If we couldn't find clang, use the default from the
             // system path. This will then error if we try to build
@@ -97,20 +258,105 @@ impl CargoCommandExt for std::process::Command {
         } else {
             // do nothing, let cc-rs find ar itself
         }
-        self.append_cflags(&args.target, toolchain::cflags(args));
+        if args.artifact_deps {
+            // The host is built natively, and cargo builds the guests it depends on for
+            // the hyperlight target, see `cargo_config` for the rustflags
+            self.env_remove("CARGO_BUILD_TARGET");
+        }
+        if args.clang.is_some() {
+            // The wrapper appends hyperlight's flags and the extra flags to every
+            // compilation, after the ones from the environment, so they only need
+            // to be passed to bindgen, which uses libclang instead of the wrapper.
+            if args.replace_cflags {
+                self.replace_cflags(&args.target, "");
+            }
+            self.append_bindgen_cflags(toolchain::cflags(args));
+        } else if args.replace_cflags {
+            self.replace_cflags(&args.target, toolchain::cflags(args));
+        } else {
+            self.append_cflags(&args.target, toolchain::cflags(args));
+        }
 
         self
     }
 }
 
 impl Args {
-    pub fn prepare_sysroot(&self) -> Result<()> {
+    /// Prepares the sysroot and toolchain, returning whether the sysroot had to be rebuilt.
+    pub fn prepare_sysroot(&self) -> Result<bool> {
+        self.validate_target().failure(Failure::Config)?;
+        doctest::check(self).failure(Failure::Config)?;
+        hypervisor::check(self);
+
+        // Sysroots already prepared by this process, by fingerprint.
+        // The lock is held during preparation, so that concurrent calls don't race
+        // writing to the same sysroot.
+        static PREPARED: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+        let fingerprint = self.fingerprint();
+        let mut prepared = PREPARED.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // the sysroot could have been removed since, e.g., by `cargo clean`
+        if prepared.contains(&fingerprint) && self.sysroot_dir().join("manifest.json").exists() {
+            return Ok(false);
+        }
+
+        compat::check(self).failure(Failure::Toolchain)?;
+        allocator::check(self).failure(Failure::Config)?;
+
+        if self.prebuilt_sysroot.is_some() {
+            // a prebuilt sysroot is only checked, nothing is written to it
+            fingerprint::check(self).failure(Failure::Sysroot)?;
+            prepared.insert(fingerprint);
+            return Ok(false);
+        }
+
+        toolchain::check_lto(self).failure(Failure::Toolchain)?;
+        toolchain::check_linker(self).failure(Failure::Toolchain)?;
+
         // Build sysroot
-        sysroot::build(self)?;
+        let rebuilt = sysroot::build(self).failure(Failure::Sysroot)?;
+        cache::record(self, cache::Entry::SysrootBuild, !rebuilt);
 
         // Build toolchain
-        toolchain::prepare(self)?;
+        toolchain::prepare(self).failure(Failure::Toolchain)?;
+        toolchain::install_libs(self).failure(Failure::Sysroot)?;
+        toolchain::check_abi(self).failure(Failure::Toolchain)?;
+        cc_wrapper::install(self)?;
+        link_map::install(self)?;
+        fingerprint::write(self)?;
+
+        prepared.insert(fingerprint);
+
+        Ok(rebuilt)
+    }
 
-        Ok(())
+    /// Hash of everything that affects the sysroot preparation.
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.manifest_path.hash(&mut hasher);
+        self.target_dir.hash(&mut hasher);
+        self.target.hash(&mut hasher);
+        self.custom_target.hash(&mut hasher);
+        self.current_dir.hash(&mut hasher);
+        self.clang.hash(&mut hasher);
+        self.ar.hash(&mut hasher);
+        self.include_dirs.hash(&mut hasher);
+        self.static_libs.hash(&mut hasher);
+        self.libm.hash(&mut hasher);
+        self.cross_language_lto.hash(&mut hasher);
+        self.cached_c_archive.hash(&mut hasher);
+        self.linker.hash(&mut hasher);
+        self.compiler_launcher.hash(&mut hasher);
+        self.emit_map.hash(&mut hasher);
+        self.prebuilt_sysroot.hash(&mut hasher);
+        self.cflags.hash(&mut hasher);
+        self.dep_cflags.hash(&mut hasher);
+        self.models.hash(&mut hasher);
+        self.env
+            .iter()
+            .collect::<BTreeMap<_, _>>()
+            .hash(&mut hasher);
+        hasher.finish()
     }
 }
@@ -0,0 +1,312 @@
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::artifact::Artifact;
+use crate::cli::{Args, note, warning};
+use crate::{atomic, generated};
+
+/// Number of sections and symbols shown in the summary of a link map.
+const SUMMARY_LEN: usize = 10;
+
+/// Writes the linker wrapper to [`Args::ld_wrapper_path`].
+///
+/// cargo is pointed to the wrapper instead of the linker. The wrapper appends the
+/// output of failed links to [`Args::ld_log_path`], for [`Hint`] to explain them.
+///
+/// With `--emit-map`, the wrapper also finds the output path in the `-o` argument
+/// and adds `-Map` so that the linker writes a map file:
+/// * for binaries and examples, next to the output, named after `CARGO_BIN_NAME`,
+///   which cargo sets when building binaries, as the output name has a hash
+/// * otherwise, as `<output>.map`
+///
+/// [`attach`] then moves the maps of binaries next to the final executable.
+///
+/// When linking a binary, the wrapper also appends the `link-args` of its
+/// `[package.metadata.hyperlight.bin.<name>]` table, from the variable named by
+/// [`link_args_var`].
+///
+/// [`link_args_var`]: crate::cargo_cmd::link_args_var
+/// [`Hint`]: crate::hint::Hint
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn install(args: &Args) -> Result<()> {
+    if cfg!(windows) {
+        if args.emit_map {
+            warning(
+                "emit-map-unsupported",
+                "--emit-map is not supported on Windows yet",
+            );
+        }
+        if !args.bin_link_args.is_empty() {
+            warning(
+                "bin-link-args-unsupported",
+                "per-binary `link-args` are not supported on Windows yet",
+            );
+        }
+        return Ok(());
+    }
+
+    // rustc adds the directory of rust-lld to the PATH of the linker
+    let linker = match &args.linker {
+        Some(linker) => linker.to_string_lossy().into_owned(),
+        None => "rust-lld".into(),
+    };
+    let log = args.ld_log_path().to_string_lossy().into_owned();
+    let script = shell_script(&linker, &log, args.emit_map);
+
+    std::fs::create_dir_all(args.triplet_dir()).context("Failed to create sysroot directory")?;
+
+    // start a new log on every invocation
+    std::fs::write(args.ld_log_path(), "").context("Failed to truncate linker log")?;
+
+    let path = args.ld_wrapper_path();
+    if std::fs::read_to_string(&path).is_ok_and(|current| current == script) {
+        return Ok(());
+    }
+
+    generated::write(args, &path, &script).context("Failed to write linker wrapper")?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .context("Failed to make linker wrapper executable")?;
+    }
+
+    Ok(())
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+fn shell_script(linker: &str, log: &str, emit_map: bool) -> String {
+    let linker = shell_quote(linker);
+    let mut script = String::from("#!/bin/sh\n");
+    if emit_map {
+        script.push_str("out=\nprev=\nfor arg do\n");
+        script.push_str("    if [ \"$prev\" = -o ]; then out=\"$arg\"; fi\n");
+        script.push_str("    prev=\"$arg\"\ndone\n");
+        script.push_str("if [ -n \"$CARGO_BIN_NAME\" ]; then\n");
+        script.push_str("    map=\"$(dirname \"$out\")/$CARGO_BIN_NAME.map\"\n");
+        script.push_str("else\n    map=\"$out.map\"\nfi\n");
+        script.push_str("if [ -n \"$out\" ]; then set -- \"$@\" \"-Map=$map\"; fi\n");
+    }
+    // the extra arguments of the binary, split on whitespace like RUSTFLAGS
+    script.push_str("if [ -n \"$CARGO_BIN_NAME\" ]; then\n");
+    script.push_str(
+        "    bin=\"$(printf '%s' \"$CARGO_BIN_NAME\" | tr '[:lower:]-' '[:upper:]_')\"\n",
+    );
+    script.push_str("    eval \"link_args=\\\"\\${CARGO_HYPERLIGHT_LINK_ARGS_$bin-}\\\"\"\n");
+    script.push_str("    set -f\n    set -- \"$@\" $link_args\nfi\n");
+    // keep the linker's output for rustc, and log it if the link fails
+    writeln!(script, "err=\"$(mktemp)\" || exec {linker} \"$@\"").unwrap();
+    writeln!(script, "{linker} \"$@\" 2>\"$err\"").unwrap();
+    script.push_str("status=$?\ncat \"$err\" >&2\n");
+    writeln!(
+        script,
+        "if [ $status -ne 0 ]; then cat \"$err\" >> {} 2>/dev/null; fi",
+        shell_quote(log)
+    )
+    .unwrap();
+    script.push_str("rm -f \"$err\"\nexit $status\n");
+    script
+}
+
+/// Places the link map of every artifact next to it, as `<executable>.map`, so that
+/// it's exported together with the executable.
+///
+/// With `-v`, a summary of the largest sections and symbols is printed as well.
+pub fn attach(args: &Args, artifacts: &[Artifact]) -> Result<()> {
+    for artifact in artifacts {
+        let executable = &artifact.executable;
+        let dir = executable
+            .parent()
+            .context("Failed to get artifact directory")?;
+        let mut map = executable.clone().into_os_string();
+        map.push(".map");
+
+        // binaries are linked in `deps` and then hard linked to their final location
+        let linked = dir.join("deps").join(format!("{}.map", artifact.name));
+        if linked.is_file() {
+            atomic::copy(&linked, &map)
+                .with_context(|| format!("Failed to copy link map {linked:?}"))?;
+        } else if !Path::new(&map).is_file() {
+            warning(
+                "missing-link-map",
+                format!("no link map was written for {:?}", artifact.name),
+            );
+            continue;
+        }
+
+        if args.verbose > 0 {
+            let content = std::fs::read_to_string(&map)
+                .with_context(|| format!("Failed to read link map {map:?}"))?;
+            note(summary(&artifact.name, &parse(&content)));
+        }
+    }
+
+    Ok(())
+}
+
+/// An output section or a symbol in a link map.
+#[derive(Debug, PartialEq)]
+pub struct MapEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+/// An input section in a link map, e.g., `libfoo-0123.rlib(foo.o):(.text.foo)`.
+#[derive(Debug, PartialEq)]
+pub struct InputSection {
+    /// Output section the input section was placed in
+    pub output: String,
+    pub name: String,
+    pub size: u64,
+}
+
+/// The sections and symbols of an LLD link map.
+#[derive(Debug, Default, PartialEq)]
+pub struct LinkMap {
+    pub sections: Vec<MapEntry>,
+    pub inputs: Vec<InputSection>,
+    pub symbols: Vec<MapEntry>,
+}
+
+/// Parses the output sections, input sections and symbols of an LLD link map.
+///
+/// Every line starts with the `VMA`, `LMA`, `Size` and `Align` columns, followed by the
+/// name. Output sections are not indented, input sections are indented by 8 columns,
+/// and symbols by 16.
+pub fn parse(content: &str) -> LinkMap {
+    let re =
+        Regex::new(r"^\s*[0-9a-f]+\s+[0-9a-f]+\s+([0-9a-f]+)\s+[0-9a-f]+ (\s*)(\S.*)$").unwrap();
+
+    let mut map = LinkMap::default();
+    for line in content.lines() {
+        let Some(caps) = re.captures(line) else {
+            continue;
+        };
+        let Ok(size) = u64::from_str_radix(&caps[1], 16) else {
+            continue;
+        };
+        let name = caps[3].trim().to_string();
+        match caps[2].len() {
+            0 => map.sections.push(MapEntry { name, size }),
+            8 => {
+                let output = map
+                    .sections
+                    .last()
+                    .map(|section| section.name.clone())
+                    .unwrap_or_default();
+                map.inputs.push(InputSection { output, name, size });
+            }
+            16 => map.symbols.push(MapEntry { name, size }),
+            _ => {}
+        }
+    }
+    map
+}
+
+/// Renders the largest sections and symbols of `map`.
+fn summary(name: &str, map: &LinkMap) -> String {
+    let mut summary = String::new();
+    let mut render = |title: &str, entries: &[MapEntry]| {
+        let mut entries = entries.iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        write!(summary, "\n  largest {title}:").unwrap();
+        for entry in entries.iter().take(SUMMARY_LEN) {
+            write!(summary, "\n    {:>10}  {}", entry.size, entry.name).unwrap();
+        }
+    };
+
+    render("sections", &map.sections);
+    render("symbols", &map.symbols);
+    format!("link map of {name:?}:{summary}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+             VMA              LMA     Size Align Out     In      Symbol
+          200120           200120       40     8 .rodata
+          200120           200120       40     8         guest.o:(.rodata.table)
+          201000           201000      1f0    16 .text
+          201000           201000      1f0    16         guest.o:(.text.entrypoint)
+          201000           201000      1a0     1                 entrypoint
+          2011a0           2011a0       50     1                 helper
+";
+
+    #[test]
+    fn parse_lld_map() {
+        let map = parse(MAP);
+        assert_eq!(
+            map,
+            LinkMap {
+                sections: vec![
+                    MapEntry {
+                        name: ".rodata".into(),
+                        size: 0x40
+                    },
+                    MapEntry {
+                        name: ".text".into(),
+                        size: 0x1f0
+                    },
+                ],
+                inputs: vec![
+                    InputSection {
+                        output: ".rodata".into(),
+                        name: "guest.o:(.rodata.table)".into(),
+                        size: 0x40
+                    },
+                    InputSection {
+                        output: ".text".into(),
+                        name: "guest.o:(.text.entrypoint)".into(),
+                        size: 0x1f0
+                    },
+                ],
+                symbols: vec![
+                    MapEntry {
+                        name: "entrypoint".into(),
+                        size: 0x1a0
+                    },
+                    MapEntry {
+                        name: "helper".into(),
+                        size: 0x50
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn summary_sorts_by_size() {
+        let summary = summary("guest", &parse(MAP));
+        let text = summary.find(".text").unwrap();
+        let rodata = summary.find(".rodata").unwrap();
+        assert!(text < rodata);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn wrapper_appends_the_link_args_of_the_binary() {
+        let script = shell_script("echo", "/dev/null", false);
+        let link = |bin: &str| {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(&script)
+                .args(["sh", "-o", "out"])
+                .env("CARGO_BIN_NAME", bin)
+                .env("CARGO_HYPERLIGHT_LINK_ARGS_MY_GUEST", "--gc-sections  -z*")
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+        assert_eq!(link("my-guest"), "-o out --gc-sections -z*\n");
+        assert_eq!(link("other"), "-o out\n");
+    }
+}
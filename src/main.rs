@@ -1,20 +1,27 @@
 use std::env;
 
-use cargo_hyperlight::cargo;
+use cargo_hyperlight::{Failure, cargo};
 
 fn main() {
-    let args = env::args_os().enumerate().filter_map(|(i, arg)| {
-        // skip the binary name and the "hyperlight" subcommand if present
-        if i == 0 || (i == 1 && arg == "hyperlight") {
-            None
-        } else {
-            Some(arg)
-        }
-    });
+    // skip the binary name and the "hyperlight" subcommand if present
+    let mut args = env::args_os().skip(1).peekable();
+    args.next_if(|arg| arg == "hyperlight");
 
-    cargo()
-        .expect("Failed to create cargo command")
-        .args(args)
-        .status()
-        .expect("Failed to execute cargo")
+    // like cargo, accept a `+toolchain` selector before the command, e.g.,
+    // `cargo hyperlight +nightly build`
+    let toolchain = args.next_if(|arg| arg.to_str().is_some_and(|arg| arg.starts_with('+')));
+
+    let result = cargo()
+        .map_err(|err| err.context("Failed to create cargo command"))
+        .and_then(|mut cargo| {
+            if let Some(toolchain) = toolchain {
+                cargo.rustup_toolchain(&toolchain.to_string_lossy()[1..]);
+            }
+            cargo.args(args).status()
+        });
+
+    if let Err(err) = result {
+        eprintln!("{err:?}");
+        std::process::exit(Failure::exit_code_of(&err));
+    }
 }
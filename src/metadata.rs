@@ -0,0 +1,403 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+use crate::allocator::Allocator;
+use crate::cargo_cmd::{CargoCmd, cargo_cmd};
+use crate::cli::Args;
+use crate::code_model::Models;
+use crate::failure::{Failure, WithFailure as _};
+
+/// The `metadata` table of a package or workspace manifest, as reported by `cargo metadata`.
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+pub struct PackageMetadata {
+    pub hyperlight: Option<HyperlightMetadata>,
+}
+
+/// The `[package.metadata.hyperlight]` (or `[workspace.metadata.hyperlight]`) table
+/// of a manifest.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct HyperlightMetadata {
+    pub export: ExportMetadata,
+    /// Versions of cargo-hyperlight allowed to build the workspace, e.g., `">=0.2"`
+    pub required_version: Option<String>,
+    /// Hyperlight target triple to build for when none is given on the command line
+    /// or in `CARGO_HYPERLIGHT_TARGET`, takes precedence over `build.target`
+    pub target: Option<String>,
+    /// Extra directories to add as `-isystem` to C compilations, before hyperlight's
+    /// own headers
+    pub include_dirs: Vec<PathBuf>,
+    /// Prebuilt static libraries to make available to the linker
+    pub static_libs: Vec<PathBuf>,
+    /// Whether to build a libm from hyperlight's musl sources
+    pub libm: bool,
+    /// Whether to link C code and Rust code with cross-language LTO
+    pub cross_language_lto: bool,
+    /// Whether to build hyperlight-guest-bin's C sources once per sysroot, instead of
+    /// in its build script
+    pub cached_c_archive: bool,
+    /// Linker to use instead of `rust-lld`, a path or a name to look up in `PATH`
+    pub linker: Option<PathBuf>,
+    /// Program to run clang through, e.g., `ccache` or `sccache`, a path or a name to
+    /// look up in `PATH`
+    pub compiler_launcher: Option<PathBuf>,
+    /// Extra C flags for the build scripts of specific packages, by package name
+    pub cflags: BTreeMap<String, Vec<String>>,
+    /// Whether to pass `--cfg hyperlight` to guest builds, defaults to `true`
+    pub emit_cfg: Option<bool>,
+    /// The functions a guest is expected to register, checked by
+    /// `cargo hyperlight check-interface`
+    pub interface: InterfaceMetadata,
+    /// Global allocator of the guest, exposed as the `hyperlight_allocator` cfg
+    pub allocator: Option<Allocator>,
+    /// Code, relocation and TLS models of the guest
+    #[serde(flatten)]
+    pub models: Models,
+    /// Settings for the builds with a specific cargo profile, by profile name
+    pub profile: BTreeMap<String, ProfileMetadata>,
+    /// Settings for specific binary targets of the package, by binary name
+    pub bin: BTreeMap<String, BinMetadata>,
+}
+
+impl HyperlightMetadata {
+    /// Makes all the relative paths in the configuration relative to `base`.
+    pub fn resolve_paths(&mut self, base: &Path) {
+        for path in self.include_dirs.iter_mut().chain(&mut self.static_libs) {
+            *path = base.join(&*path);
+        }
+        // bare names are looked up in PATH
+        for program in [&mut self.linker, &mut self.compiler_launcher]
+            .into_iter()
+            .flatten()
+        {
+            if program.components().count() > 1 {
+                *program = base.join(&*program);
+            }
+        }
+    }
+}
+
+impl HyperlightMetadata {
+    /// Parses the `hyperlight` table of the `metadata` reported by `cargo metadata` for
+    /// a manifest, `None` if it has none.
+    ///
+    /// An invalid value is an error naming its key, e.g., `code-model` in
+    /// `[package.metadata.hyperlight]`, where `table` is the name of the table.
+    pub fn from_metadata(metadata: &Value, table: &str) -> Result<Option<Self>> {
+        let Some(config) = metadata.get("hyperlight") else {
+            return Ok(None);
+        };
+        let err = match serde_json::from_value(config.clone()) {
+            Ok(config) => return Ok(Some(config)),
+            Err(err) => err,
+        };
+        // serde doesn't say which key failed, so find it by parsing them one by one
+        let key = config.as_object().and_then(|config| {
+            config.iter().find_map(|(key, value)| {
+                serde_json::from_value::<Self>(json!({ key: value }))
+                    .is_err()
+                    .then_some(key)
+            })
+        });
+        match key {
+            Some(key) => Err(err).context(format!("Invalid `{key}` in `[{table}]`")),
+            None => Err(err).context(format!("Invalid `[{table}]`")),
+        }
+        .failure(Failure::Config)
+    }
+
+    /// Returns the configuration that applies to a package, from the `metadata` reported
+    /// by `cargo metadata` for the package, if any, and for the workspace.
+    ///
+    /// The package's table replaces the workspace's, except for the `required-version`
+    /// of the workspace, which applies to all of its packages. Relative paths are made
+    /// relative to the manifest they come from.
+    pub fn resolve(package: Option<(&Value, &Path)>, workspace: (&Value, &Path)) -> Result<Self> {
+        let package_config = match package {
+            Some((metadata, dir)) => Self::from_metadata(metadata, "package.metadata.hyperlight")?
+                .map(|config| (config, dir)),
+            None => None,
+        };
+        let workspace_config = Self::from_metadata(workspace.0, "workspace.metadata.hyperlight")?
+            .map(|config| (config, workspace.1));
+
+        let workspace_version = workspace_config
+            .as_ref()
+            .and_then(|(workspace, _)| workspace.required_version.clone());
+        let mut config = match package_config.or(workspace_config) {
+            Some((mut config, base)) => {
+                config.resolve_paths(base);
+                config
+            }
+            None => Self::default(),
+        };
+        if config.required_version.is_none() {
+            config.required_version = workspace_version;
+        }
+        Ok(config)
+    }
+
+    /// Returns the settings for builds with the cargo profile `name`. Like in cargo,
+    /// the `test` and `bench` profiles fall back to `dev` and `release`.
+    pub fn profile(&self, name: &str) -> ProfileMetadata {
+        let fallback = match name {
+            "test" => Some("dev"),
+            "bench" => Some("release"),
+            _ => None,
+        };
+        iter::once(name)
+            .chain(fallback)
+            .find_map(|name| self.profile.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// A `[package.metadata.hyperlight.profile.<name>]` table, applied to the guest builds
+/// with the cargo profile `<name>`.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone, Debug, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ProfileMetadata {
+    /// Extra flags for rustc, appended to the ones from cargo-hyperlight
+    pub rustflags: Vec<String>,
+    /// Extra C flags, before the ones added with `Command::cflag`
+    pub cflags: Vec<String>,
+    /// The profile's `strip` setting, e.g., `true` or `"debuginfo"`
+    pub strip: Option<ProfileSetting>,
+    /// The profile's `lto` setting, e.g., `true` or `"thin"`
+    pub lto: Option<ProfileSetting>,
+}
+
+/// A cargo profile setting that is either a boolean or a string, like `strip` or `lto`.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ProfileSetting {
+    Bool(bool),
+    String(String),
+}
+
+impl Display for ProfileSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfileSetting::Bool(value) => write!(f, "{value}"),
+            ProfileSetting::String(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// A `[package.metadata.hyperlight.bin.<name>]` table, applied to the binary target
+/// `<name>` of the package.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BinMetadata {
+    /// Extra arguments for the linker when linking this binary
+    pub link_args: Vec<String>,
+    /// Overrides the `[package.metadata.hyperlight.export]` table for this binary
+    pub export: ExportMetadata,
+}
+
+/// The `[package.metadata.hyperlight.export]` table of a package manifest.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ExportMetadata {
+    /// Template for the names of exported binaries, e.g.,
+    /// `"{name}-{version}-{target}-{profile}.elf"`
+    pub name: Option<String>,
+}
+
+/// The `[package.metadata.hyperlight.interface]` table of a guest manifest.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct InterfaceMetadata {
+    /// Guest functions the guest registers, by name
+    pub guest_functions: BTreeMap<String, FunctionSignature>,
+    /// Host functions the guest calls, by name, used by `cargo hyperlight generate`
+    pub host_functions: BTreeMap<String, FunctionSignature>,
+}
+
+/// The signature of a function in an [`InterfaceMetadata`], using the names of the
+/// `ParameterType` and `ReturnType` variants of `hyperlight-common`, e.g., `"String"`.
+///
+/// Either part can be left out to only check that the function is registered.
+#[derive(serde::Deserialize, serde::Serialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct FunctionSignature {
+    pub parameters: Option<Vec<String>>,
+    #[serde(rename = "return")]
+    pub return_type: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataPackage {
+    id: String,
+    manifest_path: PathBuf,
+    dependencies: Vec<CargoMetadataDependency>,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataDependency {
+    name: String,
+    kind: Option<String>,
+}
+
+/// Returns the output of `cargo metadata` augmented with a `hyperlight` field
+/// containing the resolved hyperlight configuration.
+///
+/// The `hyperlight` field contains:
+/// * `target`: the resolved hyperlight target triple
+/// * `target_directory`: the resolved target directory
+/// * `sysroot`: the path of the hyperlight sysroot
+/// * `packages`: a map from the package id of each workspace member to
+///   * its `role`, which is `"guest"` for packages depending on `hyperlight-guest` or
+///     `hyperlight-guest-bin`, `"host"` for packages depending on `hyperlight-host`,
+///     or `null` otherwise
+///   * its `config`, the resolved `[package.metadata.hyperlight]` table, or the
+///     `[workspace.metadata.hyperlight]` one, see [`HyperlightMetadata::resolve`]
+///
+/// Packages outside the workspace, e.g., `hyperlight-guest-bin` itself, are left out.
+pub fn cargo_metadata(args: &Args, cargo_args: &[OsString]) -> Result<Value> {
+    let output = cargo_cmd(&args.env)?
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .arg("metadata")
+        .manifest_path(&args.manifest_path)
+        .arg("--format-version=1")
+        .args(cargo_args)
+        .checked_output()
+        .context("Failed to get cargo metadata")?;
+
+    let mut metadata: Value =
+        serde_json::from_slice(&output.stdout).context("Failed to parse cargo metadata")?;
+
+    let packages: Vec<CargoMetadataPackage> = serde_json::from_value(metadata["packages"].clone())
+        .context("Failed to parse cargo metadata packages")?;
+
+    let members = serde_json::from_value::<Vec<String>>(metadata["workspace_members"].clone())
+        .context("Failed to parse cargo metadata workspace members")?;
+    let workspace_root = PathBuf::from(metadata["workspace_root"].as_str().unwrap_or_default());
+
+    let packages = packages
+        .iter()
+        .filter(|pkg| members.contains(&pkg.id))
+        .map(|pkg| {
+            let depends_on = |names: &[&str]| {
+                pkg.dependencies
+                    .iter()
+                    .any(|dep| dep.kind.is_none() && names.contains(&dep.name.as_str()))
+            };
+            let role = if depends_on(&["hyperlight-guest", "hyperlight-guest-bin"]) {
+                json!("guest")
+            } else if depends_on(&["hyperlight-host"]) {
+                json!("host")
+            } else {
+                Value::Null
+            };
+            let dir = pkg.manifest_path.parent().unwrap_or(Path::new(""));
+            let config = HyperlightMetadata::resolve(
+                Some((&pkg.metadata, dir)),
+                (&metadata["metadata"], &workspace_root),
+            )?;
+            Ok((pkg.id.clone(), json!({ "role": role, "config": config })))
+        })
+        .collect::<Result<serde_json::Map<_, _>>>()?;
+
+    metadata["hyperlight"] = json!({
+        "target": args.target,
+        "target_directory": args.target_dir,
+        "sysroot": args.sysroot_dir(),
+        "packages": packages,
+    });
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_settings_fall_back_like_cargo() {
+        let config: HyperlightMetadata = serde_json::from_value(json!({
+            "profile": {
+                "dev": { "rustflags": ["-Cdebug-assertions"], "strip": false },
+                "release": { "cflags": ["-DNDEBUG"], "strip": "symbols", "lto": true },
+            }
+        }))
+        .unwrap();
+
+        let dev = config.profile("dev");
+        assert_eq!(dev.rustflags, ["-Cdebug-assertions"]);
+        assert_eq!(dev.strip, Some(ProfileSetting::Bool(false)));
+        assert_eq!(config.profile("test"), dev);
+
+        let release = config.profile("bench");
+        assert_eq!(release.cflags, ["-DNDEBUG"]);
+        assert_eq!(release.strip.unwrap().to_string(), "symbols");
+        assert_eq!(release.lto.unwrap().to_string(), "true");
+
+        assert_eq!(config.profile("custom"), ProfileMetadata::default());
+    }
+
+    #[test]
+    fn package_config_replaces_the_workspace_config() {
+        let workspace = json!({ "hyperlight": { "required-version": ">=0.1", "libm": true } });
+        let package = json!({ "hyperlight": { "include-dirs": ["inc"] } });
+
+        let config = HyperlightMetadata::resolve(
+            Some((&package, Path::new("/ws/guest"))),
+            (&workspace, Path::new("/ws")),
+        )
+        .unwrap();
+        assert!(!config.libm);
+        assert_eq!(config.include_dirs, [Path::new("/ws/guest/inc")]);
+        assert_eq!(config.required_version.as_deref(), Some(">=0.1"));
+
+        let config = HyperlightMetadata::resolve(
+            Some((&Value::Null, Path::new("/ws/guest"))),
+            (&workspace, Path::new("/ws")),
+        )
+        .unwrap();
+        assert!(config.libm);
+    }
+
+    #[test]
+    fn cargo_metadata_has_the_config_of_workspace_members() {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-hyperlight-metadata-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"guest\"\nedition = \"2024\"\n\n\
+            [package.metadata.hyperlight]\nlibm = true\ncode-model = \"large\"\n\n[workspace]\n",
+        )
+        .unwrap();
+        let args = Args::parse(
+            ["build"],
+            std::env::vars_os(),
+            Some(&dir),
+            crate::cli::Warning::ERROR,
+        )
+        .unwrap();
+        let metadata = cargo_metadata(&args, &["--no-deps".into()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let metadata = metadata.unwrap();
+        let packages = metadata["hyperlight"]["packages"].as_object().unwrap();
+        assert_eq!(packages.len(), 1);
+        let package = packages.values().next().unwrap();
+        assert_eq!(package["role"], Value::Null);
+        assert_eq!(package["config"]["libm"], true);
+        assert_eq!(package["config"]["code-model"], "large");
+    }
+}
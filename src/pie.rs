@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail};
+use object::elf::{self, RelocationType};
+use object::read::elf::{ElfFile64, ProgramHeader as _};
+use object::{Endianness, Object as _, ObjectKind, RelocationFlags};
+
+use crate::artifact::Artifact;
+
+/// Dynamic relocations the Hyperlight loader applies when it loads a guest at an
+/// address other than the one it was linked at.
+const SUPPORTED: &[RelocationType] = &[elf::R_X86_64_NONE, elf::R_X86_64_RELATIVE];
+
+/// Returns the name of the x86-64 relocation type `r_type`.
+fn relocation_name(r_type: RelocationType) -> String {
+    let name = match r_type {
+        elf::R_X86_64_NONE => "R_X86_64_NONE",
+        elf::R_X86_64_64 => "R_X86_64_64",
+        elf::R_X86_64_COPY => "R_X86_64_COPY",
+        elf::R_X86_64_GLOB_DAT => "R_X86_64_GLOB_DAT",
+        elf::R_X86_64_JUMP_SLOT => "R_X86_64_JUMP_SLOT",
+        elf::R_X86_64_RELATIVE => "R_X86_64_RELATIVE",
+        elf::R_X86_64_DTPMOD64 => "R_X86_64_DTPMOD64",
+        elf::R_X86_64_DTPOFF64 => "R_X86_64_DTPOFF64",
+        elf::R_X86_64_TPOFF64 => "R_X86_64_TPOFF64",
+        elf::R_X86_64_IRELATIVE => "R_X86_64_IRELATIVE",
+        RelocationType(r_type) => return format!("relocation type {r_type}"),
+    };
+    name.into()
+}
+
+/// Returns the problems with the dynamic relocations `r_types` of a guest, one for
+/// each kind of relocation the Hyperlight loader can't process.
+fn relocation_problems(r_types: impl IntoIterator<Item = RelocationType>) -> Vec<String> {
+    let mut unsupported = BTreeMap::<u32, usize>::new();
+    for r_type in r_types {
+        if !SUPPORTED.contains(&r_type) {
+            *unsupported.entry(r_type.0).or_default() += 1;
+        }
+    }
+    unsupported
+        .into_iter()
+        .map(|(r_type, count)| {
+            let name = relocation_name(RelocationType(r_type));
+            format!("{count} {name} relocation(s), which need a dynamic loader")
+        })
+        .collect()
+}
+
+/// Returns the reasons the Hyperlight loader couldn't load the ELF file in `data` at a
+/// randomized address.
+fn problems(data: &[u8]) -> Result<Vec<String>> {
+    let file = ElfFile64::<Endianness>::parse(data).context("Failed to parse ELF file")?;
+    let mut problems = vec![];
+    if file.kind() != ObjectKind::Dynamic {
+        problems.push("not position independent, it can only be loaded at its link address".into());
+    }
+    let interp = file
+        .elf_program_headers()
+        .iter()
+        .any(|header| header.p_type(file.endian()) == elf::PT_INTERP);
+    if interp {
+        problems.push("requests a dynamic loader (PT_INTERP)".into());
+    }
+    let r_types = file
+        .dynamic_relocations()
+        .into_iter()
+        .flatten()
+        .filter_map(|(_, relocation)| match relocation.flags() {
+            RelocationFlags::Elf { r_type } => Some(r_type),
+            _ => None,
+        });
+    problems.extend(relocation_problems(r_types));
+    Ok(problems)
+}
+
+/// Checks that the guest `artifacts` are static position independent executables
+/// whose relocations the Hyperlight loader can process.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all))]
+pub fn check(artifacts: &[Artifact]) -> Result<()> {
+    let mut report = vec![];
+    for artifact in artifacts {
+        let data = std::fs::read(&artifact.executable)
+            .with_context(|| format!("Failed to read {:?}", artifact.executable))?;
+        let problems =
+            problems(&data).with_context(|| format!("Failed to check {:?}", artifact.name))?;
+        report.extend(
+            problems
+                .into_iter()
+                .map(|problem| format!(" * {}: {problem}", artifact.name)),
+        );
+    }
+    if !report.is_empty() {
+        bail!(
+            "Some guests can't be loaded at a randomized address:\n{}\n\
+            Hyperlight only applies R_X86_64_RELATIVE relocations, make sure nothing \
+            overrides the relocation model or adds `-no-pie` to the link arguments",
+            report.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_relative_relocations_are_supported() {
+        let r_types = [
+            elf::R_X86_64_RELATIVE,
+            elf::R_X86_64_GLOB_DAT,
+            elf::R_X86_64_RELATIVE,
+            elf::R_X86_64_GLOB_DAT,
+            RelocationType(99),
+        ];
+        assert_eq!(
+            relocation_problems(r_types),
+            [
+                "2 R_X86_64_GLOB_DAT relocation(s), which need a dynamic loader",
+                "1 relocation type 99 relocation(s), which need a dynamic loader",
+            ]
+        );
+        assert!(relocation_problems([elf::R_X86_64_RELATIVE]).is_empty());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", target_env = "gnu"))]
+    fn dynamically_linked_executables_are_rejected() {
+        let data = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let problems = problems(&data).unwrap();
+        assert!(problems.iter().any(|problem| problem.contains("PT_INTERP")));
+    }
+}
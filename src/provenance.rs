@@ -0,0 +1,175 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::artifact::Artifact;
+use crate::cargo_cmd::{self, CargoCmd as _};
+use crate::cli::{Args, warning};
+use crate::{atomic, toolchain};
+
+/// Extension of the provenance file written next to every guest binary.
+pub const EXTENSION: &str = "provenance.json";
+
+/// Where and how a guest binary was built, to identify deployed binaries later on.
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Provenance {
+    /// Name of the binary target
+    name: String,
+    /// Hash of the binary, like in [`BuildReport`](crate::BuildReport)
+    hash: String,
+    /// Seconds since the Unix epoch, or `SOURCE_DATE_EPOCH` if set
+    built_at: u64,
+    /// Commit of the repository containing the package, if it's in one
+    git: Option<Git>,
+    /// Version of cargo-hyperlight that built the binary
+    cargo_hyperlight: String,
+    /// First line of `rustc -vV`, e.g., `rustc 1.89.0-nightly (60dabef95 2025-05-19)`
+    rustc: String,
+    /// First line of `clang --version`, if clang was found
+    clang: Option<String>,
+    target: String,
+    /// Hash of the target spec
+    target_spec: String,
+    profile: String,
+    /// Rust flags the guest was built with
+    rustflags: String,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct Git {
+    commit: String,
+    /// Whether there were uncommitted changes
+    dirty: bool,
+}
+
+/// Returns the first line of the output of `command`.
+fn first_line(command: &mut Command) -> Result<String> {
+    let output = command.checked_output()?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Returns the commit of the repository containing `manifest_path`, if it's in one.
+fn git(manifest_path: &Path) -> Option<Git> {
+    let dir = manifest_path.parent()?;
+    let git = || {
+        let mut command = Command::new("git");
+        command.current_dir(dir);
+        command
+    };
+    let commit = first_line(git().arg("rev-parse").arg("HEAD")).ok()?;
+    let status = git()
+        .arg("status")
+        .arg("--porcelain")
+        .arg("--untracked-files=no")
+        .checked_output()
+        .ok()?;
+    Some(Git {
+        commit,
+        dirty: !status.stdout.is_empty(),
+    })
+}
+
+/// Returns when the build happened, honoring `SOURCE_DATE_EPOCH` for reproducible builds.
+fn built_at(args: &Args) -> u64 {
+    args.env
+        .get(OsStr::new("SOURCE_DATE_EPOCH"))
+        .and_then(|epoch| epoch.to_str()?.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs())
+        })
+}
+
+/// Writes the provenance of every artifact next to it, as
+/// `<executable>.provenance.json`, so that it's exported together with the executable.
+///
+/// `command` is the cargo command that built the artifacts.
+pub fn write(args: &Args, command: &Command, artifacts: &[Artifact]) -> Result<()> {
+    let rustc = toolchain::rustc_version_verbose(&args.env)?;
+    let rustc = rustc.lines().next().unwrap_or_default().to_string();
+    let clang = match &args.clang {
+        Some(clang) => Some(
+            first_line(Command::new(clang).arg("--version"))
+                .with_context(|| format!("Failed to get the version of {}", clang.display()))?,
+        ),
+        None => None,
+    };
+    let target_spec = toolchain::hash_file(&args.triplet_dir().join("target.json"))?;
+    let rustflags = cargo_cmd::target_rustflags(command, &args.target).unwrap_or_default();
+    let built_at = built_at(args);
+
+    for artifact in artifacts {
+        let git = git(&artifact.manifest_path);
+        if git.is_none() {
+            warning(
+                "provenance-without-git",
+                format!(
+                    "{:?} is not in a git repository, its provenance has no commit",
+                    artifact.name
+                ),
+            );
+        }
+        let provenance = Provenance {
+            name: artifact.name.clone(),
+            hash: toolchain::hash_file(&artifact.executable)?,
+            built_at,
+            git,
+            cargo_hyperlight: env!("CARGO_PKG_VERSION").to_string(),
+            rustc: rustc.clone(),
+            clang: clang.clone(),
+            target: args.target.clone(),
+            target_spec: target_spec.clone(),
+            profile: args.profile.clone(),
+            rustflags: rustflags.join(" "),
+        };
+
+        let mut path = artifact.executable.clone().into_os_string();
+        path.push(".");
+        path.push(EXTENSION);
+        let json =
+            serde_json::to_string_pretty(&provenance).context("Failed to serialize provenance")?;
+        atomic::write(&path, json).with_context(|| format!("Failed to write {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provenance_is_kebab_case_json() {
+        let provenance = Provenance {
+            name: "guest".into(),
+            hash: "0123456789abcdef".into(),
+            built_at: 1_700_000_000,
+            git: Some(Git {
+                commit: "abc123".into(),
+                dirty: true,
+            }),
+            cargo_hyperlight: "0.1.0".into(),
+            rustc: "rustc 1.89.0-nightly (60dabef95 2025-05-19)".into(),
+            clang: None,
+            target: "x86_64-hyperlight-none".into(),
+            target_spec: "fedcba9876543210".into(),
+            profile: "release".into(),
+            rustflags: "--sysroot /sysroot".into(),
+        };
+        let json = serde_json::to_value(&provenance).unwrap();
+        assert_eq!(json["built-at"], 1_700_000_000);
+        assert_eq!(json["git"]["commit"], "abc123");
+        assert_eq!(json["git"]["dirty"], true);
+        assert_eq!(json["target-spec"], "fedcba9876543210");
+        assert!(json["clang"].is_null());
+    }
+}
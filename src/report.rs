@@ -0,0 +1,99 @@
+use std::io::Read as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+/// Summary of a guest build, returned by [`Command::status_with_report`].
+///
+/// [`Command::status_with_report`]: crate::Command::status_with_report
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BuildReport {
+    /// Time spent resolving the configuration (target, target directory, metadata)
+    pub resolve_time: Duration,
+    /// Time spent preparing the sysroot and the C toolchain
+    pub sysroot_time: Duration,
+    /// Time spent running cargo
+    pub cargo_time: Duration,
+    /// Time spent copying the artifacts to `--out-dir`, if requested
+    pub export_time: Duration,
+    /// Whether the sysroot had to be rebuilt
+    pub sysroot_rebuilt: bool,
+    /// Executables produced by the build
+    pub artifacts: Vec<ArtifactReport>,
+    /// Warnings emitted by cargo-hyperlight during the build
+    pub warnings: Vec<String>,
+}
+
+/// A warning emitted by cargo-hyperlight, passed to [`Command::warning_handler`].
+///
+/// [`Command::warning_handler`]: crate::Command::warning_handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Warning {
+    /// Stable identifier of the kind of warning, e.g., `"target-dir"` when the
+    /// target directory could not be resolved
+    pub code: &'static str,
+    /// Description of the warning, as it would be printed to stderr
+    pub message: String,
+    /// The value used instead, for warnings about settings that could not be resolved
+    pub default: Option<String>,
+}
+
+/// An executable produced by a guest build.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ArtifactReport {
+    /// Name of the binary target
+    pub name: String,
+    /// Path to the executable
+    pub path: PathBuf,
+    /// Size of the executable in bytes
+    pub size: u64,
+    /// 64-bit FNV-1a hash of the executable, as 16 hex digits.
+    /// This is stable across runs and versions, but it is not a cryptographic hash.
+    pub hash: String,
+}
+
+impl ArtifactReport {
+    pub(crate) fn new(name: String, path: PathBuf) -> Result<Self> {
+        let (size, hash) = hash_file(&path).with_context(|| format!("Failed to hash {path:?}"))?;
+        Ok(Self {
+            name,
+            path,
+            size,
+            hash: format!("{hash:016x}"),
+        })
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// Continues the FNV-1a `hash` with `bytes`.
+fn fnv1a_update(hash: u64, bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x100000001b3;
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Returns the FNV-1a hash of `bytes`, the same as [`hash_file`] for a file with them.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_update(FNV_OFFSET_BASIS, bytes)
+}
+
+/// Returns the size and FNV-1a hash of the file at `path`.
+pub(crate) fn hash_file(path: &Path) -> std::io::Result<(u64, u64)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let (mut size, mut hash) = (0, FNV_OFFSET_BASIS);
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok((size, hash));
+        }
+        hash = fnv1a_update(hash, &buf[..n]);
+        size += n as u64;
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+
+use crate::cli::{Args, note};
+use crate::{atomic, metadata};
+
+const BEGIN_MARKER: &str = "# BEGIN cargo-hyperlight, managed by `cargo hyperlight setup`";
+const END_MARKER: &str = "# END cargo-hyperlight";
+
+/// Tables written by [`setup`], which can't be declared twice in the same file.
+const TABLES: &[&str] = &["[build]", "[profile.dev]", "[profile.release]"];
+
+fn config_block(args: &Args) -> String {
+    format!(
+        r#"{BEGIN_MARKER}
+# Guests need the hyperlight sysroot and toolchain, build them with `cargo hyperlight build`.
+# Plain `cargo build` fails to find the "{target}" target specification.
+[build]
+target = "{target}"
+
+[profile.dev]
+panic = "abort"
+
+[profile.release]
+panic = "abort"
+{END_MARKER}
+"#,
+        target = args.target
+    )
+}
+
+/// Returns the contents of `config` with the cargo-hyperlight block replaced by `block`,
+/// or with `block` appended if `config` has none.
+fn update_config(config: &str, block: &str) -> Result<String> {
+    replace_block(
+        config,
+        [BEGIN_MARKER, END_MARKER],
+        TABLES,
+        block,
+        "cargo hyperlight setup",
+    )
+}
+
+/// Returns the contents of `config` with the block between the `markers` replaced by
+/// `block`, or with `block` appended if `config` has none.
+///
+/// The `tables` of `block` can't be declared outside of it, those have to be removed
+/// before running `command` again.
+pub fn replace_block(
+    config: &str,
+    [begin_marker, end_marker]: [&str; 2],
+    tables: &[&str],
+    block: &str,
+    command: &str,
+) -> Result<String> {
+    let (before, after) = match (config.find(begin_marker), config.find(end_marker)) {
+        (Some(begin), Some(end)) if begin < end => {
+            let end = end + end_marker.len();
+            let end = end + config[end..].find('\n').map_or(0, |n| n + 1);
+            (&config[..begin], &config[end..])
+        }
+        (None, None) => (config, ""),
+        _ => bail!("Malformed `{command}` block in cargo config"),
+    };
+
+    let conflicts = before
+        .lines()
+        .chain(after.lines())
+        .map(str::trim)
+        .filter(|line| tables.contains(line))
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        bail!(
+            "The cargo config already declares {}, remove those tables and run `{command}` again",
+            conflicts.join(", ")
+        );
+    }
+
+    let mut config = before.to_string();
+    if !config.is_empty() && !config.ends_with("\n\n") {
+        config.push_str(if config.ends_with('\n') { "\n" } else { "\n\n" });
+    }
+    config.push_str(block);
+    config.push_str(after);
+    Ok(config)
+}
+
+/// Writes (or updates) the cargo-hyperlight block of the workspace's `.cargo/config.toml`,
+/// setting the hyperlight target as the default build target and the recommended profile
+/// settings for guests.
+///
+/// Running it again only replaces the block, preserving the rest of the file.
+pub fn setup(args: &Args, dry_run: bool) -> Result<()> {
+    let metadata = metadata::cargo_metadata(args, &["--no-deps".into()])?;
+    let workspace_root = metadata["workspace_root"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("Failed to parse cargo metadata workspace root")?;
+
+    let path = workspace_root.join(".cargo").join("config.toml");
+    let current = match std::fs::read_to_string(&path) {
+        Ok(config) => config,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {path:?}")),
+    };
+
+    let config = update_config(&current, &config_block(args))?;
+
+    if dry_run {
+        print!("{config}");
+        return Ok(());
+    }
+
+    if config == current {
+        note(format!("{} is up to date", path.display()));
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(path.parent().unwrap()).context("Failed to create .cargo directory")?;
+    atomic::write(&path, config).context("Failed to write cargo config")?;
+    note(format!("updated {}", path.display()));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(target: &str) -> String {
+        format!("{BEGIN_MARKER}\n[build]\ntarget = \"{target}\"\n{END_MARKER}\n")
+    }
+
+    #[test]
+    fn update_config_writes_block_to_empty_file() {
+        let config = update_config("", &block("a")).unwrap();
+        assert_eq!(config, block("a"));
+    }
+
+    #[test]
+    fn update_config_appends_block_after_existing_config() {
+        let config = update_config("[net]\noffline = true", &block("a")).unwrap();
+        assert_eq!(config, format!("[net]\noffline = true\n\n{}", block("a")));
+    }
+
+    #[test]
+    fn update_config_replaces_existing_block() {
+        let current = format!("[net]\noffline = true\n\n{}", block("a"));
+        let config = update_config(&current, &block("b")).unwrap();
+        assert_eq!(config, format!("[net]\noffline = true\n\n{}", block("b")));
+        // running it again doesn't change anything
+        assert_eq!(update_config(&config, &block("b")).unwrap(), config);
+    }
+
+    #[test]
+    fn update_config_keeps_config_around_block() {
+        let current = format!(
+            "[net]\noffline = true\n\n{}\n[alias]\nb = \"build\"\n",
+            block("a")
+        );
+        let config = update_config(&current, &block("b")).unwrap();
+        assert_eq!(
+            config,
+            format!(
+                "[net]\noffline = true\n\n{}\n[alias]\nb = \"build\"\n",
+                block("b")
+            )
+        );
+    }
+
+    #[test]
+    fn update_config_rejects_conflicting_tables() {
+        let err = update_config("[build]\njobs = 4\n", &block("a")).unwrap_err();
+        assert!(err.to_string().contains("[build]"), "{err}");
+    }
+
+    #[test]
+    fn update_config_rejects_malformed_block() {
+        let current = format!("{BEGIN_MARKER}\n[build]\ntarget = \"a\"\n");
+        assert!(update_config(&current, &block("b")).is_err());
+        let current = format!("{END_MARKER}\n{BEGIN_MARKER}\n");
+        assert!(update_config(&current, &block("b")).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, ensure};
+use clap::Parser;
+
+use crate::artifact::Executable;
+use crate::cli::{note, split_args};
+use crate::command::Command;
+use crate::failure::{Failure, WithFailure as _};
+use crate::{
+    add, analyze, build_all, cache, codegen, expand, features, interface, metadata, setup, sysroot,
+    toolchain, vendor,
+};
+
+/// Subcommands implemented by cargo-hyperlight itself, rather than forwarded to cargo.
+#[derive(Parser)]
+#[command(
+    name = "cargo hyperlight",
+    no_binary_name = true,
+    disable_help_subcommand = true
+)]
+pub enum Subcommand {
+    /// Print information about the guest build without building anything
+    #[command(subcommand)]
+    Print(Print),
+
+    /// Check that `std` features don't leak into guest builds through feature unification
+    CheckFeatures {
+        /// Guest package to check, defaults to all the guests in the workspace
+        #[arg(short, long, value_name = "SPEC")]
+        package: Vec<String>,
+
+        /// Also fail on `alloc` features, for guests without a global allocator
+        #[arg(long)]
+        deny_alloc: bool,
+    },
+
+    /// Check that the guest functions registered by guests match the
+    /// `[package.metadata.hyperlight.interface]` table of their manifest
+    CheckInterface {
+        /// Guest package to check, defaults to all the guests in the workspace
+        #[arg(short, long, value_name = "SPEC")]
+        package: Vec<String>,
+    },
+
+    /// Generate the guest and host code for the `[package.metadata.hyperlight.interface]`
+    /// table of guests, in `--out-dir` or `target/hyperlight/interface`
+    Generate {
+        /// Guest package to generate the code for, defaults to all the guests in the workspace
+        #[arg(short, long, value_name = "SPEC")]
+        package: Vec<String>,
+
+        /// Also generate `host_functions.h`, to call the host functions from C code
+        #[arg(long)]
+        c_header: bool,
+    },
+
+    /// Add hyperlight-guest, hyperlight-guest-bin and hyperlight-common to a guest at
+    /// the same version, matching hyperlight-host if the workspace uses it
+    Add {
+        /// Package to add the dependencies to
+        #[arg(short, long, value_name = "SPEC")]
+        package: Option<String>,
+
+        /// Version of the hyperlight crates to add
+        #[arg(long, value_name = "VERSION")]
+        version: Option<String>,
+    },
+
+    /// Write the hyperlight target and recommended settings to the workspace's
+    /// `.cargo/config.toml`
+    Setup {
+        /// Print the resulting config instead of writing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Vendor the dependencies of the workspace and of the standard library, and
+    /// configure the workspace's `.cargo/config.toml` to build guests offline from them
+    Vendor {
+        /// Directory to vendor the sources to
+        #[arg(default_value = "vendor")]
+        path: PathBuf,
+    },
+
+    /// Export the prepared sysroot to a tarball, or import one, to distribute a prebuilt
+    /// sysroot to other machines, or remove its libraries to rebuild them
+    #[command(subcommand)]
+    Sysroot(Sysroot),
+
+    /// Inspect the sysroot cache of the target directory
+    #[command(subcommand)]
+    Cache(Cache),
+
+    /// Build the guests for the hyperlight target, then the hosts natively with the path
+    /// of every guest binary in `CARGO_HYPERLIGHT_GUEST_<NAME>`
+    BuildAll {
+        /// Guest package to build, defaults to all the guests in the workspace
+        #[arg(long, value_name = "SPEC")]
+        guest: Vec<String>,
+
+        /// Host package to build, defaults to all the hosts in the workspace
+        #[arg(long, value_name = "SPEC")]
+        host: Vec<String>,
+
+        /// Use the release profile
+        #[arg(short, long, conflicts_with = "profile")]
+        release: bool,
+
+        /// Name of the profile
+        #[arg(long, value_name = "PROFILE-NAME")]
+        profile: Option<String>,
+
+        /// Run the host after building it
+        #[arg(long)]
+        run: bool,
+
+        /// Keep building the other guests after one fails, and report which ones failed
+        #[arg(long)]
+        keep_going: bool,
+
+        /// Arguments for the host, with `--run`
+        #[arg(last = true, requires = "run")]
+        run_args: Vec<OsString>,
+    },
+
+    /// Report how much each crate contributes to a guest binary that was already built,
+    /// and the dependencies that contribute nothing
+    Analyze {
+        /// Package containing the binary
+        #[arg(short, long, value_name = "SPEC")]
+        package: Option<String>,
+
+        /// Name of the binary target
+        #[arg(long, value_name = "NAME")]
+        bin: Option<String>,
+
+        /// Name of the example target
+        #[arg(long, value_name = "NAME", conflicts_with = "bin")]
+        example: Option<String>,
+
+        /// Use the release profile
+        #[arg(short, long, conflicts_with = "profile")]
+        release: bool,
+
+        /// Name of the profile
+        #[arg(long, value_name = "PROFILE-NAME")]
+        profile: Option<String>,
+
+        /// Also report the floating-point and SIMD instructions, by crate and function
+        #[arg(long)]
+        float: bool,
+
+        /// Fail if the guest has floating-point or SIMD instructions, implies `--float`
+        #[arg(long)]
+        deny_float: bool,
+    },
+
+    /// Print the macro expanded source of a guest crate, with `cargo expand` if it's
+    /// installed, or else with `rustc -Zunpretty=expanded`
+    Expand {
+        /// Arguments for `cargo expand`, or for `cargo rustc` without it, e.g., `--lib`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+
+    /// Output cargo's metadata augmented with hyperlight-specific information
+    Metadata {
+        /// Arguments to pass to `cargo metadata`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum Print {
+    /// Print the path where the guest binary will be placed
+    ArtifactPath {
+        /// Package containing the binary
+        #[arg(short, long, value_name = "SPEC")]
+        package: Option<String>,
+
+        /// Name of the binary target
+        #[arg(long, value_name = "NAME")]
+        bin: Option<String>,
+
+        /// Name of the example target
+        #[arg(long, value_name = "NAME", conflicts_with = "bin")]
+        example: Option<String>,
+
+        /// Use the release profile
+        #[arg(short, long, conflicts_with = "profile")]
+        release: bool,
+
+        /// Name of the profile
+        #[arg(long, value_name = "PROFILE-NAME")]
+        profile: Option<String>,
+    },
+
+    /// Print the flags used to compile C code for the guest, in the format of
+    /// `BINDGEN_EXTRA_CLANG_ARGS`
+    ClangArgs,
+
+    /// Print the spec of the hyperlight target, as installed in the sysroot
+    TargetSpecJson,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Sysroot {
+    /// Prepare the sysroot and write it, with its fingerprint, to a gzipped tarball
+    Export {
+        /// Path of the tarball to write
+        file: PathBuf,
+    },
+
+    /// Extract a sysroot tarball into the target directory, after checking that its
+    /// fingerprint matches this build
+    Import {
+        /// Path of the tarball to read
+        file: PathBuf,
+    },
+
+    /// Remove the libraries of the sysroot and their build, so that the next build
+    /// rebuilds them from scratch
+    Clean,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Cache {
+    /// Print the disk usage of the sysroot builds, the toolchain and the exported
+    /// artifacts, with how often each was reused or rebuilt and when it was last used
+    Stats,
+}
+
+const SUBCOMMANDS: &[&str] = &[
+    "print",
+    "check-features",
+    "check-interface",
+    "generate",
+    "add",
+    "setup",
+    "vendor",
+    "sysroot",
+    "cache",
+    "analyze",
+    "build-all",
+    "expand",
+    "metadata",
+];
+
+impl Subcommand {
+    /// Returns whether `args` invoke a cargo-hyperlight subcommand.
+    ///
+    /// Flags handled by [`Args`](crate::cli::Args) are skipped.
+    pub fn is_subcommand(args: impl IntoIterator<Item = impl Into<OsString>>) -> bool {
+        let rest = split_args(args).rest;
+        rest.first()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| SUBCOMMANDS.contains(&name))
+    }
+
+    /// Parses and runs the cargo-hyperlight subcommand in the arguments of `command`,
+    /// if any, returning whether there was one.
+    ///
+    /// Flags handled by [`Args`](crate::cli::Args) are removed before parsing.
+    /// `--help` prints the help of the subcommand, and invalid arguments are returned
+    /// as an error.
+    pub fn run_from_args(command: &Command) -> Result<bool> {
+        if !Self::is_subcommand(command.get_args()) {
+            return Ok(false);
+        }
+
+        match Subcommand::try_parse_from(split_args(command.get_args()).rest) {
+            Ok(subcommand) => subcommand.run(command)?,
+            // --help and --version are reported as errors, but aren't failures
+            Err(err) if !err.use_stderr() => err.print().context("Failed to print help")?,
+            Err(err) => return Err(err).failure(Failure::Config),
+        }
+        Ok(true)
+    }
+
+    pub fn run(self, command: &Command) -> Result<()> {
+        match self {
+            Subcommand::Print(Print::ArtifactPath {
+                package,
+                bin,
+                example,
+                release,
+                profile,
+            }) => {
+                let profile = if release {
+                    Some("release")
+                } else {
+                    profile.as_deref()
+                };
+                let path = match &example {
+                    Some(example) => {
+                        command.target_example_path(package.as_deref(), example, profile)?
+                    }
+                    None => {
+                        command.target_artifact_path(package.as_deref(), bin.as_deref(), profile)?
+                    }
+                };
+                println!("{}", path.display());
+            }
+            Subcommand::Print(Print::ClangArgs) => {
+                let args = command.build_args()?;
+                println!("{}", toolchain::cflags(&args).to_string_lossy());
+            }
+            Subcommand::Print(Print::TargetSpecJson) => {
+                let spec = sysroot::target_spec(&command.build_args()?)?;
+                println!("{}", serde_json::to_string_pretty(&spec)?);
+            }
+            Subcommand::CheckFeatures {
+                package,
+                deny_alloc,
+            } => {
+                let args = command.build_args()?;
+                args.prepare_sysroot()
+                    .context("Failed to prepare sysroot")?;
+                features::check(&args, &package, deny_alloc).failure(Failure::Validation)?;
+            }
+            Subcommand::CheckInterface { package } => {
+                interface::check(&command.build_args()?, &package).failure(Failure::Validation)?;
+            }
+            Subcommand::Generate { package, c_header } => {
+                codegen::generate(&command.build_args()?, &package, c_header)?;
+            }
+            Subcommand::Add { package, version } => {
+                add::add(
+                    &command.build_args()?,
+                    package.as_deref(),
+                    version.as_deref(),
+                )?;
+            }
+            Subcommand::Setup { dry_run } => {
+                setup::setup(&command.build_args()?, dry_run)?;
+            }
+            Subcommand::Vendor { path } => {
+                vendor::vendor(&command.build_args()?, &path)?;
+            }
+            Subcommand::Sysroot(Sysroot::Export { file }) => {
+                let args = command.build_args()?;
+                args.prepare_sysroot()
+                    .context("Failed to prepare sysroot")?;
+                let file = args.current_dir.join(file);
+                sysroot::export(&args, &file)?;
+                note(format!("exported the sysroot to {}", file.display()));
+            }
+            Subcommand::Sysroot(Sysroot::Import { file }) => {
+                let args = command.build_args()?;
+                let file = args.current_dir.join(file);
+                sysroot::import(&args, &file)?;
+                note(format!(
+                    "imported the sysroot to {}",
+                    args.sysroot_dir().display()
+                ));
+            }
+            Subcommand::Sysroot(Sysroot::Clean) => {
+                let args = command.build_args()?;
+                ensure!(
+                    args.prebuilt_sysroot.is_none(),
+                    "A prebuilt sysroot is never written to, prepare or import it again instead"
+                );
+                sysroot::invalidate(&args)?;
+                note(format!(
+                    "removed the sysroot libraries in {}",
+                    args.sysroot_dir().display()
+                ));
+            }
+            Subcommand::Cache(Cache::Stats) => {
+                cache::stats(&command.build_args()?)?;
+            }
+            Subcommand::Analyze {
+                package,
+                bin,
+                example,
+                release,
+                profile,
+                float,
+                deny_float,
+            } => {
+                let profile = if release {
+                    Some("release")
+                } else {
+                    profile.as_deref()
+                };
+                let executable = match &example {
+                    Some(example) => Executable::Example(example),
+                    None => Executable::Bin(bin.as_deref()),
+                };
+                analyze::analyze(
+                    &command.build_args()?,
+                    package.as_deref(),
+                    executable,
+                    profile,
+                    float,
+                    deny_float,
+                )?;
+            }
+            Subcommand::BuildAll {
+                guest,
+                host,
+                release,
+                profile,
+                run,
+                keep_going,
+                run_args,
+            } => {
+                let profile = if release {
+                    Some("release")
+                } else {
+                    profile.as_deref()
+                };
+                build_all::build_all(
+                    command,
+                    &guest,
+                    &host,
+                    profile,
+                    run.then_some(run_args.as_slice()),
+                    keep_going,
+                )?;
+            }
+            Subcommand::Expand { args } => {
+                expand::expand(command, &args)?;
+            }
+            Subcommand::Metadata { args } => {
+                let metadata = metadata::cargo_metadata(&command.build_args()?, &args)?;
+                println!("{metadata}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,21 @@
+use std::ffi::OsStr;
+use std::io::{Read as _, Write as _};
+use std::iter;
 use std::ops::Not as _;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
 use anyhow::{Context, Result, bail, ensure};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use target_spec_json::TargetSpec;
 
 use crate::cargo_cmd::{CargoCmd, cargo_cmd};
-use crate::cli::Args;
+use crate::cli::{Args, note};
+use crate::failure::{Failure, WithFailure as _};
+use crate::fingerprint::{self, Fingerprint};
+use crate::{atomic, cc_wrapper, generated, link_map, timings, toolchain};
 
 const CARGO_TOML: &str = include_str!("dummy/_Cargo.toml");
 const LIB_RS: &str = include_str!("dummy/_lib.rs");
@@ -20,14 +30,171 @@ struct BuildPlan {
     invocations: Vec<Invocation>,
 }
 
-pub fn build(args: &Args) -> Result<()> {
-    let target_spec = match args.target.as_str() {
+/// Flags for cargo to build the sysroot, besides the target and the directories.
+const BUILD_FLAGS: &[&str] = &[
+    "-Zbuild-std=core,alloc",
+    "-Zbuild-std-features=compiler_builtins/mem",
+    "--release",
+];
+
+/// List of artifacts in the sysroot lib directory, written once they are all in place.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Manifest {
+    artifacts: Vec<String>,
+    /// How the artifacts were built, missing in the manifests of older versions
+    #[serde(default)]
+    build: Option<BuildInfo>,
+}
+
+/// What the sysroot artifacts were built with, besides the compiler and the flags
+/// that their names already depend on.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct BuildInfo {
+    /// Version of cargo-hyperlight that built the sysroot
+    cargo_hyperlight: String,
+    /// Hash of the target spec
+    target_spec: String,
+    /// The [`BUILD_FLAGS`]
+    flags: Vec<String>,
+}
+
+impl BuildInfo {
+    fn current(target_spec: &Path) -> Result<Self> {
+        Ok(Self {
+            cargo_hyperlight: env!("CARGO_PKG_VERSION").to_string(),
+            target_spec: toolchain::hash_file(target_spec)?,
+            flags: BUILD_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+        })
+    }
+
+    /// Describes why a sysroot built with `self` needs to be rebuilt for `current`.
+    fn outdated(&self, current: &Self) -> Option<String> {
+        if self.cargo_hyperlight != current.cargo_hyperlight {
+            Some(format!(
+                "the sysroot was built by cargo-hyperlight {}, rebuilding it with {}",
+                self.cargo_hyperlight, current.cargo_hyperlight
+            ))
+        } else if self != current {
+            Some(
+                "the target spec or the sysroot build flags changed, rebuilding the sysroot".into(),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// Installs the rust-src component for `toolchain`, unless it's already installed.
+pub fn ensure_rust_src(args: &Args, toolchain: &OsStr) -> Result<()> {
+    let installed = std::process::Command::new("rustup")
+        .env_clear()
+        .envs(args.env.iter())
+        .arg("component")
+        .arg("list")
+        .arg("--installed")
+        .arg("--toolchain")
+        .arg(toolchain)
+        .checked_output()
+        .is_ok_and(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|component| component.starts_with("rust-src"))
+        });
+
+    if installed {
+        return Ok(());
+    }
+
+    std::process::Command::new("rustup")
+        .env_clear()
+        .envs(args.env.iter())
+        .arg("--quiet")
+        .arg("component")
+        .arg("add")
+        .arg("rust-src")
+        .arg("--toolchain")
+        .arg(toolchain)
+        .checked_output()
+        .with_context(|| format!("Failed to install the rust-src component for {toolchain:?}"))?;
+
+    Ok(())
+}
+
+/// Returns the reasons the custom target spec `spec`, for the target `name`, can't be
+/// used to build hyperlight guests.
+fn custom_spec_problems(name: &str, spec: &serde_json::Value) -> Vec<String> {
+    let field = |key: &str| spec.get(key).and_then(|value| value.as_str());
+    let mut problems = vec![];
+
+    let arch = field("arch").unwrap_or_default();
+    if arch != "x86_64" {
+        problems.push(format!(
+            "`arch` is {arch:?}, but the C sources of hyperlight-guest-bin only cover \"x86_64\""
+        ));
+    }
+    if toolchain::target_arch(name) != arch {
+        problems.push(format!(
+            "the file name doesn't start with the architecture, e.g., `{arch}-{name}.json`"
+        ));
+    }
+    // rustc defaults to no operating system
+    let os = field("os").unwrap_or("none");
+    if os != "none" {
+        problems.push(format!(
+            "`os` is {os:?}, but hyperlight guests run without an operating system"
+        ));
+    }
+    if spec.pointer("/metadata/std") == Some(&serde_json::Value::Bool(true)) {
+        problems.push("`metadata.std` is true, but hyperlight guests are `no_std`".into());
+    }
+    if let Some(entry) = field("entry-name")
+        && entry != "entrypoint"
+    {
+        problems.push(format!(
+            "`entry-name` is {entry:?}, but hyperlight calls the guest's \"entrypoint\""
+        ));
+    }
+    problems
+}
+
+/// Reads the custom target spec at `path`, checking that it can be used to build
+/// hyperlight guests.
+fn custom_spec(args: &Args, path: &Path) -> Result<String> {
+    let spec = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read target spec {path:?}"))?;
+    let value = serde_json::from_str(&spec)
+        .with_context(|| format!("Failed to parse target spec {path:?}"))?;
+    let problems = custom_spec_problems(&args.target, &value);
+    if !problems.is_empty() {
+        bail!(
+            "The target spec {path:?} can't be used for hyperlight guests:\n{}",
+            fingerprint::list(&problems)
+        );
+    }
+    Ok(spec)
+}
+
+/// Returns the spec of the hyperlight target, i.e., the spec printed by rustc for the
+/// base target with cargo-hyperlight's overrides, as installed in the sysroot.
+///
+/// With `--target path/to/spec.json`, this is the custom spec, which is used as is.
+pub fn target_spec(args: &Args) -> Result<TargetSpec> {
+    if let Some(path) = &args.custom_target {
+        let spec = custom_spec(args, path)?;
+        return serde_json::from_str(&spec)
+            .with_context(|| format!("Failed to parse target spec {path:?}"));
+    }
+    Ok(match args.target.as_str() {
         "x86_64-hyperlight-none" => {
             let mut spec = get_spec(args, "x86_64-unknown-none")?;
             // entry_name seems to be ignored, use RUSTFLAGS with -Clink-args=-eentrypoint instead
             //spec.entry_name = Some("entrypoint".into());
-            spec.code_model = Some("small".into());
-            spec.linker = Some("rust-lld".into());
+            args.models.apply(&mut spec);
+            spec.linker = Some(match &args.linker {
+                Some(linker) => linker.to_string_lossy().into_owned(),
+                None => "rust-lld".into(),
+            });
             spec.linker_flavor = Some("gnu-lld".into());
             spec.pre_link_args =
                 Some([("gnu-lld".to_string(), vec!["-znostart-stop-gc".to_string()])].into());
@@ -38,6 +205,16 @@ pub fn build(args: &Args) -> Result<()> {
 Supported values are:
  * x86_64-hyperlight-none"
         ),
+    })
+}
+
+/// Builds the sysroot, returning whether it had to be rebuilt.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn build(args: &Args) -> Result<bool> {
+    let target_spec = match &args.custom_target {
+        // installed unchanged, as it can have fields that `TargetSpec` doesn't know
+        Some(path) => custom_spec(args, path)?,
+        None => serde_json::to_string_pretty(&target_spec(args)?).unwrap(),
     };
 
     let sysroot_dir = args.sysroot_dir();
@@ -47,14 +224,17 @@ Supported values are:
     let lib_dir = args.libs_dir();
     let build_plan_dir = args.build_plan_dir();
 
+    // A report left by an earlier build would be merged as if the sysroot was built now
+    let timings = timings::sysroot_report(args);
+    if timings.exists() {
+        std::fs::remove_file(&timings).context("Failed to remove sysroot timing report")?;
+    }
+
     std::fs::create_dir_all(&triplet_dir).context("Failed to create sysroot directories")?;
-    std::fs::write(
-        triplet_dir.join("target.json"),
-        serde_json::to_string_pretty(&target_spec).unwrap(),
-    )
-    .context("Failed to write target spec file")?;
+    generated::write(args, &triplet_dir.join("target.json"), target_spec)
+        .context("Failed to write target spec file")?;
 
-    let version = cargo_cmd()?
+    let version = cargo_cmd(&args.env)?
         .env_clear()
         .envs(args.env.iter())
         .current_dir(&args.current_dir)
@@ -72,25 +252,17 @@ Supported values are:
     let cargo_toml = CARGO_TOML.replace("0.0.0", version);
 
     std::fs::create_dir_all(&crate_dir).context("Failed to create target directory")?;
-    std::fs::write(crate_dir.join("Cargo.toml"), cargo_toml)
+    generated::write(args, &crate_dir.join("Cargo.toml"), cargo_toml)
         .context("Failed to write Cargo.toml")?;
-    std::fs::write(crate_dir.join("lib.rs"), LIB_RS).context("Failed to write lib.rs")?;
+    generated::write(args, &crate_dir.join("lib.rs"), LIB_RS).context("Failed to write lib.rs")?;
 
     // if we are using rustup, ensure that the rust-src component is installed
-    if let Some(rustup_toolchain) = std::env::var_os("RUSTUP_TOOLCHAIN") {
-        std::process::Command::new("rustup")
-            .arg("--quiet")
-            .arg("component")
-            .arg("add")
-            .arg("rust-src")
-            .arg("--toolchain")
-            .arg(rustup_toolchain)
-            .checked_output()
-            .context("Failed to get Rust's std lib sources")?;
+    if let Some(rustup_toolchain) = args.env.get(OsStr::new("RUSTUP_TOOLCHAIN")) {
+        ensure_rust_src(args, rustup_toolchain).failure(Failure::Toolchain)?;
     }
 
     // Use cargo build's build plan to get the list of artifacts
-    let build_plan = cargo_cmd()?
+    let build_plan = cargo_cmd(&args.env)?
         .env_clear()
         .envs(args.env.iter())
         .current_dir(&args.current_dir)
@@ -99,9 +271,7 @@ Supported values are:
         .target(&args.target)
         .manifest_path(&Some(crate_dir.join("Cargo.toml")))
         .target_dir(&build_plan_dir)
-        .arg("-Zbuild-std=core,alloc")
-        .arg("-Zbuild-std-features=compiler_builtins/mem")
-        .arg("--release")
+        .args(BUILD_FLAGS)
         .arg("-Zunstable-options")
         .arg("--build-plan")
         // build-plan is an unstable feature
@@ -138,35 +308,110 @@ Supported values are:
         );
     }
 
+    let expected = artifacts
+        .iter()
+        .filter_map(|f| Some(f.file_name()?.to_str()?.to_string()))
+        .collect::<Vec<_>>();
+
+    let manifest_path = sysroot_dir.join("manifest.json");
+    let manifest = std::fs::read(&manifest_path)
+        .ok()
+        .and_then(|manifest| serde_json::from_slice::<Manifest>(&manifest).ok());
+
+    // The artifact names don't depend on the target spec or on how cargo-hyperlight
+    // builds the sysroot, so a change in those needs a clean build.
+    let build = BuildInfo::current(&triplet_dir.join("target.json"))?;
+    let outdated = manifest
+        .as_ref()
+        .and_then(|manifest| match &manifest.build {
+            Some(previous) => previous.outdated(&build),
+            None => {
+                Some("the sysroot was built by an older cargo-hyperlight, rebuilding it".into())
+            }
+        });
+    if let Some(reason) = &outdated {
+        note(reason);
+        if target_dir.exists() {
+            std::fs::remove_dir_all(&target_dir)
+                .context("Failed to remove sysroot build directory")?;
+        }
+    }
+
+    // The manifest is written last, so a missing or outdated manifest means
+    // that the lib directory was never fully populated with these artifacts.
+    // The artifact names include a hash of the compiler and flags, so matching
+    // names are enough, even if the build directory is gone, e.g., when the
+    // sysroot was imported.
+    let up_to_date = outdated.is_none()
+        && manifest.is_some_and(|manifest| manifest.artifacts == expected)
+        && expected.iter().all(|f| lib_dir.join(f).exists());
+
+    if up_to_date {
+        #[cfg(feature = "tracing")]
+        tracing::debug!("sysroot is up to date");
+        return Ok(false);
+    }
+
     // check if any artifacts is missing
     let should_build = artifacts.iter().any(|f| !f.exists());
 
     if should_build {
         // Build the sysroot
-        let success = cargo_cmd()?
+        let mut command = cargo_cmd(&args.env)?;
+        command
             .env_clear()
             .envs(args.env.iter())
             .current_dir(&args.current_dir)
             .arg("build")
+            .args(args.timings.then_some("--timings"))
             .target(&args.target)
             .manifest_path(&Some(crate_dir.join("Cargo.toml")))
             .target_dir(&target_dir)
-            .arg("-Zbuild-std=core,alloc")
-            .arg("-Zbuild-std-features=compiler_builtins/mem")
-            .arg("--release")
+            .args(BUILD_FLAGS)
             // The core, alloc and compiler_builtins crates use unstable features
             .allow_unstable()
             .env_remove("RUSTC_WORKSPACE_WRAPPER")
-            .sysroot(&sysroot_dir)
-            .status()
-            .context("Failed to create sysroot cargo project")?
-            .success();
+            .sysroot(&sysroot_dir);
 
-        ensure!(success, "Failed to build sysroot");
+        if args.verbose > 0 {
+            let success = command
+                // stdout belongs to the user's cargo command, e.g., for `--message-format=json`
+                .stdout(std::io::stderr())
+                .status()
+                .context("Failed to create sysroot cargo project")?
+                .success();
+            ensure!(success, "Failed to build sysroot");
+        } else {
+            // the output of the sysroot build would interleave with the user's build, so
+            // it's only shown if the sysroot fails to build
+            note(format!(
+                "building the sysroot for {}, pass -v to see cargo's output",
+                args.target
+            ));
+            if console::colors_enabled_stderr() {
+                // keep the colors of the captured output for when it's shown
+                command.env("CARGO_TERM_COLOR", "always");
+            }
+            let output = command
+                .stdin(Stdio::null())
+                .output()
+                .context("Failed to create sysroot cargo project")?;
+            if !output.status.success() {
+                let mut stderr = std::io::stderr().lock();
+                let _ = stderr.write_all(&output.stdout);
+                let _ = stderr.write_all(&output.stderr);
+                bail!("Failed to build sysroot");
+            }
+        }
     }
 
     std::fs::create_dir_all(&lib_dir).context("Failed to create sysroot lib directory")?;
 
+    // Invalidate the manifest before touching the lib directory
+    if manifest_path.exists() {
+        std::fs::remove_file(&manifest_path).context("Failed to remove sysroot manifest")?;
+    }
+
     // Find any old artifacts in the sysroot lib directory
     let to_remove = lib_dir
         .read_dir()
@@ -174,12 +419,8 @@ Supported values are:
         .filter_map(|entry| {
             let entry = entry.ok()?;
             let path = entry.path();
-            let filename = path.file_name()?;
-            artifacts
-                .iter()
-                .any(|file| file.file_name() == Some(filename))
-                .not()
-                .then_some(path)
+            let filename = path.file_name()?.to_str()?;
+            expected.iter().any(|f| f == filename).not().then_some(path)
         });
 
     // Remove old artifacts
@@ -188,19 +429,89 @@ Supported values are:
     }
 
     // Copy new artifacts
-    for artifact in artifacts {
+    for artifact in &artifacts {
         let filename = artifact.file_name().unwrap();
-        let dst = lib_dir.join(filename);
-        if !dst.exists() {
-            std::fs::copy(&artifact, dst).context("Failed to copy sysroot artifact")?;
-        }
+        atomic::copy(artifact, lib_dir.join(filename))
+            .context("Failed to copy sysroot artifact")?;
     }
 
+    let manifest = Manifest {
+        artifacts: expected,
+        build: Some(build),
+    };
+    atomic::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+    .context("Failed to write sysroot manifest")?;
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(artifacts = artifacts.len(), "sysroot rebuilt");
+
+    Ok(true)
+}
+
+/// Errors of rustc meaning that crates of the sysroot are missing, unreadable, or were
+/// built by another compiler.
+const BROKEN_SYSROOT_ERRORS: &[&str] = &["E0460", "E0461", "E0462", "E0463", "E0514", "E0786"];
+
+/// Compiles an empty crate using `core` and `alloc` from the sysroot, and returns
+/// rustc's errors if they show that the sysroot is broken, e.g., a missing rlib, or a
+/// metadata mismatch after a toolchain update.
+pub fn probe(args: &Args) -> Option<String> {
+    let rustc = args
+        .env
+        .get(OsStr::new("RUSTC"))
+        .cloned()
+        .unwrap_or_else(|| "rustc".into());
+    let mut child = std::process::Command::new(&rustc)
+        .env_clear()
+        .envs(args.env.iter())
+        .allow_unstable()
+        .arg("--sysroot")
+        .arg(args.sysroot_dir())
+        .arg("--target")
+        .arg(&args.target)
+        .args(["--crate-type=lib", "--crate-name=sysroot_probe"])
+        .args(["--emit=metadata=-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    child
+        .stdin
+        .take()?
+        .write_all(b"#![no_std]\nextern crate alloc;\n")
+        .ok()?;
+    let output = child.wait_with_output().ok()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let broken = BROKEN_SYSROOT_ERRORS
+        .iter()
+        .any(|code| stderr.contains(code));
+    (!output.status.success() && broken).then(|| stderr.trim_end().to_string())
+}
+
+/// Removes the built libraries of the sysroot and their build directory, so that the
+/// next preparation rebuilds them from scratch.
+pub fn invalidate(args: &Args) -> Result<()> {
+    // the manifest goes first, like when the libraries are replaced
+    let manifest_path = args.sysroot_dir().join("manifest.json");
+    if manifest_path.exists() {
+        std::fs::remove_file(&manifest_path).context("Failed to remove sysroot manifest")?;
+    }
+    for dir in [args.build_dir(), args.libs_dir()] {
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove sysroot directory {dir:?}"))?;
+        }
+    }
     Ok(())
 }
 
 fn get_spec(args: &Args, triplet: impl AsRef<str>) -> Result<TargetSpec> {
-    let output = cargo_cmd()?
+    let output = cargo_cmd(&args.env)?
         .env_clear()
         .envs(args.env.iter())
         .current_dir(&args.current_dir)
@@ -218,3 +529,182 @@ fn get_spec(args: &Args, triplet: impl AsRef<str>) -> Result<TargetSpec> {
 
     serde_json::from_slice(&output.stdout).context("Failed to parse target spec JSON")
 }
+
+/// Writes the prepared sysroot to the gzipped tarball `file`, so that it can be
+/// imported with [`import`] on other machines instead of being built there.
+///
+/// Only the target directory of the sysroot is exported, with the libraries, the C
+/// headers and the wrappers, together with the fingerprint and the manifest. The
+/// manifest is added last, so that an interrupted import is detected as incomplete.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn export(args: &Args, file: &Path) -> Result<()> {
+    let sysroot_dir = args.sysroot_dir();
+
+    let mut files = vec![];
+    let mut pending = vec![args.triplet_dir()];
+    while let Some(dir) = pending.pop() {
+        let entries = dir
+            .read_dir()
+            .with_context(|| format!("Failed to read sysroot directory {dir:?}"))?;
+        for entry in entries {
+            let path = entry.context("Failed to read sysroot directory")?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path.strip_prefix(&sysroot_dir).unwrap().to_path_buf());
+            }
+        }
+    }
+    files.sort();
+
+    let files = iter::once(PathBuf::from(fingerprint::FILE_NAME))
+        .chain(files)
+        .chain([PathBuf::from("manifest.json")]);
+
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    builder.mode(tar::HeaderMode::Deterministic);
+    for file in files {
+        builder
+            .append_path_with_name(sysroot_dir.join(&file), &file)
+            .with_context(|| format!("Failed to add {file:?} to the sysroot archive"))?;
+    }
+    let tarball = builder
+        .into_inner()
+        .and_then(GzEncoder::finish)
+        .context("Failed to compress the sysroot archive")?;
+
+    atomic::write(file, tarball).with_context(|| format!("Failed to write {file:?}"))
+}
+
+/// Imports a sysroot exported with [`export`] into the sysroot directory of `args`,
+/// after checking that its fingerprint matches what `args` would prepare.
+///
+/// The sysroot doesn't need to be imported at the same path it was exported from.
+/// The wrappers and the fingerprint refer to the sysroot path, so they are written
+/// again after extracting it.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn import(args: &Args, file: &Path) -> Result<()> {
+    let tarball = std::fs::read(file).with_context(|| format!("Failed to read {file:?}"))?;
+
+    let mut found = None;
+    let mut archive = tar::Archive::new(GzDecoder::new(tarball.as_slice()));
+    for entry in archive
+        .entries()
+        .with_context(|| format!("Failed to read sysroot archive {file:?}"))?
+    {
+        let mut entry =
+            entry.with_context(|| format!("Failed to read sysroot archive {file:?}"))?;
+        if entry
+            .path()
+            .is_ok_and(|path| path == Path::new(fingerprint::FILE_NAME))
+        {
+            let mut content = vec![];
+            entry
+                .read_to_end(&mut content)
+                .context("Failed to read the fingerprint of the sysroot archive")?;
+            found = Some(
+                serde_json::from_slice::<Fingerprint>(&content)
+                    .context("Failed to parse the fingerprint of the sysroot archive")?,
+            );
+            break;
+        }
+    }
+    let mut found = found.with_context(|| {
+        format!(
+            "{file:?} is not a sysroot archive, it has no {}",
+            fingerprint::FILE_NAME
+        )
+    })?;
+
+    let sysroot_dir = args.sysroot_dir();
+    found.relocate(&sysroot_dir);
+    let problems = Fingerprint::of(args)?.diff(&found);
+    ensure!(
+        problems.is_empty(),
+        "The sysroot in {file:?} was prepared for a different build:\n{}\n\
+        Export it again with the same options as this build",
+        fingerprint::list(&problems)
+    );
+
+    // Invalidate the manifest before touching the sysroot, and remove the old
+    // libraries and headers, which could be left behind otherwise
+    let manifest_path = sysroot_dir.join("manifest.json");
+    if manifest_path.exists() {
+        std::fs::remove_file(&manifest_path).context("Failed to remove sysroot manifest")?;
+    }
+    for dir in [args.libs_dir(), args.includes_dir()] {
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove old sysroot directory {dir:?}"))?;
+        }
+    }
+
+    std::fs::create_dir_all(&sysroot_dir).context("Failed to create sysroot directory")?;
+    tar::Archive::new(GzDecoder::new(tarball.as_slice()))
+        .unpack(&sysroot_dir)
+        .with_context(|| format!("Failed to extract sysroot archive {file:?}"))?;
+
+    cc_wrapper::install(args)?;
+    link_map::install(args)?;
+    fingerprint::write(args)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_info(cargo_hyperlight: &str, target_spec: &str) -> BuildInfo {
+        BuildInfo {
+            cargo_hyperlight: cargo_hyperlight.into(),
+            target_spec: target_spec.into(),
+            flags: BUILD_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn outdated_sysroots_say_why() {
+        let current = build_info("0.2.0", "0123");
+        assert!(build_info("0.2.0", "0123").outdated(&current).is_none());
+        assert_eq!(
+            build_info("0.1.0", "0123").outdated(&current).unwrap(),
+            "the sysroot was built by cargo-hyperlight 0.1.0, rebuilding it with 0.2.0"
+        );
+        assert!(
+            build_info("0.2.0", "4567")
+                .outdated(&current)
+                .unwrap()
+                .contains("target spec")
+        );
+
+        let manifest: Manifest = serde_json::from_str(r#"{ "artifacts": [] }"#).unwrap();
+        assert!(manifest.build.is_none());
+    }
+
+    #[test]
+    fn custom_specs_are_checked() {
+        let spec = serde_json::json!({
+            "arch": "x86_64",
+            "llvm-target": "x86_64-unknown-none",
+            "entry-name": "entrypoint",
+        });
+        assert!(custom_spec_problems("x86_64-custom", &spec).is_empty());
+
+        let spec = serde_json::json!({
+            "arch": "x86_64",
+            "os": "linux",
+            "entry-name": "_start",
+            "metadata": { "std": true },
+        });
+        assert_eq!(
+            custom_spec_problems("custom", &spec),
+            [
+                "the file name doesn't start with the architecture, e.g., `x86_64-custom.json`",
+                "`os` is \"linux\", but hyperlight guests run without an operating system",
+                "`metadata.std` is true, but hyperlight guests are `no_std`",
+                "`entry-name` is \"_start\", but hyperlight calls the guest's \"entrypoint\"",
+            ]
+        );
+    }
+}
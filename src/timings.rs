@@ -0,0 +1,285 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::atomic;
+use crate::cli::{Args, note, warning};
+
+/// Name of the merged reports, next to cargo's `cargo-timing.html`.
+const REPORT_NAME: &str = "hyperlight-timing";
+
+/// A compilation unit in cargo's timing report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Unit {
+    name: String,
+    version: String,
+    #[serde(default)]
+    target: String,
+    /// Seconds since the start of the build
+    start: f64,
+    /// Seconds the unit took to build
+    duration: f64,
+}
+
+/// The units of one cargo build.
+#[derive(Debug, Serialize)]
+struct Build {
+    /// Seconds since the start of the merged report
+    start: f64,
+    duration: f64,
+    units: Vec<Unit>,
+}
+
+/// The merged report of the sysroot build and the user's build.
+#[derive(Debug, Serialize)]
+struct Report {
+    /// `None` if the sysroot was up to date
+    sysroot: Option<Build>,
+    build: Build,
+    duration: f64,
+}
+
+/// Returns the path of cargo's timing report for builds in `target_dir`.
+fn cargo_report(target_dir: &Path) -> PathBuf {
+    target_dir.join("cargo-timings").join("cargo-timing.html")
+}
+
+/// Returns the path of the sysroot build's timing report, which only exists if the
+/// sysroot was built with `--timings` since it was last removed.
+pub fn sysroot_report(args: &Args) -> PathBuf {
+    cargo_report(&args.build_dir())
+}
+
+/// Parses the units of cargo's HTML timing report, which embeds them as JSON in the
+/// `UNIT_DATA` constant of its script.
+fn parse_units(html: &str) -> Option<Vec<Unit>> {
+    const MARKER: &str = "const UNIT_DATA = ";
+    let start = html.find(MARKER)? + MARKER.len();
+    serde_json::Deserializer::from_str(&html[start..])
+        .into_iter::<Vec<Unit>>()
+        .next()?
+        .ok()
+}
+
+fn read_units(path: &Path) -> Result<Vec<Unit>> {
+    let html = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+    parse_units(&html).with_context(|| format!("Failed to find the units in {path:?}"))
+}
+
+/// Places the `units` of a build that starts at `start` in the merged report.
+fn build(start: f64, units: Vec<Unit>) -> Build {
+    let duration = units
+        .iter()
+        .map(|unit| unit.start + unit.duration)
+        .fold(0.0, f64::max);
+    Build {
+        start,
+        duration,
+        units,
+    }
+}
+
+fn merge_units(sysroot: Option<Vec<Unit>>, units: Vec<Unit>) -> Report {
+    let sysroot = sysroot.map(|units| build(0.0, units));
+    let start = sysroot.as_ref().map_or(0.0, |sysroot| sysroot.duration);
+    let build = build(start, units);
+    Report {
+        duration: start + build.duration,
+        sysroot,
+        build,
+    }
+}
+
+/// Renders the merged report as a standalone HTML page, with one bar per unit.
+fn render_html(report: &Report) -> String {
+    let scale = if report.duration > 0.0 {
+        100.0 / report.duration
+    } else {
+        0.0
+    };
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <title>cargo-hyperlight build timings</title>\
+        <style>body{font-family:sans-serif}td{padding:0 8px;white-space:nowrap}\
+        .bar{position:relative;width:600px}.bar div{position:absolute;height:12px;top:3px}\
+        .sysroot{background:#e3a03a}.build{background:#4a90d9}</style></head><body>\n",
+    );
+    writeln!(html, "<h1>cargo-hyperlight build timings</h1>").unwrap();
+    writeln!(html, "<table>").unwrap();
+    let sysroot = match &report.sysroot {
+        Some(sysroot) => format!("{:.1}s, {} units", sysroot.duration, sysroot.units.len()),
+        None => "up to date".into(),
+    };
+    writeln!(html, "<tr><td>Sysroot build:</td><td>{sysroot}</td></tr>").unwrap();
+    writeln!(
+        html,
+        "<tr><td>Build:</td><td>{:.1}s, {} units</td></tr>",
+        report.build.duration,
+        report.build.units.len()
+    )
+    .unwrap();
+    writeln!(
+        html,
+        "<tr><td>Total time:</td><td>{:.1}s</td></tr>",
+        report.duration
+    )
+    .unwrap();
+    writeln!(html, "</table>\n<table>").unwrap();
+    writeln!(
+        html,
+        "<tr><th>Build</th><th>Unit</th><th>Start</th><th>Duration</th><th></th></tr>"
+    )
+    .unwrap();
+
+    let builds = report
+        .sysroot
+        .iter()
+        .map(|build| ("sysroot", build))
+        .chain([("build", &report.build)]);
+    for (class, build) in builds {
+        let mut units = build.units.iter().collect::<Vec<_>>();
+        units.sort_by(|a, b| a.start.total_cmp(&b.start));
+        for unit in units {
+            let start = build.start + unit.start;
+            writeln!(
+                html,
+                "<tr><td>{class}</td><td>{} v{}{}</td><td>{start:.2}s</td><td>{:.2}s</td>\
+                <td class=\"bar\"><div class=\"{class}\" style=\"left:{:.2}%;width:{:.2}%\">\
+                </div></td></tr>",
+                escape(&unit.name),
+                escape(&unit.version),
+                escape(&unit.target),
+                unit.duration,
+                start * scale,
+                (unit.duration * scale).max(0.1),
+            )
+            .unwrap();
+        }
+    }
+    writeln!(html, "</table>\n</body></html>").unwrap();
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Merges the timing report of the sysroot build, if it was built in this run, with
+/// the report of the user's build, when `--timings` is used.
+///
+/// The merged report is written as `hyperlight-timing.html` and
+/// `hyperlight-timing.json`, next to cargo's `cargo-timing.html`. The builds don't
+/// overlap, so the user's build is placed right after the sysroot build.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn merge(args: &Args) -> Result<()> {
+    if !args.timings {
+        return Ok(());
+    }
+
+    let path = cargo_report(&args.target_dir);
+    if !path.is_file() {
+        warning(
+            "missing-timing-report",
+            format!(
+                "cargo didn't write a timing report to {}, not merging the sysroot build",
+                path.display()
+            ),
+        );
+        return Ok(());
+    }
+
+    // a prebuilt sysroot is never built, whatever report it comes with
+    let sysroot = sysroot_report(args);
+    let sysroot = match args.prebuilt_sysroot.is_none() && sysroot.is_file() {
+        true => Some(read_units(&sysroot)?),
+        false => None,
+    };
+    let report = merge_units(sysroot, read_units(&path)?);
+
+    let dir = path.parent().unwrap();
+    let html = dir.join(format!("{REPORT_NAME}.html"));
+    atomic::write(&html, render_html(&report)).context("Failed to write timing report")?;
+    atomic::write(
+        dir.join(format!("{REPORT_NAME}.json")),
+        serde_json::to_vec_pretty(&report).unwrap(),
+    )
+    .context("Failed to write timing report")?;
+
+    note(format!(
+        "timing report with the sysroot build saved to {}",
+        html.display()
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HTML: &str = r#"<script>
+const UNIT_DATA = [
+  {
+    "i": 0,
+    "name": "rustc-demangle",
+    "version": "0.1.28",
+    "mode": "todo",
+    "target": "",
+    "start": 0.26,
+    "duration": 0.3,
+    "unblocked_units": [1]
+  },
+  {
+    "i": 1,
+    "name": "guest",
+    "version": "0.1.0",
+    "target": " guest \"bin\"",
+    "start": 0.56,
+    "duration": 0.06
+  }
+];
+const CONCURRENCY_DATA = [];
+</script>"#;
+
+    fn unit(name: &str, start: f64, duration: f64) -> Unit {
+        Unit {
+            name: name.into(),
+            version: "0.1.0".into(),
+            target: String::new(),
+            start,
+            duration,
+        }
+    }
+
+    #[test]
+    fn parse_cargo_report() {
+        let units = parse_units(HTML).unwrap();
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].name, "rustc-demangle");
+        assert_eq!(units[1].target, " guest \"bin\"");
+        assert_eq!(units[1].start, 0.56);
+        assert_eq!(parse_units("<html></html>"), None);
+    }
+
+    #[test]
+    fn build_follows_sysroot() {
+        let report = merge_units(
+            Some(vec![unit("core", 0.0, 10.0), unit("alloc", 10.0, 2.0)]),
+            vec![unit("guest", 0.5, 1.5)],
+        );
+        assert_eq!(report.sysroot.as_ref().unwrap().duration, 12.0);
+        assert_eq!(report.build.start, 12.0);
+        assert_eq!(report.build.duration, 2.0);
+        assert_eq!(report.duration, 14.0);
+
+        let report = merge_units(None, vec![unit("guest", 0.5, 1.5)]);
+        assert_eq!(report.build.start, 0.0);
+        assert_eq!(report.duration, 2.0);
+        assert!(render_html(&report).contains("up to date"));
+    }
+}
@@ -1,28 +1,474 @@
-use std::ffi::OsString;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail, ensure};
+use object::Object as _;
 use regex::Regex;
 
 use crate::cargo_cmd::{CargoCmd, cargo_cmd};
-use crate::cli::Args;
+use crate::cli::{Args, note, warning};
+use crate::failure::{Failure, WithFailure as _};
+use crate::{atomic, cache, generated, report};
 
 #[derive(serde::Deserialize)]
 struct CargoMetadata {
     packages: Vec<CargoMetadataPackage>,
+    resolve: Option<CargoMetadataResolve>,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataResolve {
+    nodes: Vec<CargoMetadataNode>,
+}
+
+#[derive(serde::Deserialize)]
+struct CargoMetadataNode {
+    id: String,
+    dependencies: Vec<String>,
+    #[serde(default)]
+    features: Vec<String>,
 }
 
 #[derive(serde::Deserialize)]
 struct CargoMetadataPackage {
+    id: String,
     name: String,
     manifest_path: PathBuf,
-    #[allow(dead_code)]
-    // we can use this if we ever change the include paths to be copied
     version: semver::Version,
 }
 
+/// Headers copied into the sysroot include directory, with the hash of their contents.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IncludeManifest {
+    version: semver::Version,
+    files: BTreeMap<PathBuf, String>,
+}
+
+/// Version of hyperlight-guest-bin whose musl sources were used to build libm,
+/// and whether its `libc` feature already provided the math functions.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
+struct LibmManifest {
+    version: semver::Version,
+    #[serde(default)]
+    libc: bool,
+}
+
+/// Compiles every one of `sources` into the object file at the same position of
+/// `objects`, in parallel, through the compiler launcher if there's one.
+fn compile(
+    args: &Args,
+    clang: &Path,
+    flags: &[OsString],
+    sources: &[PathBuf],
+    objects: &[PathBuf],
+) -> Result<()> {
+    let (program, launched) = match &args.compiler_launcher {
+        Some(launcher) => (launcher.as_path(), Some(clang)),
+        None => (clang, None),
+    };
+    let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = sources.len().div_ceil(jobs).max(1);
+    std::thread::scope(|scope| -> Result<()> {
+        let handles = sources
+            .chunks(chunk_size)
+            .zip(objects.chunks(chunk_size))
+            .map(|(sources, objects)| {
+                scope.spawn(move || {
+                    for (src, obj) in sources.iter().zip(objects) {
+                        std::process::Command::new(program)
+                            .args(launched)
+                            .args(flags)
+                            .arg("-c")
+                            .arg(src)
+                            .arg("-o")
+                            .arg(obj)
+                            .checked_output()
+                            .with_context(|| format!("Failed to compile {src:?}"))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("compilation thread panicked"))
+    })
+}
+
+/// Creates the static library `path` from `objects`, replacing any previous one.
+fn archive(ar: &Path, path: &Path, objects: &[PathBuf]) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path).context("Failed to remove old archive")?;
+    }
+    std::process::Command::new(ar)
+        .arg("rcs")
+        .arg(path)
+        .args(objects)
+        .checked_output()?;
+    Ok(())
+}
+
+/// Builds `libm.a` from the musl math sources vendored in hyperlight-guest-bin
+/// and places it in the sysroot lib directory, so that `-lm` resolves.
+///
+/// When hyperlight-guest-bin is built with its `libc` feature, the math functions are
+/// already part of `libhyperlight_guest_bin.a`, and an empty `libm.a` is installed
+/// instead, as a second copy of every math symbol would conflict with them.
+///
+/// The library is only rebuilt if it's missing or hyperlight-guest-bin's version
+/// or features changed.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(%version)))]
+fn build_libm(
+    args: &Args,
+    hyperlight_guest_bin_dir: &Path,
+    version: &semver::Version,
+    libc: bool,
+) -> Result<()> {
+    let libm = args.libs_dir().join("libm.a");
+    let manifest_path = args.triplet_dir().join("libm.json");
+    let manifest = LibmManifest {
+        version: version.clone(),
+        libc,
+    };
+
+    let up_to_date = std::fs::read(&manifest_path)
+        .ok()
+        .and_then(|current| serde_json::from_slice::<LibmManifest>(&current).ok())
+        .is_some_and(|current| current == manifest && libm.exists());
+    cache::record(args, cache::Entry::Libm, up_to_date);
+
+    if up_to_date {
+        return Ok(());
+    }
+
+    if libc {
+        note(
+            "hyperlight-guest-bin's `libc` feature already provides the math functions, \
+            installing an empty libm so that `-lm` resolves",
+        );
+        atomic::write(&libm, "!<arch>\n").context("Failed to write empty libm archive")?;
+        atomic::write(
+            &manifest_path,
+            serde_json::to_vec_pretty(&manifest).unwrap(),
+        )
+        .context("Failed to write libm manifest")?;
+        return Ok(());
+    }
+
+    let clang = args
+        .clang
+        .as_ref()
+        .context("Could not find 'clang' in PATH")
+        .failure(Failure::Toolchain)?;
+    let ar = args
+        .ar
+        .as_ref()
+        .context("Could not find 'ar' or 'llvm-ar' in PATH")
+        .failure(Failure::Toolchain)?;
+
+    let arch = target_arch(&args.target);
+    let musl_dir = hyperlight_guest_bin_dir.join("third_party/musl");
+    let arch_dir = musl_dir.join("arch").join(arch);
+    ensure!(
+        arch_dir.is_dir(),
+        "hyperlight-guest-bin doesn't include the musl sources for {arch}"
+    );
+
+    // like musl's own build, the architecture specific implementations (C or assembly)
+    // replace the generic ones with the same name
+    let mut sources = BTreeMap::new();
+    for dir in [
+        musl_dir.join("src/math"),
+        musl_dir.join("src/math").join(arch),
+    ] {
+        let Ok(entries) = dir.read_dir() else {
+            continue;
+        };
+        for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+            let source = path
+                .extension()
+                .is_some_and(|ext| ext == "c" || ext == "s" || ext == "S");
+            if source && let Some(stem) = path.file_stem() {
+                sources.insert(stem.to_owned(), path);
+            }
+        }
+    }
+    ensure!(!sources.is_empty(), "Failed to find musl math sources");
+
+    let obj_dir = args.sysroot_dir().join("libm");
+    std::fs::create_dir_all(&obj_dir).context("Failed to create libm build directory")?;
+
+    let mut flags = cflags_list(args);
+    for dir in [
+        musl_dir.join("src/include"),
+        musl_dir.join("src/internal"),
+        arch_dir,
+        musl_dir.join("arch/generic"),
+        musl_dir.join("include"),
+    ] {
+        flags.push("-I".into());
+        flags.push(dir.into());
+    }
+    flags.extend(["-DHYPERLIGHT", "-O2", "-fPIC", "-w"].map(OsString::from));
+
+    let objects = sources
+        .keys()
+        .map(|stem| obj_dir.join(stem).with_extension("o"))
+        .collect::<Vec<_>>();
+    let sources = sources.into_values().collect::<Vec<_>>();
+    compile(args, clang, &flags, &sources, &objects)?;
+
+    let tmp = obj_dir.join("libm.a");
+    archive(ar, &tmp, &objects).context("Failed to archive libm")?;
+
+    atomic::copy(&tmp, &libm).context("Failed to copy libm into the sysroot")?;
+    atomic::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+    .context("Failed to write libm manifest")?;
+
+    Ok(())
+}
+
+/// Version, features and C flags hyperlight-guest-bin's C archive was built with.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
+struct CArchiveManifest {
+    version: semver::Version,
+    features: Vec<String>,
+    flags: Vec<String>,
+}
+
+/// Name of the library hyperlight-guest-bin's build script builds from its C sources.
+const C_ARCHIVE: &str = "hyperlight_guest_bin";
+
+/// Builds hyperlight-guest-bin's C sources (printf and musl, depending on its
+/// features) once into `libhyperlight_guest_bin.a` in the sysroot lib directory, the
+/// same library its build script would build, which then only needs to be linked.
+///
+/// The archive is only rebuilt if hyperlight-guest-bin's version, its features or the
+/// C flags changed.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(%version)))]
+fn build_c_archive(
+    args: &Args,
+    hyperlight_guest_bin_dir: &Path,
+    version: &semver::Version,
+    features: &[String],
+) -> Result<()> {
+    let printf = features.iter().any(|feature| feature == "printf");
+    let libc = features.iter().any(|feature| feature == "libc");
+
+    let mut flags = cflags_list(args);
+    flags.extend(args.cflags.iter().cloned());
+    let dep_cflags = args.dep_cflags.get("hyperlight-guest-bin");
+    flags.extend(dep_cflags.into_iter().flatten().map(OsString::from));
+    let mut includes = vec![];
+    if printf {
+        includes.push("third_party/printf");
+    }
+    if libc {
+        includes.extend([
+            "third_party/musl/src/include",
+            "third_party/musl/include",
+            "third_party/musl/src/internal",
+            "third_party/musl/arch/generic",
+            "third_party/musl/arch/x86_64",
+        ]);
+    }
+    for dir in includes {
+        flags.push("-I".into());
+        flags.push(hyperlight_guest_bin_dir.join(dir).into());
+    }
+    // like the build script, which also silences the warnings of the sources
+    flags.extend(["-DHYPERLIGHT", "-O2", "-fPIC", "-w"].map(OsString::from));
+
+    let lib = args.libs_dir().join(format!("lib{C_ARCHIVE}.a"));
+    let manifest_path = args.triplet_dir().join("c-archive.json");
+    let manifest = CArchiveManifest {
+        version: version.clone(),
+        features: features.to_vec(),
+        flags: flags
+            .iter()
+            .map(|flag| flag.to_string_lossy().into_owned())
+            .collect(),
+    };
+
+    let up_to_date = std::fs::read(&manifest_path)
+        .ok()
+        .and_then(|current| serde_json::from_slice::<CArchiveManifest>(&current).ok())
+        .is_some_and(|current| current == manifest && lib.exists());
+    cache::record(args, cache::Entry::CArchive, up_to_date);
+
+    if up_to_date {
+        return Ok(());
+    }
+
+    let mut sources = vec![];
+    if printf {
+        sources.push(hyperlight_guest_bin_dir.join("third_party/printf/printf.c"));
+    }
+    if libc {
+        sources.extend(find_files(
+            &hyperlight_guest_bin_dir.join("third_party/musl"),
+            &["c", "s"],
+        )?);
+    }
+
+    let obj_dir = args.sysroot_dir().join("c-archive");
+    std::fs::create_dir_all(&obj_dir).context("Failed to create C archive build directory")?;
+
+    // sources in different directories can have the same name
+    let objects = sources
+        .iter()
+        .map(|src| {
+            let name = src
+                .strip_prefix(hyperlight_guest_bin_dir)
+                .unwrap_or(src)
+                .to_string_lossy()
+                .replace(['/', '\\'], "_");
+            obj_dir.join(name).with_extension("o")
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    if !sources.is_empty() {
+        let clang = args
+            .clang
+            .as_ref()
+            .context("Could not find 'clang' in PATH")
+            .failure(Failure::Toolchain)?;
+        compile(args, clang, &flags, &sources, &objects)?;
+    }
+
+    let ar = args
+        .ar
+        .as_ref()
+        .context("Could not find 'ar' or 'llvm-ar' in PATH")
+        .failure(Failure::Toolchain)?;
+    let tmp = obj_dir.join(format!("lib{C_ARCHIVE}.a"));
+    archive(ar, &tmp, &objects).context("Failed to archive hyperlight-guest-bin's C sources")?;
+    atomic::copy(&tmp, &lib).context("Failed to copy the C archive into the sysroot")?;
+    atomic::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+    .context("Failed to write C archive manifest")?;
+
+    if args.verbose > 0 {
+        note(format!(
+            "built {} C sources of hyperlight-guest-bin into {lib:?} in {:.2?}",
+            sources.len(),
+            start.elapsed(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the `--config` values that override the build script of hyperlight-guest-bin,
+/// identified by its `links = "c"`, to link the archive built by [`build_c_archive`]
+/// instead, and to point dependent build scripts to the headers in the sysroot.
+pub fn c_archive_config(args: &Args) -> Vec<String> {
+    if !args.cached_c_archive {
+        return vec![];
+    }
+    let key = format!("target.{}.c", args.target);
+    // TOML basic strings escape like JSON strings
+    let string = |value: &Path| serde_json::to_string(&value.to_string_lossy()).unwrap();
+    let search = format!("native={}", args.libs_dir().to_string_lossy());
+    vec![
+        format!("{key}.rustc-link-lib=[\"static={C_ARCHIVE}\"]"),
+        format!("{key}.rustc-link-search=[{}]", string(Path::new(&search))),
+        format!("{key}.include={}", string(&args.includes_dir())),
+    ]
+}
+
+/// Static libraries copied into the sysroot lib directory.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct LibsManifest {
+    libs: Vec<String>,
+}
+
+/// Copies the user provided static libraries into the sysroot lib directory,
+/// which is in the linker search path, and removes the ones no longer requested.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn install_libs(args: &Args) -> Result<()> {
+    let lib_dir = args.libs_dir();
+    let manifest_path = args.triplet_dir().join("libs.json");
+    let old_manifest = std::fs::read(&manifest_path)
+        .ok()
+        .and_then(|manifest| serde_json::from_slice::<LibsManifest>(&manifest).ok())
+        .unwrap_or_default();
+
+    let mut manifest = LibsManifest::default();
+    for lib in &args.static_libs {
+        let name = lib
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("Invalid static library path {lib:?}"))?;
+        ensure!(
+            name.starts_with("lib") && name.ends_with(".a"),
+            "Static library {lib:?} must be named lib<name>.a"
+        );
+        atomic::copy(lib, lib_dir.join(name))
+            .with_context(|| format!("Failed to copy static library {lib:?}"))?;
+        manifest.libs.push(name.to_string());
+    }
+
+    for name in old_manifest.libs {
+        let path = lib_dir.join(&name);
+        if !manifest.libs.contains(&name) && path.exists() {
+            std::fs::remove_file(path).context("Failed to remove old static library")?;
+        }
+    }
+
+    atomic::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+    .context("Failed to write static libraries manifest")?;
+
+    Ok(())
+}
+
+/// Returns all the files under `dir` with one of the `extensions`, recursively.
+fn find_files(dir: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = dir
+            .read_dir()
+            .with_context(|| format!("Failed to read source directory {dir:?}"))?;
+        for entry in entries {
+            let entry = entry.context("Failed to read source file")?;
+            let path = entry.path();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                pending.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|ext| extensions.iter().any(|e| ext == *e))
+            {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Returns the hash of the contents of the file at `path`, which is stable across
+/// runs and versions, so that it can be stored in the manifests.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let (_, hash) = report::hash_file(path).with_context(|| format!("Failed to read {path:?}"))?;
+    Ok(format!("{hash:016x}"))
+}
+
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
 pub fn prepare(args: &Args) -> Result<()> {
-    let metadata = cargo_cmd()?
+    let metadata = cargo_cmd(&args.env)?
         .env_clear()
         .envs(args.env.iter())
         .current_dir(&args.current_dir)
@@ -35,73 +481,541 @@ pub fn prepare(args: &Args) -> Result<()> {
     let metadata = serde_json::from_slice::<CargoMetadata>(&metadata.stdout)
         .context("Failed to parse cargo metadata")?;
 
-    let hyperlight_guest_bin = metadata
+    let mut candidates = metadata
         .packages
-        .into_iter()
-        .find(|pkg| pkg.name == "hyperlight-guest-bin")
-        .context("Could not find hyperlight-guest-bin package in cargo metadata")?;
+        .iter()
+        .filter(|pkg| pkg.name == "hyperlight-guest-bin");
+
+    let Some(hyperlight_guest_bin) = candidates.next() else {
+        // Guests that don't use hyperlight-guest-bin (e.g., pure Rust guests
+        // using only hyperlight-guest) don't need its C headers.
+        ensure!(
+            !args.libm,
+            "Building libm requires hyperlight-guest-bin as a dependency"
+        );
+        return Ok(());
+    };
 
-    let hyperlight_guest_bin_dir = hyperlight_guest_bin
+    if candidates.next().is_some() {
+        bail!(
+            "Found multiple versions of hyperlight-guest-bin in the dependency graph:\n{}\n\
+            The C headers of only one of them can be used, make sure all guests \
+            depend on the same version of hyperlight-guest-bin",
+            describe_dependents(&metadata, "hyperlight-guest-bin")
+        );
+    }
+
+    let mut hyperlight_guest_bin_dir = hyperlight_guest_bin
         .manifest_path
         .parent()
-        .context("Failed to get directory for hyperlight-guest-bin")?;
+        .context("Failed to get directory for hyperlight-guest-bin")?
+        .to_path_buf();
+
+    let missing = INCLUDE_DIRS
+        .iter()
+        .filter(|dir| !hyperlight_guest_bin_dir.join(dir).is_dir())
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        // This can happen with git dependencies using a sparse checkout, or with
+        // patched dependencies. Fall back to the headers of the same version
+        // published on crates.io.
+        warning(
+            "missing-guest-headers",
+            format!(
+                "hyperlight-guest-bin at {hyperlight_guest_bin_dir:?} is missing the include \
+            directories {missing:?}, using the headers from hyperlight-guest-bin {} \
+            on crates.io instead",
+                hyperlight_guest_bin.version
+            ),
+        );
+        hyperlight_guest_bin_dir = fetch_from_registry(args, &hyperlight_guest_bin.version)
+            .context("Failed to get fallback headers for hyperlight-guest-bin")?;
+        ensure!(
+            INCLUDE_DIRS
+                .iter()
+                .all(|dir| hyperlight_guest_bin_dir.join(dir).is_dir()),
+            "hyperlight-guest-bin at {hyperlight_guest_bin_dir:?} is missing include directories"
+        );
+    }
 
     let include_dst_dir = args.includes_dir();
 
     std::fs::create_dir_all(&include_dst_dir)
         .context("Failed to create sysroot include directory")?;
 
-    const INCLUDE_DIRS: &[&str] = &[
-        "third_party/printf/",
-        "third_party/musl/include",
-        "third_party/musl/arch/generic",
-        "third_party/musl/arch/x86_64",
-        "third_party/musl/src/internal",
-    ];
+    let start = Instant::now();
 
+    let mut files = BTreeMap::new();
     for dir in INCLUDE_DIRS {
         let include_src_dir = hyperlight_guest_bin_dir.join(dir);
-        let files = glob::glob(&format!("{}/**/*.h", include_src_dir.display()))
-            .context("Failed to read include source directory")?;
+        for src in find_files(&include_src_dir, &["h"])? {
+            let dst = src.strip_prefix(&include_src_dir).unwrap().to_path_buf();
+            files.insert(dst, src);
+        }
+    }
+
+    let manifest_path = args.triplet_dir().join("include.json");
+    let old_manifest = std::fs::read(&manifest_path)
+        .ok()
+        .and_then(|manifest| serde_json::from_slice::<IncludeManifest>(&manifest).ok());
+
+    let manifest_version = hyperlight_guest_bin.version.clone();
+    let old_files = old_manifest
+        .as_ref()
+        // headers from a different version are all considered stale
+        .filter(|manifest| manifest.version == manifest_version)
+        .map(|manifest| &manifest.files);
+
+    // Hash and copy the headers in parallel, this is noticeable on network filesystems
+    let files = files.into_iter().collect::<Vec<_>>();
+    let jobs = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = files.len().div_ceil(jobs).max(1);
+    let results = std::thread::scope(|scope| {
+        let handles = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(|| {
+                    chunk
+                        .iter()
+                        .map(|(file, src)| {
+                            let hash = hash_file(src)?;
+                            let dst = include_dst_dir.join(file);
+
+                            let unchanged = old_files
+                                .and_then(|files| files.get(file))
+                                .is_some_and(|old_hash| *old_hash == hash && dst.exists());
+
+                            if !unchanged {
+                                generated::copy(args, src, &dst)
+                                    .context("Failed to copy include file")?;
+                            }
+
+                            Ok((file.clone(), hash, !unchanged))
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("header copy thread panicked"))
+            .collect::<Result<Vec<_>>>()
+    })?;
 
-        for file in files {
-            let src = file.context("Failed to read include source file")?;
-            let dst = src.strip_prefix(&include_src_dir).unwrap();
-            let dst = include_dst_dir.join(dst);
+    let mut manifest = IncludeManifest {
+        version: manifest_version,
+        files: BTreeMap::new(),
+    };
+    let mut copied = 0;
+    for (file, hash, was_copied) in results.into_iter().flatten() {
+        copied += was_copied as usize;
+        manifest.files.insert(file, hash);
+    }
 
-            std::fs::create_dir_all(dst.parent().unwrap())
-                .context("Failed to create include subdirectory")?;
-            std::fs::copy(&src, &dst).context("Failed to copy include file")?;
+    // Remove headers that are no longer provided by hyperlight-guest-bin
+    let old_files = old_manifest
+        .map(|manifest| manifest.files)
+        .unwrap_or_default();
+    let mut removed = 0;
+    for file in old_files.keys() {
+        if !manifest.files.contains_key(file) {
+            let dst = include_dst_dir.join(file);
+            if dst.exists() {
+                std::fs::remove_file(&dst).context("Failed to remove stale include file")?;
+                removed += 1;
+            }
         }
     }
 
+    atomic::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+    .context("Failed to write include manifest")?;
+    cache::record(args, cache::Entry::Toolchain, copied == 0 && removed == 0);
+
+    let features = metadata
+        .resolve
+        .iter()
+        .flat_map(|resolve| &resolve.nodes)
+        .find(|node| node.id == hyperlight_guest_bin.id)
+        .map(|node| node.features.clone())
+        .unwrap_or_default();
+
+    if args.cached_c_archive {
+        build_c_archive(
+            args,
+            &hyperlight_guest_bin_dir,
+            &manifest.version,
+            &features,
+        )
+        .context("Failed to build hyperlight-guest-bin's C archive")?;
+    } else if args.triplet_dir().join("c-archive.json").exists() {
+        // the archive in the sysroot would be linked instead of the build script's
+        let _ = std::fs::remove_file(args.libs_dir().join(format!("lib{C_ARCHIVE}.a")));
+        std::fs::remove_file(args.triplet_dir().join("c-archive.json"))
+            .context("Failed to remove C archive manifest")?;
+    }
+
+    if args.libm {
+        let libc = features.iter().any(|feature| feature == "libc");
+        build_libm(args, &hyperlight_guest_bin_dir, &manifest.version, libc)
+            .context("Failed to build libm")?;
+    } else if args.triplet_dir().join("libm.json").exists() {
+        // libm was previously built but is no longer requested
+        let _ = std::fs::remove_file(args.libs_dir().join("libm.a"));
+        std::fs::remove_file(args.triplet_dir().join("libm.json"))
+            .context("Failed to remove libm manifest")?;
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        headers = manifest.files.len(),
+        copied,
+        removed,
+        "prepared toolchain headers"
+    );
+
+    if args.verbose > 0 {
+        note(format!(
+            "prepared {} toolchain headers in {:.2?} ({copied} copied, {removed} removed)",
+            manifest.files.len(),
+            start.elapsed(),
+        ));
+    }
+
     Ok(())
 }
 
+const INCLUDE_DIRS: &[&str] = &[
+    "third_party/printf/",
+    "third_party/musl/include",
+    "third_party/musl/arch/generic",
+    "third_party/musl/arch/x86_64",
+    "third_party/musl/src/internal",
+];
+
+const HEADERS_CARGO_TOML: &str = r#"[package]
+name = "headers"
+version = "0.1.0"
+edition = "2021"
+
+[workspace]
+
+[lib]
+path = "lib.rs"
+
+[dependencies]
+hyperlight-guest-bin = "=0.0.0"
+"#;
+
+/// Returns the source directory of the crates.io release of hyperlight-guest-bin
+/// `version`, downloading it if necessary.
+fn fetch_from_registry(args: &Args, version: &semver::Version) -> Result<PathBuf> {
+    let crate_dir = args.sysroot_dir().join("headers");
+    std::fs::create_dir_all(&crate_dir).context("Failed to create headers crate directory")?;
+    std::fs::write(
+        crate_dir.join("Cargo.toml"),
+        HEADERS_CARGO_TOML.replace("0.0.0", &version.to_string()),
+    )
+    .context("Failed to write Cargo.toml")?;
+    std::fs::write(crate_dir.join("lib.rs"), "").context("Failed to write lib.rs")?;
+
+    let metadata = cargo_cmd(&args.env)?
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&args.current_dir)
+        .arg("metadata")
+        .manifest_path(&Some(crate_dir.join("Cargo.toml")))
+        .arg("--format-version=1")
+        .checked_output()
+        .context("Failed to get cargo metadata")?;
+
+    let metadata = serde_json::from_slice::<CargoMetadata>(&metadata.stdout)
+        .context("Failed to parse cargo metadata")?;
+
+    let package = metadata
+        .packages
+        .into_iter()
+        .find(|pkg| pkg.name == "hyperlight-guest-bin")
+        .context("Could not find hyperlight-guest-bin package in cargo metadata")?;
+
+    let dir = package
+        .manifest_path
+        .parent()
+        .context("Failed to get directory for hyperlight-guest-bin")?
+        .to_path_buf();
+
+    Ok(dir)
+}
+
+/// Lists every version of the package `name` in the dependency graph,
+/// together with the packages that depend on it.
+fn describe_dependents(metadata: &CargoMetadata, name: &str) -> String {
+    let package = |id: &str| metadata.packages.iter().find(|pkg| pkg.id == id);
+    let nodes = metadata
+        .resolve
+        .as_ref()
+        .map(|resolve| resolve.nodes.as_slice())
+        .unwrap_or_default();
+
+    let mut lines = vec![];
+    for pkg in metadata.packages.iter().filter(|pkg| pkg.name == name) {
+        let dependents = nodes
+            .iter()
+            .filter(|node| node.dependencies.contains(&pkg.id))
+            .filter_map(|node| package(&node.id))
+            .map(|dep| format!("{} {}", dep.name, dep.version))
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            " * {} {} required by: {}",
+            pkg.name,
+            pkg.version,
+            dependents.join(", ")
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Compiler and flags whose output was checked to match the target ABI.
+#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
+struct AbiManifest {
+    clang: PathBuf,
+    flags: Vec<OsString>,
+}
+
+/// Checks that clang, with hyperlight's flags, produces objects for the target's
+/// architecture, by compiling a tiny C file and inspecting the resulting object.
+///
+/// A misconfigured compiler would otherwise only surface as a confusing error
+/// when linking the guest.
+/// The check only runs again if clang or the flags change.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn check_abi(args: &Args) -> Result<()> {
+    let Some(clang) = &args.clang else {
+        // without clang there's no C code to build
+        return Ok(());
+    };
+
+    let arch = target_arch(&args.target);
+    let expected = match arch {
+        "x86_64" => object::Architecture::X86_64,
+        "aarch64" => object::Architecture::Aarch64,
+        _ => return Ok(()),
+    };
+
+    let manifest = AbiManifest {
+        clang: clang.clone(),
+        // with cross-language LTO clang emits bitcode, not an ELF object to inspect
+        flags: cflags_list(args)
+            .into_iter()
+            .filter(|flag| !flag.to_string_lossy().starts_with("-flto"))
+            .collect(),
+    };
+    let manifest_path = args.triplet_dir().join("abi.json");
+    let up_to_date = std::fs::read(&manifest_path)
+        .ok()
+        .and_then(|current| serde_json::from_slice::<AbiManifest>(&current).ok())
+        .is_some_and(|current| current == manifest);
+
+    if up_to_date {
+        return Ok(());
+    }
+
+    let src = args.sysroot_dir().join("abi-check.c");
+    let obj = src.with_extension("o");
+    atomic::write(&src, "int hyperlight_abi_check(void) { return 0; }\n")
+        .context("Failed to write ABI check source")?;
+
+    let compiled = std::process::Command::new(clang)
+        .args(&manifest.flags)
+        .arg("-c")
+        .arg(&src)
+        .arg("-o")
+        .arg(&obj)
+        .checked_output();
+    if let Err(err) = compiled {
+        // C code won't build either, but guests without C dependencies are fine
+        warning(
+            "c-compiler",
+            format!("could not compile C code with {}: {err:#}", clang.display()),
+        );
+        return Ok(());
+    }
+
+    let data = std::fs::read(&obj).context("Failed to read ABI check object")?;
+    let file = object::File::parse(&*data).context("Failed to parse ABI check object")?;
+
+    let found = format!(
+        "{:?} {:?} {}-bit {}-endian",
+        file.format(),
+        file.architecture(),
+        if file.is_64() { 64 } else { 32 },
+        if file.is_little_endian() {
+            "little"
+        } else {
+            "big"
+        },
+    );
+    ensure!(
+        file.format() == object::BinaryFormat::Elf
+            && file.architecture() == expected
+            && file.is_64()
+            && file.is_little_endian(),
+        "{} produces {found} objects, but the {} target needs Elf {expected:?} 64-bit little-endian objects\n\
+        Make sure the clang in your PATH can build for {arch}",
+        clang.display(),
+        args.target,
+    );
+
+    atomic::write(
+        &manifest_path,
+        serde_json::to_vec_pretty(&manifest).unwrap(),
+    )
+    .context("Failed to write ABI check manifest")?;
+
+    Ok(())
+}
+
+/// Returns the flags to inject in the C flags environment variables: hyperlight's
+/// flags followed by any extra flags added with [`Command::cflag`](crate::Command::cflag).
 pub fn cflags(args: &Args) -> OsString {
+    let mut flags = OsString::new();
+    for flag in cflags_list(args).iter().chain(&args.cflags) {
+        if !flags.is_empty() {
+            flags.push(" ");
+        }
+        flags.push(flag);
+    }
+    flags
+}
+
+/// Returns the architecture of the `target` triple, e.g., `x86_64`.
+pub fn target_arch(target: &str) -> &str {
+    target.split_once('-').map_or(target, |(arch, _)| arch)
+}
+
+/// Returns the clang `--target` to compile C code for the hyperlight `target` triple,
+/// the bare-metal ELF target of the same architecture.
+fn clang_target(target: &str) -> String {
+    format!("{}-unknown-none-elf", target_arch(target))
+}
+
+/// Returns the legacy clang `--target` for the hyperlight `target` triple, the freestanding
+/// linux target of the same architecture, which needs `__linux__` undefined.
+///
+/// This is a terrible hack, see
+/// https://github.com/hyperlight-dev/hyperlight/blob/main/src/hyperlight_guest_bin/build.rs#L80
+/// * we need stack clash protection, because the stack is right smack in the middle of
+///   everything in the guest
+/// * clang refuses to do stack clash protection unless it is required by a target ABI
+///   (Windows, MacOS) or the target is Linux or FreeBSD (see `RenderSCPOptions` in
+///   https://github.com/llvm/llvm-project/blob/1bb52e9/clang/lib/Driver/ToolChains/Clang.cpp#L3724)
+///
+/// Hopefully a flag to force stack clash protection on generic targets will
+/// eventually show up.
+fn legacy_clang_target(target: &str) -> String {
+    format!("{}-unknown-linux-none", target_arch(target))
+}
+
+/// Returns the flags selecting the clang target for the hyperlight `target` triple.
+///
+/// The bare-metal target is only used if `clang` does stack clash protection for it,
+/// otherwise it falls back to the legacy freestanding linux one.
+fn clang_target_flags(clang: Option<&Path>, target: &str) -> Vec<String> {
+    match clang {
+        Some(clang) if clang_supports_target(clang, target) => {
+            vec![format!("--target={}", clang_target(target))]
+        }
+        // without clang there's nothing to check, so use the target known to work
+        _ => vec![
+            format!("--target={}", legacy_clang_target(target)),
+            "-U__linux__".into(),
+        ],
+    }
+}
+
+/// Returns whether `clang` supports the bare-metal clang target for the hyperlight
+/// `target` triple, including stack clash protection.
+///
+/// Clang ignores `-fstack-clash-protection` on targets where it doesn't emit the stack
+/// probes, and reports it as an unused argument, which is turned into an error here.
+/// The result is cached, as this runs several times for every command.
+fn clang_supports_target(clang: &Path, target: &str) -> bool {
+    static SUPPORTED: Mutex<BTreeMap<(PathBuf, String), bool>> = Mutex::new(BTreeMap::new());
+
+    let key = (clang.to_path_buf(), target.to_string());
+    let mut supported = SUPPORTED.lock().unwrap_or_else(PoisonError::into_inner);
+    *supported.entry(key).or_insert_with(|| {
+        let clang_target = clang_target(target);
+        let probe = |flags: &[&str]| {
+            std::process::Command::new(clang)
+                .arg(format!("--target={clang_target}"))
+                .args(flags)
+                .args(["-ffreestanding", "-fsyntax-only", "-x", "c", "-"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+
+        if !probe(&[]) {
+            warning(
+                "clang-target",
+                format!(
+                    "{} doesn't support the {clang_target:?} target, falling back to {:?}",
+                    clang.display(),
+                    legacy_clang_target(target),
+                ),
+            );
+            return false;
+        }
+
+        // this is expected with current clang versions, so there's no warning
+        probe(&[
+            "-fstack-clash-protection",
+            "-Werror=unused-command-line-argument",
+        ])
+    })
+}
+
+/// Returns the flags used to compile C code for the hyperlight target.
+pub fn cflags_list(args: &Args) -> Vec<OsString> {
     const FLAGS: &[&str] = &[
-        // terrible hack, see
-        // https://github.com/hyperlight-dev/hyperlight/blob/main/src/hyperlight_guest_bin/build.rs#L80
-        "--target=x86_64-unknown-linux-none",
-        "-U__linux__",
         // We don't support stack protectors at the moment, but Arch Linux clang
         // auto-enables them for -linux platforms, so explicitly disable them.
         "-fno-stack-protector",
+        // the stack is in the middle of the guest's memory, see `legacy_clang_target`
         "-fstack-clash-protection",
+        "-nostdinc",
+    ];
+    const X86_64_FLAGS: &[&str] = &[
         "-mstack-probe-size=4096",
+        // We don't use a different stack for all interrupts, so there
+        // can be no red zone
         "-mno-red-zone",
-        "-nostdinc",
     ];
 
-    let mut flags = OsString::new();
-    for flag in FLAGS {
-        flags.push(flag);
-        flags.push(" ");
+    let mut flags = clang_target_flags(args.clang.as_deref(), &args.target)
+        .into_iter()
+        .map(OsString::from)
+        .collect::<Vec<_>>();
+    flags.extend(FLAGS.iter().map(OsString::from));
+    if args.target.starts_with("x86_64-") {
+        flags.extend(X86_64_FLAGS.iter().map(OsString::from));
+    }
+    if args.cross_language_lto {
+        // emit LLVM bitcode for the linker to optimize together with `-Clinker-plugin-lto`
+        flags.push("-flto=thin".into());
+    }
+    flags.extend(args.models.cflags().into_iter().map(OsString::from));
+    // user provided include directories take precedence over hyperlight's headers
+    for dir in args.include_dirs.iter().chain([&args.includes_dir()]) {
+        flags.push("-isystem".into());
+        flags.push(dir.into());
     }
-    flags.push(" ");
-    flags.push("-isystem");
-    flags.push(" ");
-    flags.push(args.includes_dir().as_os_str());
     flags
 }
 
@@ -131,3 +1045,161 @@ pub fn find_ar() -> Result<PathBuf> {
         .next()
         .context("Could not find 'ar' or 'llvm-ar' in PATH")
 }
+
+/// Finds a `program` given in the configuration, e.g., the linker, either a path
+/// relative to `cwd`, or a name to look up in `PATH`.
+pub fn find_program(
+    program: &Path,
+    cwd: &Path,
+    env: &HashMap<OsString, OsString>,
+) -> Option<PathBuf> {
+    let paths = env.get(OsStr::new("PATH"));
+    which::which_in(program, paths, cwd).ok()
+}
+
+/// Checks that the linker selected with `--linker` can link hyperlight guests.
+///
+/// The target spec uses the `gnu-lld` flavor and `-znostart-stop-gc`, which was added
+/// in LLD 13. With cross-language LTO the linker also has to read rustc's bitcode.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn check_linker(args: &Args) -> Result<()> {
+    const MIN_LLD_VERSION: u32 = 13;
+
+    let Some(linker) = &args.linker else {
+        // rust-lld always matches rustc
+        return Ok(());
+    };
+
+    ensure!(
+        linker.is_file(),
+        "Could not find the linker {}",
+        linker.display()
+    );
+
+    let output = std::process::Command::new(linker)
+        .arg("--version")
+        .checked_output()
+        .with_context(|| format!("Failed to get the version of {}", linker.display()))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let re = Regex::new(r"LLD (\d+)").unwrap();
+    let version: u32 = re
+        .captures(&output)
+        .and_then(|caps| caps[1].parse().ok())
+        .with_context(|| {
+            format!(
+                "{} is not LLD, hyperlight guests can only be linked with LLD",
+                linker.display()
+            )
+        })?;
+
+    ensure!(
+        version >= MIN_LLD_VERSION,
+        "{} is LLD {version}, but at least LLD {MIN_LLD_VERSION} is required",
+        linker.display(),
+    );
+
+    if args.cross_language_lto {
+        let rustc_version = rustc_llvm_version(&args.env)?;
+        ensure!(
+            version == rustc_version,
+            "Cross-language LTO requires the linker and rustc to use the same LLVM version, \
+             but rustc uses LLVM {rustc_version} and {} is LLD {version}",
+            linker.display(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Returns the output of `rustc -vV`.
+pub fn rustc_version_verbose(env: &HashMap<OsString, OsString>) -> Result<String> {
+    let rustc = env
+        .get(OsStr::new("RUSTC"))
+        .cloned()
+        .unwrap_or_else(|| "rustc".into());
+    let output = std::process::Command::new(&rustc)
+        .env_clear()
+        .envs(env.iter())
+        .arg("-vV")
+        .checked_output()
+        .context("Failed to get rustc version")?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the major LLVM version used by rustc, from the `LLVM version` line of
+/// `rustc -vV`.
+fn rustc_llvm_version(env: &HashMap<OsString, OsString>) -> Result<u32> {
+    let output = rustc_version_verbose(env)?;
+    let re = Regex::new(r"(?m)^LLVM version: (\d+)").unwrap();
+    re.captures(&output)
+        .and_then(|caps| caps[1].parse().ok())
+        .context("Failed to find the LLVM version in the rustc version")
+}
+
+/// Returns the major version of `clang`, from the output of `clang --version`.
+fn clang_version(clang: &Path) -> Result<u32> {
+    let output = std::process::Command::new(clang)
+        .arg("--version")
+        .checked_output()
+        .with_context(|| format!("Failed to get the version of {}", clang.display()))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let re = Regex::new(r"clang version (\d+)").unwrap();
+    re.captures(&output)
+        .and_then(|caps| caps[1].parse().ok())
+        .with_context(|| format!("Failed to find the version of {}", clang.display()))
+}
+
+/// Finds the clang and llvm-ar to use for cross-language LTO.
+///
+/// The linker can only read bitcode from the LLVM version it was built with, which
+/// for `rust-lld` is rustc's LLVM (see [`check_linker`] for other linkers), so this
+/// prefers the tools postfixed with rustc's LLVM major version, e.g., `clang-20`. GNU
+/// `ar` can't index bitcode objects, so only `llvm-ar` is considered. [`check_lto`]
+/// reports any mismatch when the sysroot is prepared.
+pub fn find_lto_tools(env: &HashMap<OsString, OsString>) -> (Option<PathBuf>, Option<PathBuf>) {
+    let find = |name: &str, version: Option<u32>| {
+        version
+            .and_then(|version| which::which(format!("{name}-{version}")).ok())
+            .or_else(|| which::which(name).ok())
+    };
+
+    let version = rustc_llvm_version(env).ok();
+    let clang = find("clang", version).or_else(|| find_cc().ok());
+    let ar = find("llvm-ar", version).or_else(|| find_ar().ok());
+    (clang, ar)
+}
+
+/// Checks that the C toolchain can take part in cross-language LTO with rustc.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip_all, fields(target = %args.target)))]
+pub fn check_lto(args: &Args) -> Result<()> {
+    if !args.cross_language_lto {
+        return Ok(());
+    }
+
+    let clang = args
+        .clang
+        .as_ref()
+        .context("Cross-language LTO requires clang, but it could not be found in PATH")?;
+    let rustc_version = rustc_llvm_version(&args.env)?;
+    let clang_version = clang_version(clang)?;
+    ensure!(
+        rustc_version == clang_version,
+        "Cross-language LTO requires clang and rustc to use the same LLVM version, \
+         but rustc uses LLVM {rustc_version} and {} is version {clang_version}\n\
+         Install clang-{rustc_version} and llvm-{rustc_version} to fix this",
+        clang.display(),
+    );
+
+    let is_llvm_ar = args.ar.as_ref().is_some_and(|ar| {
+        ar.file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("llvm-ar"))
+    });
+    ensure!(
+        is_llvm_ar,
+        "Cross-language LTO requires llvm-ar to archive bitcode objects, \
+         but it could not be found in PATH\n\
+         Install llvm-{rustc_version} to fix this",
+    );
+
+    Ok(())
+}
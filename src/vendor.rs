@@ -0,0 +1,143 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{Context, Result, ensure};
+
+use crate::cargo_cmd::{CargoCmd as _, cargo_cmd};
+use crate::cli::{Args, note};
+use crate::{atomic, metadata, setup, sysroot};
+
+// not containing the markers of `cargo hyperlight setup`, whose block can be in the same file
+const BEGIN_MARKER: &str = "# BEGIN vendored sources, managed by `cargo hyperlight vendor`";
+const END_MARKER: &str = "# END vendored sources";
+
+/// Returns the `Cargo.toml` of the standard library sources in the rust-src component,
+/// whose dependencies `-Zbuild-std` builds the sysroot with.
+fn library_manifest(args: &Args) -> Result<PathBuf> {
+    if let Some(toolchain) = args.env.get(OsStr::new("RUSTUP_TOOLCHAIN")) {
+        sysroot::ensure_rust_src(args, toolchain)?;
+    }
+
+    let rustc = args
+        .env
+        .get(OsStr::new("RUSTC"))
+        .cloned()
+        .unwrap_or_else(|| "rustc".into());
+    let output = std::process::Command::new(&rustc)
+        .env_clear()
+        .envs(args.env.iter())
+        .arg("--print")
+        .arg("sysroot")
+        .checked_output()
+        .context("Failed to get the rustc sysroot")?;
+    let rustc_sysroot = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+    let manifest = rustc_sysroot.join("lib/rustlib/src/rust/library/Cargo.toml");
+    ensure!(
+        manifest.is_file(),
+        "Could not find the standard library sources in {}, install the rust-src component",
+        rustc_sysroot.display()
+    );
+    Ok(manifest)
+}
+
+/// Returns the cargo-hyperlight block with the source replacement `config` printed by
+/// `cargo vendor`.
+fn config_block(config: &str) -> String {
+    format!(
+        "{BEGIN_MARKER}\n\
+        # Builds the guests, and their sysroot, from the vendored sources.\n\
+        {}\n\
+        {END_MARKER}\n",
+        config.trim()
+    )
+}
+
+/// Vendors the dependencies of the workspace and of the standard library into `path`,
+/// and writes the source replacement for them to the workspace's `.cargo/config.toml`,
+/// so that guests and their sysroot can be built offline.
+///
+/// The headers of `hyperlight-guest-bin` are part of its crate, so they are vendored
+/// with it. The rust-src component still has to be installed on the offline machine.
+pub fn vendor(args: &Args, path: &Path) -> Result<()> {
+    let metadata = metadata::cargo_metadata(args, &["--no-deps".into()])?;
+    let workspace_root = metadata["workspace_root"]
+        .as_str()
+        .map(PathBuf::from)
+        .context("Failed to parse cargo metadata workspace root")?;
+
+    // cargo vendor prints the directory as it's passed, and relative directories in the
+    // cargo config are relative to the directory containing `.cargo`
+    let path = args.current_dir.join(path);
+    let path = path.strip_prefix(&workspace_root).unwrap_or(&path);
+
+    let library = library_manifest(args)?;
+    let output = cargo_cmd(&args.env)?
+        .env_clear()
+        .envs(args.env.iter())
+        .current_dir(&workspace_root)
+        .arg("vendor")
+        .manifest_path(&args.manifest_path)
+        .arg("--sync")
+        .arg(&library)
+        .arg(path)
+        // the standard library's manifest uses unstable cargo features
+        .allow_unstable()
+        .stderr(Stdio::inherit())
+        .checked_output()
+        .context("Failed to vendor dependencies")?;
+    let block = config_block(&String::from_utf8_lossy(&output.stdout));
+
+    let config_path = workspace_root.join(".cargo").join("config.toml");
+    let current = match std::fs::read_to_string(&config_path) {
+        Ok(config) => config,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {config_path:?}")),
+    };
+    let tables = block
+        .lines()
+        .filter(|line| line.starts_with('['))
+        .collect::<Vec<_>>();
+    let config = setup::replace_block(
+        &current,
+        [BEGIN_MARKER, END_MARKER],
+        &tables,
+        &block,
+        "cargo hyperlight vendor",
+    )?;
+
+    std::fs::create_dir_all(config_path.parent().unwrap())
+        .context("Failed to create .cargo directory")?;
+    atomic::write(&config_path, config).context("Failed to write cargo config")?;
+    note(format!(
+        "vendored the sources to {}, and updated {}",
+        workspace_root.join(path).display(),
+        config_path.display()
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendor_config_is_a_replaceable_block() {
+        let vendored = "[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n\
+            [source.vendored-sources]\ndirectory = \"vendor\"\n";
+        let block = config_block(vendored);
+        assert!(block.starts_with(BEGIN_MARKER));
+        assert!(block.ends_with(&format!("directory = \"vendor\"\n{END_MARKER}\n")));
+
+        let tables = ["[source.crates-io]", "[source.vendored-sources]"];
+        let current = "[net]\noffline = true\n";
+        let config =
+            setup::replace_block(current, [BEGIN_MARKER, END_MARKER], &tables, &block, "").unwrap();
+        let again =
+            setup::replace_block(&config, [BEGIN_MARKER, END_MARKER], &tables, &block, "").unwrap();
+        assert_eq!(config, again);
+        assert!(config.starts_with(current));
+    }
+}